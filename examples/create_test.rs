@@ -19,8 +19,8 @@ extern crate embedded_sdmmc;
 const FILE_TO_CREATE: &'static str = "CREATE.TXT";
 
 use embedded_sdmmc::{
-    Block, BlockCount, BlockDevice, BlockIdx, Error, Mode, TimeSource, Timestamp, VolumeIdx,
-    VolumeManager,
+    Block, BlockCount, BlockDevice, BlockIdx, BlockNumber, Error, Mode, TimeSource, Timestamp,
+    VolumeIdx, VolumeManager,
 };
 use std::cell::RefCell;
 use std::fs::{File, OpenOptions};
@@ -90,7 +90,7 @@ impl BlockDevice for LinuxBlockDevice {
 
     fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
         let num_blocks = self.file.borrow().metadata().unwrap().len() / 512;
-        Ok(BlockCount(num_blocks as u32))
+        Ok(BlockCount(num_blocks as BlockNumber))
     }
 }
 
@@ -163,6 +163,10 @@ fn main() {
                 }
                 buffer.push(b'\n');
             }
+            println!("\nReserving space for file");
+            volume_mgr
+                .allocate(&mut volume, &mut f, 64 * 1024, false)
+                .unwrap();
             println!("\nAppending to file");
             let num_written1 = volume_mgr.write(&mut volume, &mut f, &buffer1[..]).unwrap();
             let num_written = volume_mgr.write(&mut volume, &mut f, &buffer[..]).unwrap();
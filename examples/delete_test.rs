@@ -19,8 +19,8 @@ extern crate embedded_sdmmc;
 const FILE_TO_DELETE: &'static str = "DELETE.TXT";
 
 use embedded_sdmmc::{
-    Block, BlockCount, BlockDevice, BlockIdx, Error, Mode, TimeSource, Timestamp, VolumeIdx,
-    VolumeManager,
+    Block, BlockCount, BlockDevice, BlockIdx, BlockNumber, Error, Mode, TimeSource, Timestamp,
+    VolumeIdx, VolumeManager,
 };
 use std::cell::RefCell;
 use std::fs::{File, OpenOptions};
@@ -90,7 +90,7 @@ impl BlockDevice for LinuxBlockDevice {
 
     fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
         let num_blocks = self.file.borrow().metadata().unwrap().len() / 512;
-        Ok(BlockCount(num_blocks as u32))
+        Ok(BlockCount(num_blocks as BlockNumber))
     }
 }
 
@@ -149,14 +149,14 @@ fn main() {
                 volume_mgr.find_directory_entry(&volume, &root_dir, FILE_TO_DELETE)
             );
 
-            match volume_mgr.delete_file_in_dir(&volume, &root_dir, FILE_TO_DELETE) {
+            match volume_mgr.delete_file_in_dir(&mut volume, &root_dir, FILE_TO_DELETE) {
                 Ok(()) => (),
                 Err(error) => println!("\tCannot delete file: {:?}", error),
             }
             println!("\tClosing {}...", FILE_TO_DELETE);
             volume_mgr.close_file(&volume, f).unwrap();
 
-            match volume_mgr.delete_file_in_dir(&volume, &root_dir, FILE_TO_DELETE) {
+            match volume_mgr.delete_file_in_dir(&mut volume, &root_dir, FILE_TO_DELETE) {
                 Ok(()) => println!("\tDeleted {}.", FILE_TO_DELETE),
                 Err(error) => println!("\tCannot delete {}: {:?}", FILE_TO_DELETE, error),
             }
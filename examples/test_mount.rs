@@ -27,8 +27,8 @@ const FILE_TO_PRINT: &'static str = "README.TXT";
 const FILE_TO_CHECKSUM: &'static str = "64MB.DAT";
 
 use embedded_sdmmc::{
-    Block, BlockCount, BlockDevice, BlockIdx, Error, Mode, TimeSource, Timestamp, VolumeIdx,
-    VolumeManager,
+    Block, BlockCount, BlockDevice, BlockIdx, BlockNumber, Error, Mode, TimeSource, Timestamp,
+    VolumeIdx, VolumeManager,
 };
 use std::cell::RefCell;
 use std::fs::File;
@@ -93,7 +93,7 @@ impl BlockDevice for LinuxBlockDevice {
 
     fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
         let num_blocks = self.file.borrow().metadata().unwrap().len() / 512;
-        Ok(BlockCount(num_blocks as u32))
+        Ok(BlockCount(num_blocks as BlockNumber))
     }
 }
 
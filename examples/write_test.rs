@@ -18,8 +18,8 @@ extern crate embedded_sdmmc;
 const FILE_TO_WRITE: &str = "README.TXT";
 
 use embedded_sdmmc::{
-    Block, BlockCount, BlockDevice, BlockIdx, Error, Mode, TimeSource, Timestamp, VolumeIdx,
-    VolumeManager,
+    Block, BlockCount, BlockDevice, BlockIdx, BlockNumber, Error, Mode, TimeSource, Timestamp,
+    VolumeIdx, VolumeManager,
 };
 use std::cell::RefCell;
 use std::fs::{File, OpenOptions};
@@ -89,7 +89,7 @@ impl BlockDevice for LinuxBlockDevice {
 
     fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
         let num_blocks = self.file.borrow().metadata().unwrap().len() / 512;
-        Ok(BlockCount(num_blocks as u32))
+        Ok(BlockCount(num_blocks as BlockNumber))
     }
 }
 
@@ -162,6 +162,7 @@ fn main() {
             let num_written1 = volume_mgr.write(&mut volume, &mut f, &buffer1[..]).unwrap();
             let num_written = volume_mgr.write(&mut volume, &mut f, &buffer[..]).unwrap();
             println!("Number of bytes written: {}\n", num_written + num_written1);
+            volume_mgr.flush_file(&mut volume, &f).unwrap();
 
             f.seek_from_start(0).unwrap();
             println!("\tFinding {}...", FILE_TO_WRITE);
@@ -194,10 +195,13 @@ fn main() {
                 )
                 .unwrap();
 
-            let buffer = b"Hello\n";
+            let buffer = b"Hello\nWorld\n";
             let num_written = volume_mgr.write(&mut volume, &mut f, &buffer[..]).unwrap();
             println!("\nNumber of bytes written: {}\n", num_written);
 
+            println!("\nTruncating file to 6 bytes");
+            volume_mgr.truncate(&mut volume, &mut f, 6).unwrap();
+
             println!("\tFinding {}...", FILE_TO_WRITE);
             println!(
                 "\tFound {}?: {:?}",
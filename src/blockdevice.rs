@@ -3,30 +3,46 @@
 //! Generic code for handling block devices, such as types for identifying
 //! a particular block on a block device by its index.
 
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
 /// Represents a standard 512 byte block (also known as a sector). IBM PC
 /// formatted 5.25" and 3.5" floppy disks, SD/MMC cards up to 1 GiB in size
 /// and IDE/SATA Hard Drives up to about 2 TiB all have 512 byte blocks.
 ///
-/// This library does not support devices with a block size other than 512
-/// bytes.
+/// This library always reads and writes 512 byte blocks. Devices with larger
+/// logical sectors can be used, as long as they can read and write each 512
+/// byte part of a sector - see `BlockDevice::sector_size`.
 #[derive(Clone)]
 pub struct Block {
     /// The 512 bytes in this block (or sector).
     pub contents: [u8; Block::LEN],
 }
 
+/// The integer type used for block addresses and counts. This is a `u32`,
+/// which allows for devices of up to 2 TiB, unless the `lba64` feature is
+/// enabled, in which case it is a `u64`.
+#[cfg(not(feature = "lba64"))]
+pub type BlockNumber = u32;
+
+/// The integer type used for block addresses and counts. This is a `u64`,
+/// because the `lba64` feature is enabled.
+#[cfg(feature = "lba64")]
+pub type BlockNumber = u64;
+
 /// Represents the linear numeric address of a block (or sector). The first
 /// block on a disk gets `BlockIdx(0)` (which usually contains the Master Boot
 /// Record).
 #[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
-pub struct BlockIdx(pub u32);
+pub struct BlockIdx(pub BlockNumber);
 
 /// Represents the a number of blocks (or sectors). Add this to a `BlockIdx`
 /// to get an actual address on disk.
 #[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
-pub struct BlockCount(pub u32);
+pub struct BlockCount(pub BlockNumber);
 
 /// An iterator returned from `Block::range`.
 pub struct BlockIter {
@@ -35,7 +51,8 @@ pub struct BlockIter {
 }
 
 /// Represents a block device - a device which can read and write blocks (or
-/// sectors). Only supports devices which are <= 2 TiB in size.
+/// sectors). Only supports devices which are <= 2 TiB in size, unless the
+/// `lba64` feature is enabled.
 pub trait BlockDevice {
     /// The errors that the `BlockDevice` can return. Must be debug formattable.
     type Error: core::fmt::Debug;
@@ -50,11 +67,156 @@ pub trait BlockDevice {
     fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error>;
     /// Determine how many blocks this device can hold.
     fn num_blocks(&self) -> Result<BlockCount, Self::Error>;
+    /// Get the size of the device's logical sectors in bytes. Partition
+    /// tables count in sectors, so we need to know this to find partitions
+    /// on devices with sectors larger than a `Block`. Only 512 (the default),
+    /// 1024, 2048 and 4096 byte sectors are supported.
+    ///
+    /// We still write a `Block` at a time, so a device with larger sectors
+    /// has to read, modify and write back a whole sector for every block
+    /// which doesn't cover it all. With 4096 byte sectors, a write of one
+    /// block costs a whole sector, and writing a run of blocks which starts
+    /// or ends part way through a sector means the sector at each end is
+    /// written twice. Flash wears out, so such devices are best given
+    /// writes that start and end on sector boundaries.
+    fn sector_size(&self) -> usize {
+        Block::LEN
+    }
+    /// Tell the device that the contents of some blocks are no longer
+    /// needed, so it can erase or forget them. Afterwards they may read back
+    /// as anything. The default does nothing.
+    fn discard(
+        &self,
+        _start_block_idx: BlockIdx,
+        _num_blocks: BlockCount,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    /// Find out which medium is in the device, as a number which changes
+    /// whenever the medium is changed (such as when an SD card is removed
+    /// and another is inserted), so that volumes opened on the old one can
+    /// be thrown away. Devices with removable media may take the chance to
+    /// start using a new one. The default is always 0.
+    fn media_generation(&self) -> Result<u32, Self::Error> {
+        Ok(0)
+    }
+}
+
+/// Represents a block device which is accessed asynchronously, like an SD
+/// card driven by DMA. This is the same as `BlockDevice`, except that reads
+/// and writes return a `Future`, so the executor can run other tasks while
+/// the device is busy. Use it with an `AsyncVolumeManager`.
+pub trait AsyncBlockDevice {
+    /// The errors that the `AsyncBlockDevice` can return. Must be debug
+    /// formattable.
+    type Error: core::fmt::Debug;
+    /// Read one or more blocks, starting at the given block index.
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        reason: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>>;
+    /// Write one or more blocks, starting at the given block index.
+    fn write(
+        &self,
+        blocks: &[Block],
+        start_block_idx: BlockIdx,
+    ) -> impl Future<Output = Result<(), Self::Error>>;
+    /// Determine how many blocks this device can hold.
+    fn num_blocks(&self) -> impl Future<Output = Result<BlockCount, Self::Error>>;
+    /// Get the size of the device's logical sectors in bytes. See
+    /// `BlockDevice::sector_size`.
+    fn sector_size(&self) -> usize {
+        Block::LEN
+    }
+    /// Tell the device that the contents of some blocks are no longer
+    /// needed. See `BlockDevice::discard`. The default does nothing.
+    fn discard(
+        &self,
+        _start_block_idx: BlockIdx,
+        _num_blocks: BlockCount,
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        async { Ok(()) }
+    }
+    /// Find out which medium is in the device. See
+    /// `BlockDevice::media_generation`. The default is always 0.
+    fn media_generation(&self) -> impl Future<Output = Result<u32, Self::Error>> {
+        async { Ok(0) }
+    }
+}
+
+/// Lets a `BlockDevice` be used as an `AsyncBlockDevice`. Every operation
+/// is complete the first time its `Future` is polled.
+pub(crate) struct Blocking<D>(pub(crate) D);
+
+impl<D> AsyncBlockDevice for Blocking<D>
+where
+    D: BlockDevice,
+{
+    type Error = D::Error;
+
+    async fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        reason: &str,
+    ) -> Result<(), Self::Error> {
+        self.0.read(blocks, start_block_idx, reason)
+    }
+
+    async fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.0.write(blocks, start_block_idx)
+    }
+
+    async fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        self.0.num_blocks()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.0.sector_size()
+    }
+
+    async fn discard(
+        &self,
+        start_block_idx: BlockIdx,
+        num_blocks: BlockCount,
+    ) -> Result<(), Self::Error> {
+        self.0.discard(start_block_idx, num_blocks)
+    }
+
+    async fn media_generation(&self) -> Result<u32, Self::Error> {
+        self.0.media_generation()
+    }
+}
+
+/// Run a `Future` which never has to wait, like one which only uses a
+/// `Blocking` device, to completion.
+pub(crate) fn block_on<F>(future: F) -> F::Output
+where
+    F: Future,
+{
+    fn clone(_data: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn noop(_data: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+    // Nothing ever wakes us, so we just poll until we're done
+    let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) };
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(result) = future.as_mut().poll(&mut context) {
+            return result;
+        }
+    }
 }
 
 impl Block {
-    /// All our blocks are a fixed length of 512 bytes. We do not support
-    /// 'Advanced Format' Hard Drives with 4 KiB blocks, nor weird old
+    /// All our blocks are a fixed length of 512 bytes. File systems with
+    /// larger sectors (like 'Advanced Format' Hard Drives with 4 KiB sectors)
+    /// are handled as several blocks per sector. We do not support weird old
     /// pre-3.5-inch floppy disk formats.
     pub const LEN: usize = 512;
 
@@ -166,7 +328,20 @@ impl BlockIdx {
     /// volume. Useful if your underlying block device actually works in
     /// bytes, like `open("/dev/mmcblk0")` does on Linux.
     pub fn into_bytes(self) -> u64 {
-        (u64::from(self.0)) * (Block::LEN as u64)
+        number::to_u64(self.0) * (Block::LEN as u64)
+    }
+
+    /// Get the block index as a `u32`, if it fits. Devices which take 32-bit
+    /// block addresses (like SD cards in SPI mode) can't reach any blocks
+    /// beyond that.
+    pub fn to_u32(self) -> Option<u32> {
+        number::to_u32(self.0)
+    }
+
+    /// Create a block index from a 64-bit Logical Block Address, if it fits
+    /// in a `BlockNumber`. It always does if the `lba64` feature is enabled.
+    pub fn from_u64(lba: u64) -> Option<BlockIdx> {
+        number::from_u64(lba).map(BlockIdx)
     }
 
     /// Create an iterator from the current `BlockIdx` through the given
@@ -180,7 +355,49 @@ impl BlockCount {
     /// Take a number of blocks and increment by the integer number of blocks
     /// required to get to the block that holds the byte at the given offset.
     pub fn offset_bytes(self, offset: u32) -> Self {
-        BlockCount(self.0 + (offset / Block::LEN_U32))
+        BlockCount(self.0 + (offset / Block::LEN_U32) as BlockNumber)
+    }
+
+    /// Create a block count from a 64-bit number of blocks, if it fits in a
+    /// `BlockNumber`. It always does if the `lba64` feature is enabled.
+    pub fn from_u64(num_blocks: u64) -> Option<BlockCount> {
+        number::from_u64(num_blocks).map(BlockCount)
+    }
+}
+
+/// Conversions between `BlockNumber` and the fixed size integer types.
+#[cfg(not(feature = "lba64"))]
+mod number {
+    use core::convert::TryFrom;
+
+    pub(super) fn to_u64(n: u32) -> u64 {
+        u64::from(n)
+    }
+
+    pub(super) fn to_u32(n: u32) -> Option<u32> {
+        Some(n)
+    }
+
+    pub(super) fn from_u64(n: u64) -> Option<u32> {
+        u32::try_from(n).ok()
+    }
+}
+
+/// Conversions between `BlockNumber` and the fixed size integer types.
+#[cfg(feature = "lba64")]
+mod number {
+    use core::convert::TryFrom;
+
+    pub(super) fn to_u64(n: u64) -> u64 {
+        n
+    }
+
+    pub(super) fn to_u32(n: u64) -> Option<u32> {
+        u32::try_from(n).ok()
+    }
+
+    pub(super) fn from_u64(n: u64) -> Option<u64> {
+        Some(n)
     }
 }
 
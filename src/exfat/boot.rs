@@ -0,0 +1,121 @@
+//! exFAT Boot Sector
+
+use crate::blockdevice::{Block, BlockCount, BlockIdx, BlockNumber};
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Represents the Main (or Backup) Boot Sector. This is the first sector of
+/// an exFAT formatted partition, and it describes where the FAT, the cluster
+/// heap and the root directory are.
+///
+/// The fields count in sectors of `bytes_per_sector` bytes, which can be
+/// larger than a `Block`. The functions which return a `BlockCount` convert
+/// to `Block`s for you.
+pub struct BootSector<'a> {
+    data: &'a [u8; 512],
+}
+
+impl<'a> BootSector<'a> {
+    pub(crate) const FOOTER_VALUE: u16 = 0xAA55;
+
+    /// The number of sectors in a boot region, including the checksum
+    /// sector.
+    pub(crate) const REGION_SECTORS: BlockNumber = 12;
+
+    /// The number of sectors in a boot region covered by the checksum.
+    pub(crate) const CHECKSUMMED_SECTORS: BlockNumber = 11;
+
+    /// Attempt to parse an exFAT Boot Sector from a 512 byte sector.
+    pub fn create_from_bytes(data: &[u8; 512]) -> Result<BootSector<'_>, &'static str> {
+        let boot = BootSector { data };
+        if boot.footer() != Self::FOOTER_VALUE {
+            return Err("Bad boot sector footer");
+        }
+        if boot.file_system_name() != b"EXFAT   " {
+            return Err("Not an exFAT boot sector");
+        }
+        if boot.data[11..64].iter().any(|b| *b != 0) {
+            return Err("exFAT boot sector has a BIOS Parameter Block");
+        }
+        if boot.file_system_revision() >> 8 != 1 {
+            return Err("Unsupported exFAT revision");
+        }
+        if !(9..=12).contains(&boot.bytes_per_block_shift())
+            || boot.blocks_per_cluster_shift() > 25 - boot.bytes_per_block_shift()
+            || boot.number_of_fats() == 0
+            || boot.number_of_fats() > 2
+        {
+            return Err("Invalid exFAT boot sector");
+        }
+        Ok(boot)
+    }
+
+    define_field!(partition_offset, u64, 64);
+    define_field!(volume_length, u64, 72);
+    define_field!(fat_offset, u32, 80);
+    define_field!(fat_length, u32, 84);
+    define_field!(cluster_heap_offset, u32, 88);
+    define_field!(cluster_count, u32, 92);
+    define_field!(first_cluster_of_root_directory, u32, 96);
+    define_field!(volume_serial_number, u32, 100);
+    define_field!(file_system_revision, u16, 104);
+    define_field!(volume_flags, u16, 106);
+    define_field!(bytes_per_block_shift, u8, 108);
+    define_field!(blocks_per_cluster_shift, u8, 109);
+    define_field!(number_of_fats, u8, 110);
+    define_field!(drive_select, u8, 111);
+    define_field!(percent_in_use, u8, 112);
+    define_field!(footer, u16, 510);
+
+    /// Get the file system name, which is always `"EXFAT   "`.
+    pub fn file_system_name(&self) -> &[u8] {
+        &self.data[3..11]
+    }
+
+    /// Get the size of a sector in bytes.
+    pub fn bytes_per_sector(&self) -> u32 {
+        1 << self.bytes_per_block_shift()
+    }
+
+    /// Get the number of `Block`s in each sector.
+    pub fn blocks_per_sector(&self) -> u32 {
+        self.bytes_per_sector() / Block::LEN_U32
+    }
+
+    /// Get the size of a cluster in `Block`s.
+    pub fn blocks_per_cluster(&self) -> u32 {
+        self.blocks_per_sector() << self.blocks_per_cluster_shift()
+    }
+
+    /// Which of the two FATs (and allocation bitmaps) is in use. Only
+    /// volumes with two FATs use the second.
+    pub fn active_fat(&self) -> u8 {
+        (self.volume_flags() & 0x0001) as u8
+    }
+
+    /// Where the active FAT starts, relative to the start of the volume.
+    pub fn fat_start(&self) -> BlockCount {
+        let sector = self.fat_offset() + (u32::from(self.active_fat()) * self.fat_length());
+        self.sectors(sector)
+    }
+
+    /// Where the cluster heap starts, relative to the start of the volume.
+    pub fn first_data_block(&self) -> BlockCount {
+        self.sectors(self.cluster_heap_offset())
+    }
+
+    /// Converts a number of sectors into a number of `Block`s.
+    fn sectors(&self, sectors: u32) -> BlockCount {
+        BlockCount(sectors as BlockNumber * BlockNumber::from(self.blocks_per_sector()))
+    }
+
+    /// Where the boot region with the given index (0 for the Main Boot
+    /// Region, 1 for the Backup) starts on the disk.
+    pub(crate) fn region_start(
+        lba_start: BlockIdx,
+        region: u32,
+        blocks_per_sector: u32,
+    ) -> BlockIdx {
+        let sectors = BlockNumber::from(region) * Self::REGION_SECTORS;
+        lba_start + BlockCount(sectors * BlockNumber::from(blocks_per_sector))
+    }
+}
@@ -0,0 +1,344 @@
+//! exFAT directory entry sets
+
+use crate::{exfat::checksum16, Attributes, BlockIdx, Cluster, DirEntry, ShortFileName, Timestamp};
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The length of a directory entry, in bytes
+pub(crate) const ENTRY_LEN: usize = 32;
+
+/// Set in the type of every directory entry which is in use
+pub(crate) const TYPE_IN_USE: u8 = 0x80;
+/// Set in the type of secondary directory entries
+pub(crate) const TYPE_SECONDARY: u8 = 0x40;
+/// The end of the directory
+pub(crate) const TYPE_END: u8 = 0x00;
+/// Allocation Bitmap entry
+pub(crate) const TYPE_BITMAP: u8 = 0x81;
+/// Up-case Table entry
+pub(crate) const TYPE_UPCASE: u8 = 0x82;
+/// Volume Label entry
+pub(crate) const TYPE_LABEL: u8 = 0x83;
+/// File entry, the first of each file or directory's entry set
+pub(crate) const TYPE_FILE: u8 = 0x85;
+/// Stream Extension entry, which says where the data is
+pub(crate) const TYPE_STREAM: u8 = 0xC0;
+/// File Name entry, holding up to 15 characters of the name
+pub(crate) const TYPE_NAME: u8 = 0xC1;
+
+/// The Stream Extension flag saying the file may have clusters
+pub(crate) const FLAG_ALLOCATION_POSSIBLE: u8 = 0x01;
+/// The Stream Extension flag saying the clusters are contiguous and the FAT
+/// is not used
+pub(crate) const FLAG_NO_FAT_CHAIN: u8 = 0x02;
+
+/// The longest name a file can have, in UTF-16 code units
+pub(crate) const MAX_NAME_LEN: usize = 255;
+/// The number of UTF-16 code units in each File Name entry
+pub(crate) const NAME_CHARS_PER_ENTRY: usize = 15;
+/// The most secondary entries we can rewrite. This is enough for a Stream
+/// Extension and a 255 character name, which always fits in three blocks.
+pub(crate) const MAX_SECONDARY_COUNT: u8 = 18;
+
+// Offsets within a File entry
+pub(crate) const FILE_SECONDARY_COUNT: usize = 1;
+pub(crate) const FILE_SET_CHECKSUM: usize = 2;
+pub(crate) const FILE_ATTRIBUTES: usize = 4;
+pub(crate) const FILE_CREATE_TIMESTAMP: usize = 8;
+pub(crate) const FILE_MODIFY_TIMESTAMP: usize = 12;
+pub(crate) const FILE_ACCESS_TIMESTAMP: usize = 16;
+pub(crate) const FILE_CREATE_10MS: usize = 20;
+pub(crate) const FILE_UTC_OFFSETS: usize = 22;
+
+// Offsets within a Stream Extension entry
+pub(crate) const STREAM_FLAGS: usize = 1;
+pub(crate) const STREAM_NAME_LENGTH: usize = 3;
+pub(crate) const STREAM_NAME_HASH: usize = 4;
+pub(crate) const STREAM_VALID_DATA_LENGTH: usize = 8;
+pub(crate) const STREAM_FIRST_CLUSTER: usize = 20;
+pub(crate) const STREAM_DATA_LENGTH: usize = 24;
+
+/// The exFAT specific parts of a directory entry.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) struct ExFatEntryInfo {
+    /// How many entries follow the File entry in this set
+    pub(crate) secondary_count: u8,
+    /// The blocks after `DirEntry::entry_block` this set runs on to, if any
+    pub(crate) next_blocks: [Option<BlockIdx>; 2],
+    /// The clusters are contiguous, and are not recorded in the FAT
+    pub(crate) no_fat_chain: bool,
+    /// How much of the file has been written. Anything beyond this reads as
+    /// zero.
+    pub(crate) valid_length: u64,
+    /// The length of the file
+    pub(crate) data_length: u64,
+}
+
+impl ExFatEntryInfo {
+    /// The block holding entry `idx` of the set, and the offset of the entry
+    /// within that block.
+    pub(crate) fn entry_position(&self, entry: &DirEntry, idx: usize) -> Option<(BlockIdx, usize)> {
+        let per_block = crate::Block::LEN / ENTRY_LEN;
+        let position = (entry.entry_offset as usize / ENTRY_LEN) + idx;
+        let block = match position / per_block {
+            0 => entry.entry_block,
+            n => self.next_blocks.get(n - 1).copied().flatten()?,
+        };
+        Some((block, (position % per_block) * ENTRY_LEN))
+    }
+}
+
+/// A directory entry set, assembled from the entries in a directory.
+pub(crate) struct EntrySet {
+    /// The first cluster of the file, or zero if it has none
+    pub(crate) first_cluster: u32,
+    /// The file's attributes
+    pub(crate) attributes: u16,
+    /// When the file was created
+    pub(crate) create_timestamp: u32,
+    /// When the file was last modified
+    pub(crate) modify_timestamp: u32,
+    /// The hash of the up-cased name
+    pub(crate) name_hash: u16,
+    /// The file name, as UTF-16
+    pub(crate) name: [u16; MAX_NAME_LEN],
+    /// How many UTF-16 code units of `name` are used
+    pub(crate) name_len: usize,
+    /// The block holding the File entry
+    pub(crate) entry_block: BlockIdx,
+    /// The offset of the File entry within `entry_block`
+    pub(crate) entry_offset: u32,
+    /// Everything else we need to remember in a `DirEntry`
+    pub(crate) info: ExFatEntryInfo,
+}
+
+impl EntrySet {
+    /// The file name, as UTF-16
+    pub(crate) fn name(&self) -> &[u16] {
+        &self.name[0..self.name_len]
+    }
+
+    /// Convert the entry set into a `DirEntry`.
+    pub(crate) fn to_dir_entry(&self) -> DirEntry {
+        DirEntry {
+            name: short_name(self.name()),
+            mtime: timestamp(self.modify_timestamp),
+            ctime: timestamp(self.create_timestamp),
+            attributes: Attributes::create_from_fat(self.attributes as u8),
+            cluster: Cluster(self.first_cluster),
+            size: self.info.data_length,
+            entry_block: self.entry_block,
+            entry_offset: self.entry_offset,
+            exfat: Some(self.info.clone()),
+        }
+    }
+}
+
+/// Assembles entry sets from the entries of a directory, one at a time,
+/// checking the set checksum as it goes.
+pub(crate) struct EntrySetParser {
+    set: EntrySet,
+    /// We are part way through a set
+    active: bool,
+    /// How many secondary entries we have seen
+    seen: u8,
+    /// How long the Stream Extension says the name is
+    name_length: usize,
+    checksum: u16,
+    expected_checksum: u16,
+}
+
+impl EntrySetParser {
+    pub(crate) fn new() -> EntrySetParser {
+        EntrySetParser {
+            set: EntrySet {
+                first_cluster: 0,
+                attributes: 0,
+                create_timestamp: 0,
+                modify_timestamp: 0,
+                name_hash: 0,
+                name: [0; MAX_NAME_LEN],
+                name_len: 0,
+                entry_block: BlockIdx(0),
+                entry_offset: 0,
+                info: ExFatEntryInfo {
+                    secondary_count: 0,
+                    next_blocks: [None; 2],
+                    no_fat_chain: false,
+                    valid_length: 0,
+                    data_length: 0,
+                },
+            },
+            active: false,
+            seen: 0,
+            name_length: 0,
+            checksum: 0,
+            expected_checksum: 0,
+        }
+    }
+
+    /// Add the next entry in the directory, which is at `offset` within
+    /// `block`. Returns the set once its last entry has been added, if the
+    /// set is intact.
+    pub(crate) fn push(&mut self, data: &[u8], block: BlockIdx, offset: u32) -> Option<&EntrySet> {
+        let entry_type = data[0];
+        if entry_type == TYPE_FILE {
+            let secondary_count = data[FILE_SECONDARY_COUNT];
+            self.active = secondary_count >= 2;
+            self.seen = 0;
+            self.name_length = 0;
+            self.checksum = checksum16(0, data, true);
+            self.expected_checksum = LittleEndian::read_u16(&data[FILE_SET_CHECKSUM..]);
+            let set = &mut self.set;
+            set.attributes = LittleEndian::read_u16(&data[FILE_ATTRIBUTES..]);
+            set.create_timestamp = LittleEndian::read_u32(&data[FILE_CREATE_TIMESTAMP..]);
+            set.modify_timestamp = LittleEndian::read_u32(&data[FILE_MODIFY_TIMESTAMP..]);
+            set.name_len = 0;
+            set.entry_block = block;
+            set.entry_offset = offset;
+            set.info.secondary_count = secondary_count;
+            set.info.next_blocks = [None; 2];
+            return None;
+        }
+        if !self.active {
+            return None;
+        }
+        if (entry_type & (TYPE_IN_USE | TYPE_SECONDARY)) != (TYPE_IN_USE | TYPE_SECONDARY) {
+            // Set ended early
+            self.active = false;
+            return None;
+        }
+        self.seen += 1;
+        self.checksum = checksum16(self.checksum, data, false);
+        let set = &mut self.set;
+        if block != set.entry_block && !set.info.next_blocks.contains(&Some(block)) {
+            if let Some(slot) = set.info.next_blocks.iter_mut().find(|b| b.is_none()) {
+                *slot = Some(block);
+            }
+        }
+        if self.seen == 1 {
+            if entry_type != TYPE_STREAM {
+                self.active = false;
+                return None;
+            }
+            let flags = data[STREAM_FLAGS];
+            set.info.no_fat_chain = (flags & FLAG_NO_FAT_CHAIN) != 0;
+            set.name_hash = LittleEndian::read_u16(&data[STREAM_NAME_HASH..]);
+            set.info.valid_length = LittleEndian::read_u64(&data[STREAM_VALID_DATA_LENGTH..]);
+            set.first_cluster = LittleEndian::read_u32(&data[STREAM_FIRST_CLUSTER..]);
+            set.info.data_length = LittleEndian::read_u64(&data[STREAM_DATA_LENGTH..]);
+            self.name_length = usize::from(data[STREAM_NAME_LENGTH]);
+        } else if entry_type == TYPE_NAME {
+            let count = core::cmp::min(NAME_CHARS_PER_ENTRY, self.name_length - set.name_len);
+            for idx in 0..count {
+                set.name[set.name_len] = LittleEndian::read_u16(&data[2 + (idx * 2)..]);
+                set.name_len += 1;
+            }
+        }
+        if self.seen == set.info.secondary_count {
+            self.active = false;
+            if self.checksum == self.expected_checksum
+                && set.name_len == self.name_length
+                && set.name_len != 0
+            {
+                return Some(&self.set);
+            }
+        }
+        None
+    }
+}
+
+/// Convert an exFAT timestamp into a `Timestamp`.
+pub(crate) fn timestamp(value: u32) -> Timestamp {
+    Timestamp::from_fat((value >> 16) as u16, value as u16)
+}
+
+/// Can this UTF-16 code unit appear in an exFAT file name?
+pub(crate) fn is_valid_name_char(ch: u16) -> bool {
+    !matches!(
+        ch,
+        0x0000
+            ..=0x001F
+                | 0x0022
+                | 0x002A
+                | 0x002F
+                | 0x003A
+                | 0x003C
+                | 0x003E
+                | 0x003F
+                | 0x005C
+                | 0x007C
+    )
+}
+
+/// Adds an up-cased character to an exFAT name hash.
+pub(crate) fn name_hash(mut hash: u16, upcased: u16) -> u16 {
+    for b in upcased.to_le_bytes().iter() {
+        hash = hash.rotate_right(1).wrapping_add(u16::from(*b));
+    }
+    hash
+}
+
+/// Make up an MS-DOS 8.3 name for a long file name, so it can be given in a
+/// `DirEntry`.
+///
+/// The name is upper-cased, and characters which can't appear in a short
+/// name are replaced with underscores. If anything had to be changed or
+/// dropped (other than the case), the base name is cut to six characters
+/// and `~1` is added, as Windows would do. Two long names can get the same
+/// short name, so files are never looked up by it.
+pub(crate) fn short_name(name: &[u16]) -> ShortFileName {
+    const BASE_LEN: usize = 8;
+    const EXT_LEN: usize = 3;
+    let mut contents = [b' '; BASE_LEN + EXT_LEN];
+    let (base, ext) = match name.iter().rposition(|ch| *ch == u16::from(b'.')) {
+        Some(0) | None => (name, &name[0..0]),
+        Some(idx) => (&name[0..idx], &name[idx + 1..]),
+    };
+    let mut lossy = false;
+    let base_len = convert_short(base, &mut contents[0..BASE_LEN], &mut lossy);
+    convert_short(ext, &mut contents[BASE_LEN..], &mut lossy);
+    if base_len == 0 {
+        contents[0] = b'_';
+        lossy = true;
+    }
+    if lossy {
+        let tail = core::cmp::min(base_len.max(1), BASE_LEN - 2);
+        contents[tail] = b'~';
+        contents[tail + 1] = b'1';
+        for b in contents[tail + 2..BASE_LEN].iter_mut() {
+            *b = b' ';
+        }
+    }
+    ShortFileName { contents }
+}
+
+/// Copy as much of `name` as will fit into `out`, as short name characters.
+/// Returns how many characters were used.
+fn convert_short(name: &[u16], out: &mut [u8], lossy: &mut bool) -> usize {
+    let mut len = 0;
+    for ch in name.iter() {
+        let ch = match *ch {
+            // Spaces and dots are dropped from short names
+            0x20 | 0x2E => {
+                *lossy = true;
+                continue;
+            }
+            ch @ 0x61..=0x7A => ch as u8 - 32,
+            ch @ 0x21..=0x7E if !matches!(ch, 0x22 | 0x2A..=0x2C | 0x2F | 0x3A..=0x3F | 0x5B..=0x5D | 0x7C) => {
+                ch as u8
+            }
+            _ => {
+                *lossy = true;
+                b'_'
+            }
+        };
+        if len == out.len() {
+            *lossy = true;
+            break;
+        }
+        out[len] = ch;
+        len += 1;
+    }
+    len
+}
@@ -0,0 +1,162 @@
+//! embedded-sdmmc-rs - exFAT file system implementation
+//!
+//! Implements the Extended File Allocation Table file system, as used on
+//! SDXC cards. Files and directories are described by sets of directory
+//! entries (a File entry, a Stream Extension entry and one or more File Name
+//! entries), free space is tracked in an allocation bitmap, and long file
+//! names are compared case-insensitively using the volume's up-case table.
+
+/// The FAT entry which marks the end of a cluster chain
+pub(crate) const END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+
+/// The FAT entry which marks a bad cluster
+pub(crate) const BAD_CLUSTER: u32 = 0xFFFF_FFF7;
+
+mod boot;
+mod direntry;
+mod volume;
+
+pub use boot::BootSector;
+pub(crate) use direntry::ExFatEntryInfo;
+pub use volume::{parse_volume, ExFatVolume};
+
+/// Adds `data` to a 32-bit exFAT checksum, as used for the boot region and
+/// the up-case table.
+pub(crate) fn checksum32(mut checksum: u32, data: &[u8]) -> u32 {
+    for b in data {
+        checksum = checksum.rotate_right(1).wrapping_add(u32::from(*b));
+    }
+    checksum
+}
+
+/// Adds a 32 byte directory entry to a 16-bit exFAT entry set checksum. The
+/// checksum field itself (bytes 2 and 3 of the first entry) is skipped.
+pub(crate) fn checksum16(mut checksum: u16, entry: &[u8], is_first: bool) -> u16 {
+    for (idx, b) in entry.iter().enumerate() {
+        if is_first && (idx == 2 || idx == 3) {
+            continue;
+        }
+        checksum = checksum.rotate_right(1).wrapping_add(u16::from(*b));
+    }
+    checksum
+}
+
+#[cfg(test)]
+mod test {
+    use super::direntry::{name_hash, short_name, EntrySetParser};
+    use super::*;
+    use crate::{BlockIdx, ShortFileName, Timestamp};
+
+    fn utf16(name: &str) -> ([u16; 255], usize) {
+        let mut buffer = [0u16; 255];
+        let mut len = 0;
+        for ch in name.encode_utf16() {
+            buffer[len] = ch;
+            len += 1;
+        }
+        (buffer, len)
+    }
+
+    #[test]
+    fn table_checksum() {
+        assert_eq!(checksum32(0, &[]), 0);
+        assert_eq!(checksum32(0, &[1]), 1);
+        // 1 rotates round to the top bit
+        assert_eq!(checksum32(0, &[1, 0]), 0x8000_0000);
+        assert_eq!(checksum32(0, &[1, 0, 2]), 0x4000_0002);
+        // Checksums can be calculated a piece at a time
+        let data = [0x12, 0x34, 0x56, 0x78, 0x9A];
+        assert_eq!(
+            checksum32(checksum32(0, &data[0..2]), &data[2..]),
+            checksum32(0, &data)
+        );
+    }
+
+    #[test]
+    fn set_checksum() {
+        let mut entry = [0u8; 32];
+        entry[0] = 0x85;
+        entry[1] = 2;
+        let checksum = checksum16(0, &entry, true);
+        // Storing the checksum in the entry doesn't change it
+        entry[2..4].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(checksum16(0, &entry, true), checksum);
+        // But it does if this isn't the first entry of the set
+        assert_ne!(checksum16(0, &entry, false), checksum);
+        assert_eq!(checksum16(0, &[1, 0], false), 0x8000);
+    }
+
+    #[test]
+    fn short_names() {
+        let cases = [
+            ("README.TXT", "README.TXT"),
+            ("readme.txt", "README.TXT"),
+            ("Makefile", "MAKEFILE"),
+            ("Café.txt", "CAF_~1.TXT"),
+            ("A very long name.text", "AVERYL~1.TEX"),
+            ("archive.tar.gz", "ARCHIV~1.GZ"),
+            (".profile", "PROFIL~1"),
+        ];
+        for (long, short) in cases.iter() {
+            let (buffer, len) = utf16(long);
+            assert_eq!(
+                short_name(&buffer[0..len]),
+                ShortFileName::create_from_str(short).unwrap(),
+                "{}",
+                long
+            );
+        }
+    }
+
+    #[test]
+    fn name_hashes() {
+        // Hashes are of the up-cased name
+        let (buffer, len) = utf16("README.TXT");
+        let hash = buffer[0..len]
+            .iter()
+            .fold(0, |hash, ch| name_hash(hash, *ch));
+        assert_eq!(hash, 0xEB26);
+    }
+
+    #[test]
+    fn parse_entry_set() {
+        const SET: [u8; 96] = hex!(
+            "8502a162200000005c64b1565c64c1565c64c256000000000000000000000000
+             c001000ff8eb0000d2040000000000000000000005000000d204000000000000
+             c100480065006c006c006f00200077006f0072006c0064002e00740078007400"
+        );
+        let mut parser = EntrySetParser::new();
+        assert!(parser.push(&SET[0..32], BlockIdx(7), 448).is_none());
+        assert!(parser.push(&SET[32..64], BlockIdx(7), 480).is_none());
+        // The set runs on to the next block
+        let set = parser.push(&SET[64..96], BlockIdx(8), 0).unwrap();
+        let (buffer, len) = utf16("Hello world.txt");
+        assert_eq!(set.name(), &buffer[0..len]);
+        let entry = set.to_dir_entry();
+        assert_eq!(
+            entry.name,
+            ShortFileName::create_from_str("HELLOW~1.TXT").unwrap()
+        );
+        assert_eq!(entry.cluster.0, 5);
+        assert_eq!(entry.size, 1234);
+        assert_eq!(
+            entry.mtime,
+            Timestamp::from_calendar(2023, 6, 1, 12, 34, 56).unwrap()
+        );
+        assert_eq!(entry.entry_block, BlockIdx(7));
+        assert_eq!(entry.entry_offset, 448);
+        let info = entry.exfat.as_ref().unwrap();
+        assert_eq!(info.next_blocks, [Some(BlockIdx(8)), None]);
+        assert_eq!(info.entry_position(&entry, 1), Some((BlockIdx(7), 480)));
+        assert_eq!(info.entry_position(&entry, 2), Some((BlockIdx(8), 0)));
+        assert!(!info.no_fat_chain);
+
+        // A damaged set is skipped
+        let mut damaged = SET;
+        damaged[70] = b'J';
+        let mut parser = EntrySetParser::new();
+        for (idx, entry) in damaged.chunks(32).enumerate() {
+            assert!(parser.push(entry, BlockIdx(0), idx as u32 * 32).is_none());
+        }
+    }
+}
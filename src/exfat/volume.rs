@@ -0,0 +1,1776 @@
+//! exFAT volume
+
+#[cfg(feature = "log")]
+use log::{debug, trace, warn};
+
+#[cfg(feature = "defmt-log")]
+use defmt::{debug, trace, warn};
+
+use crate::{
+    exfat::{
+        checksum16, checksum32,
+        direntry::{
+            is_valid_name_char, name_hash, short_name, EntrySetParser, ENTRY_LEN,
+            FILE_ACCESS_TIMESTAMP, FILE_ATTRIBUTES, FILE_CREATE_10MS, FILE_CREATE_TIMESTAMP,
+            FILE_MODIFY_TIMESTAMP, FILE_SECONDARY_COUNT, FILE_SET_CHECKSUM, FILE_UTC_OFFSETS,
+            FLAG_ALLOCATION_POSSIBLE, FLAG_NO_FAT_CHAIN, MAX_NAME_LEN, MAX_SECONDARY_COUNT,
+            NAME_CHARS_PER_ENTRY, STREAM_DATA_LENGTH, STREAM_FIRST_CLUSTER, STREAM_FLAGS,
+            STREAM_NAME_HASH, STREAM_NAME_LENGTH, STREAM_VALID_DATA_LENGTH, TYPE_BITMAP, TYPE_END,
+            TYPE_FILE, TYPE_IN_USE, TYPE_LABEL, TYPE_NAME, TYPE_STREAM, TYPE_UPCASE,
+        },
+        BootSector, ExFatEntryInfo, BAD_CLUSTER, END_OF_CHAIN,
+    },
+    fat::{VolumeName, RESERVED_ENTRIES},
+    AllocationPolicy, AsyncBlockDevice, AsyncVolumeManager, Attributes, Block, BlockCount,
+    BlockIdx, BlockNumber, Cluster, DirEntry, Directory, Error, FilenameError, TimeSource,
+    VolumeType,
+};
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Identifies an exFAT Volume on the disk.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(PartialEq, Eq, Debug)]
+pub struct ExFatVolume {
+    /// The block number of the start of the partition. All other BlockIdx values are relative to this.
+    pub(crate) lba_start: BlockIdx,
+    /// The number of blocks in this volume
+    pub(crate) num_blocks: BlockCount,
+    /// The name of this volume
+    pub(crate) name: VolumeName,
+    /// Number of 512 byte blocks (or Blocks) in a cluster
+    pub(crate) blocks_per_cluster: u32,
+    /// The block the cluster heap starts in. Relative to start of partition
+    /// (so add `self.lba_offset` before passing to volume manager)
+    pub(crate) first_data_block: BlockCount,
+    /// The block the active FAT starts in. Relative to start of partition
+    /// (so add `self.lba_offset` before passing to volume manager)
+    pub(crate) fat_start: BlockCount,
+    /// Total number of clusters
+    pub(crate) cluster_count: u32,
+    /// The first cluster of the root directory
+    pub(crate) root_dir_cluster: Cluster,
+    /// The first cluster of the allocation bitmap, which is contiguous
+    pub(crate) bitmap_cluster: Cluster,
+    /// The first cluster of the up-case table, which is contiguous
+    pub(crate) upcase_cluster: Cluster,
+    /// The length of the up-case table in bytes
+    pub(crate) upcase_length: u32,
+    /// The up-case table maps `a`-`z` to `A`-`Z` and leaves the rest of
+    /// ASCII alone, so we don't need to look ASCII characters up
+    pub(crate) ascii_upcase: bool,
+    /// How full the volume is, in percent, or 0xFF if unknown
+    pub(crate) percent_in_use: u8,
+    /// Number of the next expected free cluster
+    pub(crate) next_free_cluster: Option<Cluster>,
+}
+
+/// Tracks a run of unused directory entries, long enough for a new entry
+/// set.
+struct FreeEntries {
+    /// The first unused entry in the run
+    start: Option<(BlockIdx, u32)>,
+    /// The blocks the run covers, in order
+    blocks: [BlockIdx; 3],
+    /// How many of `blocks` are in use
+    num_blocks: usize,
+    /// How many unused entries are in the run
+    count: usize,
+}
+
+impl FreeEntries {
+    fn add(&mut self, block: BlockIdx, offset: u32) {
+        if self.start.is_none() {
+            self.start = Some((block, offset));
+            self.num_blocks = 0;
+        }
+        if self.num_blocks == 0 || self.blocks[self.num_blocks - 1] != block {
+            // A set is never longer than three blocks
+            if self.num_blocks < self.blocks.len() {
+                self.blocks[self.num_blocks] = block;
+                self.num_blocks += 1;
+            }
+        }
+        self.count += 1;
+    }
+
+    fn reset(&mut self) {
+        self.start = None;
+        self.num_blocks = 0;
+        self.count = 0;
+    }
+}
+
+/// Where we've got to in reading a directory.
+struct DirReader {
+    /// The cluster we're reading
+    cluster: Cluster,
+    /// How many clusters long the directory is, if it isn't in the FAT
+    contiguous: Option<u32>,
+    /// How many clusters into the directory we are
+    cluster_idx: u32,
+    /// The next block to read, counting from the start of `cluster`
+    block_in_cluster: u32,
+}
+
+/// Where the boot sector keeps how much of the volume is in use
+const PERCENT_IN_USE: usize = 112;
+/// The value of `PercentInUse` when it isn't known
+const PERCENT_UNKNOWN: u8 = 0xFF;
+
+impl ExFatVolume {
+    /// Mark the volume as in use. exFAT keeps a rough percentage of the
+    /// volume which is in use in the boot sector. We don't count free
+    /// clusters, so the first time we change the allocation bitmap we set it
+    /// to "unknown". The field is not covered by the boot region checksum.
+    pub async fn update_info_sector<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &mut self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        if self.percent_in_use != PERCENT_UNKNOWN {
+            self.clear_percent_in_use(volume_mgr).await?;
+            self.percent_in_use = PERCENT_UNKNOWN;
+        }
+        Ok(())
+    }
+
+    /// Set `PercentInUse` to "unknown" in the Main Boot Sector.
+    async fn clear_percent_in_use<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let mut blocks = [Block::new()];
+        volume_mgr
+            .block_device
+            .read(&mut blocks, self.lba_start, "read_boot_sector")
+            .await
+            .map_err(Error::DeviceError)?;
+        blocks[0][PERCENT_IN_USE] = PERCENT_UNKNOWN;
+        volume_mgr
+            .block_device
+            .write(&blocks, self.lba_start)
+            .await
+            .map_err(Error::DeviceError)
+    }
+
+    /// Number of bytes in a cluster.
+    pub(crate) fn bytes_per_cluster(&self) -> u32 {
+        self.blocks_per_cluster * Block::LEN_U32
+    }
+
+    /// How many clusters it takes to hold `length` bytes.
+    pub(crate) fn clusters_for(&self, length: u64) -> u32 {
+        if length == 0 {
+            0
+        } else {
+            (((length - 1) / u64::from(self.bytes_per_cluster())) + 1) as u32
+        }
+    }
+
+    /// One more than the highest numbered cluster on the volume.
+    fn end_cluster(&self) -> Cluster {
+        Cluster(self.cluster_count + RESERVED_ENTRIES)
+    }
+
+    /// Converts a cluster number (or `Cluster`) to a block number (or
+    /// `BlockIdx`). Gives an absolute `BlockIdx` you can pass to the
+    /// volume manager.
+    pub(crate) fn cluster_to_block(&self, cluster: Cluster) -> BlockIdx {
+        let cluster_num = match cluster {
+            Cluster::ROOT_DIR => self.root_dir_cluster.0,
+            c => c.0,
+        };
+        let first_block_of_cluster =
+            BlockCount((cluster_num - 2) as BlockNumber * self.blocks_per_cluster as BlockNumber);
+        self.lba_start + self.first_data_block + first_block_of_cluster
+    }
+
+    /// Look in the FAT to see which cluster comes next.
+    pub(crate) async fn next_cluster<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        cluster: Cluster,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let mut blocks = [Block::new()];
+        let fat_offset = cluster.0 * 4;
+        let this_fat_block_num = self.lba_start + self.fat_start.offset_bytes(fat_offset);
+        let this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
+        volume_mgr
+            .block_device
+            .read(&mut blocks, this_fat_block_num, "next_cluster")
+            .await
+            .map_err(Error::DeviceError)?;
+        let fat_entry =
+            LittleEndian::read_u32(&blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3]);
+        match fat_entry {
+            0x0000_0000 => {
+                // Jumped to free space
+                Err(Error::JumpedFree)
+            }
+            BAD_CLUSTER => {
+                // Bad cluster
+                Err(Error::BadCluster)
+            }
+            0x0000_0001 | 0xFFFF_FFF8..=END_OF_CHAIN => {
+                // There is no next cluster
+                Err(Error::EndOfFile)
+            }
+            f => {
+                // Seems legit
+                Ok(Cluster(f))
+            }
+        }
+    }
+
+    /// Write `count` consecutive FAT entries starting at `first_cluster`, so
+    /// each cluster points to the next, and the last one holds `last_value`.
+    /// Each block of the FAT is only read and written once.
+    async fn write_fat_run<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        first_cluster: Cluster,
+        count: u32,
+        last_value: u32,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let mut blocks = [Block::new()];
+        let last_cluster = first_cluster + (count - 1);
+        let mut cluster = first_cluster;
+        while cluster.0 <= last_cluster.0 {
+            let fat_offset = cluster.0 * 4;
+            let this_fat_block_num = self.lba_start + self.fat_start.offset_bytes(fat_offset);
+            let mut this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
+            volume_mgr
+                .block_device
+                .read(&mut blocks, this_fat_block_num, "read_fat")
+                .await
+                .map_err(Error::DeviceError)?;
+            while this_fat_ent_offset <= Block::LEN - 4 && cluster.0 <= last_cluster.0 {
+                let value = if cluster == last_cluster {
+                    last_value
+                } else {
+                    cluster.0 + 1
+                };
+                LittleEndian::write_u32(
+                    &mut blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
+                    value,
+                );
+                this_fat_ent_offset += 4;
+                cluster += 1;
+            }
+            volume_mgr
+                .block_device
+                .write(&blocks, this_fat_block_num)
+                .await
+                .map_err(Error::DeviceError)?;
+        }
+        Ok(())
+    }
+
+    /// Counts how many clusters, starting with `cluster`, follow one another
+    /// on disk according to the FAT, up to `max`. Walks the FAT a block at a
+    /// time rather than a cluster at a time.
+    pub(crate) async fn contiguous_clusters<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        cluster: Cluster,
+        max: u32,
+    ) -> Result<u32, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let end_cluster = self.end_cluster();
+        if cluster.0 < RESERVED_ENTRIES || cluster.0 >= end_cluster.0 {
+            return Ok(0);
+        }
+        let mut blocks = [Block::new()];
+        let mut current_cluster = cluster;
+        let mut count = 1;
+        loop {
+            let fat_offset = current_cluster.0 * 4;
+            let this_fat_block_num = self.lba_start + self.fat_start.offset_bytes(fat_offset);
+            let mut this_fat_ent_offset = (fat_offset % Block::LEN_U32) as usize;
+            volume_mgr
+                .block_device
+                .read(&mut blocks, this_fat_block_num, "contiguous_clusters")
+                .await
+                .map_err(Error::DeviceError)?;
+            while this_fat_ent_offset <= Block::LEN - 4 {
+                let next = LittleEndian::read_u32(
+                    &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
+                );
+                if next != current_cluster.0 + 1 || next >= end_cluster.0 || count >= max {
+                    return Ok(count);
+                }
+                count += 1;
+                this_fat_ent_offset += 4;
+                current_cluster += 1;
+            }
+        }
+    }
+
+    /// Walks the allocation bitmap between start_cluster and end_cluster,
+    /// calling `func` with the first cluster and the length of each run of
+    /// free clusters.
+    ///
+    /// A run is reported when it ends, or as soon as it reaches `max_length`
+    /// clusters. The walk stops early if `func` returns `true`, in which case
+    /// we return `true`.
+    async fn scan_free_runs<D, T, F, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        start_cluster: Cluster,
+        end_cluster: Cluster,
+        max_length: u32,
+        mut func: F,
+    ) -> Result<bool, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+        F: FnMut(Cluster, u32) -> bool,
+    {
+        const BITS_PER_BLOCK: u32 = Block::LEN_U32 * 8;
+        let mut blocks = [Block::new()];
+        let mut current_cluster = start_cluster;
+        let mut run_start = start_cluster;
+        let mut run_length = 0;
+        let first_bitmap_block = self.cluster_to_block(self.bitmap_cluster);
+        while current_cluster.0 < end_cluster.0 {
+            let bit = current_cluster.0 - RESERVED_ENTRIES;
+            volume_mgr
+                .block_device
+                .read(
+                    &mut blocks,
+                    first_bitmap_block + BlockCount((bit / BITS_PER_BLOCK) as BlockNumber),
+                    "scan_free_runs",
+                )
+                .await
+                .map_err(Error::DeviceError)?;
+            let mut bit = bit % BITS_PER_BLOCK;
+            while bit < BITS_PER_BLOCK && current_cluster.0 < end_cluster.0 {
+                let is_free = (blocks[0][(bit / 8) as usize] & (1 << (bit % 8))) == 0;
+                if is_free {
+                    if run_length == 0 {
+                        run_start = current_cluster;
+                    }
+                    run_length += 1;
+                    if run_length == max_length {
+                        if func(run_start, run_length) {
+                            return Ok(true);
+                        }
+                        run_length = 0;
+                    }
+                } else if run_length != 0 {
+                    if func(run_start, run_length) {
+                        return Ok(true);
+                    }
+                    run_length = 0;
+                }
+                bit += 1;
+                current_cluster += 1;
+            }
+        }
+        if run_length != 0 {
+            return Ok(func(run_start, run_length));
+        }
+        Ok(false)
+    }
+
+    /// Finds the first run of `count` consecutive free clusters after the
+    /// start_cluster and before end_cluster. Returns the first cluster in the
+    /// run.
+    async fn find_free_run<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        start_cluster: Cluster,
+        end_cluster: Cluster,
+        count: u32,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let mut found = None;
+        self.scan_free_runs(
+            volume_mgr,
+            start_cluster,
+            end_cluster,
+            count,
+            |start, length| {
+                if length >= count {
+                    found = Some(start);
+                }
+                found.is_some()
+            },
+        )
+        .await?;
+        found.ok_or(Error::NotEnoughSpace)
+    }
+
+    /// Finds the smallest run of free clusters on the volume which can hold
+    /// at least `count` clusters. Returns the first cluster in the run.
+    async fn find_best_free_run<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        count: u32,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let mut best: Option<(Cluster, u32)> = None;
+        self.scan_free_runs(
+            volume_mgr,
+            Cluster(RESERVED_ENTRIES),
+            self.end_cluster(),
+            u32::MAX,
+            |start, length| {
+                let better = match best {
+                    Some((_, best_length)) => length < best_length,
+                    None => true,
+                };
+                if length >= count && better {
+                    best = Some((start, length));
+                }
+                // Can't do better than an exact fit
+                length == count
+            },
+        )
+        .await?;
+        best.map(|(start, _)| start).ok_or(Error::NotEnoughSpace)
+    }
+
+    /// Finds the largest run of free clusters on the volume. Returns the first
+    /// cluster in the run.
+    async fn find_largest_free_run<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let mut largest: Option<(Cluster, u32)> = None;
+        self.scan_free_runs(
+            volume_mgr,
+            Cluster(RESERVED_ENTRIES),
+            self.end_cluster(),
+            u32::MAX,
+            |start, length| {
+                let larger = match largest {
+                    Some((_, largest_length)) => length > largest_length,
+                    None => true,
+                };
+                if larger {
+                    largest = Some((start, length));
+                }
+                false
+            },
+        )
+        .await?;
+        largest.map(|(start, _)| start).ok_or(Error::NotEnoughSpace)
+    }
+
+    /// Checks whether the clusters following `prev_cluster` are free, so the
+    /// chain ending in `prev_cluster` can be extended in-place by `count`
+    /// clusters.
+    async fn can_extend_in_place<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        prev_cluster: Cluster,
+        count: u32,
+    ) -> Result<bool, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let first_cluster = prev_cluster + 1;
+        if prev_cluster.0 < RESERVED_ENTRIES
+            || u64::from(first_cluster.0) + u64::from(count) > u64::from(self.end_cluster().0)
+        {
+            return Ok(false);
+        }
+        match self
+            .find_free_run(volume_mgr, first_cluster, first_cluster + count, count)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(Error::NotEnoughSpace) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Mark `count` clusters starting at `first_cluster` as in use (or free)
+    /// in the allocation bitmap.
+    async fn set_bitmap<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        first_cluster: Cluster,
+        count: u32,
+        in_use: bool,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        const BITS_PER_BLOCK: u32 = Block::LEN_U32 * 8;
+        let mut blocks = [Block::new()];
+        let first_bitmap_block = self.cluster_to_block(self.bitmap_cluster);
+        let mut bit = first_cluster.0 - RESERVED_ENTRIES;
+        let end_bit = bit + count;
+        while bit < end_bit {
+            let block_idx = first_bitmap_block + BlockCount((bit / BITS_PER_BLOCK) as BlockNumber);
+            volume_mgr
+                .block_device
+                .read(&mut blocks, block_idx, "read_bitmap")
+                .await
+                .map_err(Error::DeviceError)?;
+            let block_end_bit = ((bit / BITS_PER_BLOCK) + 1) * BITS_PER_BLOCK;
+            while bit < end_bit && bit < block_end_bit {
+                let byte = &mut blocks[0][((bit % BITS_PER_BLOCK) / 8) as usize];
+                if in_use {
+                    *byte |= 1 << (bit % 8);
+                } else {
+                    *byte &= !(1 << (bit % 8));
+                }
+                bit += 1;
+            }
+            volume_mgr
+                .block_device
+                .write(&blocks, block_idx)
+                .await
+                .map_err(Error::DeviceError)?;
+        }
+        Ok(())
+    }
+
+    /// Marks `count` free clusters starting at `first_cluster` as in use and
+    /// as a single chain, and appends that chain to `prev_cluster` (if
+    /// given).
+    async fn link_run<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &mut self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        prev_cluster: Option<Cluster>,
+        first_cluster: Cluster,
+        count: u32,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let last_cluster = first_cluster + (count - 1);
+        self.set_bitmap(volume_mgr, first_cluster, count, true)
+            .await?;
+        self.write_fat_run(volume_mgr, first_cluster, count, END_OF_CHAIN)
+            .await?;
+        if let Some(cluster) = prev_cluster {
+            self.write_fat_run(volume_mgr, cluster, 1, first_cluster.0)
+                .await?;
+        }
+        self.next_free_cluster = Some(last_cluster + 1);
+        Ok(())
+    }
+
+    /// Allocates `count` clusters and appends them to the chain ending in
+    /// `prev_cluster` (if given), returning the first new cluster.
+    ///
+    /// Like FAT volumes, we try to extend the chain in-place first, then look
+    /// for a run of free clusters chosen according to the volume manager's
+    /// [`AllocationPolicy`], and only then fall back to allocating clusters
+    /// one at a time.
+    pub(crate) async fn alloc_clusters<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &mut self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        prev_cluster: Option<Cluster>,
+        count: u32,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        debug!(
+            "Allocating {} clusters, prev_cluster={:?}",
+            count, prev_cluster
+        );
+        if count == 0 {
+            return Err(Error::AllocationError);
+        }
+        let end_cluster = self.end_cluster();
+        let run_start = match prev_cluster {
+            Some(prev) if self.can_extend_in_place(volume_mgr, prev, count).await? => Ok(prev + 1),
+            _ => match volume_mgr.allocation_policy {
+                AllocationPolicy::FirstFit => {
+                    self.find_free_run(volume_mgr, Cluster(RESERVED_ENTRIES), end_cluster, count)
+                        .await
+                }
+                AllocationPolicy::NextFit => {
+                    let start_cluster = match self.next_free_cluster {
+                        Some(cluster) if cluster.0 < end_cluster.0 => cluster,
+                        _ => Cluster(RESERVED_ENTRIES),
+                    };
+                    match self
+                        .find_free_run(volume_mgr, start_cluster, end_cluster, count)
+                        .await
+                    {
+                        Err(Error::NotEnoughSpace) if start_cluster.0 > RESERVED_ENTRIES => {
+                            self.find_free_run(
+                                volume_mgr,
+                                Cluster(RESERVED_ENTRIES),
+                                end_cluster,
+                                count,
+                            )
+                            .await
+                        }
+                        result => result,
+                    }
+                }
+                AllocationPolicy::BestFitContiguous => {
+                    self.find_best_free_run(volume_mgr, count).await
+                }
+            },
+        };
+        match run_start {
+            Ok(first_cluster) => {
+                debug!("Found contiguous run at {:?}", first_cluster);
+                self.link_run(volume_mgr, prev_cluster, first_cluster, count)
+                    .await?;
+                return Ok(first_cluster);
+            }
+            Err(Error::NotEnoughSpace) => {}
+            Err(e) => return Err(e),
+        }
+        debug!("No contiguous run found, allocating one at a time");
+        let first_cluster = self.alloc_cluster(volume_mgr, prev_cluster, false).await?;
+        let mut last_cluster = first_cluster;
+        for _ in 1..count {
+            last_cluster = self
+                .alloc_cluster(volume_mgr, Some(last_cluster), false)
+                .await?;
+        }
+        Ok(first_cluster)
+    }
+
+    /// Tries to allocate a cluster
+    pub(crate) async fn alloc_cluster<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &mut self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        prev_cluster: Option<Cluster>,
+        zero: bool,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        debug!("Allocating new cluster, prev_cluster={:?}", prev_cluster);
+        let end_cluster = self.end_cluster();
+        let new_cluster = match volume_mgr.allocation_policy {
+            AllocationPolicy::FirstFit => {
+                self.find_free_run(volume_mgr, Cluster(RESERVED_ENTRIES), end_cluster, 1)
+                    .await?
+            }
+            AllocationPolicy::BestFitContiguous if !zero => match prev_cluster {
+                Some(prev) if self.can_extend_in_place(volume_mgr, prev, 1).await? => prev + 1,
+                // We don't know how big this file will get, so start it in
+                // the biggest gap we have.
+                _ => self.find_largest_free_run(volume_mgr).await?,
+            },
+            // Directories grow a cluster at a time and stay small, so they
+            // aren't worth a scan of the whole FAT. They use next fit.
+            AllocationPolicy::NextFit | AllocationPolicy::BestFitContiguous => {
+                let start_cluster = match self.next_free_cluster {
+                    Some(cluster) if cluster.0 < end_cluster.0 => cluster,
+                    _ => Cluster(RESERVED_ENTRIES),
+                };
+                trace!(
+                    "Finding next free between {:?}..={:?}",
+                    start_cluster,
+                    end_cluster
+                );
+                match self
+                    .find_free_run(volume_mgr, start_cluster, end_cluster, 1)
+                    .await
+                {
+                    Ok(cluster) => cluster,
+                    Err(Error::NotEnoughSpace) if start_cluster.0 > RESERVED_ENTRIES => {
+                        self.find_free_run(volume_mgr, Cluster(RESERVED_ENTRIES), end_cluster, 1)
+                            .await?
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        self.link_run(volume_mgr, prev_cluster, new_cluster, 1)
+            .await?;
+        if zero {
+            let blocks = [Block::new()];
+            let first_block = self.cluster_to_block(new_cluster);
+            let num_blocks = BlockCount(self.blocks_per_cluster as BlockNumber);
+            for block in first_block.range(num_blocks) {
+                volume_mgr
+                    .block_device
+                    .write(&blocks, block)
+                    .await
+                    .map_err(Error::DeviceError)?;
+            }
+        }
+        debug!("All done, returning {:?}", new_cluster);
+        Ok(new_cluster)
+    }
+
+    /// Marks the input cluster as an EOF and all the subsequent clusters in the chain as free
+    pub(crate) async fn truncate_cluster_chain<
+        D,
+        T,
+        const MAX_DIRS: usize,
+        const MAX_FILES: usize,
+    >(
+        &mut self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        cluster: Cluster,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        if cluster.0 < RESERVED_ENTRIES {
+            // file doesn't have any valid cluster allocated, there is nothing to do
+            return Ok(());
+        }
+        let next = match self.next_cluster(volume_mgr, cluster).await {
+            Ok(n) => n,
+            Err(Error::EndOfFile) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        self.write_fat_run(volume_mgr, cluster, 1, END_OF_CHAIN)
+            .await?;
+        self.free_cluster_chain(volume_mgr, next, None).await
+    }
+
+    /// Frees every cluster in the chain starting at `cluster`. If
+    /// `contiguous` is given, the chain is that many clusters long and is
+    /// not recorded in the FAT.
+    async fn free_cluster_chain<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        cluster: Cluster,
+        contiguous: Option<u32>,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        if let Some(count) = contiguous {
+            if count != 0 {
+                self.set_bitmap(volume_mgr, cluster, count, false).await?;
+                self.discard_clusters(volume_mgr, cluster, count).await;
+            }
+        } else {
+            // Free the chain a run of contiguous clusters at a time
+            let mut run_start = cluster;
+            loop {
+                let run_length = self
+                    .contiguous_clusters(volume_mgr, run_start, u32::MAX)
+                    .await?;
+                if run_length == 0 {
+                    return Err(Error::BadCluster);
+                }
+                let run_end = run_start + (run_length - 1);
+                let next = self.next_cluster(volume_mgr, run_end).await;
+                self.set_bitmap(volume_mgr, run_start, run_length, false)
+                    .await?;
+                self.discard_clusters(volume_mgr, run_start, run_length)
+                    .await;
+                match next {
+                    Ok(n) => run_start = n,
+                    Err(Error::EndOfFile) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the block device that a run of clusters has been freed.
+    async fn discard_clusters<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        first: Cluster,
+        count: u32,
+    ) where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let num_blocks =
+            BlockCount(BlockNumber::from(self.blocks_per_cluster) * BlockNumber::from(count));
+        volume_mgr
+            .discard_blocks(self.cluster_to_block(first), num_blocks)
+            .await;
+    }
+
+    /// Frees all the clusters of a file, leaving it empty.
+    pub(crate) async fn free_file<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        entry: &mut DirEntry,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let info = entry.exfat.as_mut().ok_or(Error::Unsupported)?;
+        if entry.cluster.0 >= RESERVED_ENTRIES {
+            let contiguous = if info.no_fat_chain {
+                Some(self.clusters_for(info.data_length))
+            } else {
+                None
+            };
+            self.free_cluster_chain(volume_mgr, entry.cluster, contiguous)
+                .await?;
+        }
+        info.no_fat_chain = false;
+        info.valid_length = 0;
+        info.data_length = 0;
+        entry.cluster = Cluster::EMPTY;
+        entry.size = 0;
+        Ok(())
+    }
+
+    /// Get a file ready to be opened. We can't yet write to files with more
+    /// space allocated than has been written, and the clusters of a file we
+    /// are going to write to must be recorded in the FAT.
+    pub(crate) async fn prepare_to_open<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        entry: &mut DirEntry,
+        writable: bool,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let info = entry.exfat.as_mut().ok_or(Error::Unsupported)?;
+        if writable {
+            if info.valid_length != info.data_length || info.secondary_count > MAX_SECONDARY_COUNT {
+                return Err(Error::Unsupported);
+            }
+            if info.no_fat_chain && entry.cluster.0 >= RESERVED_ENTRIES {
+                let count = self.clusters_for(info.data_length);
+                if count != 0 {
+                    self.write_fat_run(volume_mgr, entry.cluster, count, END_OF_CHAIN)
+                        .await?;
+                }
+                info.no_fat_chain = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Find where a directory starts, and how long it is if it isn't
+    /// recorded in the FAT. We look at the directory's entry on disk, as it
+    /// may have grown since it was opened.
+    async fn dir_chain<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        dir: &Directory,
+    ) -> Result<(Cluster, Option<u32>), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let entry = match &dir.entry {
+            None => return Ok((self.root_dir_cluster, None)),
+            Some(entry) => entry,
+        };
+        let info = entry.exfat.as_ref().ok_or(Error::Unsupported)?;
+        let (block, offset) = info
+            .entry_position(entry, 1)
+            .ok_or(Error::FormatError("Directory entry set too long"))?;
+        let mut blocks = [Block::new()];
+        volume_mgr
+            .block_device
+            .read(&mut blocks, block, "read_dir")
+            .await
+            .map_err(Error::DeviceError)?;
+        let data = &blocks[0][offset..offset + ENTRY_LEN];
+        let first_cluster = Cluster(LittleEndian::read_u32(&data[STREAM_FIRST_CLUSTER..]));
+        if data[0] != TYPE_STREAM
+            || first_cluster.0 < RESERVED_ENTRIES
+            || first_cluster.0 >= self.end_cluster().0
+        {
+            return Err(Error::FormatError("Bad directory entry"));
+        }
+        if (data[STREAM_FLAGS] & FLAG_NO_FAT_CHAIN) != 0 {
+            let data_length = LittleEndian::read_u64(&data[STREAM_DATA_LENGTH..]);
+            Ok((first_cluster, Some(self.clusters_for(data_length))))
+        } else {
+            Ok((first_cluster, None))
+        }
+    }
+
+    /// Start reading a directory a block at a time, with `next_dir_block`.
+    async fn open_dir_reader<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        dir: &Directory,
+    ) -> Result<DirReader, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let (cluster, contiguous) = self.dir_chain(volume_mgr, dir).await?;
+        Ok(DirReader {
+            cluster,
+            contiguous,
+            cluster_idx: 0,
+            block_in_cluster: 0,
+        })
+    }
+
+    /// Read the next block of a directory into `blocks`. Returns the
+    /// cluster and block we read, or `None` at the end of the directory.
+    async fn next_dir_block<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        reader: &mut DirReader,
+        blocks: &mut [Block],
+    ) -> Result<Option<(Cluster, BlockIdx)>, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        if reader.block_in_cluster == self.blocks_per_cluster {
+            reader.cluster_idx += 1;
+            reader.cluster = match reader.contiguous {
+                Some(count) if reader.cluster_idx < count => reader.cluster + 1,
+                Some(_) => return Ok(None),
+                None => match self.next_cluster(volume_mgr, reader.cluster).await {
+                    Ok(n) => n,
+                    Err(Error::EndOfFile) => return Ok(None),
+                    Err(e) => return Err(e),
+                },
+            };
+            reader.block_in_cluster = 0;
+        }
+        let block = self.cluster_to_block(reader.cluster)
+            + BlockCount(reader.block_in_cluster as BlockNumber);
+        volume_mgr
+            .block_device
+            .read(blocks, block, "read_dir")
+            .await
+            .map_err(Error::DeviceError)?;
+        reader.block_in_cluster += 1;
+        Ok(Some((reader.cluster, block)))
+    }
+
+    /// Calls `func` with every 32 byte entry in a directory, along with the
+    /// cluster and block it is in and its offset within the block. Stops
+    /// early, returning `true`, if `func` returns `true`.
+    async fn walk_dir<D, T, F, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        dir: &Directory,
+        mut func: F,
+    ) -> Result<bool, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+        F: FnMut(&[u8], Cluster, BlockIdx, u32) -> Result<bool, Error<D::Error>>,
+    {
+        let mut reader = self.open_dir_reader(volume_mgr, dir).await?;
+        let mut blocks = [Block::new()];
+        while let Some((cluster, block)) = self
+            .next_dir_block(volume_mgr, &mut reader, &mut blocks)
+            .await?
+        {
+            for entry in 0..Block::LEN / ENTRY_LEN {
+                let start = entry * ENTRY_LEN;
+                let data = &blocks[0][start..start + ENTRY_LEN];
+                if func(data, cluster, block, start as u32)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Calls callback `func` with every valid entry in the given directory.
+    /// Useful for performing directory listings.
+    pub(crate) async fn iterate_dir<D, T, F, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        dir: &Directory,
+        mut func: F,
+    ) -> Result<(), Error<D::Error>>
+    where
+        F: FnMut(&DirEntry),
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let mut parser = EntrySetParser::new();
+        self.walk_dir(volume_mgr, dir, |data, _cluster, block, offset| {
+            if data[0] == TYPE_END {
+                // Can quit early
+                return Ok(true);
+            }
+            if let Some(set) = parser.push(data, block, offset) {
+                func(&set.to_dir_entry());
+            }
+            Ok(false)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Up-case a name using the volume's up-case table. The whole name is
+    /// done in one pass over the table.
+    async fn upcase_name<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        name: &mut [u16],
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        // Which characters still need looking up. Once a character has been
+        // mapped we mustn't look up what it was mapped to.
+        let mut pending = [0u64; MAX_NAME_LEN.div_ceil(64)];
+        let mut highest = None;
+        for (idx, ch) in name.iter_mut().enumerate() {
+            if *ch < 0x80 && self.ascii_upcase {
+                if (0x61..=0x7A).contains(ch) {
+                    *ch -= 0x20;
+                }
+            } else {
+                pending[idx / 64] |= 1 << (idx % 64);
+                highest = highest.max(Some(*ch));
+            }
+        }
+        let highest = match highest {
+            Some(ch) => u32::from(ch),
+            None => return Ok(()),
+        };
+        self.scan_upcase_table(volume_mgr, |first, _count, mapping| {
+            if let Some(mapping) = mapping {
+                for (idx, ch) in name.iter_mut().enumerate() {
+                    let bit = 1 << (idx % 64);
+                    if (pending[idx / 64] & bit) != 0 && u32::from(*ch) == first {
+                        *ch = mapping;
+                        pending[idx / 64] &= !bit;
+                    }
+                }
+            }
+            // The table is in order, so we can stop once we're past every
+            // character in the name
+            first >= highest
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Walks the up-case table. `func` is called with the first character
+    /// of a run of characters, the length of the run, and what they map to.
+    /// Runs of more than one character always map to themselves, and are
+    /// given `None`. The walk stops if `func` returns `true`.
+    async fn scan_upcase_table<D, T, F, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        mut func: F,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+        F: FnMut(u32, u32, Option<u16>) -> bool,
+    {
+        let mut blocks = [Block::new()];
+        let first_block = self.cluster_to_block(self.upcase_cluster);
+        let mut next_char = 0;
+        let mut identity_run = false;
+        let mut offset = 0;
+        while offset + 1 < self.upcase_length {
+            let start = (offset % Block::LEN_U32) as usize;
+            if start == 0 {
+                volume_mgr
+                    .block_device
+                    .read(
+                        &mut blocks,
+                        first_block + BlockCount((offset / Block::LEN_U32) as BlockNumber),
+                        "read_upcase",
+                    )
+                    .await
+                    .map_err(Error::DeviceError)?;
+            }
+            let value = LittleEndian::read_u16(&blocks[0][start..start + 2]);
+            offset += 2;
+            let stop = if identity_run {
+                identity_run = false;
+                let stop = func(next_char, u32::from(value), None);
+                next_char += u32::from(value);
+                stop
+            } else if value == 0xFFFF {
+                identity_run = true;
+                false
+            } else {
+                let stop = func(next_char, 1, Some(value));
+                next_char += 1;
+                stop
+            };
+            if stop {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert a file name to UTF-16, checking it is a valid exFAT name.
+    /// Returns the length of the name.
+    fn encode_name(name: &str, buffer: &mut [u16; MAX_NAME_LEN]) -> Result<usize, FilenameError> {
+        let mut len = 0;
+        for ch in name.encode_utf16() {
+            if len == MAX_NAME_LEN {
+                return Err(FilenameError::NameTooLong);
+            }
+            if !is_valid_name_char(ch) {
+                return Err(FilenameError::InvalidCharacter);
+            }
+            buffer[len] = ch;
+            len += 1;
+        }
+        if len == 0 {
+            return Err(FilenameError::FilenameEmpty);
+        }
+        Ok(len)
+    }
+
+    /// Get an entry from the given directory.
+    ///
+    /// The name is compared with each file's long name, ignoring case. The
+    /// short names we make up for `DirEntry`s aren't unique, so files can't
+    /// be found by them.
+    pub(crate) async fn find_directory_entry<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        dir: &Directory,
+        name: &str,
+    ) -> Result<DirEntry, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let mut match_name = [0u16; MAX_NAME_LEN];
+        let match_len = Self::encode_name(name, &mut match_name).map_err(Error::FilenameError)?;
+        let match_name = &mut match_name[0..match_len];
+        self.upcase_name(volume_mgr, match_name).await?;
+        let match_hash = match_name.iter().fold(0, |hash, ch| name_hash(hash, *ch));
+        let mut parser = EntrySetParser::new();
+        // We can't use `walk_dir`, as comparing names may need to read the
+        // up-case table
+        let mut reader = self.open_dir_reader(volume_mgr, dir).await?;
+        let mut blocks = [Block::new()];
+        let mut candidate = [0u16; MAX_NAME_LEN];
+        while let Some((_cluster, block)) = self
+            .next_dir_block(volume_mgr, &mut reader, &mut blocks)
+            .await?
+        {
+            for entry in 0..Block::LEN / ENTRY_LEN {
+                let start = entry * ENTRY_LEN;
+                let data = &blocks[0][start..start + ENTRY_LEN];
+                if data[0] == TYPE_END {
+                    return Err(Error::FileNotFound);
+                }
+                if let Some(set) = parser.push(data, block, start as u32) {
+                    if set.name_hash != match_hash || set.name().len() != match_len {
+                        continue;
+                    }
+                    let candidate = &mut candidate[0..match_len];
+                    candidate.copy_from_slice(set.name());
+                    self.upcase_name(volume_mgr, candidate).await?;
+                    if candidate == match_name {
+                        return Ok(set.to_dir_entry());
+                    }
+                }
+            }
+        }
+        Err(Error::FileNotFound)
+    }
+
+    /// Rewrite the entries of the set belonging to `entry`. `func` is called
+    /// with the index of each entry within the set and its contents, and the
+    /// set checksum is updated to match.
+    async fn rewrite_entry_set<D, T, F, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        entry: &DirEntry,
+        mut func: F,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+        F: FnMut(usize, &mut [u8]),
+    {
+        let info = entry.exfat.as_ref().ok_or(Error::Unsupported)?;
+        if info.secondary_count > MAX_SECONDARY_COUNT {
+            return Err(Error::Unsupported);
+        }
+        let mut blocks = [Block::new()];
+        let mut current_block = None;
+        let mut checksum = 0;
+        for idx in 0..=usize::from(info.secondary_count) {
+            let (block, offset) = info
+                .entry_position(entry, idx)
+                .ok_or(Error::FormatError("Directory entry set too long"))?;
+            if current_block != Some(block) {
+                if let Some(current_block) = current_block {
+                    volume_mgr
+                        .block_device
+                        .write(&blocks, current_block)
+                        .await
+                        .map_err(Error::DeviceError)?;
+                }
+                volume_mgr
+                    .block_device
+                    .read(&mut blocks, block, "read_dir")
+                    .await
+                    .map_err(Error::DeviceError)?;
+                current_block = Some(block);
+            }
+            let data = &mut blocks[0][offset..offset + ENTRY_LEN];
+            func(idx, data);
+            checksum = checksum16(checksum, data, idx == 0);
+        }
+        let first_offset = entry.entry_offset as usize;
+        if current_block != Some(entry.entry_block) {
+            volume_mgr
+                .block_device
+                .write(&blocks, current_block.unwrap())
+                .await
+                .map_err(Error::DeviceError)?;
+            volume_mgr
+                .block_device
+                .read(&mut blocks, entry.entry_block, "read_dir")
+                .await
+                .map_err(Error::DeviceError)?;
+        }
+        LittleEndian::write_u16(&mut blocks[0][first_offset + FILE_SET_CHECKSUM..], checksum);
+        volume_mgr
+            .block_device
+            .write(&blocks, entry.entry_block)
+            .await
+            .map_err(Error::DeviceError)?;
+        Ok(())
+    }
+
+    /// Writes a file's attributes, timestamps, first cluster and length back
+    /// to its directory entry set.
+    pub(crate) async fn write_entry<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        entry: &DirEntry,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let info = entry.exfat.as_ref().ok_or(Error::Unsupported)?;
+        let mut flags = FLAG_ALLOCATION_POSSIBLE;
+        if info.no_fat_chain {
+            flags |= FLAG_NO_FAT_CHAIN;
+        }
+        let (valid_length, data_length) = (info.valid_length, info.data_length);
+        self.rewrite_entry_set(volume_mgr, entry, |idx, data| match idx {
+            0 => {
+                LittleEndian::write_u16(
+                    &mut data[FILE_ATTRIBUTES..],
+                    u16::from(entry.attributes.0),
+                );
+                data[FILE_CREATE_TIMESTAMP..FILE_CREATE_TIMESTAMP + 4]
+                    .copy_from_slice(&entry.ctime.serialize_to_fat()[..]);
+                data[FILE_MODIFY_TIMESTAMP..FILE_MODIFY_TIMESTAMP + 4]
+                    .copy_from_slice(&entry.mtime.serialize_to_fat()[..]);
+                data[FILE_ACCESS_TIMESTAMP..FILE_ACCESS_TIMESTAMP + 4]
+                    .copy_from_slice(&entry.mtime.serialize_to_fat()[..]);
+                // We don't know the hundredths of a second or the time zone
+                data[FILE_CREATE_10MS..FILE_CREATE_10MS + 2].copy_from_slice(&[0, 0]);
+                data[FILE_UTC_OFFSETS..FILE_UTC_OFFSETS + 3].copy_from_slice(&[0, 0, 0]);
+            }
+            1 => {
+                data[STREAM_FLAGS] = flags;
+                LittleEndian::write_u64(&mut data[STREAM_VALID_DATA_LENGTH..], valid_length);
+                LittleEndian::write_u32(&mut data[STREAM_FIRST_CLUSTER..], entry.cluster.0);
+                LittleEndian::write_u64(&mut data[STREAM_DATA_LENGTH..], data_length);
+            }
+            _ => {}
+        })
+        .await
+    }
+
+    /// Finds space for a new entry set in the directory and writes it there.
+    /// The directory is extended if there isn't enough space.
+    pub(crate) async fn write_new_directory_entry<
+        D,
+        T,
+        const MAX_DIRS: usize,
+        const MAX_FILES: usize,
+    >(
+        &mut self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        dir: &Directory,
+        name: &str,
+        attributes: Attributes,
+    ) -> Result<DirEntry, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let mut encoded_name = [0u16; MAX_NAME_LEN];
+        let name_len = Self::encode_name(name, &mut encoded_name).map_err(Error::FilenameError)?;
+        let name = &encoded_name[0..name_len];
+        let mut upcased_name = [0u16; MAX_NAME_LEN];
+        upcased_name[0..name_len].copy_from_slice(name);
+        self.upcase_name(volume_mgr, &mut upcased_name[0..name_len])
+            .await?;
+        let hash = upcased_name[0..name_len]
+            .iter()
+            .fold(0, |hash, ch| name_hash(hash, *ch));
+        let name_entries = ((name_len - 1) / NAME_CHARS_PER_ENTRY) + 1;
+        let num_entries = 2 + name_entries;
+
+        // Find enough unused entries in a row
+        let mut free = FreeEntries {
+            start: None,
+            blocks: [BlockIdx(0); 3],
+            num_blocks: 0,
+            count: 0,
+        };
+        let mut last_cluster = Cluster::EMPTY;
+        self.walk_dir(volume_mgr, dir, |data, cluster, block, offset| {
+            last_cluster = cluster;
+            if (data[0] & TYPE_IN_USE) != 0 {
+                free.reset();
+            } else {
+                free.add(block, offset);
+            }
+            Ok(free.count == num_entries)
+        })
+        .await?;
+
+        // Grow the directory if we have to
+        let mut added_clusters = 0;
+        while free.count < num_entries {
+            if added_clusters == 0 {
+                let (first_cluster, contiguous) = self.dir_chain(volume_mgr, dir).await?;
+                if let Some(count) = contiguous {
+                    // We're going to need a FAT chain
+                    self.write_fat_run(volume_mgr, first_cluster, count, END_OF_CHAIN)
+                        .await?;
+                }
+            }
+            let new_cluster = self
+                .alloc_cluster(volume_mgr, Some(last_cluster), true)
+                .await?;
+            let first_block = self.cluster_to_block(new_cluster);
+            for block in first_block.range(BlockCount(self.blocks_per_cluster as BlockNumber)) {
+                for entry in 0..Block::LEN / ENTRY_LEN {
+                    if free.count < num_entries {
+                        free.add(block, (entry * ENTRY_LEN) as u32);
+                    }
+                }
+            }
+            last_cluster = new_cluster;
+            added_clusters += 1;
+        }
+        if added_clusters != 0 {
+            if let Some(dir_entry) = &dir.entry {
+                // The directory's length lives in its own entry set
+                let added_length = u64::from(added_clusters * self.bytes_per_cluster());
+                self.rewrite_entry_set(volume_mgr, dir_entry, |idx, data| {
+                    if idx == 1 {
+                        data[STREAM_FLAGS] &= !FLAG_NO_FAT_CHAIN;
+                        let length =
+                            LittleEndian::read_u64(&data[STREAM_DATA_LENGTH..]) + added_length;
+                        LittleEndian::write_u64(&mut data[STREAM_VALID_DATA_LENGTH..], length);
+                        LittleEndian::write_u64(&mut data[STREAM_DATA_LENGTH..], length);
+                    }
+                })
+                .await?;
+            }
+            self.update_info_sector(volume_mgr).await?;
+        }
+
+        // Build the new set
+        let (entry_block, entry_offset) = free.start.unwrap();
+        let ctime = volume_mgr.timesource.get_timestamp();
+        let mut entry = DirEntry::new(
+            short_name(name),
+            attributes,
+            Cluster::EMPTY,
+            ctime,
+            entry_block,
+            entry_offset,
+        );
+        entry.exfat = Some(ExFatEntryInfo {
+            secondary_count: (num_entries - 1) as u8,
+            next_blocks: [
+                free.blocks[1..free.num_blocks].first().copied(),
+                free.blocks[1..free.num_blocks].get(1).copied(),
+            ],
+            no_fat_chain: false,
+            valid_length: 0,
+            data_length: 0,
+        });
+        let make_entry = |idx: usize| {
+            let mut data = [0u8; ENTRY_LEN];
+            match idx {
+                0 => {
+                    data[0] = TYPE_FILE;
+                    data[FILE_SECONDARY_COUNT] = (num_entries - 1) as u8;
+                    LittleEndian::write_u16(&mut data[FILE_ATTRIBUTES..], u16::from(attributes.0));
+                    let timestamp = ctime.serialize_to_fat();
+                    data[FILE_CREATE_TIMESTAMP..FILE_CREATE_TIMESTAMP + 4]
+                        .copy_from_slice(&timestamp[..]);
+                    data[FILE_MODIFY_TIMESTAMP..FILE_MODIFY_TIMESTAMP + 4]
+                        .copy_from_slice(&timestamp[..]);
+                    data[FILE_ACCESS_TIMESTAMP..FILE_ACCESS_TIMESTAMP + 4]
+                        .copy_from_slice(&timestamp[..]);
+                }
+                1 => {
+                    data[0] = TYPE_STREAM;
+                    data[STREAM_FLAGS] = FLAG_ALLOCATION_POSSIBLE;
+                    data[STREAM_NAME_LENGTH] = name_len as u8;
+                    LittleEndian::write_u16(&mut data[STREAM_NAME_HASH..], hash);
+                }
+                n => {
+                    data[0] = TYPE_NAME;
+                    let chars = name.chunks(NAME_CHARS_PER_ENTRY).nth(n - 2).unwrap_or(&[]);
+                    for (idx, ch) in chars.iter().enumerate() {
+                        LittleEndian::write_u16(&mut data[2 + (idx * 2)..], *ch);
+                    }
+                }
+            }
+            data
+        };
+        let mut checksum = 0;
+        for idx in 0..num_entries {
+            checksum = checksum16(checksum, &make_entry(idx), idx == 0);
+        }
+
+        // Write it out, a block at a time
+        let mut blocks = [Block::new()];
+        let mut idx = 0;
+        for (block_num, block) in free.blocks[0..free.num_blocks].iter().enumerate() {
+            volume_mgr
+                .block_device
+                .read(&mut blocks, *block, "read_dir")
+                .await
+                .map_err(Error::DeviceError)?;
+            let mut offset = if block_num == 0 {
+                entry_offset as usize
+            } else {
+                0
+            };
+            while offset < Block::LEN && idx < num_entries {
+                let mut data = make_entry(idx);
+                if idx == 0 {
+                    LittleEndian::write_u16(&mut data[FILE_SET_CHECKSUM..], checksum);
+                }
+                blocks[0][offset..offset + ENTRY_LEN].copy_from_slice(&data);
+                offset += ENTRY_LEN;
+                idx += 1;
+            }
+            volume_mgr
+                .block_device
+                .write(&blocks, *block)
+                .await
+                .map_err(Error::DeviceError)?;
+        }
+        Ok(entry)
+    }
+
+    /// Delete an entry from the given directory, and free its clusters.
+    pub(crate) async fn delete_directory_entry<
+        D,
+        T,
+        const MAX_DIRS: usize,
+        const MAX_FILES: usize,
+    >(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        dir: &Directory,
+        name: &str,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let mut entry = self.find_directory_entry(volume_mgr, dir, name).await?;
+        self.rewrite_entry_set(volume_mgr, &entry, |_idx, data| {
+            data[0] &= !TYPE_IN_USE;
+        })
+        .await?;
+        self.free_file(volume_mgr, &mut entry).await?;
+        if self.percent_in_use != PERCENT_UNKNOWN {
+            self.clear_percent_in_use(volume_mgr).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the checksum of the boot region starting at `start`.
+async fn check_boot_region<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+    start: BlockIdx,
+    blocks_per_sector: u32,
+) -> Result<bool, Error<D::Error>>
+where
+    D: AsyncBlockDevice,
+    T: TimeSource,
+{
+    const VOLUME_FLAGS: usize = 106;
+    let mut blocks = [Block::new()];
+    let mut checksum = 0;
+    let checksummed_len =
+        BlockCount(BootSector::CHECKSUMMED_SECTORS * BlockNumber::from(blocks_per_sector));
+    for (idx, block) in start.range(checksummed_len).enumerate() {
+        volume_mgr
+            .block_device
+            .read(&mut blocks, block, "read_boot_region")
+            .await
+            .map_err(Error::DeviceError)?;
+        if idx == 0 {
+            // These fields change, so they aren't covered
+            checksum = checksum32(checksum, &blocks[0][0..VOLUME_FLAGS]);
+            checksum = checksum32(checksum, &blocks[0][VOLUME_FLAGS + 2..PERCENT_IN_USE]);
+            checksum = checksum32(checksum, &blocks[0][PERCENT_IN_USE + 1..]);
+        } else {
+            checksum = checksum32(checksum, &blocks[0][..]);
+        }
+    }
+    // The checksum is repeated to fill the whole of the next sector
+    let checksum_start = start + checksummed_len;
+    for block in checksum_start.range(BlockCount(BlockNumber::from(blocks_per_sector))) {
+        volume_mgr
+            .block_device
+            .read(&mut blocks, block, "read_boot_checksum")
+            .await
+            .map_err(Error::DeviceError)?;
+        if !blocks[0][..]
+            .chunks(4)
+            .all(|chunk| LittleEndian::read_u32(chunk) == checksum)
+        {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Load the boot sector from the start of the given partition and determine
+/// if the partition contains a valid exFAT file system.
+pub async fn parse_volume<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+    lba_start: BlockIdx,
+    num_blocks: BlockCount,
+) -> Result<VolumeType, Error<D::Error>>
+where
+    D: AsyncBlockDevice,
+    T: TimeSource,
+    D::Error: core::fmt::Debug,
+{
+    // Use the Backup Boot Region if the Main Boot Region is damaged. We find
+    // out how big the sectors are from the boot sector itself, so if we're
+    // looking for the Backup Boot Region we try each sector size in turn.
+    let mut blocks = [Block::new()];
+    let mut blocks_per_sector = None;
+    'regions: for region_idx in 0..2 {
+        let sizes: &[u32] = if region_idx == 0 { &[1] } else { &[1, 2, 4, 8] };
+        for &size in sizes {
+            let start = BootSector::region_start(lba_start, region_idx, size);
+            volume_mgr
+                .block_device
+                .read(&mut blocks, start, "read_boot_sector")
+                .await
+                .map_err(Error::DeviceError)?;
+            let boot = match BootSector::create_from_bytes(&blocks[0]) {
+                Ok(boot) if region_idx == 0 || boot.blocks_per_sector() == size => boot,
+                _ => continue,
+            };
+            if check_boot_region(volume_mgr, start, boot.blocks_per_sector()).await? {
+                blocks_per_sector = Some(boot.blocks_per_sector());
+                break 'regions;
+            }
+        }
+        warn!("Boot region {} is damaged", region_idx);
+    }
+    let blocks_per_sector = blocks_per_sector.ok_or(Error::FormatError("No good boot region"))?;
+    // `check_boot_region` doesn't touch `blocks`, so we still have the boot
+    // sector which passed
+    let boot = BootSector::create_from_bytes(&blocks[0]).map_err(Error::FormatError)?;
+    debug!("exFAT volume with {} clusters", boot.cluster_count());
+    let volume_end = BlockCount::from_u64(boot.volume_length())
+        .and_then(|length| length.0.checked_mul(BlockNumber::from(blocks_per_sector)))
+        .and_then(|length| lba_start.0.checked_add(length));
+    if volume_end.is_none() {
+        // Without the `lba64` feature we can't reach the end of the volume
+        return Err(Error::FormatError("Volume too large"));
+    }
+    let mut volume = ExFatVolume {
+        lba_start,
+        num_blocks,
+        name: VolumeName::new([b' '; 11]),
+        blocks_per_cluster: boot.blocks_per_cluster(),
+        first_data_block: boot.first_data_block(),
+        fat_start: boot.fat_start(),
+        cluster_count: boot.cluster_count(),
+        root_dir_cluster: Cluster(boot.first_cluster_of_root_directory()),
+        bitmap_cluster: Cluster::INVALID,
+        upcase_cluster: Cluster::INVALID,
+        upcase_length: 0,
+        ascii_upcase: false,
+        percent_in_use: boot.percent_in_use(),
+        next_free_cluster: None,
+    };
+    if volume.root_dir_cluster.0 < RESERVED_ENTRIES
+        || volume.root_dir_cluster.0 >= volume.end_cluster().0
+    {
+        return Err(Error::FormatError("Bad root directory cluster"));
+    }
+    let active_fat = boot.active_fat();
+
+    // Find the allocation bitmap, up-case table and volume label
+    let root_dir = Directory {
+        cluster: Cluster::ROOT_DIR,
+        entry: None,
+    };
+    let mut bitmap = None;
+    let mut upcase = None;
+    let mut name = [b' '; 11];
+    volume
+        .walk_dir(volume_mgr, &root_dir, |data, _cluster, _block, _offset| {
+            let first_cluster = Cluster(LittleEndian::read_u32(&data[20..24]));
+            let data_length = LittleEndian::read_u64(&data[24..32]);
+            match data[0] {
+                TYPE_END => return Ok(true),
+                // Volumes with two FATs have two bitmaps
+                TYPE_BITMAP if (data[1] & 0x01) == active_fat => {
+                    bitmap = Some((first_cluster, data_length));
+                }
+                TYPE_UPCASE => {
+                    upcase = Some((
+                        first_cluster,
+                        data_length,
+                        LittleEndian::read_u32(&data[4..8]),
+                    ));
+                }
+                TYPE_LABEL => {
+                    let len = core::cmp::min(usize::from(data[1]), name.len());
+                    for (idx, b) in name[0..len].iter_mut().enumerate() {
+                        let ch = LittleEndian::read_u16(&data[2 + (idx * 2)..]);
+                        *b = if (0x20..0x7F).contains(&ch) {
+                            ch as u8
+                        } else {
+                            b'_'
+                        };
+                    }
+                }
+                _ => {}
+            }
+            Ok(bitmap.is_some() && upcase.is_some() && name[0] != b' ')
+        })
+        .await?;
+    volume.name = VolumeName::new(name);
+
+    let (bitmap_cluster, bitmap_length) =
+        bitmap.ok_or(Error::FormatError("No allocation bitmap"))?;
+    let bitmap_bytes = if volume.cluster_count == 0 {
+        0
+    } else {
+        ((volume.cluster_count - 1) / 8) + 1
+    };
+    if bitmap_length < u64::from(bitmap_bytes)
+        || volume
+            .contiguous_clusters(
+                volume_mgr,
+                bitmap_cluster,
+                volume.clusters_for(bitmap_length),
+            )
+            .await?
+            < volume.clusters_for(bitmap_length)
+    {
+        return Err(Error::FormatError("Bad allocation bitmap"));
+    }
+    volume.bitmap_cluster = bitmap_cluster;
+
+    let (upcase_cluster, upcase_length, upcase_checksum) =
+        upcase.ok_or(Error::FormatError("No up-case table"))?;
+    if upcase_length > 0x2_0000
+        || volume
+            .contiguous_clusters(
+                volume_mgr,
+                upcase_cluster,
+                volume.clusters_for(upcase_length),
+            )
+            .await?
+            < volume.clusters_for(upcase_length)
+    {
+        return Err(Error::FormatError("Bad up-case table"));
+    }
+    volume.upcase_cluster = upcase_cluster;
+    volume.upcase_length = upcase_length as u32;
+
+    // Check the up-case table, and whether it treats ASCII the usual way
+    let mut checksum = 0;
+    let first_block = volume.cluster_to_block(upcase_cluster);
+    for (idx, block) in first_block
+        .range(BlockCount(
+            (volume.clusters_for(upcase_length) * volume.blocks_per_cluster) as BlockNumber,
+        ))
+        .enumerate()
+    {
+        let offset = idx * Block::LEN;
+        if offset >= upcase_length as usize {
+            break;
+        }
+        volume_mgr
+            .block_device
+            .read(&mut blocks, block, "read_upcase")
+            .await
+            .map_err(Error::DeviceError)?;
+        let len = core::cmp::min(Block::LEN, upcase_length as usize - offset);
+        checksum = checksum32(checksum, &blocks[0][0..len]);
+    }
+    if checksum != upcase_checksum {
+        return Err(Error::FormatError("Bad up-case table checksum"));
+    }
+    let mut ascii_upcase = true;
+    volume
+        .scan_upcase_table(volume_mgr, |first, count, mapping| {
+            for ch in first..core::cmp::min(first + count, 0x80) {
+                let expected = if (0x61..=0x7A).contains(&ch) {
+                    ch - 0x20
+                } else {
+                    ch
+                };
+                if u32::from(mapping.unwrap_or(ch as u16)) != expected {
+                    ascii_upcase = false;
+                }
+            }
+            first + count >= 0x80
+        })
+        .await?;
+    volume.ascii_upcase = ascii_upcase;
+    Ok(VolumeType::ExFat(volume))
+}
@@ -1,7 +1,7 @@
 //! Boot Parameter Block
 
 use crate::{
-    blockdevice::{Block, BlockCount},
+    blockdevice::{Block, BlockCount, BlockNumber},
     fat::{FatType, OnDiskDirEntry},
 };
 use byteorder::{ByteOrder, LittleEndian};
@@ -9,6 +9,9 @@ use byteorder::{ByteOrder, LittleEndian};
 /// Represents a Boot Parameter Block. This is the first sector of a FAT
 /// formatted partition, and it describes various properties of the FAT
 /// filesystem.
+///
+/// The BPB counts in sectors of `bytes_per_block` bytes, which can be larger
+/// than a `Block`.
 pub struct Bpb<'a> {
     data: &'a [u8; 512],
     pub(crate) fat_type: FatType,
@@ -28,10 +31,14 @@ impl<'a> Bpb<'a> {
         if bpb.footer() != Self::FOOTER_VALUE {
             return Err("Bad BPB footer");
         }
+        if bpb.bytes_per_block() == 0 || bpb.blocks_per_cluster() == 0 {
+            return Err("Bad BPB geometry");
+        }
 
+        let bytes_per_block = u32::from(bpb.bytes_per_block());
         let root_dir_blocks = ((u32::from(bpb.root_entries_count()) * OnDiskDirEntry::LEN_U32)
-            + (Block::LEN_U32 - 1))
-            / Block::LEN_U32;
+            + (bytes_per_block - 1))
+            / bytes_per_block;
         let data_blocks = bpb.total_blocks()
             - (u32::from(bpb.reserved_block_count())
                 + (u32::from(bpb.num_fats()) * bpb.fat_size())
@@ -95,18 +102,26 @@ impl<'a> Bpb<'a> {
 
     // FAT32 only functions
 
-    /// On a FAT32 volume, return the free block count from the Info Block. On
-    /// a FAT16 volume, returns None.
+    /// On a FAT32 volume, return the location of the Info Block (in
+    /// `Block`s from the start of the volume). On a FAT16 volume, returns
+    /// None.
     pub fn fs_info_block(&self) -> Option<BlockCount> {
         if self.fat_type != FatType::Fat32 {
             None
         } else {
-            Some(BlockCount(u32::from(self.fs_info())))
+            Some(BlockCount(
+                BlockNumber::from(self.fs_info()) * BlockNumber::from(self.blocks_per_sector()),
+            ))
         }
     }
 
     // Magic functions that get the right FAT16/FAT32 result
 
+    /// Get the number of `Block`s in each sector.
+    pub fn blocks_per_sector(&self) -> u16 {
+        self.bytes_per_block() / Block::LEN as u16
+    }
+
     /// Get the size of the File Allocation Table in blocks.
     pub fn fat_size(&self) -> u32 {
         let result = u32::from(self.fat_size16());
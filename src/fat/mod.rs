@@ -109,6 +109,7 @@ mod test {
                 size: 0,
                 entry_block: BlockIdx(0),
                 entry_offset: 0,
+                exfat: None,
             }),
             Expected::Lfn(
                 true,
@@ -127,6 +128,7 @@ mod test {
                 size: 0,
                 entry_block: BlockIdx(0),
                 entry_offset: 0,
+                exfat: None,
             }),
             Expected::Lfn(
                 true,
@@ -152,6 +154,7 @@ mod test {
                 size: 11120,
                 entry_block: BlockIdx(0),
                 entry_offset: 0,
+                exfat: None,
             }),
             Expected::Lfn(
                 true,
@@ -169,6 +172,7 @@ mod test {
                 size: 18693,
                 entry_block: BlockIdx(0),
                 entry_offset: 0,
+                exfat: None,
             }),
             Expected::Lfn(
                 true,
@@ -194,6 +198,7 @@ mod test {
                 size: 1494,
                 entry_block: BlockIdx(0),
                 entry_offset: 0,
+                exfat: None,
             }),
             Expected::Lfn(
                 true,
@@ -219,6 +224,7 @@ mod test {
                 size: 12108,
                 entry_block: BlockIdx(0),
                 entry_offset: 0,
+                exfat: None,
             }),
             Expected::Lfn(
                 true,
@@ -315,5 +321,18 @@ mod test {
         assert_eq!(bpb.fat_size(), 32);
         assert_eq!(bpb.total_blocks(), 122_880);
         assert_eq!(bpb.fat_type, FatType::Fat16);
+        assert_eq!(bpb.blocks_per_sector(), 1);
+
+        // The same volume, formatted with 4096 byte sectors
+        let mut large = BPB_EXAMPLE;
+        large[11..13].copy_from_slice(&4096u16.to_le_bytes());
+        let bpb = Bpb::create_from_bytes(&large).unwrap();
+        assert_eq!(bpb.bytes_per_block(), 4096);
+        assert_eq!(bpb.blocks_per_sector(), 8);
+        assert_eq!(bpb.total_clusters(), (122_880 - 1 - 64 - 4) / 16);
+
+        let mut bad = BPB_EXAMPLE;
+        bad[11..13].copy_from_slice(&[0, 0]);
+        assert!(Bpb::create_from_bytes(&bad).is_err());
     }
 }
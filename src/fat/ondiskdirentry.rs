@@ -160,9 +160,10 @@ impl<'a> OnDiskDirEntry<'a> {
             } else {
                 self.first_cluster_fat16()
             },
-            size: self.file_size(),
+            size: u64::from(self.file_size()),
             entry_block,
             entry_offset,
+            exfat: None,
         };
         result.name.contents.copy_from_slice(&self.data[0..11]);
         result
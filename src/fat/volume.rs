@@ -11,8 +11,9 @@ use crate::{
         Bpb, Fat16Info, Fat32Info, FatSpecificInfo, FatType, InfoSector, OnDiskDirEntry,
         RESERVED_ENTRIES,
     },
-    Attributes, Block, BlockCount, BlockDevice, BlockIdx, Cluster, DirEntry, Directory, Error,
-    ShortFileName, TimeSource, VolumeManager, VolumeType,
+    AllocationPolicy, AsyncBlockDevice, AsyncVolumeManager, Attributes, Block, BlockCount,
+    BlockIdx, BlockNumber, Cluster, DirEntry, Directory, Error, ShortFileName, TimeSource,
+    VolumeType,
 };
 use byteorder::{ByteOrder, LittleEndian};
 use core::convert::TryFrom;
@@ -51,7 +52,7 @@ pub struct FatVolume {
     /// The name of this volume
     pub(crate) name: VolumeName,
     /// Number of 512 byte blocks (or Blocks) in a cluster
-    pub(crate) blocks_per_cluster: u8,
+    pub(crate) blocks_per_cluster: u16,
     /// The block the data starts in. Relative to start of partition (so add
     /// `self.lba_offset` before passing to volume manager)
     pub(crate) first_data_block: BlockCount,
@@ -70,12 +71,12 @@ pub struct FatVolume {
 
 impl FatVolume {
     /// Write a new entry in the FAT
-    pub fn update_info_sector<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    pub async fn update_info_sector<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
         &mut self,
-        volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
     ) -> Result<(), Error<D::Error>>
     where
-        D: BlockDevice,
+        D: AsyncBlockDevice,
         T: TimeSource,
     {
         match &self.fat_specific_info {
@@ -88,6 +89,7 @@ impl FatVolume {
                 volume_mgr
                     .block_device
                     .read(&mut blocks, fat32_info.info_location, "read_info_sector")
+                    .await
                     .map_err(Error::DeviceError)?;
                 let block = &mut blocks[0];
                 if let Some(count) = self.free_clusters_count {
@@ -99,6 +101,7 @@ impl FatVolume {
                 volume_mgr
                     .block_device
                     .write(&blocks, fat32_info.info_location)
+                    .await
                     .map_err(Error::DeviceError)?;
             }
         }
@@ -114,14 +117,14 @@ impl FatVolume {
     }
 
     /// Write a new entry in the FAT
-    fn update_fat<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    async fn update_fat<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
         &mut self,
-        volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
         cluster: Cluster,
         new_value: Cluster,
     ) -> Result<(), Error<D::Error>>
     where
-        D: BlockDevice,
+        D: AsyncBlockDevice,
         T: TimeSource,
     {
         let mut blocks = [Block::new()];
@@ -134,6 +137,7 @@ impl FatVolume {
                 volume_mgr
                     .block_device
                     .read(&mut blocks, this_fat_block_num, "read_fat")
+                    .await
                     .map_err(Error::DeviceError)?;
                 let entry = match new_value {
                     Cluster::INVALID => 0xFFF6,
@@ -155,6 +159,7 @@ impl FatVolume {
                 volume_mgr
                     .block_device
                     .read(&mut blocks, this_fat_block_num, "read_fat")
+                    .await
                     .map_err(Error::DeviceError)?;
                 let entry = match new_value {
                     Cluster::INVALID => 0x0FFF_FFF6,
@@ -175,18 +180,19 @@ impl FatVolume {
         volume_mgr
             .block_device
             .write(&blocks, this_fat_block_num)
+            .await
             .map_err(Error::DeviceError)?;
         Ok(())
     }
 
     /// Look in the FAT to see which cluster comes next.
-    pub(crate) fn next_cluster<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    pub(crate) async fn next_cluster<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
         &self,
-        volume_mgr: &VolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
         cluster: Cluster,
     ) -> Result<Cluster, Error<D::Error>>
     where
-        D: BlockDevice,
+        D: AsyncBlockDevice,
         T: TimeSource,
     {
         let mut blocks = [Block::new()];
@@ -198,6 +204,7 @@ impl FatVolume {
                 volume_mgr
                     .block_device
                     .read(&mut blocks, this_fat_block_num, "next_cluster")
+                    .await
                     .map_err(Error::DeviceError)?;
                 let fat_entry = LittleEndian::read_u16(
                     &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 1],
@@ -224,6 +231,7 @@ impl FatVolume {
                 volume_mgr
                     .block_device
                     .read(&mut blocks, this_fat_block_num, "next_cluster")
+                    .await
                     .map_err(Error::DeviceError)?;
                 let fat_entry = LittleEndian::read_u32(
                     &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
@@ -265,8 +273,9 @@ impl FatVolume {
                     Cluster::ROOT_DIR => fat16_info.first_root_dir_block,
                     Cluster(c) => {
                         // FirstSectorofCluster = ((N – 2) * BPB_SecPerClus) + FirstDataSector;
-                        let first_block_of_cluster =
-                            BlockCount((c - 2) * u32::from(self.blocks_per_cluster));
+                        let first_block_of_cluster = BlockCount(
+                            (c - 2) as BlockNumber * BlockNumber::from(self.blocks_per_cluster),
+                        );
                         self.first_data_block + first_block_of_cluster
                     }
                 };
@@ -278,8 +287,9 @@ impl FatVolume {
                     c => c.0,
                 };
                 // FirstSectorofCluster = ((N – 2) * BPB_SecPerClus) + FirstDataSector;
-                let first_block_of_cluster =
-                    BlockCount((cluster_num - 2) * u32::from(self.blocks_per_cluster));
+                let first_block_of_cluster = BlockCount(
+                    (cluster_num - 2) as BlockNumber * BlockNumber::from(self.blocks_per_cluster),
+                );
                 self.lba_start + self.first_data_block + first_block_of_cluster
             }
         }
@@ -287,15 +297,20 @@ impl FatVolume {
 
     /// Finds a empty entry space and writes the new entry to it, allocates a new cluster if it's
     /// needed
-    pub(crate) fn write_new_directory_entry<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    pub(crate) async fn write_new_directory_entry<
+        D,
+        T,
+        const MAX_DIRS: usize,
+        const MAX_FILES: usize,
+    >(
         &mut self,
-        volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
         dir: &Directory,
         name: ShortFileName,
         attributes: Attributes,
     ) -> Result<DirEntry, Error<D::Error>>
     where
-        D: BlockDevice,
+        D: AsyncBlockDevice,
         T: TimeSource,
     {
         match &self.fat_specific_info {
@@ -309,16 +324,18 @@ impl FatVolume {
 
                 let dir_size = match dir.cluster {
                     Cluster::ROOT_DIR => BlockCount(
-                        ((u32::from(fat16_info.root_entries_count) * 32) + (Block::LEN as u32 - 1))
-                            / Block::LEN as u32,
+                        ((BlockNumber::from(fat16_info.root_entries_count) * 32)
+                            + (Block::LEN as BlockNumber - 1))
+                            / Block::LEN as BlockNumber,
                     ),
-                    _ => BlockCount(u32::from(self.blocks_per_cluster)),
+                    _ => BlockCount(BlockNumber::from(self.blocks_per_cluster)),
                 };
                 while let Some(cluster) = current_cluster {
                     for block in first_dir_block_num.range(dir_size) {
                         volume_mgr
                             .block_device
                             .read(&mut blocks, block, "read_dir")
+                            .await
                             .map_err(Error::DeviceError)?;
                         for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
                             let start = entry * OnDiskDirEntry::LEN;
@@ -340,19 +357,20 @@ impl FatVolume {
                                 volume_mgr
                                     .block_device
                                     .write(&blocks, block)
+                                    .await
                                     .map_err(Error::DeviceError)?;
                                 return Ok(entry);
                             }
                         }
                     }
                     if cluster != Cluster::ROOT_DIR {
-                        current_cluster = match self.next_cluster(volume_mgr, cluster) {
+                        current_cluster = match self.next_cluster(volume_mgr, cluster).await {
                             Ok(n) => {
                                 first_dir_block_num = self.cluster_to_block(n);
                                 Some(n)
                             }
                             Err(Error::EndOfFile) => {
-                                let c = self.alloc_cluster(volume_mgr, Some(cluster), true)?;
+                                let c = self.alloc_cluster(volume_mgr, Some(cluster), true).await?;
                                 first_dir_block_num = self.cluster_to_block(c);
                                 Some(c)
                             }
@@ -365,19 +383,21 @@ impl FatVolume {
                 Err(Error::NotEnoughSpace)
             }
             FatSpecificInfo::Fat32(fat32_info) => {
-                let mut first_dir_block_num = match dir.cluster {
-                    Cluster::ROOT_DIR => self.cluster_to_block(fat32_info.first_root_dir_cluster),
-                    _ => self.cluster_to_block(dir.cluster),
+                let first_cluster = match dir.cluster {
+                    Cluster::ROOT_DIR => fat32_info.first_root_dir_cluster,
+                    _ => dir.cluster,
                 };
-                let mut current_cluster = Some(dir.cluster);
+                let mut first_dir_block_num = self.cluster_to_block(first_cluster);
+                let mut current_cluster = Some(first_cluster);
                 let mut blocks = [Block::new()];
 
-                let dir_size = BlockCount(u32::from(self.blocks_per_cluster));
+                let dir_size = BlockCount(BlockNumber::from(self.blocks_per_cluster));
                 while let Some(cluster) = current_cluster {
                     for block in first_dir_block_num.range(dir_size) {
                         volume_mgr
                             .block_device
                             .read(&mut blocks, block, "read_dir")
+                            .await
                             .map_err(Error::DeviceError)?;
                         for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
                             let start = entry * OnDiskDirEntry::LEN;
@@ -399,18 +419,19 @@ impl FatVolume {
                                 volume_mgr
                                     .block_device
                                     .write(&blocks, block)
+                                    .await
                                     .map_err(Error::DeviceError)?;
                                 return Ok(entry);
                             }
                         }
                     }
-                    current_cluster = match self.next_cluster(volume_mgr, cluster) {
+                    current_cluster = match self.next_cluster(volume_mgr, cluster).await {
                         Ok(n) => {
                             first_dir_block_num = self.cluster_to_block(n);
                             Some(n)
                         }
                         Err(Error::EndOfFile) => {
-                            let c = self.alloc_cluster(volume_mgr, Some(cluster), true)?;
+                            let c = self.alloc_cluster(volume_mgr, Some(cluster), true).await?;
                             first_dir_block_num = self.cluster_to_block(c);
                             Some(c)
                         }
@@ -424,15 +445,15 @@ impl FatVolume {
 
     /// Calls callback `func` with every valid entry in the given directory.
     /// Useful for performing directory listings.
-    pub(crate) fn iterate_dir<D, T, F, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    pub(crate) async fn iterate_dir<D, T, F, const MAX_DIRS: usize, const MAX_FILES: usize>(
         &self,
-        volume_mgr: &VolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
         dir: &Directory,
         mut func: F,
     ) -> Result<(), Error<D::Error>>
     where
         F: FnMut(&DirEntry),
-        D: BlockDevice,
+        D: AsyncBlockDevice,
         T: TimeSource,
     {
         match &self.fat_specific_info {
@@ -444,10 +465,11 @@ impl FatVolume {
                 let mut current_cluster = Some(dir.cluster);
                 let dir_size = match dir.cluster {
                     Cluster::ROOT_DIR => BlockCount(
-                        ((u32::from(fat16_info.root_entries_count) * 32) + (Block::LEN as u32 - 1))
-                            / Block::LEN as u32,
+                        ((BlockNumber::from(fat16_info.root_entries_count) * 32)
+                            + (Block::LEN as BlockNumber - 1))
+                            / Block::LEN as BlockNumber,
                     ),
-                    _ => BlockCount(u32::from(self.blocks_per_cluster)),
+                    _ => BlockCount(BlockNumber::from(self.blocks_per_cluster)),
                 };
                 let mut blocks = [Block::new()];
                 while let Some(cluster) = current_cluster {
@@ -455,6 +477,7 @@ impl FatVolume {
                         volume_mgr
                             .block_device
                             .read(&mut blocks, block, "read_dir")
+                            .await
                             .map_err(Error::DeviceError)?;
                         for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
                             let start = entry * OnDiskDirEntry::LEN;
@@ -472,7 +495,7 @@ impl FatVolume {
                         }
                     }
                     if cluster != Cluster::ROOT_DIR {
-                        current_cluster = match self.next_cluster(volume_mgr, cluster) {
+                        current_cluster = match self.next_cluster(volume_mgr, cluster).await {
                             Ok(n) => {
                                 first_dir_block_num = self.cluster_to_block(n);
                                 Some(n)
@@ -493,10 +516,13 @@ impl FatVolume {
                 let mut blocks = [Block::new()];
                 while let Some(cluster) = current_cluster {
                     let block_idx = self.cluster_to_block(cluster);
-                    for block in block_idx.range(BlockCount(u32::from(self.blocks_per_cluster))) {
+                    for block in
+                        block_idx.range(BlockCount(BlockNumber::from(self.blocks_per_cluster)))
+                    {
                         volume_mgr
                             .block_device
                             .read(&mut blocks, block, "read_dir")
+                            .await
                             .map_err(Error::DeviceError)?;
                         for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
                             let start = entry * OnDiskDirEntry::LEN;
@@ -513,7 +539,7 @@ impl FatVolume {
                             }
                         }
                     }
-                    current_cluster = match self.next_cluster(volume_mgr, cluster) {
+                    current_cluster = match self.next_cluster(volume_mgr, cluster).await {
                         Ok(n) => Some(n),
                         _ => None,
                     };
@@ -524,14 +550,14 @@ impl FatVolume {
     }
 
     /// Get an entry from the given directory
-    pub(crate) fn find_directory_entry<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    pub(crate) async fn find_directory_entry<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
         &self,
-        volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
         dir: &Directory,
         name: &str,
     ) -> Result<DirEntry, Error<D::Error>>
     where
-        D: BlockDevice,
+        D: AsyncBlockDevice,
         T: TimeSource,
     {
         let match_name = ShortFileName::create_from_str(name).map_err(Error::FilenameError)?;
@@ -544,26 +570,25 @@ impl FatVolume {
                 };
                 let dir_size = match dir.cluster {
                     Cluster::ROOT_DIR => BlockCount(
-                        ((u32::from(fat16_info.root_entries_count) * 32) + (Block::LEN as u32 - 1))
-                            / Block::LEN as u32,
+                        ((BlockNumber::from(fat16_info.root_entries_count) * 32)
+                            + (Block::LEN as BlockNumber - 1))
+                            / Block::LEN as BlockNumber,
                     ),
-                    _ => BlockCount(u32::from(self.blocks_per_cluster)),
+                    _ => BlockCount(BlockNumber::from(self.blocks_per_cluster)),
                 };
 
                 while let Some(cluster) = current_cluster {
                     for block in first_dir_block_num.range(dir_size) {
-                        match self.find_entry_in_block(
-                            volume_mgr,
-                            FatType::Fat16,
-                            &match_name,
-                            block,
-                        ) {
+                        match self
+                            .find_entry_in_block(volume_mgr, FatType::Fat16, &match_name, block)
+                            .await
+                        {
                             Err(Error::NotInBlock) => continue,
                             x => return x,
                         }
                     }
                     if cluster != Cluster::ROOT_DIR {
-                        current_cluster = match self.next_cluster(volume_mgr, cluster) {
+                        current_cluster = match self.next_cluster(volume_mgr, cluster).await {
                             Ok(n) => {
                                 first_dir_block_num = self.cluster_to_block(n);
                                 Some(n)
@@ -583,18 +608,18 @@ impl FatVolume {
                 };
                 while let Some(cluster) = current_cluster {
                     let block_idx = self.cluster_to_block(cluster);
-                    for block in block_idx.range(BlockCount(u32::from(self.blocks_per_cluster))) {
-                        match self.find_entry_in_block(
-                            volume_mgr,
-                            FatType::Fat32,
-                            &match_name,
-                            block,
-                        ) {
+                    for block in
+                        block_idx.range(BlockCount(BlockNumber::from(self.blocks_per_cluster)))
+                    {
+                        match self
+                            .find_entry_in_block(volume_mgr, FatType::Fat32, &match_name, block)
+                            .await
+                        {
                             Err(Error::NotInBlock) => continue,
                             x => return x,
                         }
                     }
-                    current_cluster = match self.next_cluster(volume_mgr, cluster) {
+                    current_cluster = match self.next_cluster(volume_mgr, cluster).await {
                         Ok(n) => Some(n),
                         _ => None,
                     }
@@ -605,21 +630,22 @@ impl FatVolume {
     }
 
     /// Finds an entry in a given block
-    fn find_entry_in_block<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    async fn find_entry_in_block<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
         &self,
-        volume_mgr: &mut VolumeManager<D, T, MAX_FILES, MAX_DIRS>,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_FILES, MAX_DIRS>,
         fat_type: FatType,
         match_name: &ShortFileName,
         block: BlockIdx,
     ) -> Result<DirEntry, Error<D::Error>>
     where
-        D: BlockDevice,
+        D: AsyncBlockDevice,
         T: TimeSource,
     {
         let mut blocks = [Block::new()];
         volume_mgr
             .block_device
             .read(&mut blocks, block, "read_dir")
+            .await
             .map_err(Error::DeviceError)?;
         for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
             let start = entry * OnDiskDirEntry::LEN;
@@ -638,15 +664,36 @@ impl FatVolume {
         Err(Error::NotInBlock)
     }
 
-    /// Delete an entry from the given directory
-    pub(crate) fn delete_directory_entry<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
-        &self,
-        volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+    /// Delete an entry from the given directory, and free its clusters.
+    pub(crate) async fn delete_directory_entry<
+        D,
+        T,
+        const MAX_DIRS: usize,
+        const MAX_FILES: usize,
+    >(
+        &mut self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
         dir: &Directory,
         name: &str,
     ) -> Result<(), Error<D::Error>>
     where
-        D: BlockDevice,
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let cluster = self.remove_directory_entry(volume_mgr, dir, name).await?;
+        self.free_cluster_chain(volume_mgr, cluster).await
+    }
+
+    /// Mark an entry in the given directory as deleted, returning the first
+    /// cluster of the file it was for.
+    async fn remove_directory_entry<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        dir: &Directory,
+        name: &str,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
         T: TimeSource,
     {
         let match_name = ShortFileName::create_from_str(name).map_err(Error::FilenameError)?;
@@ -659,21 +706,25 @@ impl FatVolume {
                 };
                 let dir_size = match dir.cluster {
                     Cluster::ROOT_DIR => BlockCount(
-                        ((u32::from(fat16_info.root_entries_count) * 32) + (Block::LEN as u32 - 1))
-                            / Block::LEN as u32,
+                        ((BlockNumber::from(fat16_info.root_entries_count) * 32)
+                            + (Block::LEN as BlockNumber - 1))
+                            / Block::LEN as BlockNumber,
                     ),
-                    _ => BlockCount(u32::from(self.blocks_per_cluster)),
+                    _ => BlockCount(BlockNumber::from(self.blocks_per_cluster)),
                 };
 
                 while let Some(cluster) = current_cluster {
                     for block in first_dir_block_num.range(dir_size) {
-                        match self.delete_entry_in_block(volume_mgr, &match_name, block) {
+                        match self
+                            .delete_entry_in_block(volume_mgr, FatType::Fat16, &match_name, block)
+                            .await
+                        {
                             Err(Error::NotInBlock) => continue,
                             x => return x,
                         }
                     }
                     if cluster != Cluster::ROOT_DIR {
-                        current_cluster = match self.next_cluster(volume_mgr, cluster) {
+                        current_cluster = match self.next_cluster(volume_mgr, cluster).await {
                             Ok(n) => {
                                 first_dir_block_num = self.cluster_to_block(n);
                                 Some(n)
@@ -693,13 +744,18 @@ impl FatVolume {
                 };
                 while let Some(cluster) = current_cluster {
                     let block_idx = self.cluster_to_block(cluster);
-                    for block in block_idx.range(BlockCount(u32::from(self.blocks_per_cluster))) {
-                        match self.delete_entry_in_block(volume_mgr, &match_name, block) {
+                    for block in
+                        block_idx.range(BlockCount(BlockNumber::from(self.blocks_per_cluster)))
+                    {
+                        match self
+                            .delete_entry_in_block(volume_mgr, FatType::Fat32, &match_name, block)
+                            .await
+                        {
                             Err(Error::NotInBlock) => continue,
                             x => return x,
                         }
                     }
-                    current_cluster = match self.next_cluster(volume_mgr, cluster) {
+                    current_cluster = match self.next_cluster(volume_mgr, cluster).await {
                         Ok(n) => Some(n),
                         _ => None,
                     }
@@ -710,20 +766,22 @@ impl FatVolume {
     }
 
     /// Deletes an entry in a given block
-    fn delete_entry_in_block<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    async fn delete_entry_in_block<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
         &self,
-        volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        fat_type: FatType,
         match_name: &ShortFileName,
         block: BlockIdx,
-    ) -> Result<(), Error<D::Error>>
+    ) -> Result<Cluster, Error<D::Error>>
     where
-        D: BlockDevice,
+        D: AsyncBlockDevice,
         T: TimeSource,
     {
         let mut blocks = [Block::new()];
         volume_mgr
             .block_device
             .read(&mut blocks, block, "read_dir")
+            .await
             .map_err(Error::DeviceError)?;
         for entry in 0..Block::LEN / OnDiskDirEntry::LEN {
             let start = entry * OnDiskDirEntry::LEN;
@@ -733,27 +791,37 @@ impl FatVolume {
                 // Can quit early
                 return Err(Error::FileNotFound);
             } else if dir_entry.matches(match_name) {
+                // Safe, since Block::LEN always fits on a u32
+                let cluster = dir_entry
+                    .get_entry(fat_type, block, u32::try_from(start).unwrap())
+                    .cluster;
                 let mut blocks = blocks;
                 blocks[0].contents[start] = 0xE5;
                 volume_mgr
                     .block_device
                     .write(&blocks, block)
+                    .await
                     .map_err(Error::DeviceError)?;
-                return Ok(());
+                return Ok(cluster);
             }
         }
         Err(Error::NotInBlock)
     }
 
     /// Finds the next free cluster after the start_cluster and before end_cluster
-    pub(crate) fn find_next_free_cluster<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    pub(crate) async fn find_next_free_cluster<
+        D,
+        T,
+        const MAX_DIRS: usize,
+        const MAX_FILES: usize,
+    >(
         &self,
-        volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
         start_cluster: Cluster,
         end_cluster: Cluster,
     ) -> Result<Cluster, Error<D::Error>>
     where
-        D: BlockDevice,
+        D: AsyncBlockDevice,
         T: TimeSource,
     {
         let mut blocks = [Block::new()];
@@ -777,9 +845,11 @@ impl FatVolume {
                     volume_mgr
                         .block_device
                         .read(&mut blocks, this_fat_block_num, "next_cluster")
+                        .await
                         .map_err(Error::DeviceError)?;
 
-                    while this_fat_ent_offset <= Block::LEN - 2 {
+                    while this_fat_ent_offset <= Block::LEN - 2 && current_cluster.0 < end_cluster.0
+                    {
                         let fat_entry = LittleEndian::read_u16(
                             &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 1],
                         );
@@ -809,9 +879,11 @@ impl FatVolume {
                     volume_mgr
                         .block_device
                         .read(&mut blocks, this_fat_block_num, "next_cluster")
+                        .await
                         .map_err(Error::DeviceError)?;
 
-                    while this_fat_ent_offset <= Block::LEN - 4 {
+                    while this_fat_ent_offset <= Block::LEN - 4 && current_cluster.0 < end_cluster.0
+                    {
                         let fat_entry = LittleEndian::read_u32(
                             &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
                         ) & 0x0FFF_FFFF;
@@ -828,70 +900,483 @@ impl FatVolume {
         Err(Error::NotEnoughSpace)
     }
 
+    /// Walks the FAT between start_cluster and end_cluster, calling `func`
+    /// with the first cluster and the length of each run of free clusters.
+    ///
+    /// A run is reported when it ends, or as soon as it reaches `max_length`
+    /// clusters. The walk stops early if `func` returns `true`, in which case
+    /// we return `true`.
+    async fn scan_free_runs<D, T, F, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        start_cluster: Cluster,
+        end_cluster: Cluster,
+        max_length: u32,
+        mut func: F,
+    ) -> Result<bool, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+        F: FnMut(Cluster, u32) -> bool,
+    {
+        let mut blocks = [Block::new()];
+        let mut current_cluster = start_cluster;
+        let mut run_start = start_cluster;
+        let mut run_length = 0;
+        let entry_len: usize = match &self.fat_specific_info {
+            FatSpecificInfo::Fat16(_fat16_info) => 2,
+            FatSpecificInfo::Fat32(_fat32_info) => 4,
+        };
+        while current_cluster.0 < end_cluster.0 {
+            let fat_offset = current_cluster.0 * entry_len as u32;
+            let this_fat_block_num = self.lba_start + self.fat_start.offset_bytes(fat_offset);
+            let mut this_fat_ent_offset =
+                usize::try_from(fat_offset % Block::LEN_U32).map_err(|_| Error::ConversionError)?;
+            volume_mgr
+                .block_device
+                .read(&mut blocks, this_fat_block_num, "scan_free_runs")
+                .await
+                .map_err(Error::DeviceError)?;
+            while this_fat_ent_offset <= Block::LEN - entry_len && current_cluster.0 < end_cluster.0
+            {
+                let is_free = match &self.fat_specific_info {
+                    FatSpecificInfo::Fat16(_fat16_info) => {
+                        LittleEndian::read_u16(
+                            &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 1],
+                        ) == 0
+                    }
+                    FatSpecificInfo::Fat32(_fat32_info) => {
+                        LittleEndian::read_u32(
+                            &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
+                        ) & 0x0FFF_FFFF
+                            == 0
+                    }
+                };
+                if is_free {
+                    if run_length == 0 {
+                        run_start = current_cluster;
+                    }
+                    run_length += 1;
+                    if run_length == max_length {
+                        if func(run_start, run_length) {
+                            return Ok(true);
+                        }
+                        run_length = 0;
+                    }
+                } else if run_length != 0 {
+                    if func(run_start, run_length) {
+                        return Ok(true);
+                    }
+                    run_length = 0;
+                }
+                this_fat_ent_offset += entry_len;
+                current_cluster += 1;
+            }
+        }
+        if run_length != 0 {
+            return Ok(func(run_start, run_length));
+        }
+        Ok(false)
+    }
+
+    /// Finds the first run of `count` consecutive free clusters after the
+    /// start_cluster and before end_cluster. Returns the first cluster in the
+    /// run.
+    pub(crate) async fn find_free_run<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        start_cluster: Cluster,
+        end_cluster: Cluster,
+        count: u32,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let mut found = None;
+        self.scan_free_runs(
+            volume_mgr,
+            start_cluster,
+            end_cluster,
+            count,
+            |start, length| {
+                if length >= count {
+                    found = Some(start);
+                }
+                found.is_some()
+            },
+        )
+        .await?;
+        found.ok_or(Error::NotEnoughSpace)
+    }
+
+    /// Counts how many clusters, starting with `cluster`, follow one another
+    /// on disk - that is, how far along the chain each cluster's successor
+    /// is simply the next cluster number. Stops counting at `max`. Walks the
+    /// FAT a block at a time rather than a cluster at a time.
+    pub(crate) async fn contiguous_clusters<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        cluster: Cluster,
+        max: u32,
+    ) -> Result<u32, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let end_cluster = Cluster(self.cluster_count + RESERVED_ENTRIES);
+        if cluster.0 < RESERVED_ENTRIES || cluster.0 >= end_cluster.0 {
+            return Ok(0);
+        }
+        let mut blocks = [Block::new()];
+        let mut current_cluster = cluster;
+        let mut count = 1;
+        let entry_len: usize = match &self.fat_specific_info {
+            FatSpecificInfo::Fat16(_fat16_info) => 2,
+            FatSpecificInfo::Fat32(_fat32_info) => 4,
+        };
+        loop {
+            let fat_offset = current_cluster.0 * entry_len as u32;
+            let this_fat_block_num = self.lba_start + self.fat_start.offset_bytes(fat_offset);
+            let mut this_fat_ent_offset =
+                usize::try_from(fat_offset % Block::LEN_U32).map_err(|_| Error::ConversionError)?;
+            volume_mgr
+                .block_device
+                .read(&mut blocks, this_fat_block_num, "contiguous_clusters")
+                .await
+                .map_err(Error::DeviceError)?;
+            while this_fat_ent_offset <= Block::LEN - entry_len {
+                let next = match &self.fat_specific_info {
+                    FatSpecificInfo::Fat16(_fat16_info) => u32::from(LittleEndian::read_u16(
+                        &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 1],
+                    )),
+                    FatSpecificInfo::Fat32(_fat32_info) => {
+                        LittleEndian::read_u32(
+                            &blocks[0][this_fat_ent_offset..=this_fat_ent_offset + 3],
+                        ) & 0x0FFF_FFFF
+                    }
+                };
+                if next != current_cluster.0 + 1 || next >= end_cluster.0 || count >= max {
+                    return Ok(count);
+                }
+                count += 1;
+                this_fat_ent_offset += entry_len;
+                current_cluster += 1;
+            }
+        }
+    }
+
+    /// Finds the smallest run of free clusters on the volume which can hold
+    /// at least `count` clusters. Returns the first cluster in the run.
+    async fn find_best_free_run<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        count: u32,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let end_cluster = Cluster(self.cluster_count + RESERVED_ENTRIES);
+        let mut best: Option<(Cluster, u32)> = None;
+        self.scan_free_runs(
+            volume_mgr,
+            Cluster(RESERVED_ENTRIES),
+            end_cluster,
+            u32::MAX,
+            |start, length| {
+                let better = match best {
+                    Some((_, best_length)) => length < best_length,
+                    None => true,
+                };
+                if length >= count && better {
+                    best = Some((start, length));
+                }
+                // Can't do better than an exact fit
+                length == count
+            },
+        )
+        .await?;
+        best.map(|(start, _)| start).ok_or(Error::NotEnoughSpace)
+    }
+
+    /// Finds the largest run of free clusters on the volume. Returns the first
+    /// cluster in the run.
+    async fn find_largest_free_run<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let end_cluster = Cluster(self.cluster_count + RESERVED_ENTRIES);
+        let mut largest: Option<(Cluster, u32)> = None;
+        self.scan_free_runs(
+            volume_mgr,
+            Cluster(RESERVED_ENTRIES),
+            end_cluster,
+            u32::MAX,
+            |start, length| {
+                let larger = match largest {
+                    Some((_, largest_length)) => length > largest_length,
+                    None => true,
+                };
+                if larger {
+                    largest = Some((start, length));
+                }
+                false
+            },
+        )
+        .await?;
+        largest.map(|(start, _)| start).ok_or(Error::NotEnoughSpace)
+    }
+
+    /// Checks whether the cluster following `prev_cluster` is free, so the
+    /// chain ending in `prev_cluster` can be extended in-place by `count`
+    /// clusters.
+    async fn can_extend_in_place<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        prev_cluster: Cluster,
+        count: u32,
+    ) -> Result<bool, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let end_cluster = Cluster(self.cluster_count + RESERVED_ENTRIES);
+        let first_cluster = prev_cluster + 1;
+        if prev_cluster.0 < RESERVED_ENTRIES
+            || u64::from(first_cluster.0) + u64::from(count) > u64::from(end_cluster.0)
+        {
+            return Ok(false);
+        }
+        match self
+            .find_free_run(volume_mgr, first_cluster, first_cluster + count, count)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(Error::NotEnoughSpace) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Marks `count` free clusters starting at `first_cluster` as a single
+    /// chain, and appends that chain to `prev_cluster` (if given).
+    async fn link_run<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &mut self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        prev_cluster: Option<Cluster>,
+        first_cluster: Cluster,
+        count: u32,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let last_cluster = first_cluster + (count - 1);
+        let mut cluster = first_cluster;
+        while cluster.0 < last_cluster.0 {
+            self.update_fat(volume_mgr, cluster, cluster + 1).await?;
+            cluster += 1;
+        }
+        self.update_fat(volume_mgr, last_cluster, Cluster::END_OF_FILE)
+            .await?;
+        if let Some(cluster) = prev_cluster {
+            self.update_fat(volume_mgr, cluster, first_cluster).await?;
+        }
+        if let Some(ref mut number_free_cluster) = self.free_clusters_count {
+            *number_free_cluster -= count;
+        }
+        match self.next_free_cluster {
+            Some(cluster) if cluster.0 < first_cluster.0 || cluster.0 > last_cluster.0 => {}
+            _ => self.next_free_cluster = Some(last_cluster + 1),
+        }
+        Ok(())
+    }
+
+    /// Allocates `count` clusters and appends them to the chain ending in
+    /// `prev_cluster` (if given), returning the first new cluster.
+    ///
+    /// We try to extend the chain in-place first, then look for a run of
+    /// free clusters large enough to hold all `count` clusters, chosen
+    /// according to the volume manager's [`AllocationPolicy`]. Only if there
+    /// is no such run do we fall back to allocating clusters one at a time,
+    /// wherever they happen to be free.
+    pub(crate) async fn alloc_clusters<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &mut self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        prev_cluster: Option<Cluster>,
+        count: u32,
+    ) -> Result<Cluster, Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        debug!(
+            "Allocating {} clusters, prev_cluster={:?}",
+            count, prev_cluster
+        );
+        if count == 0 {
+            return Err(Error::AllocationError);
+        }
+        let end_cluster = Cluster(self.cluster_count + RESERVED_ENTRIES);
+        let run_start = match prev_cluster {
+            Some(prev) if self.can_extend_in_place(volume_mgr, prev, count).await? => Ok(prev + 1),
+            _ => match volume_mgr.allocation_policy {
+                AllocationPolicy::FirstFit => {
+                    self.find_free_run(volume_mgr, Cluster(RESERVED_ENTRIES), end_cluster, count)
+                        .await
+                }
+                AllocationPolicy::NextFit => {
+                    let start_cluster = match self.next_free_cluster {
+                        Some(cluster) if cluster.0 < end_cluster.0 => cluster,
+                        _ => Cluster(RESERVED_ENTRIES),
+                    };
+                    match self
+                        .find_free_run(volume_mgr, start_cluster, end_cluster, count)
+                        .await
+                    {
+                        Err(Error::NotEnoughSpace) if start_cluster.0 > RESERVED_ENTRIES => {
+                            self.find_free_run(
+                                volume_mgr,
+                                Cluster(RESERVED_ENTRIES),
+                                end_cluster,
+                                count,
+                            )
+                            .await
+                        }
+                        result => result,
+                    }
+                }
+                AllocationPolicy::BestFitContiguous => {
+                    self.find_best_free_run(volume_mgr, count).await
+                }
+            },
+        };
+        match run_start {
+            Ok(first_cluster) => {
+                debug!("Found contiguous run at {:?}", first_cluster);
+                self.link_run(volume_mgr, prev_cluster, first_cluster, count)
+                    .await?;
+                return Ok(first_cluster);
+            }
+            Err(Error::NotEnoughSpace) => {}
+            Err(e) => return Err(e),
+        }
+        debug!("No contiguous run found, allocating one at a time");
+        let first_cluster = self.alloc_cluster(volume_mgr, prev_cluster, false).await?;
+        let mut last_cluster = first_cluster;
+        for _ in 1..count {
+            match self
+                .alloc_cluster(volume_mgr, Some(last_cluster), false)
+                .await
+            {
+                Ok(cluster) => last_cluster = cluster,
+                Err(e) => {
+                    // Give back what we took, so a failed allocation
+                    // doesn't leave the file holding all the free space
+                    match prev_cluster {
+                        Some(prev) => self.truncate_cluster_chain(volume_mgr, prev).await?,
+                        None => self.free_cluster_chain(volume_mgr, first_cluster).await?,
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(first_cluster)
+    }
+
     /// Tries to allocate a cluster
-    pub(crate) fn alloc_cluster<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    pub(crate) async fn alloc_cluster<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
         &mut self,
-        volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
         prev_cluster: Option<Cluster>,
         zero: bool,
     ) -> Result<Cluster, Error<D::Error>>
     where
-        D: BlockDevice,
+        D: AsyncBlockDevice,
         T: TimeSource,
     {
         debug!("Allocating new cluster, prev_cluster={:?}", prev_cluster);
         let end_cluster = Cluster(self.cluster_count + RESERVED_ENTRIES);
-        let start_cluster = match self.next_free_cluster {
-            Some(cluster) if cluster.0 < end_cluster.0 => cluster,
-            _ => Cluster(RESERVED_ENTRIES),
-        };
-        trace!(
-            "Finding next free between {:?}..={:?}",
-            start_cluster,
-            end_cluster
-        );
-        let new_cluster = match self.find_next_free_cluster(volume_mgr, start_cluster, end_cluster)
-        {
-            Ok(cluster) => cluster,
-            Err(_) if start_cluster.0 > RESERVED_ENTRIES => {
-                debug!(
-                    "Retrying, finding next free between {:?}..={:?}",
-                    Cluster(RESERVED_ENTRIES),
+        let new_cluster = match volume_mgr.allocation_policy {
+            AllocationPolicy::FirstFit => {
+                self.find_next_free_cluster(volume_mgr, Cluster(RESERVED_ENTRIES), end_cluster)
+                    .await?
+            }
+            AllocationPolicy::BestFitContiguous if !zero => match prev_cluster {
+                Some(prev) if self.can_extend_in_place(volume_mgr, prev, 1).await? => prev + 1,
+                // We don't know how big this file will get, so start it in
+                // the biggest gap we have.
+                _ => self.find_largest_free_run(volume_mgr).await?,
+            },
+            // Directories grow a cluster at a time and stay small, so they
+            // aren't worth a scan of the whole FAT. They use next fit.
+            AllocationPolicy::NextFit | AllocationPolicy::BestFitContiguous => {
+                let start_cluster = match self.next_free_cluster {
+                    Some(cluster) if cluster.0 < end_cluster.0 => cluster,
+                    _ => Cluster(RESERVED_ENTRIES),
+                };
+                trace!(
+                    "Finding next free between {:?}..={:?}",
+                    start_cluster,
                     end_cluster
                 );
-                self.find_next_free_cluster(volume_mgr, Cluster(RESERVED_ENTRIES), end_cluster)?
+                match self
+                    .find_next_free_cluster(volume_mgr, start_cluster, end_cluster)
+                    .await
+                {
+                    Ok(cluster) => cluster,
+                    Err(_) if start_cluster.0 > RESERVED_ENTRIES => {
+                        debug!(
+                            "Retrying, finding next free between {:?}..={:?}",
+                            Cluster(RESERVED_ENTRIES),
+                            end_cluster
+                        );
+                        self.find_next_free_cluster(
+                            volume_mgr,
+                            Cluster(RESERVED_ENTRIES),
+                            end_cluster,
+                        )
+                        .await?
+                    }
+                    Err(e) => return Err(e),
+                }
             }
-            Err(e) => return Err(e),
         };
-        self.update_fat(volume_mgr, new_cluster, Cluster::END_OF_FILE)?;
+        self.update_fat(volume_mgr, new_cluster, Cluster::END_OF_FILE)
+            .await?;
         if let Some(cluster) = prev_cluster {
             trace!(
                 "Updating old cluster {:?} to {:?} in FAT",
                 cluster,
                 new_cluster
             );
-            self.update_fat(volume_mgr, cluster, new_cluster)?;
+            self.update_fat(volume_mgr, cluster, new_cluster).await?;
         }
         trace!(
             "Finding next free between {:?}..={:?}",
             new_cluster,
             end_cluster
         );
-        self.next_free_cluster =
-            match self.find_next_free_cluster(volume_mgr, new_cluster, end_cluster) {
-                Ok(cluster) => Some(cluster),
-                Err(_) if new_cluster.0 > RESERVED_ENTRIES => {
-                    match self.find_next_free_cluster(
-                        volume_mgr,
-                        Cluster(RESERVED_ENTRIES),
-                        end_cluster,
-                    ) {
-                        Ok(cluster) => Some(cluster),
-                        Err(e) => return Err(e),
-                    }
+        self.next_free_cluster = match self
+            .find_next_free_cluster(volume_mgr, new_cluster, end_cluster)
+            .await
+        {
+            Ok(cluster) => Some(cluster),
+            Err(_) if new_cluster.0 > RESERVED_ENTRIES => {
+                match self
+                    .find_next_free_cluster(volume_mgr, Cluster(RESERVED_ENTRIES), end_cluster)
+                    .await
+                {
+                    Ok(cluster) => Some(cluster),
+                    Err(e) => return Err(e),
                 }
-                Err(e) => return Err(e),
-            };
+            }
+            Err(e) => return Err(e),
+        };
         debug!("Next free cluster is {:?}", self.next_free_cluster);
         if let Some(ref mut number_free_cluster) = self.free_clusters_count {
             *number_free_cluster -= 1;
@@ -899,11 +1384,12 @@ impl FatVolume {
         if zero {
             let blocks = [Block::new()];
             let first_block = self.cluster_to_block(new_cluster);
-            let num_blocks = BlockCount(u32::from(self.blocks_per_cluster));
+            let num_blocks = BlockCount(BlockNumber::from(self.blocks_per_cluster));
             for block in first_block.range(num_blocks) {
                 volume_mgr
                     .block_device
                     .write(&blocks, block)
+                    .await
                     .map_err(Error::DeviceError)?;
             }
         }
@@ -912,61 +1398,110 @@ impl FatVolume {
     }
 
     /// Marks the input cluster as an EOF and all the subsequent clusters in the chain as free
-    pub(crate) fn truncate_cluster_chain<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    pub(crate) async fn truncate_cluster_chain<
+        D,
+        T,
+        const MAX_DIRS: usize,
+        const MAX_FILES: usize,
+    >(
         &mut self,
-        volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
         cluster: Cluster,
     ) -> Result<(), Error<D::Error>>
     where
-        D: BlockDevice,
+        D: AsyncBlockDevice,
         T: TimeSource,
     {
         if cluster.0 < RESERVED_ENTRIES {
             // file doesn't have any valid cluster allocated, there is nothing to do
             return Ok(());
         }
-        let mut next = match self.next_cluster(volume_mgr, cluster) {
+        let next = match self.next_cluster(volume_mgr, cluster).await {
             Ok(n) => n,
             Err(Error::EndOfFile) => return Ok(()),
             Err(e) => return Err(e),
         };
-        if let Some(ref mut next_free_cluster) = self.next_free_cluster {
-            if next_free_cluster.0 > next.0 {
-                *next_free_cluster = next;
-            }
-        } else {
-            self.next_free_cluster = Some(next);
+        self.update_fat(volume_mgr, cluster, Cluster::END_OF_FILE)
+            .await?;
+        self.free_cluster_chain(volume_mgr, next).await
+    }
+
+    /// Marks the input cluster and all the subsequent clusters in the chain
+    /// as free
+    pub(crate) async fn free_cluster_chain<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &mut self,
+        volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        cluster: Cluster,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        if cluster.0 < RESERVED_ENTRIES {
+            return Ok(());
         }
-        self.update_fat(volume_mgr, cluster, Cluster::END_OF_FILE)?;
+        match self.next_free_cluster {
+            Some(next_free_cluster) if next_free_cluster.0 <= cluster.0 => {}
+            _ => self.next_free_cluster = Some(cluster),
+        }
+        // The freed clusters are discarded a contiguous run at a time
+        let mut next = cluster;
+        let mut run_start = next;
+        let mut run_length = 0;
         loop {
-            match self.next_cluster(volume_mgr, next) {
-                Ok(n) => {
-                    self.update_fat(volume_mgr, next, Cluster::EMPTY)?;
-                    next = n;
-                }
-                Err(Error::EndOfFile) => {
-                    self.update_fat(volume_mgr, next, Cluster::EMPTY)?;
-                    break;
-                }
+            let result = match self.next_cluster(volume_mgr, next).await {
+                Ok(n) => Some(n),
+                Err(Error::EndOfFile) => None,
                 Err(e) => return Err(e),
-            }
+            };
+            self.update_fat(volume_mgr, next, Cluster::EMPTY).await?;
             if let Some(ref mut number_free_cluster) = self.free_clusters_count {
                 *number_free_cluster += 1;
             };
+            if next != run_start + run_length {
+                self.discard_clusters(volume_mgr, run_start, run_length)
+                    .await;
+                run_start = next;
+                run_length = 0;
+            }
+            run_length += 1;
+            match result {
+                Some(n) => next = n,
+                None => break,
+            }
         }
+        self.discard_clusters(volume_mgr, run_start, run_length)
+            .await;
         Ok(())
     }
+
+    /// Tell the block device that a run of clusters has been freed.
+    async fn discard_clusters<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+        &self,
+        volume_mgr: &AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+        first: Cluster,
+        count: u32,
+    ) where
+        D: AsyncBlockDevice,
+        T: TimeSource,
+    {
+        let num_blocks =
+            BlockCount(BlockNumber::from(self.blocks_per_cluster) * BlockNumber::from(count));
+        volume_mgr
+            .discard_blocks(self.cluster_to_block(first), num_blocks)
+            .await;
+    }
 }
 
 /// Load the boot parameter block from the start of the given partition and
 /// determine if the partition contains a valid FAT16 or FAT32 file system.
-pub fn parse_volume<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
-    volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES>,
+pub async fn parse_volume<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>(
+    volume_mgr: &mut AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>,
     lba_start: BlockIdx,
     num_blocks: BlockCount,
 ) -> Result<VolumeType, Error<D::Error>>
 where
-    D: BlockDevice,
+    D: AsyncBlockDevice,
     T: TimeSource,
     D::Error: core::fmt::Debug,
 {
@@ -974,29 +1509,38 @@ where
     volume_mgr
         .block_device
         .read(&mut blocks, lba_start, "read_bpb")
+        .await
         .map_err(Error::DeviceError)?;
     let block = &blocks[0];
     let bpb = Bpb::create_from_bytes(block).map_err(Error::FormatError)?;
+    // The BPB counts in sectors, which can be bigger than our blocks
+    if !matches!(bpb.bytes_per_block(), 512 | 1024 | 2048 | 4096) {
+        return Err(Error::BadBlockSize(bpb.bytes_per_block()));
+    }
+    let blocks_per_sector = BlockNumber::from(bpb.blocks_per_sector());
+    let blocks_per_cluster = u16::from(bpb.blocks_per_cluster()) * bpb.blocks_per_sector();
+    let fat_start = BlockCount(BlockNumber::from(bpb.reserved_block_count()) * blocks_per_sector);
+    let fats_size = BlockCount(
+        BlockNumber::from(bpb.num_fats()) * bpb.fat_size() as BlockNumber * blocks_per_sector,
+    );
     match bpb.fat_type {
         FatType::Fat16 => {
-            if bpb.bytes_per_block() as usize != Block::LEN {
-                return Err(Error::BadBlockSize(bpb.bytes_per_block()));
-            }
             // FirstDataSector = BPB_ResvdSecCnt + (BPB_NumFATs * FATSz) + RootDirSectors;
-            let root_dir_blocks = ((u32::from(bpb.root_entries_count()) * OnDiskDirEntry::LEN_U32)
-                + (Block::LEN_U32 - 1))
-                / Block::LEN_U32;
-            let fat_start = BlockCount(u32::from(bpb.reserved_block_count()));
-            let first_root_dir_block =
-                fat_start + BlockCount(u32::from(bpb.num_fats()) * bpb.fat_size());
-            let first_data_block = first_root_dir_block + BlockCount(root_dir_blocks);
+            let bytes_per_sector = u32::from(bpb.bytes_per_block());
+            let root_dir_sectors = ((u32::from(bpb.root_entries_count())
+                * OnDiskDirEntry::LEN_U32)
+                + (bytes_per_sector - 1))
+                / bytes_per_sector;
+            let first_root_dir_block = fat_start + fats_size;
+            let first_data_block = first_root_dir_block
+                + BlockCount(root_dir_sectors as BlockNumber * blocks_per_sector);
             let mut volume = FatVolume {
                 lba_start,
                 num_blocks,
                 name: VolumeName { data: [0u8; 11] },
-                blocks_per_cluster: bpb.blocks_per_cluster(),
+                blocks_per_cluster,
                 first_data_block: (first_data_block),
-                fat_start: BlockCount(u32::from(bpb.reserved_block_count())),
+                fat_start,
                 free_clusters_count: None,
                 next_free_cluster: None,
                 cluster_count: bpb.total_clusters(),
@@ -1010,8 +1554,7 @@ where
         }
         FatType::Fat32 => {
             // FirstDataSector = BPB_ResvdSecCnt + (BPB_NumFATs * FATSz);
-            let first_data_block = u32::from(bpb.reserved_block_count())
-                + (u32::from(bpb.num_fats()) * bpb.fat_size());
+            let first_data_block = fat_start + fats_size;
 
             // Safe to unwrap since this is a Fat32 Type
             let info_location = bpb.fs_info_block().unwrap();
@@ -1023,6 +1566,7 @@ where
                     lba_start + info_location,
                     "read_info_sector",
                 )
+                .await
                 .map_err(Error::DeviceError)?;
             let info_block = &info_blocks[0];
             let info_sector =
@@ -1032,9 +1576,9 @@ where
                 lba_start,
                 num_blocks,
                 name: VolumeName { data: [0u8; 11] },
-                blocks_per_cluster: bpb.blocks_per_cluster(),
-                first_data_block: BlockCount(first_data_block),
-                fat_start: BlockCount(u32::from(bpb.reserved_block_count())),
+                blocks_per_cluster,
+                first_data_block,
+                fat_start,
                 free_clusters_count: info_sector.free_clusters_count(),
                 next_free_cluster: info_sector.next_free_cluster(),
                 cluster_count: bpb.total_clusters(),
@@ -1,6 +1,7 @@
 use core::convert::TryFrom;
 
 use crate::blockdevice::BlockIdx;
+use crate::exfat::ExFatEntryInfo;
 use crate::fat::{FatType, OnDiskDirEntry};
 use crate::filesystem::{Attributes, Cluster, ShortFileName, Timestamp};
 
@@ -20,11 +21,13 @@ pub struct DirEntry {
     /// The starting cluster of the file. The FAT tells us the following Clusters.
     pub cluster: Cluster,
     /// The size of the file in bytes.
-    pub size: u32,
+    pub size: u64,
     /// The disk block of this entry
     pub entry_block: BlockIdx,
     /// The offset on its block (in bytes)
     pub entry_offset: u32,
+    /// The rest of the entry set, for files on exFAT volumes
+    pub(crate) exfat: Option<ExFatEntryInfo>,
 }
 
 /// Represents an open directory on disk.
@@ -62,7 +65,8 @@ impl DirEntry {
             .unwrap()
             .to_le_bytes();
         data[26..28].copy_from_slice(&cluster_lo[..]);
-        data[28..32].copy_from_slice(&self.size.to_le_bytes()[..]);
+        // FAT files are never larger than `MAX_FILE_SIZE`
+        data[28..32].copy_from_slice(&(self.size as u32).to_le_bytes()[..]);
         data
     }
 
@@ -83,6 +87,7 @@ impl DirEntry {
             size: 0,
             entry_block,
             entry_offset,
+            exfat: None,
         }
     }
 }
@@ -0,0 +1,201 @@
+use crate::filesystem::Cluster;
+
+/// How many runs of clusters each open file remembers.
+const MAX_EXTENTS: usize = 8;
+
+/// A run of clusters in a file which follow one another on disk.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Extent {
+    /// Which cluster of the file (counting from zero) the run starts at.
+    file_cluster: u32,
+    /// The cluster on disk the run starts at.
+    cluster: Cluster,
+    /// How many clusters are in the run.
+    length: u32,
+}
+
+impl Extent {
+    const EMPTY: Extent = Extent {
+        file_cluster: 0,
+        cluster: Cluster::EMPTY,
+        length: 0,
+    };
+
+    /// The cluster of the file just after this run.
+    fn end(&self) -> u32 {
+        self.file_cluster + self.length
+    }
+
+    /// Does this run map file clusters to disk clusters the same way as
+    /// `other` does?
+    fn lines_up_with(&self, other: &Extent) -> bool {
+        self.cluster.0.wrapping_sub(self.file_cluster)
+            == other.cluster.0.wrapping_sub(other.file_cluster)
+    }
+}
+
+/// Remembers where parts of a file's cluster chain are on disk, so that we
+/// can seek around a file without walking the FAT from the start each time.
+///
+/// We keep a small number of runs of contiguous clusters, sorted by where
+/// they are in the file. A contiguous file needs only one. For a fragmented
+/// file, runs are added as the FAT is walked and, once the table is full,
+/// the run whose loss leaves the smallest gap is forgotten - so what we keep
+/// stays spread out across the file.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExtentCache {
+    extents: [Extent; MAX_EXTENTS + 1],
+    count: usize,
+}
+
+impl ExtentCache {
+    /// Create an empty cache, which knows nothing about the file.
+    pub(crate) fn new() -> ExtentCache {
+        ExtentCache {
+            extents: [Extent::EMPTY; MAX_EXTENTS + 1],
+            count: 0,
+        }
+    }
+
+    /// Record that `length` clusters, starting with cluster `file_cluster`
+    /// of the file, are on disk in order starting at `cluster`.
+    pub(crate) fn record_run(&mut self, file_cluster: u32, cluster: Cluster, length: u32) {
+        if length == 0 {
+            return;
+        }
+        let mut new = Extent {
+            file_cluster,
+            cluster,
+            length,
+        };
+        let mut idx = 0;
+        while idx < self.count {
+            let old = self.extents[idx];
+            let touches = old.file_cluster <= new.end() && new.file_cluster <= old.end();
+            let overlaps = old.file_cluster < new.end() && new.file_cluster < old.end();
+            if touches && old.lines_up_with(&new) {
+                // Merge the two runs
+                let end = core::cmp::max(old.end(), new.end());
+                if old.file_cluster < new.file_cluster {
+                    new.file_cluster = old.file_cluster;
+                    new.cluster = old.cluster;
+                }
+                new.length = end - new.file_cluster;
+                self.remove(idx);
+            } else if overlaps {
+                // Out of date - the new information wins
+                self.remove(idx);
+            } else {
+                idx += 1;
+            }
+        }
+        let idx = self.extents[0..self.count]
+            .iter()
+            .position(|e| e.file_cluster > new.file_cluster)
+            .unwrap_or(self.count);
+        self.extents.copy_within(idx..self.count, idx + 1);
+        self.extents[idx] = new;
+        self.count += 1;
+        if self.count > MAX_EXTENTS {
+            // Forget the run (other than the first and last) whose removal
+            // opens up the smallest gap.
+            let victim = (1..self.count - 1)
+                .min_by_key(|&i| self.extents[i + 1].file_cluster - self.extents[i - 1].end())
+                .unwrap_or(self.count - 1);
+            self.remove(victim);
+        }
+    }
+
+    /// Find the closest cluster we know about at or before cluster
+    /// `file_cluster` of the file. Returns which cluster of the file that
+    /// is, and where it is on disk.
+    pub(crate) fn find(&self, file_cluster: u32) -> Option<(u32, Cluster)> {
+        self.extents[0..self.count]
+            .iter()
+            .rev()
+            .find(|e| e.file_cluster <= file_cluster)
+            .map(|e| {
+                let offset = core::cmp::min(file_cluster - e.file_cluster, e.length - 1);
+                (e.file_cluster + offset, e.cluster + offset)
+            })
+    }
+
+    /// Forget everything beyond the first `num_clusters` clusters of the
+    /// file, because the file has been cut short.
+    pub(crate) fn truncate(&mut self, num_clusters: u32) {
+        let mut idx = 0;
+        while idx < self.count {
+            let extent = &mut self.extents[idx];
+            if extent.file_cluster >= num_clusters {
+                self.remove(idx);
+            } else {
+                extent.length = core::cmp::min(extent.length, num_clusters - extent.file_cluster);
+                idx += 1;
+            }
+        }
+    }
+
+    fn remove(&mut self, idx: usize) {
+        self.extents.copy_within(idx + 1..self.count, idx);
+        self.count -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn contiguous() {
+        let mut cache = ExtentCache::new();
+        assert_eq!(cache.find(0), None);
+        cache.record_run(0, Cluster(100), 10);
+        assert_eq!(cache.find(0), Some((0, Cluster(100))));
+        assert_eq!(cache.find(9), Some((9, Cluster(109))));
+        assert_eq!(cache.find(50), Some((9, Cluster(109))));
+        // Walking on from the end extends the run
+        cache.record_run(10, Cluster(110), 1);
+        assert_eq!(cache.count, 1);
+        assert_eq!(cache.find(50), Some((10, Cluster(110))));
+        cache.truncate(4);
+        assert_eq!(cache.find(50), Some((3, Cluster(103))));
+    }
+
+    #[test]
+    fn fragmented() {
+        let mut cache = ExtentCache::new();
+        cache.record_run(0, Cluster(100), 2);
+        cache.record_run(2, Cluster(200), 1);
+        cache.record_run(3, Cluster(201), 1);
+        cache.record_run(4, Cluster(50), 1);
+        assert_eq!(cache.count, 3);
+        assert_eq!(cache.find(1), Some((1, Cluster(101))));
+        assert_eq!(cache.find(3), Some((3, Cluster(201))));
+        assert_eq!(cache.find(7), Some((4, Cluster(50))));
+        cache.truncate(3);
+        assert_eq!(cache.count, 2);
+        assert_eq!(cache.find(7), Some((2, Cluster(200))));
+        // Out of date information is replaced
+        cache.record_run(2, Cluster(300), 1);
+        assert_eq!(cache.find(7), Some((2, Cluster(300))));
+        assert_eq!(cache.count, 2);
+    }
+
+    #[test]
+    fn eviction() {
+        let mut cache = ExtentCache::new();
+        // Every cluster in a different place
+        for i in 0..100 {
+            cache.record_run(i, Cluster(1000 - i), 1);
+        }
+        assert_eq!(cache.count, MAX_EXTENTS);
+        // We still know where the start and the end are
+        assert_eq!(cache.find(0), Some((0, Cluster(1000))));
+        assert_eq!(cache.find(99), Some((99, Cluster(901))));
+        // And have something in the middle
+        let (idx, _) = cache.find(50).unwrap();
+        assert!(idx > 0);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::filesystem::{Cluster, DirEntry};
+use crate::filesystem::{Cluster, DirEntry, ExtentCache};
 
 /// Represents an open file on disk.
 #[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
@@ -7,11 +7,14 @@ pub struct File {
     /// The starting point of the file.
     pub(crate) starting_cluster: Cluster,
     /// The current cluster, and how many bytes that short-cuts us
-    pub(crate) current_cluster: (u32, Cluster),
+    pub(crate) current_cluster: (u64, Cluster),
+    /// Where we know parts of the cluster chain to be, so we can find
+    /// offsets without walking the FAT.
+    pub(crate) extents: ExtentCache,
     /// How far through the file we've read (in bytes).
-    pub(crate) current_offset: u32,
+    pub(crate) current_offset: u64,
     /// The length of the file, in bytes.
-    pub(crate) length: u32,
+    pub(crate) length: u64,
     /// What mode the file was opened in
     pub(crate) mode: Mode,
     /// DirEntry of this file
@@ -51,12 +54,12 @@ impl File {
     }
 
     /// How long is the file?
-    pub fn length(&self) -> u32 {
+    pub fn length(&self) -> u64 {
         self.length
     }
 
     /// Seek to a new position in the file, relative to the start of the file.
-    pub fn seek_from_start(&mut self, offset: u32) -> Result<(), FileError> {
+    pub fn seek_from_start(&mut self, offset: u64) -> Result<(), FileError> {
         if offset <= self.length {
             self.current_offset = offset;
             if offset < self.current_cluster.0 {
@@ -70,10 +73,10 @@ impl File {
     }
 
     /// Seek to a new position in the file, relative to the end of the file.
-    pub fn seek_from_end(&mut self, offset: u32) -> Result<(), FileError> {
+    pub fn seek_from_end(&mut self, offset: u64) -> Result<(), FileError> {
         if offset <= self.length {
             self.current_offset = self.length - offset;
-            if offset < self.current_cluster.0 {
+            if self.current_offset < self.current_cluster.0 {
                 // Back to start
                 self.current_cluster = (0, self.starting_cluster);
             }
@@ -84,23 +87,36 @@ impl File {
     }
 
     /// Seek to a new position in the file, relative to the current position.
-    pub fn seek_from_current(&mut self, offset: i32) -> Result<(), FileError> {
-        let new_offset = i64::from(self.current_offset) + i64::from(offset);
-        if new_offset >= 0 && new_offset <= i64::from(self.length) {
-            self.current_offset = new_offset as u32;
-            Ok(())
+    pub fn seek_from_current(&mut self, offset: i64) -> Result<(), FileError> {
+        let new_offset = if offset >= 0 {
+            self.current_offset.checked_add(offset as u64)
         } else {
-            Err(FileError::InvalidOffset)
+            self.current_offset.checked_sub(offset.unsigned_abs())
+        };
+        match new_offset {
+            Some(new_offset) if new_offset <= self.length => {
+                self.current_offset = new_offset;
+                if new_offset < self.current_cluster.0 {
+                    // Back to start
+                    self.current_cluster = (0, self.starting_cluster);
+                }
+                Ok(())
+            }
+            _ => Err(FileError::InvalidOffset),
         }
     }
 
     /// Amount of file left to read.
-    pub fn left(&self) -> u32 {
+    pub fn left(&self) -> u64 {
         self.length - self.current_offset
     }
 
-    pub(crate) fn update_length(&mut self, new: u32) {
+    pub(crate) fn update_length(&mut self, new: u64) {
         self.length = new;
         self.entry.size = new;
+        if let Some(info) = &mut self.entry.exfat {
+            info.valid_length = new;
+            info.data_length = new;
+        }
     }
 }
@@ -3,12 +3,14 @@
 //! Implements generic file system components. These should be applicable to
 //! most (if not all) supported filesystems.
 
-/// Maximum file size supported by this library
-pub const MAX_FILE_SIZE: u32 = core::u32::MAX;
+/// Maximum file size supported on FAT volumes. Files on exFAT volumes can be
+/// larger.
+pub const MAX_FILE_SIZE: u64 = core::u32::MAX as u64;
 
 mod attributes;
 mod cluster;
 mod directory;
+mod extents;
 mod filename;
 mod files;
 mod timestamp;
@@ -16,6 +18,7 @@ mod timestamp;
 pub use self::attributes::Attributes;
 pub use self::cluster::Cluster;
 pub use self::directory::{DirEntry, Directory};
+pub(crate) use self::extents::ExtentCache;
 pub use self::filename::{FilenameError, ShortFileName};
 pub use self::files::{File, FileError, Mode};
 pub use self::timestamp::{TimeSource, Timestamp};
@@ -0,0 +1,196 @@
+//! GUID Partition Table support
+//!
+//! Disks larger than 2 TiB can't be described by a Master Boot Record, so
+//! they are partitioned with a GUID Partition Table (GPT) instead. The MBR
+//! then holds a single 'protective' partition covering the whole disk, the
+//! GPT header lives in block 1, and an array of partition entries follows
+//! it.
+
+use crate::blockdevice::Block;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The partition type GUID of a Microsoft Basic Data partition, as stored on
+/// disk. These hold FAT, exFAT (or NTFS) file systems.
+pub const BASIC_DATA_PARTITION: [u8; 16] = [
+    0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+];
+
+/// Represents the GPT Header, found in the second sector of the disk. Like
+/// everything else in a GPT, it counts in the device's logical sectors.
+pub struct GptHeader<'a> {
+    data: &'a [u8; 512],
+}
+
+impl<'a> GptHeader<'a> {
+    /// The smallest header we understand.
+    const MIN_HEADER_SIZE: u32 = 92;
+
+    /// Attempt to parse a GPT Header from a 512 byte block.
+    pub fn create_from_bytes(data: &[u8; 512]) -> Result<GptHeader<'_>, &'static str> {
+        let header = GptHeader { data };
+        if header.signature() != b"EFI PART" {
+            return Err("Bad GPT header signature");
+        }
+        let header_size = header.header_size();
+        if !(Self::MIN_HEADER_SIZE..=Block::LEN_U32).contains(&header_size) {
+            return Err("Bad GPT header size");
+        }
+        // The checksum is calculated with the checksum field set to zero
+        let mut crc = crc32(0, &data[0..16]);
+        crc = crc32(crc, &[0; 4]);
+        crc = crc32(crc, &data[20..header_size as usize]);
+        if crc != header.header_crc32() {
+            return Err("Bad GPT header checksum");
+        }
+        // We need a whole number of entries in every block
+        let entry_size = header.partition_entry_size();
+        if !(GptEntry::MIN_LEN..=Block::LEN_U32).contains(&entry_size)
+            || !entry_size.is_power_of_two()
+        {
+            return Err("Bad GPT partition entry size");
+        }
+        Ok(header)
+    }
+
+    define_field!(revision, u32, 8);
+    define_field!(header_size, u32, 12);
+    define_field!(header_crc32, u32, 16);
+    define_field!(current_lba, u64, 24);
+    define_field!(backup_lba, u64, 32);
+    define_field!(first_usable_lba, u64, 40);
+    define_field!(last_usable_lba, u64, 48);
+    define_field!(partition_entry_lba, u64, 72);
+    define_field!(num_partition_entries, u32, 80);
+    define_field!(partition_entry_size, u32, 84);
+    define_field!(partition_array_crc32, u32, 88);
+
+    /// Get the signature, which is always `"EFI PART"`.
+    pub fn signature(&self) -> &[u8] {
+        &self.data[0..8]
+    }
+
+    /// Get the GUID identifying this disk.
+    pub fn disk_guid(&self) -> &[u8] {
+        &self.data[56..72]
+    }
+}
+
+/// Represents one entry in the GPT partition entry array.
+pub struct GptEntry<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> GptEntry<'a> {
+    /// The smallest partition entry allowed.
+    pub(crate) const MIN_LEN: u32 = 128;
+
+    /// Create a new GPT partition entry from the given bytes.
+    pub fn new(data: &[u8]) -> GptEntry<'_> {
+        GptEntry { data }
+    }
+
+    define_field!(first_lba, u64, 32);
+    define_field!(last_lba, u64, 40);
+    define_field!(attributes, u64, 48);
+
+    /// Get the partition type GUID, as stored on disk.
+    pub fn type_guid(&self) -> &[u8] {
+        &self.data[0..16]
+    }
+
+    /// Get the GUID identifying this partition.
+    pub fn partition_guid(&self) -> &[u8] {
+        &self.data[16..32]
+    }
+
+    /// Unused entries have a partition type GUID of all zeros.
+    pub fn is_unused(&self) -> bool {
+        self.type_guid().iter().all(|b| *b == 0)
+    }
+
+    /// How many sectors long the partition is, or `None` if it ends before
+    /// it starts.
+    pub fn num_sectors(&self) -> Option<u64> {
+        let last = self.last_lba().checked_sub(self.first_lba())?;
+        last.checked_add(1)
+    }
+}
+
+/// Adds `data` to a CRC-32 (the same one zlib and Ethernet use). Start from
+/// zero, and pass the result of one call to the next to checksum data a
+/// piece at a time.
+pub(crate) fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_header() -> [u8; 512] {
+        let mut data = [0u8; 512];
+        data[0..8].copy_from_slice(b"EFI PART");
+        LittleEndian::write_u32(&mut data[8..12], 0x0001_0000);
+        LittleEndian::write_u32(&mut data[12..16], 92);
+        LittleEndian::write_u64(&mut data[24..32], 1);
+        LittleEndian::write_u64(&mut data[32..40], 0x1_0000_0000);
+        LittleEndian::write_u64(&mut data[72..80], 2);
+        LittleEndian::write_u32(&mut data[80..84], 128);
+        LittleEndian::write_u32(&mut data[84..88], 128);
+        let crc = crc32(0, &data[0..92]);
+        LittleEndian::write_u32(&mut data[16..20], crc);
+        data
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn header() {
+        let data = make_header();
+        let header = GptHeader::create_from_bytes(&data).unwrap();
+        assert_eq!(header.backup_lba(), 0x1_0000_0000);
+        assert_eq!(header.num_partition_entries(), 128);
+        assert_eq!(header.partition_entry_lba(), 2);
+
+        let mut damaged = data;
+        damaged[80] = 4;
+        assert!(GptHeader::create_from_bytes(&damaged).is_err());
+    }
+
+    #[test]
+    fn entry() {
+        let mut data = [0u8; 128];
+        let entry = GptEntry::new(&data);
+        assert!(entry.is_unused());
+
+        data[0..16].copy_from_slice(&BASIC_DATA_PARTITION);
+        LittleEndian::write_u64(&mut data[32..40], 2048);
+        LittleEndian::write_u64(&mut data[40..48], 4095);
+        let entry = GptEntry::new(&data);
+        assert!(!entry.is_unused());
+        assert_eq!(entry.type_guid(), &BASIC_DATA_PARTITION);
+        assert_eq!(entry.first_lba(), 2048);
+        assert_eq!(entry.num_sectors(), Some(2048));
+
+        // This partition ends before it starts
+        LittleEndian::write_u64(&mut data[40..48], 2047);
+        let entry = GptEntry::new(&data);
+        assert_eq!(entry.num_sectors(), None);
+    }
+}
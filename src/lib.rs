@@ -2,10 +2,10 @@
 //!
 //! > An SD/MMC Library written in Embedded Rust
 //!
-//! This crate is intended to allow you to read/write files on a FAT formatted
-//! SD card on your Rust Embedded device, as easily as using the `SdFat` Arduino
-//! library. It is written in pure-Rust, is `#![no_std]` and does not use
-//! `alloc` or `collections` to keep the memory footprint low. In the first
+//! This crate is intended to allow you to read/write files on a FAT or exFAT
+//! formatted SD card on your Rust Embedded device, as easily as using the
+//! `SdFat` Arduino library. It is written in pure-Rust, is `#![no_std]` and
+//! does not use `alloc` or `collections` to keep the memory footprint low. In the first
 //! instance it is designed for readability and simplicity over performance.
 //!
 //! ## Using the crate
@@ -19,17 +19,23 @@
 //! ```rust,no_run
 //! # struct DummySpi;
 //! # struct DummyCsPin;
+//! # struct DummyDelay;
 //! # struct DummyUart;
 //! # struct DummyTimeSource;
-//! # impl embedded_hal::blocking::spi::Transfer<u8> for  DummySpi {
-//! #   type Error = ();
-//! #   fn transfer<'w>(&mut self, data: &'w mut [u8]) -> Result<&'w [u8], ()> { Ok(&[0]) }
+//! # impl embedded_hal::spi::ErrorType for DummySpi { type Error = core::convert::Infallible; }
+//! # impl embedded_hal::spi::SpiBus for DummySpi {
+//! #   fn read(&mut self, _: &mut [u8]) -> Result<(), Self::Error> { Ok(()) }
+//! #   fn write(&mut self, _: &[u8]) -> Result<(), Self::Error> { Ok(()) }
+//! #   fn transfer(&mut self, _: &mut [u8], _: &[u8]) -> Result<(), Self::Error> { Ok(()) }
+//! #   fn transfer_in_place(&mut self, _: &mut [u8]) -> Result<(), Self::Error> { Ok(()) }
+//! #   fn flush(&mut self) -> Result<(), Self::Error> { Ok(()) }
 //! # }
-//! # impl embedded_hal::digital::v2::OutputPin for DummyCsPin {
-//! #   type Error = ();
-//! #   fn set_low(&mut self) -> Result<(), ()> { Ok(()) }
-//! #   fn set_high(&mut self) -> Result<(), ()> { Ok(()) }
+//! # impl embedded_hal::digital::ErrorType for DummyCsPin { type Error = core::convert::Infallible; }
+//! # impl embedded_hal::digital::OutputPin for DummyCsPin {
+//! #   fn set_low(&mut self) -> Result<(), Self::Error> { Ok(()) }
+//! #   fn set_high(&mut self) -> Result<(), Self::Error> { Ok(()) }
 //! # }
+//! # impl embedded_hal::delay::DelayNs for DummyDelay { fn delay_ns(&mut self, _: u32) {} }
 //! # impl embedded_sdmmc::TimeSource for DummyTimeSource {
 //! #   fn get_timestamp(&self) -> embedded_sdmmc::Timestamp { embedded_sdmmc::Timestamp::from_fat(0, 0) }
 //! # }
@@ -37,10 +43,11 @@
 //! # use std::fmt::Write;
 //! # use embedded_sdmmc::VolumeManager;
 //! # fn main() -> Result<(), embedded_sdmmc::Error<embedded_sdmmc::SdMmcError>> {
-//! # let mut sdmmc_spi = DummySpi;
+//! # let mut sdmmc_bus = DummySpi;
 //! # let mut sdmmc_cs = DummyCsPin;
+//! # let delay = DummyDelay;
 //! # let time_source = DummyTimeSource;
-//! let mut spi_dev = embedded_sdmmc::SdMmcSpi::new(sdmmc_spi, sdmmc_cs);
+//! let mut spi_dev = embedded_sdmmc::SdMmcSpi::new(sdmmc_bus, sdmmc_cs, delay);
 //! let block = spi_dev.acquire()?;
 //! println!("Card size {} bytes", block.card_size_bytes()?);
 //! let mut volume_mgr = VolumeManager::new(block, time_source);
@@ -63,12 +70,21 @@
 //! # }
 //! ```
 //!
+//! If your block device is asynchronous, implement `AsyncBlockDevice` instead
+//! and use an `AsyncVolumeManager`. It has the same functions as a
+//! `VolumeManager`, but the ones which might touch the device are `async`.
+//!
 //! ## Features
 //!
 //! * `defmt-log`: By turning off the default features and enabling the
 //! `defmt-log` feature you can configure this crate to log messages over defmt
 //! instead.
 //!
+//! * `lba64`: Use 64-bit block numbers, so that devices larger than 2 TiB
+//!   (like SDUC cards, or big disks on a USB bridge) can be used. Block
+//!   numbers are 32-bit by default, as that's smaller and faster on
+//!   microcontrollers.
+//!
 //! Make sure that either the `log` feature or the `defmt-log` feature is
 //! enabled.
 
@@ -89,22 +105,36 @@ extern crate hex_literal;
 mod structure;
 
 pub mod blockdevice;
+pub mod exfat;
 pub mod fat;
 pub mod filesystem;
+pub mod gpt;
 pub mod sdmmc;
+pub mod sdmmc_async;
 pub mod sdmmc_proto;
+pub mod sdmmc_sdio;
 
-pub use crate::blockdevice::{Block, BlockCount, BlockDevice, BlockIdx};
+pub use crate::blockdevice::{
+    AsyncBlockDevice, Block, BlockCount, BlockDevice, BlockIdx, BlockNumber,
+};
+pub use crate::exfat::ExFatVolume;
 pub use crate::fat::FatVolume;
 pub use crate::filesystem::{
     Attributes, Cluster, DirEntry, Directory, File, FilenameError, Mode, ShortFileName, TimeSource,
     Timestamp, MAX_FILE_SIZE,
 };
 pub use crate::sdmmc::Error as SdMmcError;
-pub use crate::sdmmc::{BlockSpi, SdMmcSpi};
+pub use crate::sdmmc::{BlockSpi, CardDetect, CardDetectPin, NoCardDetect, RetryStats, SdMmcSpi};
+pub use crate::sdmmc_async::{AsyncBlockSpi, AsyncSdMmcSpi};
+pub use crate::sdmmc_sdio::{BlockSdio, SdHost, SdMmcSdio};
+
+#[cfg(test)]
+mod sdmmc_sim;
+#[cfg(test)]
+mod testing;
 
 mod volume_mgr;
-pub use volume_mgr::VolumeManager;
+pub use volume_mgr::{AllocationPolicy, AsyncVolumeManager, DiscardPolicy, VolumeManager};
 
 #[deprecated]
 pub use volume_mgr::VolumeManager as Controller;
@@ -168,6 +198,9 @@ where
     BadBlockSize(u16),
     /// Entry not found in the block
     NotInBlock,
+    /// The medium (such as an SD card) was changed after the volume was
+    /// opened, so it must be opened again
+    MediaChanged,
 }
 
 impl<E> From<E> for Error<E>
@@ -185,6 +218,8 @@ where
 pub struct Volume {
     idx: VolumeIdx,
     volume_type: VolumeType,
+    /// The block device's `media_generation` when the volume was opened
+    generation: u32,
 }
 
 /// This enum holds the data for the various different types of filesystems we
@@ -194,10 +229,13 @@ pub struct Volume {
 pub enum VolumeType {
     /// FAT16/FAT32 formatted volumes.
     Fat(FatVolume),
+    /// exFAT formatted volumes.
+    ExFat(ExFatVolume),
 }
 
 /// A `VolumeIdx` is a number which identifies a volume (or partition) on a
 /// disk. `VolumeIdx(0)` is the first primary partition on an MBR partitioned
+/// disk, or the first entry in the partition table on a GPT partitioned
 /// disk.
 #[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -228,6 +266,12 @@ const PARTITION_ID_FAT16: u8 = 0x06;
 /// Marker for a FAT32 partition. What Macosx disk utility (and also SD-Card formatter?)
 /// use.
 const PARTITION_ID_FAT32_CHS_LBA: u8 = 0x0B;
+/// Marker for an exFAT (or NTFS) partition. What SDXC cards are formatted
+/// with.
+const PARTITION_ID_EXFAT: u8 = 0x07;
+/// Marker for the protective partition covering a disk which has a GUID
+/// Partition Table.
+const PARTITION_ID_GPT_PROTECTIVE: u8 = 0xEE;
 
 // ****************************************************************************
 //
@@ -474,6 +518,66 @@ mod tests {
         }
     }
 
+    /// Makes a `DummyBlockDevice` wait before every read, like a device
+    /// doing DMA would.
+    struct SlowBlockDevice(DummyBlockDevice);
+
+    /// A `Future` which is pending the first time it is polled.
+    struct YieldOnce(bool);
+
+    impl core::future::Future for YieldOnce {
+        type Output = ();
+        fn poll(
+            mut self: core::pin::Pin<&mut Self>,
+            cx: &mut core::task::Context<'_>,
+        ) -> core::task::Poll<()> {
+            if self.0 {
+                core::task::Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                core::task::Poll::Pending
+            }
+        }
+    }
+
+    impl AsyncBlockDevice for SlowBlockDevice {
+        type Error = Error;
+
+        async fn read(
+            &self,
+            blocks: &mut [Block],
+            start_block_idx: BlockIdx,
+            reason: &str,
+        ) -> Result<(), Self::Error> {
+            YieldOnce(false).await;
+            self.0.read(blocks, start_block_idx, reason)
+        }
+
+        async fn write(
+            &self,
+            blocks: &[Block],
+            start_block_idx: BlockIdx,
+        ) -> Result<(), Self::Error> {
+            self.0.write(blocks, start_block_idx)
+        }
+
+        async fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+            self.0.num_blocks()
+        }
+    }
+
+    #[test]
+    fn partition0_async() {
+        let mut c: AsyncVolumeManager<SlowBlockDevice, Clock, 2, 2> =
+            AsyncVolumeManager::new_with_limits(SlowBlockDevice(DummyBlockDevice), Clock);
+        let v = blockdevice::block_on(c.get_volume(VolumeIdx(0))).unwrap();
+
+        let mut c: VolumeManager<DummyBlockDevice, Clock, 2, 2> =
+            VolumeManager::new_with_limits(DummyBlockDevice, Clock);
+        assert_eq!(v, c.get_volume(VolumeIdx(0)).unwrap());
+    }
+
     #[test]
     fn partition0() {
         let mut c: VolumeManager<DummyBlockDevice, Clock, 2, 2> =
@@ -498,10 +602,61 @@ mod tests {
                         first_root_dir_cluster: Cluster(2),
                         info_location: BlockIdx(1) + BlockCount(1),
                     })
-                })
+                }),
+                generation: 0,
             }
         );
     }
+
+    /// A `DummyBlockDevice` whose medium can be swapped.
+    struct RemovableBlockDevice(DummyBlockDevice, core::cell::Cell<u32>);
+
+    impl BlockDevice for RemovableBlockDevice {
+        type Error = Error;
+
+        fn read(
+            &self,
+            blocks: &mut [Block],
+            start_block_idx: BlockIdx,
+            reason: &str,
+        ) -> Result<(), Self::Error> {
+            self.0.read(blocks, start_block_idx, reason)
+        }
+
+        fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+            self.0.write(blocks, start_block_idx)
+        }
+
+        fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+            self.0.num_blocks()
+        }
+
+        fn media_generation(&self) -> Result<u32, Self::Error> {
+            Ok(self.1.get())
+        }
+    }
+
+    #[test]
+    fn media_changed() {
+        let mut c: VolumeManager<RemovableBlockDevice, Clock, 2, 2> =
+            VolumeManager::new_with_limits(
+                RemovableBlockDevice(DummyBlockDevice, core::cell::Cell::new(1)),
+                Clock,
+            );
+        let v = c.get_volume(VolumeIdx(0)).unwrap();
+        let root = c.open_root_dir(&v).unwrap();
+        assert!(c.has_open_handles());
+
+        c.device().1.set(2);
+        assert!(matches!(
+            c.iterate_dir(&v, &root, |_| {}),
+            Err(crate::Error::MediaChanged)
+        ));
+        assert!(!c.has_open_handles());
+
+        let v = c.get_volume(VolumeIdx(0)).unwrap();
+        assert!(c.open_root_dir(&v).is_ok());
+    }
 }
 
 // ****************************************************************************
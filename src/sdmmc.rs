@@ -2,46 +2,183 @@
 //!
 //! Implements the SD/MMC protocol on some generic SPI interface.
 //!
-//! This is currently optimised for readability and debugability, not
-//! performance.
+//! The protocol itself is in [`sdmmc_async`](crate::sdmmc_async), and
+//! `SdMmcSpi` runs it on a blocking SPI bus, where every step is finished
+//! the first time it is polled. So the blocking and async drivers behave
+//! the same way.
 
+use super::blockdevice::block_on;
+use super::sdmmc_async::{AsyncBlockSpi, AsyncSdMmcSpi};
 use super::sdmmc_proto::*;
-use super::{Block, BlockCount, BlockDevice, BlockIdx};
-use core::cell::RefCell;
+use super::{AsyncBlockDevice, Block, BlockCount, BlockDevice, BlockIdx};
 use core::ops::Deref;
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal::spi::{ErrorType, SpiBus};
 
-#[cfg(feature = "log")]
-use log::{debug, trace, warn};
+/// How long to wait before polling a card which isn't ready yet again. The
+/// wait doubles each time, up to `MAX_POLL_INTERVAL_US`.
+pub(crate) const POLL_INTERVAL_US: u32 = 10;
 
-#[cfg(feature = "defmt-log")]
-use defmt::{debug, trace, warn};
+/// The longest we wait between polls of a card which isn't ready yet.
+pub(crate) const MAX_POLL_INTERVAL_US: u32 = 4000;
 
-const DEFAULT_DELAY_COUNT: u32 = 32_000;
+/// How long a card can take to force erase itself, in milliseconds.
+pub(crate) const FORCE_ERASE_TIMEOUT_MS: u32 = 3 * 60 * 1000;
 
 /// Represents an inactive SD Card interface.
-/// Built from an SPI peripheral and a Chip
-/// Select pin. We need Chip Select to be separate so we can clock out some
-/// bytes without Chip Select asserted (which puts the card into SPI mode).
-pub struct SdMmcSpi<SPI, CS>
+///
+/// Built from an SPI bus, a Chip Select pin and a delay. We take the bus
+/// rather than an `SpiDevice` because we need to clock out some bytes
+/// without Chip Select asserted (which puts the card into SPI mode), and we
+/// need to keep Chip Select asserted from the start of each command until
+/// the card has finished with it. So this must have the bus to itself.
+///
+/// This runs the same code as [`AsyncSdMmcSpi`], on a blocking bus, and
+/// sleeps on the delay between polls of a card which is busy.
+pub struct SdMmcSpi<SPI, CS, DELAY, CD = NoCardDetect>
 where
-    SPI: embedded_hal::blocking::spi::Transfer<u8>,
-    CS: embedded_hal::digital::v2::OutputPin,
-    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+    CD: CardDetect,
 {
-    spi: RefCell<SPI>,
-    cs: RefCell<CS>,
-    card_type: CardType,
-    state: State,
+    inner: AsyncSdMmcSpi<BlockingSpi<SPI>, CS, BlockingDelay<DELAY>, CD>,
 }
 
 /// An initialized block device used to access the SD card.
 /// **Caution**: any data must be flushed manually before dropping `BlockSpi`, see `deinit`.
 /// Uses SPI mode.
-pub struct BlockSpi<'a, SPI, CS>(&'a mut SdMmcSpi<SPI, CS>)
+pub struct BlockSpi<'a, SPI, CS, DELAY, CD = NoCardDetect>(
+    AsyncBlockSpi<'a, BlockingSpi<SPI>, CS, BlockingDelay<DELAY>, CD>,
+)
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+    CD: CardDetect;
+
+/// Lets a blocking SPI bus be used as an async one. Every transfer is
+/// complete the first time its `Future` is polled.
+struct BlockingSpi<SPI> {
+    spi: SPI,
+    /// The `set_clock` callback given to `acquire_with_clock`. The async
+    /// driver keeps a plain `fn`, which can't capture this, so it calls
+    /// [`set_clock`] which calls this.
+    set_clock: Option<fn(&mut SPI, u32)>,
+}
+
+/// Set the clock of a `BlockingSpi` with its `set_clock` callback.
+fn set_clock<SPI>(spi: &mut BlockingSpi<SPI>, hz: u32) {
+    if let Some(set_clock) = spi.set_clock {
+        set_clock(&mut spi.spi, hz);
+    }
+}
+
+impl<SPI> ErrorType for BlockingSpi<SPI>
+where
+    SPI: SpiBus<u8>,
+{
+    type Error = SPI::Error;
+}
+
+impl<SPI> embedded_hal_async::spi::SpiBus<u8> for BlockingSpi<SPI>
+where
+    SPI: SpiBus<u8>,
+{
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.spi.read(words)
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.spi.write(words)
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        self.spi.transfer(read, write)
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.spi.transfer_in_place(words)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.spi.flush()
+    }
+}
+
+/// Lets a blocking delay be used as an async one.
+struct BlockingDelay<DELAY>(DELAY);
+
+impl<DELAY> embedded_hal_async::delay::DelayNs for BlockingDelay<DELAY>
+where
+    DELAY: DelayNs,
+{
+    async fn delay_ns(&mut self, ns: u32) {
+        self.0.delay_ns(ns)
+    }
+
+    async fn delay_us(&mut self, us: u32) {
+        self.0.delay_us(us)
+    }
+
+    async fn delay_ms(&mut self, ms: u32) {
+        self.0.delay_ms(ms)
+    }
+}
+
+/// Something which can tell whether there is a card in the socket, like
+/// the card detect switch most SD card sockets have.
+pub trait CardDetect {
+    /// Is there a card in the socket?
+    fn is_card_inserted(&mut self) -> Result<bool, Error>;
+}
+
+/// For sockets without a card detect switch. It always says there is a
+/// card, so a missing card is only noticed when it doesn't answer.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoCardDetect;
+
+impl CardDetect for NoCardDetect {
+    fn is_card_inserted(&mut self) -> Result<bool, Error> {
+        Ok(true)
+    }
+}
+
+/// A card detect switch wired to a GPIO pin.
+pub struct CardDetectPin<P>
+where
+    P: InputPin,
+{
+    pin: P,
+    active_low: bool,
+}
+
+impl<P> CardDetectPin<P>
 where
-    SPI: embedded_hal::blocking::spi::Transfer<u8>,
-    CS: embedded_hal::digital::v2::OutputPin,
-    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug;
+    P: InputPin,
+{
+    /// Use a pin which is low while a card is inserted if `active_low` is
+    /// set (as when the switch connects it to ground), or high otherwise.
+    pub fn new(pin: P, active_low: bool) -> CardDetectPin<P> {
+        CardDetectPin { pin, active_low }
+    }
+
+    /// Get the pin back.
+    pub fn free(self) -> P {
+        self.pin
+    }
+}
+
+impl<P> CardDetect for CardDetectPin<P>
+where
+    P: InputPin,
+{
+    fn is_card_inserted(&mut self) -> Result<bool, Error> {
+        let low = self.pin.is_low().map_err(|_e| Error::GpioError)?;
+        Ok(low == self.active_low)
+    }
+}
 
 /// The possible errors `SdMmcSpi` can generate.
 #[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
@@ -61,6 +198,8 @@ pub enum Error {
     TimeoutACommand(u8),
     /// We got a bad response from Command 58
     Cmd58Error,
+    /// The card wouldn't use 512 byte blocks when we sent it Command 16
+    Cmd16Error,
     /// We failed to read the Card Specific Data register
     RegisterReadError,
     /// We got a CRC mismatch (card gave us, we calculated)
@@ -69,12 +208,53 @@ pub enum Error {
     ReadError,
     /// Error writing to the card
     WriteError,
+    /// The card didn't accept a block of data we wrote to it, such as
+    /// because it arrived with a bad CRC
+    WriteRejected,
+    /// Error erasing blocks on the card
+    EraseError,
     /// Can't perform this operation with the card in this state
     BadState,
     /// Couldn't find the card
     CardNotFound,
     /// Couldn't set a GPIO pin
     GpioError,
+    /// The block index is beyond what the card can address
+    BlockOutOfRange,
+    /// The card refused to set or clear its password, or to lock or unlock
+    LockUnlockError,
+    /// A card password can be at most `MAX_PASSWORD_LEN` bytes long
+    PasswordTooLong,
+    /// The card answered a command with a card status which reports an
+    /// error. This is the whole card status, as in an R1 response.
+    CardStatusError(u32),
+}
+
+impl Error {
+    /// Is this something which might not happen if the transfer is tried
+    /// again? Data can be corrupted on its way to or from the card, or be
+    /// late if the card is slow. A card which reports an error once it has
+    /// programmed a block has a problem that writing it again won't fix.
+    pub(crate) fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Error::CrcError(..) | Error::TimeoutReadBuffer | Error::WriteRejected
+        )
+    }
+}
+
+/// How often blocks have had to be read or written again.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RetryStats {
+    /// How many times a read was started again
+    pub read_retries: u32,
+    /// How many times a write was started again
+    pub write_retries: u32,
+    /// How many reads failed after running out of retries
+    pub read_failures: u32,
+    /// How many writes failed after running out of retries
+    pub write_failures: u32,
 }
 
 /// The possible states `SdMmcSpi` can be in.
@@ -92,407 +272,467 @@ pub enum State {
 /// The different types of card we support.
 #[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
 #[derive(Debug, Copy, Clone, PartialEq)]
-enum CardType {
+pub(crate) enum CardType {
     SD1,
     SD2,
     SDHC,
+    /// An MMC or eMMC, addressed in bytes
+    Mmc,
+    /// An MMC or eMMC larger than 2 GiB, addressed in sectors
+    MmcHc,
 }
 
-/// A terrible hack for busy-waiting the CPU while we wait for the card to
-/// sort itself out.
-///
-/// @TODO replace this!
-struct Delay(u32);
+impl CardType {
+    /// Convert a block index into the address the card expects in a read or
+    /// write command. Standard capacity cards are addressed in bytes, and
+    /// high capacity cards in blocks, but either way it must fit in 32 bits.
+    pub(crate) fn card_address(self, block_idx: BlockIdx) -> Result<u32, Error> {
+        let block_idx = block_idx.to_u32().ok_or(Error::BlockOutOfRange)?;
+        match self {
+            CardType::SD1 | CardType::SD2 | CardType::Mmc => block_idx
+                .checked_mul(Block::LEN_U32)
+                .ok_or(Error::BlockOutOfRange),
+            CardType::SDHC | CardType::MmcHc => Ok(block_idx),
+        }
+    }
 
-impl Delay {
-    fn new() -> Delay {
-        Delay(DEFAULT_DELAY_COUNT)
+    /// Is this an MMC or eMMC, rather than an SD card?
+    pub(crate) fn is_mmc(self) -> bool {
+        matches!(self, CardType::Mmc | CardType::MmcHc)
     }
+}
 
-    fn delay(&mut self, err: Error) -> Result<(), Error> {
-        if self.0 == 0 {
-            Err(err)
-        } else {
-            let dummy_var: u32 = 0;
-            for _ in 0..100 {
-                unsafe { core::ptr::read_volatile(&dummy_var) };
-            }
-            self.0 -= 1;
-            Ok(())
+/// Build the six bytes which send a command to the card.
+pub(crate) fn command_frame(command: u8, arg: u32) -> [u8; 6] {
+    let mut frame = [
+        0x40 | command,
+        (arg >> 24) as u8,
+        (arg >> 16) as u8,
+        (arg >> 8) as u8,
+        arg as u8,
+        0,
+    ];
+    frame[5] = crc7(&frame[0..5]);
+    frame
+}
+
+/// How a card erases blocks, which we find out when it is initialised.
+#[derive(Debug, Clone)]
+pub(crate) struct EraseInfo {
+    /// The CSD, which says whether the card can erase single blocks.
+    pub(crate) csd: Csd,
+    /// The SD Status, which says how long erasing takes. Not every card
+    /// has one.
+    pub(crate) status: Option<SdStatus>,
+}
+
+/// Work out which blocks to erase, as block numbers that fit in 32 bits.
+/// Cards which can't erase single blocks erase whole erase sectors, so we
+/// shrink the range to the sectors which lie entirely within it. Returns
+/// `None` if that leaves nothing to erase.
+pub(crate) fn erase_range(
+    csd: &Csd,
+    first_block: BlockIdx,
+    last_block: BlockIdx,
+) -> Result<Option<(u32, u32)>, Error> {
+    let first = first_block.to_u32().ok_or(Error::BlockOutOfRange)?;
+    let last = last_block.to_u32().ok_or(Error::BlockOutOfRange)?;
+    if last < first {
+        return Ok(None);
+    }
+    if csd.erase_single_block_enabled() {
+        return Ok(Some((first, last)));
+    }
+    let sector_size = csd.erase_sector_blocks();
+    let first = first
+        .checked_next_multiple_of(sector_size)
+        .ok_or(Error::BlockOutOfRange)?;
+    let end = (u64::from(last) + 1) / u64::from(sector_size) * u64::from(sector_size);
+    if end <= u64::from(first) {
+        return Ok(None);
+    }
+    Ok(Some((first, (end - 1) as u32)))
+}
+
+/// Build the data block which goes with CMD42. Replacing a password sends
+/// the old one followed by the new one. Returns the block, and how many bytes
+/// of it to send.
+pub(crate) fn lock_data(
+    flags: u8,
+    old_password: &[u8],
+    new_password: &[u8],
+) -> Result<([u8; 2 + 2 * MAX_PASSWORD_LEN], usize), Error> {
+    if old_password.len() > MAX_PASSWORD_LEN || new_password.len() > MAX_PASSWORD_LEN {
+        return Err(Error::PasswordTooLong);
+    }
+    let mut data = [0; 2 + 2 * MAX_PASSWORD_LEN];
+    data[0] = flags;
+    if flags & LOCK_ERASE != 0 {
+        // A force erase is just the flags
+        return Ok((data, 1));
+    }
+    let password_len = old_password.len() + new_password.len();
+    data[1] = password_len as u8;
+    data[2..2 + old_password.len()].copy_from_slice(old_password);
+    data[2 + old_password.len()..2 + password_len].copy_from_slice(new_password);
+    Ok((data, 2 + password_len))
+}
+
+/// Decode the Card Specific Data read from a card, checking the CRC7 at the
+/// end if we're checking CRCs. An MMC's CSD has the same layout as a
+/// version 1 SD card CSD, as far as we're concerned, whatever version it
+/// says it is.
+pub(crate) fn decode_csd(
+    data: [u8; 16],
+    card_type: CardType,
+    options: &AcquireOpts,
+) -> Result<Csd, Error> {
+    let csd = if card_type.is_mmc() {
+        Csd::V1(CsdV1 { data })
+    } else {
+        Csd::from_data(data)
+    };
+    let calc_crc = crc7(&csd.data()[0..15]);
+    if options.require_crc && calc_crc != csd.crc() {
+        return Err(Error::CrcError(u16::from(csd.crc()), u16::from(calc_crc)));
+    }
+    Ok(csd)
+}
+
+/// How long erasing some blocks can take, in milliseconds. The SD Status
+/// tells us, if the card supports it. Otherwise we allow as long as it
+/// would take to write every block.
+pub(crate) fn erase_timeout_ms(
+    status: Option<&SdStatus>,
+    num_blocks: u32,
+    options: &AcquireOpts,
+) -> u32 {
+    status
+        .and_then(|status| {
+            status.erase_timeout_ms(u64::from(num_blocks) * u64::from(Block::LEN_U32))
+        })
+        .unwrap_or_else(|| options.busy_timeout_ms.saturating_mul(num_blocks))
+}
+
+/// Keeps track of how long we have spent waiting for the card to sort
+/// itself out. We sleep between polls, for `POLL_INTERVAL_US` at first and
+/// then twice as long each time, up to `MAX_POLL_INTERVAL_US`, so a card
+/// which is nearly ready is soon polled again. Only the time spent sleeping
+/// is counted, so we always wait at least as long as we were asked to.
+/// Polling takes time too, but once the sleeps are long it is a small part
+/// of the total, even with the SPI clock at 400 kHz.
+pub(crate) struct Timeout {
+    waited_us: u32,
+    limit_us: u32,
+    interval_us: u32,
+}
+
+impl Timeout {
+    pub(crate) fn new(limit_ms: u32) -> Timeout {
+        Timeout {
+            waited_us: 0,
+            limit_us: limit_ms.saturating_mul(1000),
+            interval_us: POLL_INTERVAL_US,
+        }
+    }
+
+    /// Call this before sleeping between polls. Returns how long to sleep
+    /// for, in microseconds, or fails with `err` once we have waited long
+    /// enough.
+    pub(crate) fn poll(&mut self, err: Error) -> Result<u32, Error> {
+        if self.waited_us >= self.limit_us {
+            return Err(err);
         }
+        let interval_us = self.interval_us;
+        self.waited_us = self.waited_us.saturating_add(interval_us);
+        self.interval_us = (interval_us * 2).min(MAX_POLL_INTERVAL_US);
+        Ok(interval_us)
     }
 }
 
 /// Options for acquiring the card.
+///
+/// The timeouts are in milliseconds. The defaults are the limits the SD
+/// specification sets, so only change them for cards which don't keep to
+/// it.
 #[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
 #[derive(Debug)]
 pub struct AcquireOpts {
     /// Some cards don't support CRC mode. At least a 512MiB Transcend one.
     pub require_crc: bool,
+    /// How long to wait for the response to a command. Cards should reply
+    /// within 8 bytes, so this only matters if the card is missing or
+    /// misbehaving.
+    pub command_timeout_ms: u32,
+    /// How long to wait for the card to leave its idle state when it is
+    /// initialised. The specification allows one second. An MMC gets this
+    /// long to answer ACMD41, and then as long again to finish with CMD1.
+    pub init_timeout_ms: u32,
+    /// How long to wait for a block of data to start arriving. The
+    /// specification allows 100 ms.
+    pub read_timeout_ms: u32,
+    /// How long to wait for the card to stop being busy, such as after a
+    /// block has been written. The specification allows 250 ms for SDHC
+    /// cards and 500 ms for SDXC cards.
+    pub busy_timeout_ms: u32,
+    /// The fastest the SPI clock can run once the card is initialised, in
+    /// Hz, whatever the card says it supports. Only used by
+    /// `acquire_with_clock`.
+    pub max_clock_hz: u32,
+    /// How many times to start a read or write again, from the block which
+    /// failed, if a block is corrupted, rejected by the card or doesn't
+    /// arrive in time.
+    pub retries: u32,
 }
 
 impl Default for AcquireOpts {
     fn default() -> Self {
-        AcquireOpts { require_crc: true }
+        AcquireOpts {
+            require_crc: true,
+            command_timeout_ms: 10,
+            init_timeout_ms: 1000,
+            read_timeout_ms: 100,
+            busy_timeout_ms: 500,
+            max_clock_hz: 25_000_000,
+            retries: 3,
+        }
+    }
+}
+
+/// The fastest clock speed a card can be initialised at, in Hz.
+pub(crate) const INIT_CLOCK_HZ: u32 = 400_000;
+
+/// The fastest clock speed a card supports, in Hz. Each SPI clock moves one
+/// bit, so this is TRAN_SPEED from the CSD. No faster than `max_clock_hz`.
+pub(crate) fn clock_hz(csd: &Csd, options: &AcquireOpts) -> u32 {
+    csd.max_transfer_rate_kbps()
+        .map_or(options.max_clock_hz, |kbps| {
+            kbps.saturating_mul(1000).min(options.max_clock_hz)
+        })
+}
+
+impl<SPI, CS, DELAY> SdMmcSpi<SPI, CS, DELAY>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+{
+    /// Create a new SD/MMC interface using a raw SPI bus.
+    pub fn new(spi: SPI, cs: CS, delay: DELAY) -> SdMmcSpi<SPI, CS, DELAY> {
+        Self::with_card_detect(spi, cs, delay, NoCardDetect)
     }
 }
 
-impl<SPI, CS> SdMmcSpi<SPI, CS>
+impl<SPI, CS, DELAY, CD> SdMmcSpi<SPI, CS, DELAY, CD>
 where
-    SPI: embedded_hal::blocking::spi::Transfer<u8>,
-    CS: embedded_hal::digital::v2::OutputPin,
-    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+    CD: CardDetect,
 {
-    /// Create a new SD/MMC interface using a raw SPI interface.
-    pub fn new(spi: SPI, cs: CS) -> SdMmcSpi<SPI, CS> {
+    /// Create a new SD/MMC interface using a raw SPI bus, for a socket with
+    /// a card detect switch. The card is only used while the switch says it
+    /// is inserted.
+    pub fn with_card_detect(
+        spi: SPI,
+        cs: CS,
+        delay: DELAY,
+        card_detect: CD,
+    ) -> SdMmcSpi<SPI, CS, DELAY, CD> {
         SdMmcSpi {
-            spi: RefCell::new(spi),
-            cs: RefCell::new(cs),
-            card_type: CardType::SD1,
-            state: State::NoInit,
+            inner: AsyncSdMmcSpi::with_card_detect(
+                BlockingSpi {
+                    spi,
+                    set_clock: None,
+                },
+                cs,
+                BlockingDelay(delay),
+                card_detect,
+            ),
         }
     }
 
-    fn cs_high(&self) -> Result<(), Error> {
-        self.cs
-            .borrow_mut()
-            .set_high()
-            .map_err(|_| Error::GpioError)
+    /// Initializes the card into a known state
+    pub fn acquire(&mut self) -> Result<BlockSpi<'_, SPI, CS, DELAY, CD>, Error> {
+        self.acquire_with_opts(Default::default())
     }
 
-    fn cs_low(&self) -> Result<(), Error> {
-        self.cs.borrow_mut().set_low().map_err(|_| Error::GpioError)
+    /// Initializes the card into a known state, with the SPI clock at
+    /// 400 kHz as the specification requires, and then speeds it up.
+    /// `set_clock` is given the SPI bus and a clock speed in Hz - first
+    /// 400 kHz, and then the fastest the card supports (no faster than
+    /// `options.max_clock_hz`) - and should switch the SPI clock to it, or
+    /// to the fastest speed below it which the SPI can do. It is kept, and
+    /// called again whenever the card is initialised again.
+    pub fn acquire_with_clock(
+        &mut self,
+        options: AcquireOpts,
+        set_clock: fn(&mut SPI, u32),
+    ) -> Result<BlockSpi<'_, SPI, CS, DELAY, CD>, Error> {
+        self.inner.spi()?.set_clock = Some(set_clock);
+        block_on(self.inner.acquire_with_clock(options, self::set_clock)).map(BlockSpi)
     }
 
     /// Initializes the card into a known state
-    pub fn acquire(&mut self) -> Result<BlockSpi<SPI, CS>, Error> {
-        self.acquire_with_opts(Default::default())
+    pub fn acquire_with_opts(
+        &mut self,
+        options: AcquireOpts,
+    ) -> Result<BlockSpi<'_, SPI, CS, DELAY, CD>, Error> {
+        block_on(self.inner.acquire_with_opts(options)).map(BlockSpi)
     }
 
-    /// Initializes the card into a known state
-    pub fn acquire_with_opts(&mut self, options: AcquireOpts) -> Result<BlockSpi<SPI, CS>, Error> {
-        debug!("acquiring card with opts: {:?}", options);
-        let f = |s: &mut Self| {
-            // Assume it hasn't worked
-            s.state = State::Error;
-            trace!("Reset card..");
-            // Supply minimum of 74 clock cycles without CS asserted.
-            s.cs_high()?;
-            for _ in 0..10 {
-                s.send(0xFF)?;
-            }
-            // Assert CS
-            s.cs_low()?;
-            // Enter SPI mode
-            let mut delay = Delay::new();
-            let mut attempts = 32;
-            while attempts > 0 {
-                trace!("Enter SPI mode, attempt: {}..", 32i32 - attempts);
-
-                match s.card_command(CMD0, 0) {
-                    Err(Error::TimeoutCommand(0)) => {
-                        // Try again?
-                        warn!("Timed out, trying again..");
-                        // Try flushing the card as done here: https://github.com/greiman/SdFat/blob/master/src/SdCard/SdSpiCard.cpp#L170,
-                        // https://github.com/rust-embedded-community/embedded-sdmmc-rs/pull/65#issuecomment-1270709448
-                        for _ in 0..0xFF {
-                            s.send(0xFF)?;
-                        }
-                        attempts -= 1;
-                    }
-                    Err(e) => {
-                        return Err(e);
-                    }
-                    Ok(R1_IDLE_STATE) => {
-                        break;
-                    }
-                    Ok(r) => {
-                        // Try again
-                        warn!("Got response: {:x}, trying again..", r);
-                    }
-                }
-
-                delay.delay(Error::TimeoutCommand(CMD0))?;
-            }
-            if attempts == 0 {
-                return Err(Error::CardNotFound);
-            }
-            // Enable CRC
-            debug!("Enable CRC: {}", options.require_crc);
-            if s.card_command(CMD59, 1)? != R1_IDLE_STATE && options.require_crc {
-                return Err(Error::CantEnableCRC);
-            }
-            // Check card version
-            let mut delay = Delay::new();
-            loop {
-                if s.card_command(CMD8, 0x1AA)? == (R1_ILLEGAL_COMMAND | R1_IDLE_STATE) {
-                    s.card_type = CardType::SD1;
-                    break;
-                }
-                s.receive()?;
-                s.receive()?;
-                s.receive()?;
-                let status = s.receive()?;
-                if status == 0xAA {
-                    s.card_type = CardType::SD2;
-                    break;
-                }
-                delay.delay(Error::TimeoutCommand(CMD8))?;
-            }
-            debug!("Card version: {:?}", s.card_type);
-
-            let arg = match s.card_type {
-                CardType::SD1 => 0,
-                CardType::SD2 | CardType::SDHC => 0x4000_0000,
-            };
-
-            let mut delay = Delay::new();
-            while s.card_acmd(ACMD41, arg)? != R1_READY_STATE {
-                delay.delay(Error::TimeoutACommand(ACMD41))?;
-            }
-
-            if s.card_type == CardType::SD2 {
-                if s.card_command(CMD58, 0)? != 0 {
-                    return Err(Error::Cmd58Error);
-                }
-                if (s.receive()? & 0xC0) == 0xC0 {
-                    s.card_type = CardType::SDHC;
-                }
-                // Discard other three bytes
-                s.receive()?;
-                s.receive()?;
-                s.receive()?;
-            }
-            s.state = State::Idle;
-            Ok(())
-        };
-        let result = f(self);
-        self.cs_high()?;
-        let _ = self.receive();
-        result.map(move |()| BlockSpi(self))
-    }
-
-    /// Perform a function that might error with the chipselect low.
-    /// Always releases the chipselect, even if the function errors.
-    fn with_chip_select_mut<F, T>(&self, func: F) -> Result<T, Error>
-    where
-        F: FnOnce(&Self) -> Result<T, Error>,
-    {
-        self.cs_low()?;
-        let result = func(self);
-        self.cs_high()?;
-        result
-    }
-
-    /// Perform a function that might error with the chipselect low.
-    /// Always releases the chipselect, even if the function errors.
-    fn with_chip_select<F, T>(&self, func: F) -> Result<T, Error>
-    where
-        F: FnOnce(&Self) -> Result<T, Error>,
-    {
-        self.cs_low()?;
-        let result = func(self);
-        self.cs_high()?;
-        result
-    }
-
-    /// Perform an application-specific command.
-    fn card_acmd(&self, command: u8, arg: u32) -> Result<u8, Error> {
-        self.card_command(CMD55, 0)?;
-        self.card_command(command, arg)
-    }
-
-    /// Perform a command.
-    fn card_command(&self, command: u8, arg: u32) -> Result<u8, Error> {
-        if command != CMD0 && command != CMD12 {
-            self.wait_not_busy()?;
-        }
+    /// Get a temporary borrow on the underlying SPI bus. Useful if you need
+    /// to re-clock the SPI.
+    pub fn spi(&mut self) -> Result<&mut SPI, Error> {
+        self.inner.spi().map(|spi| &mut spi.spi)
+    }
+}
 
-        let mut buf = [
-            0x40 | command,
-            (arg >> 24) as u8,
-            (arg >> 16) as u8,
-            (arg >> 8) as u8,
-            arg as u8,
-            0,
-        ];
-        buf[5] = crc7(&buf[0..5]);
+impl<SPI, CS, DELAY, CD> BlockSpi<'_, SPI, CS, DELAY, CD>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+    CD: CardDetect,
+{
+    /// Get a temporary borrow on the underlying SPI bus. Useful if you need
+    /// to re-clock the SPI.
+    pub fn spi(&mut self) -> Result<&mut SPI, Error> {
+        self.0.spi().map(|spi| &mut spi.spi)
+    }
 
-        for b in buf.iter() {
-            self.send(*b)?;
-        }
+    /// Is the card still there? This asks the card for its status with
+    /// CMD13, which only a card that has been initialised answers. Once the
+    /// card has been removed, this instead tries to initialise whichever
+    /// card is in the socket now, as the next read or write would.
+    pub fn check_card_present(&self) -> Result<bool, Error> {
+        block_on(self.0.check_card_present())
+    }
 
-        // skip stuff byte for stop read
-        if command == CMD12 {
-            let _result = self.receive()?;
-        }
+    /// Initialise the card again now, rather than on the next read or
+    /// write. Unless it is the same card as before (with the same CID), it
+    /// counts as a new card, so any volumes opened on the old one must be
+    /// opened again. If the card was acquired with `acquire_with_clock`,
+    /// the SPI clock is set again the same way.
+    pub fn reacquire(&self) -> Result<(), Error> {
+        block_on(self.0.reacquire())
+    }
 
-        for _ in 0..512 {
-            let result = self.receive()?;
-            if (result & 0x80) == ERROR_OK {
-                return Ok(result);
-            }
-        }
+    /// How often blocks have had to be read or written again, since the
+    /// `SdMmcSpi` was created or [`reset_retry_stats`](Self::reset_retry_stats)
+    /// was called.
+    pub fn retry_stats(&self) -> RetryStats {
+        self.0.retry_stats()
+    }
 
-        Err(Error::TimeoutCommand(command))
+    /// Set the retry statistics back to zero.
+    pub fn reset_retry_stats(&self) {
+        self.0.reset_retry_stats()
     }
 
-    /// Receive a byte from the SD card by clocking in an 0xFF byte.
-    fn receive(&self) -> Result<u8, Error> {
-        self.transfer(0xFF)
+    /// Return the usable size of this SD card in bytes. MMCs larger than
+    /// 2 GiB give their size in the Extended CSD.
+    pub fn card_size_bytes(&self) -> Result<u64, Error> {
+        block_on(self.0.card_size_bytes())
     }
 
-    /// Send a byte from the SD card.
-    fn send(&self, out: u8) -> Result<(), Error> {
-        let _ = self.transfer(out)?;
-        Ok(())
+    /// Erase some blocks on the card. Both ends of the range are included.
+    /// Erased blocks read back as all zeros or all ones, depending on the
+    /// card.
+    ///
+    /// Cards which can't erase single blocks (see
+    /// [`erase_single_block_enabled`](Self::erase_single_block_enabled))
+    /// erase whole erase sectors, so only the sectors which lie entirely
+    /// within the range are erased.
+    pub fn erase(&self, first_block: BlockIdx, last_block: BlockIdx) -> Result<(), Error> {
+        block_on(self.0.erase(first_block, last_block))
     }
 
-    /// Send one byte and receive one byte.
-    fn transfer(&self, out: u8) -> Result<u8, Error> {
-        let mut spi = self.spi.borrow_mut();
-        spi.transfer(&mut [out])
-            .map(|b| b[0])
-            .map_err(|_e| Error::Transport)
+    /// Can this card erase single blocks?
+    pub fn erase_single_block_enabled(&self) -> Result<bool, Error> {
+        block_on(self.0.erase_single_block_enabled())
     }
 
-    /// Spin until the card returns 0xFF, or we spin too many times and
-    /// timeout.
-    fn wait_not_busy(&self) -> Result<(), Error> {
-        let mut delay = Delay::new();
-        loop {
-            let s = self.receive()?;
-            if s == 0xFF {
-                break;
-            }
-            delay.delay(Error::TimeoutWaitNotBusy)?;
-        }
-        Ok(())
+    /// Is the card write protected, either temporarily or permanently? This
+    /// comes from the CSD, so the write protect switch on an SD card's
+    /// case isn't included.
+    pub fn is_write_protected(&self) -> Result<bool, Error> {
+        block_on(self.0.is_write_protected())
     }
 
-    /// Get a temporary borrow on the underlying SPI device. Useful if you
-    /// need to re-clock the SPI.
-    pub fn spi(&mut self) -> core::cell::RefMut<SPI> {
-        self.spi.borrow_mut()
+    /// Read the card status register.
+    pub fn card_status(&self) -> Result<CardStatus, Error> {
+        block_on(self.0.card_status())
     }
-}
 
-impl<SPI, CS> BlockSpi<'_, SPI, CS>
-where
-    SPI: embedded_hal::blocking::spi::Transfer<u8>,
-    CS: embedded_hal::digital::v2::OutputPin,
-    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
-{
-    /// Get a temporary borrow on the underlying SPI device. Useful if you
-    /// need to re-clock the SPI.
-    pub fn spi(&mut self) -> core::cell::RefMut<SPI> {
-        self.0.spi.borrow_mut()
+    /// Is the card locked with a password? A locked card can't be read or
+    /// written until it is unlocked.
+    pub fn is_locked(&self) -> Result<bool, Error> {
+        block_on(self.0.is_locked())
     }
 
-    /// Mark the card as unused.
-    /// This should be kept infallible, because Drop is unable to fail.
-    /// See https://github.com/rust-lang/rfcs/issues/814
-    // If there is any need to flush data, it should be implemented here.
-    fn deinit(&mut self) {
-        self.0.state = State::NoInit;
+    /// Set the card's password, replacing `old_password` (which is empty if
+    /// the card doesn't have one yet). The card is locked the next time it
+    /// is powered up. Passwords can be up to 16 bytes long.
+    pub fn set_password(&self, old_password: &[u8], new_password: &[u8]) -> Result<(), Error> {
+        block_on(self.0.set_password(old_password, new_password))
     }
 
-    /// Return the usable size of this SD card in bytes.
-    pub fn card_size_bytes(&self) -> Result<u64, Error> {
-        self.0.with_chip_select(|_s| {
-            let csd = self.read_csd()?;
-            match csd {
-                Csd::V1(ref contents) => Ok(contents.card_capacity_bytes()),
-                Csd::V2(ref contents) => Ok(contents.card_capacity_bytes()),
-            }
-        })
+    /// Remove the card's password.
+    pub fn clear_password(&self, password: &[u8]) -> Result<(), Error> {
+        block_on(self.0.clear_password(password))
     }
 
-    /// Erase some blocks on the card.
-    pub fn erase(&mut self, _first_block: BlockIdx, _last_block: BlockIdx) -> Result<(), Error> {
-        unimplemented!();
+    /// Lock the card, so it can't be read or written until it is unlocked.
+    pub fn lock(&self, password: &[u8]) -> Result<(), Error> {
+        block_on(self.0.lock(password))
     }
 
-    /// Can this card erase single blocks?
-    pub fn erase_single_block_enabled(&self) -> Result<bool, Error> {
-        self.0.with_chip_select(|_s| {
-            let csd = self.read_csd()?;
-            match csd {
-                Csd::V1(ref contents) => Ok(contents.erase_single_block_enabled()),
-                Csd::V2(ref contents) => Ok(contents.erase_single_block_enabled()),
-            }
-        })
+    /// Unlock a locked card.
+    pub fn unlock(&self, password: &[u8]) -> Result<(), Error> {
+        block_on(self.0.unlock(password))
     }
 
-    /// Read the 'card specific data' block.
-    fn read_csd(&self) -> Result<Csd, Error> {
-        match self.0.card_type {
-            CardType::SD1 => {
-                let mut csd = CsdV1::new();
-                if self.0.card_command(CMD9, 0)? != 0 {
-                    return Err(Error::RegisterReadError);
-                }
-                self.read_data(&mut csd.data)?;
-                Ok(Csd::V1(csd))
-            }
-            CardType::SD2 | CardType::SDHC => {
-                let mut csd = CsdV2::new();
-                if self.0.card_command(CMD9, 0)? != 0 {
-                    return Err(Error::RegisterReadError);
-                }
-                self.read_data(&mut csd.data)?;
-                Ok(Csd::V2(csd))
-            }
-        }
+    /// Erase a card whose password has been forgotten. Everything on the
+    /// card is lost, along with the password. This can take a few minutes.
+    pub fn force_erase(&self) -> Result<(), Error> {
+        block_on(self.0.force_erase())
     }
 
-    /// Read an arbitrary number of bytes from the card. Always fills the
-    /// given buffer, so make sure it's the right size.
-    fn read_data(&self, buffer: &mut [u8]) -> Result<(), Error> {
-        // Get first non-FF byte.
-        let mut delay = Delay::new();
-        let status = loop {
-            let s = self.0.receive()?;
-            if s != 0xFF {
-                break s;
-            }
-            delay.delay(Error::TimeoutReadBuffer)?;
-        };
-        if status != DATA_START_BLOCK {
-            return Err(Error::ReadError);
-        }
+    /// Read the 'card specific data' register, which describes the card's
+    /// capacity, speed and erase and write protect features.
+    pub fn read_csd(&self) -> Result<Csd, Error> {
+        block_on(self.0.read_csd())
+    }
 
-        for b in buffer.iter_mut() {
-            *b = self.0.receive()?;
-        }
+    /// Read the 'card identification' register, which says who made the
+    /// card and gives its product name and serial number.
+    pub fn read_cid(&self) -> Result<Cid, Error> {
+        block_on(self.0.read_cid())
+    }
 
-        let mut crc = u16::from(self.0.receive()?);
-        crc <<= 8;
-        crc |= u16::from(self.0.receive()?);
+    /// Read an MMC's 'extended CSD' register, which gives the size of MMCs
+    /// larger than 2 GiB. SD cards don't have one.
+    pub fn read_ext_csd(&self) -> Result<ExtCsd, Error> {
+        block_on(self.0.read_ext_csd())
+    }
 
-        let calc_crc = crc16(buffer);
-        if crc != calc_crc {
-            return Err(Error::CrcError(crc, calc_crc));
-        }
+    /// Read the 'SD configuration register', which says which version of
+    /// the SD specification the card supports.
+    pub fn read_scr(&self) -> Result<Scr, Error> {
+        block_on(self.0.read_scr())
+    }
 
-        Ok(())
+    /// Read the 'operation conditions register', which gives the card's
+    /// supported voltages and whether it is high capacity.
+    pub fn read_ocr(&self) -> Result<Ocr, Error> {
+        block_on(self.0.read_ocr())
     }
 
-    /// Write an arbitrary number of bytes to the card.
-    fn write_data(&self, token: u8, buffer: &[u8]) -> Result<(), Error> {
-        let calc_crc = crc16(buffer);
-        self.0.send(token)?;
-        for &b in buffer.iter() {
-            self.0.send(b)?;
-        }
-        self.0.send((calc_crc >> 8) as u8)?;
-        self.0.send(calc_crc as u8)?;
-        let status = self.0.receive()?;
-        if (status & DATA_RES_MASK) != DATA_RES_ACCEPTED {
-            Err(Error::WriteError)
-        } else {
-            Ok(())
-        }
+    /// Read the 'SD Status' register, which gives the card's speed class
+    /// and Allocation Unit size.
+    pub fn read_sd_status(&self) -> Result<SdStatus, Error> {
+        block_on(self.0.read_sd_status())
     }
 }
 
@@ -515,13 +755,30 @@ impl<U: BlockDevice, T: Deref<Target = U>> BlockDevice for T {
     fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
         self.deref().num_blocks()
     }
+
+    fn sector_size(&self) -> usize {
+        self.deref().sector_size()
+    }
+
+    fn discard(
+        &self,
+        start_block_idx: BlockIdx,
+        num_blocks: BlockCount,
+    ) -> Result<(), Self::Error> {
+        self.deref().discard(start_block_idx, num_blocks)
+    }
+
+    fn media_generation(&self) -> Result<u32, Self::Error> {
+        self.deref().media_generation()
+    }
 }
 
-impl<SPI, CS> BlockDevice for BlockSpi<'_, SPI, CS>
+impl<SPI, CS, DELAY, CD> BlockDevice for BlockSpi<'_, SPI, CS, DELAY, CD>
 where
-    SPI: embedded_hal::blocking::spi::Transfer<u8>,
-    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
-    CS: embedded_hal::digital::v2::OutputPin,
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+    CD: CardDetect,
 {
     type Error = Error;
 
@@ -530,79 +787,497 @@ where
         &self,
         blocks: &mut [Block],
         start_block_idx: BlockIdx,
-        _reason: &str,
+        reason: &str,
     ) -> Result<(), Self::Error> {
-        let start_idx = match self.0.card_type {
-            CardType::SD1 | CardType::SD2 => start_block_idx.0 * 512,
-            CardType::SDHC => start_block_idx.0,
-        };
-        self.0.with_chip_select(|s| {
-            if blocks.len() == 1 {
-                // Start a single-block read
-                s.card_command(CMD17, start_idx)?;
-                self.read_data(&mut blocks[0].contents)?;
-            } else {
-                // Start a multi-block read
-                s.card_command(CMD18, start_idx)?;
-                for block in blocks.iter_mut() {
-                    self.read_data(&mut block.contents)?;
-                }
-                // Stop the read
-                s.card_command(CMD12, 0)?;
-            }
-            Ok(())
-        })
+        block_on(self.0.read(blocks, start_block_idx, reason))
     }
 
     /// Write one or more blocks, starting at the given block index.
     fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
-        let start_idx = match self.0.card_type {
-            CardType::SD1 | CardType::SD2 => start_block_idx.0 * 512,
-            CardType::SDHC => start_block_idx.0,
-        };
-        self.0.with_chip_select_mut(|s| {
-            if blocks.len() == 1 {
-                // Start a single-block write
-                s.card_command(CMD24, start_idx)?;
-                self.write_data(DATA_START_BLOCK, &blocks[0].contents)?;
-                s.wait_not_busy()?;
-                if s.card_command(CMD13, 0)? != 0x00 {
-                    return Err(Error::WriteError);
-                }
-                if s.receive()? != 0x00 {
-                    return Err(Error::WriteError);
-                }
-            } else {
-                // Start a multi-block write
-                s.card_command(CMD25, start_idx)?;
-                for block in blocks.iter() {
-                    s.wait_not_busy()?;
-                    self.write_data(WRITE_MULTIPLE_TOKEN, &block.contents)?;
-                }
-                // Stop the write
-                s.wait_not_busy()?;
-                s.send(STOP_TRAN_TOKEN)?;
-            }
-            Ok(())
-        })
+        block_on(self.0.write(blocks, start_block_idx))
     }
 
     /// Determine how many blocks this device can hold.
     fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
-        let num_bytes = self.card_size_bytes()?;
-        let num_blocks = (num_bytes / 512) as u32;
-        Ok(BlockCount(num_blocks))
+        block_on(self.0.num_blocks())
+    }
+
+    /// Erase blocks which are no longer needed.
+    fn discard(
+        &self,
+        start_block_idx: BlockIdx,
+        num_blocks: BlockCount,
+    ) -> Result<(), Self::Error> {
+        block_on(self.0.discard(start_block_idx, num_blocks))
+    }
+
+    /// Count how many different cards have been initialised. A card which
+    /// has been removed, or has stopped answering, is initialised again
+    /// first, so this goes up if a different card has been put in.
+    fn media_generation(&self) -> Result<u32, Self::Error> {
+        block_on(self.0.media_generation())
     }
 }
 
-impl<SPI, CS> Drop for BlockSpi<'_, SPI, CS>
-where
-    SPI: embedded_hal::blocking::spi::Transfer<u8>,
-    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
-    CS: embedded_hal::digital::v2::OutputPin,
-{
-    fn drop(&mut self) {
-        self.deinit()
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sdmmc_sim::{SimAcmd23, SimCard, SimKind, SimSpi, ERASE_BUSY, PROGRAM_BUSY};
+
+    #[test]
+    fn test_card_address() {
+        assert_eq!(CardType::SD1.card_address(BlockIdx(3)).unwrap(), 1536);
+        assert_eq!(CardType::Mmc.card_address(BlockIdx(3)).unwrap(), 1536);
+        assert_eq!(CardType::SDHC.card_address(BlockIdx(3)).unwrap(), 3);
+        assert_eq!(CardType::MmcHc.card_address(BlockIdx(3)).unwrap(), 3);
+        assert!(CardType::Mmc.card_address(BlockIdx(0x80_0000)).is_err());
+    }
+
+    #[test]
+    fn test_decode_csd() {
+        // An MMC which says it has a version 1.2 CSD
+        let mut data = [
+            0x00, 0x26, 0x00, 0x32, 0x5F, 0x59, 0x83, 0xC8, 0xAD, 0xDB, 0xCF, 0xFF, 0xD2, 0x40,
+            0x40, 0xA5,
+        ];
+        data[0] = 0x80;
+        data[15] = crc7(&data[0..15]);
+        let options = AcquireOpts::default();
+        assert!(matches!(
+            decode_csd(data, CardType::Mmc, &options).unwrap(),
+            Csd::V1(_)
+        ));
+        assert!(matches!(
+            decode_csd(data, CardType::SD2, &options).unwrap(),
+            Csd::V2(_)
+        ));
+        data[15] ^= 0x02;
+        assert!(decode_csd(data, CardType::Mmc, &options).is_err());
+    }
+
+    #[test]
+    fn test_clock_hz() {
+        let options = AcquireOpts::default();
+        let mut csd = CsdV2::new();
+        // 2.5 x 10 Mbit/s
+        csd.data[3] = 0x32;
+        assert_eq!(clock_hz(&Csd::V2(csd.clone()), &options), 25_000_000);
+        // 5.0 x 10 Mbit/s, for a card in high speed mode
+        csd.data[3] = 0x5A;
+        assert_eq!(clock_hz(&Csd::V2(csd.clone()), &options), 25_000_000);
+        let options = AcquireOpts {
+            max_clock_hz: 50_000_000,
+            ..Default::default()
+        };
+        assert_eq!(clock_hz(&Csd::V2(csd.clone()), &options), 50_000_000);
+        // Reserved values leave the clock as fast as we're allowed
+        csd.data[3] = 0x00;
+        assert_eq!(clock_hz(&Csd::V2(csd), &options), 50_000_000);
+    }
+
+    #[test]
+    fn test_lock_data() {
+        let (data, len) = lock_data(LOCK_SET_PWD, b"old", b"new!").unwrap();
+        assert_eq!(&data[0..len], b"\x01\x07oldnew!");
+        let (data, len) = lock_data(LOCK_LOCK_UNLOCK, b"secret", &[]).unwrap();
+        assert_eq!(&data[0..len], b"\x04\x06secret");
+        let (data, len) = lock_data(LOCK_ERASE, &[], &[]).unwrap();
+        assert_eq!(&data[0..len], b"\x08");
+        assert!(matches!(
+            lock_data(LOCK_CLR_PWD, &[0; 17], &[]),
+            Err(Error::PasswordTooLong)
+        ));
+    }
+
+    #[test]
+    fn test_command_frame() {
+        assert_eq!(command_frame(CMD0, 0), [0x40, 0, 0, 0, 0, 0x95]);
+        assert_eq!(command_frame(CMD8, 0x1AA), [0x48, 0, 0, 1, 0xAA, 0x87]);
+    }
+
+    /// Blocks holding a different pattern each.
+    fn blocks(count: usize, seed: u8) -> Vec<Block> {
+        (0..count)
+            .map(|idx| {
+                let mut block = Block::new();
+                for (pos, b) in block.contents.iter_mut().enumerate() {
+                    *b = (pos as u8).wrapping_mul(7) ^ (idx as u8) ^ seed;
+                }
+                block
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_transactions() {
+        // However long the card takes to answer or send a block, we find
+        // it, and Chip Select stays low from the start of each command to
+        // the end of its response and any block of data.
+        for (response_delay, read_delay) in [(0, 0), (1, 1), (8, 20), (1, 128), (8, 200)] {
+            let mut card = SimCard::new(SimKind::Sdhc);
+            card.response_delay = response_delay;
+            card.read_delay = read_delay;
+            let spi = SimSpi::new(card);
+            let mut sdmmc = SdMmcSpi::new(spi.clone(), spi.cs(), spi.delay());
+            let block_spi = sdmmc.acquire().unwrap();
+            let written = blocks(3, 0x5A);
+            block_spi.write(&written, BlockIdx(10)).unwrap();
+            block_spi.write(&written[1..2], BlockIdx(20)).unwrap();
+            assert_eq!(block_spi.read_csd().unwrap().card_capacity_bytes(), 1 << 30);
+            assert_eq!(block_spi.read_cid().unwrap().serial_number(), 0x1234_5678);
+            block_spi.read_scr().unwrap();
+            block_spi.read_sd_status().unwrap();
+            let mut read = [Block::new()];
+            block_spi.read(&mut read, BlockIdx(20), "test").unwrap();
+            assert_eq!(read[0].contents, written[1].contents);
+            let splits = spi.card().stats.split_exchanges;
+            assert_eq!(splits, 0, "read delay {}", read_delay);
+
+            // A multi-block read carries on from one block to the next
+            let mut read = [Block::new(), Block::new(), Block::new()];
+            block_spi.read(&mut read, BlockIdx(10), "test").unwrap();
+            for (read, written) in read.iter().zip(&written) {
+                assert_eq!(read.contents, written.contents);
+            }
+            assert_eq!(block_spi.retry_stats(), RetryStats::default());
+        }
+    }
+
+    #[test]
+    fn test_init_timeout() {
+        // A card which never leaves its idle state is given up on after
+        // about as long as we are allowed to wait, even at 400 kHz
+        let mut card = SimCard::new(SimKind::Sdhc);
+        card.init_polls = u32::MAX;
+        let spi = SimSpi::new(card);
+        let mut sdmmc = SdMmcSpi::new(spi.clone(), spi.cs(), spi.delay());
+        assert!(matches!(
+            sdmmc.acquire(),
+            Err(Error::TimeoutACommand(ACMD41))
+        ));
+        let elapsed_ms = spi.card().elapsed_ns / 1_000_000;
+        let limit_ms = u64::from(AcquireOpts::default().init_timeout_ms);
+        assert!(
+            (limit_ms..limit_ms * 6 / 5).contains(&elapsed_ms),
+            "{} ms",
+            elapsed_ms
+        );
+    }
+
+    #[test]
+    fn test_chip_select_gap() {
+        // The card gives up on a command if Chip Select goes high before
+        // it has sent the block the command asked for
+        let spi = SimSpi::new(SimCard::new(SimKind::Sdhc));
+        let mut sdmmc = SdMmcSpi::new(spi.clone(), spi.cs(), spi.delay());
+        let block_spi = sdmmc.acquire().unwrap();
+        let written = blocks(1, 0x42);
+        block_spi.write(&written, BlockIdx(9)).unwrap();
+        let mut bus = spi.clone();
+        let mut cs = spi.cs();
+        let mut read = [0xFF; 600];
+        cs.set_low().unwrap();
+        bus.write(&command_frame(CMD17, 9)).unwrap();
+        bus.transfer_in_place(&mut read[0..2]).unwrap();
+        cs.set_high().unwrap();
+        cs.set_low().unwrap();
+        bus.transfer_in_place(&mut read).unwrap();
+        cs.set_high().unwrap();
+        assert!(read.iter().all(|b| *b == 0xFF));
+        assert_eq!(spi.card().stats.split_exchanges, 1);
+
+        // Which doesn't happen to us
+        let mut read = [Block::new()];
+        block_spi.read(&mut read, BlockIdx(9), "test").unwrap();
+        assert_eq!(read[0].contents, written[0].contents);
+        assert_eq!(spi.card().stats.split_exchanges, 1);
+    }
+
+    #[test]
+    fn test_erase() {
+        let spi = SimSpi::new(SimCard::new(SimKind::Sdhc));
+        let mut sdmmc = SdMmcSpi::new(spi.clone(), spi.cs(), spi.delay());
+        let block_spi = sdmmc.acquire().unwrap();
+        let written = blocks(4, 0x33);
+        block_spi.write(&written, BlockIdx(100)).unwrap();
+        // The card was asked how it erases when it was initialised, and
+        // isn't asked again
+        let registers = |spi: &SimSpi| {
+            let card = spi.card();
+            (card.stats.count(CMD9), card.stats.count(0x80 | ACMD13))
+        };
+        assert_eq!(registers(&spi), (1, 1));
+        block_spi.erase(BlockIdx(101), BlockIdx(102)).unwrap();
+        block_spi.discard(BlockIdx(100), BlockCount(1)).unwrap();
+        assert_eq!(registers(&spi), (1, 1));
+        for block in 100..103 {
+            assert_eq!(spi.card().block(block), [0; 512]);
+        }
+        assert_eq!(spi.card().block(103), written[3].contents);
+
+        // A card which stops answering is initialised again, and asked
+        // again, before the next erase
+        spi.card().present = false;
+        assert!(matches!(
+            block_spi.erase(BlockIdx(103), BlockIdx(103)),
+            Err(Error::TimeoutCommand(CMD32))
+        ));
+        assert!(matches!(
+            block_spi.erase(BlockIdx(103), BlockIdx(103)),
+            Err(Error::CardNotFound)
+        ));
+        spi.card().present = true;
+        block_spi.erase(BlockIdx(103), BlockIdx(103)).unwrap();
+        assert_eq!(registers(&spi), (2, 2));
+        assert_eq!(spi.card().block(103), [0; 512]);
+    }
+
+    #[test]
+    fn test_mmc() {
+        // A standard capacity MMC is told to use 512 byte blocks, or it
+        // won't read or write any
+        let spi = SimSpi::new(SimCard::new(SimKind::Mmc));
+        let mut sdmmc = SdMmcSpi::new(spi.clone(), spi.cs(), spi.delay());
+        let block_spi = sdmmc.acquire().unwrap();
+        assert!(spi.card().stats.commands.contains(&(CMD16, 512)));
+        let written = blocks(2, 0xA5);
+        block_spi.write(&written, BlockIdx(7)).unwrap();
+        // It is addressed in bytes
+        assert!(spi.card().stats.commands.contains(&(CMD25, 7 * 512)));
+        let mut read = [Block::new(), Block::new()];
+        block_spi.read(&mut read, BlockIdx(7), "test").unwrap();
+        for (read, written) in read.iter().zip(&written) {
+            assert_eq!(read.contents, written.contents);
+        }
+        assert_eq!(spi.card().block(8), written[1].contents);
+    }
+
+    #[test]
+    fn test_reacquire() {
+        let spi = SimSpi::new(SimCard::new(SimKind::Sdhc));
+        let mut sdmmc = SdMmcSpi::new(spi.clone(), spi.cs(), spi.delay());
+        let set_clock = |spi: &mut SimSpi, hz| spi.card().clock_hz = hz;
+        let block_spi = sdmmc
+            .acquire_with_clock(Default::default(), set_clock)
+            .unwrap();
+        assert_eq!(spi.card().clock_hz, 25_000_000);
+        assert_eq!(block_spi.media_generation().unwrap(), 1);
+        let written = blocks(1, 0x11);
+        block_spi.write(&written, BlockIdx(5)).unwrap();
+
+        // A card which misses a command, but still answers CMD13, can be
+        // used again straight away
+        spi.card().ignore_commands = 1;
+        let mut read = [Block::new()];
+        assert!(matches!(
+            block_spi.read(&mut read, BlockIdx(5), "test"),
+            Err(Error::TimeoutCommand(CMD17))
+        ));
+        block_spi.read(&mut read, BlockIdx(5), "test").unwrap();
+        assert_eq!(read[0].contents, written[0].contents);
+        assert_eq!(block_spi.media_generation().unwrap(), 1);
+
+        // One which has gone is initialised again once it is back, with
+        // the SPI clock set again
+        spi.card().set_present(false);
+        assert!(matches!(
+            block_spi.read(&mut read, BlockIdx(5), "test"),
+            Err(Error::TimeoutCommand(CMD17))
+        ));
+        assert!(matches!(
+            block_spi.media_generation(),
+            Err(Error::CardNotFound)
+        ));
+        assert!(!block_spi.check_card_present().unwrap());
+        spi.card().clock_hz = INIT_CLOCK_HZ;
+        spi.card().set_present(true);
+        assert!(block_spi.check_card_present().unwrap());
+        assert_eq!(spi.card().clock_hz, 25_000_000);
+        block_spi.read(&mut read, BlockIdx(5), "test").unwrap();
+        assert_eq!(read[0].contents, written[0].contents);
+        // It is the same card, so volumes opened on it are still good
+        assert_eq!(block_spi.media_generation().unwrap(), 1);
+
+        // A different card is a new one, once it has stopped answering
+        spi.card().set_present(false);
+        spi.card().set_present(true);
+        spi.card().serial = 0x8765_4321;
+        assert!(matches!(
+            block_spi.read(&mut read, BlockIdx(5), "test"),
+            Err(Error::TimeoutCommand(CMD17))
+        ));
+        assert_eq!(block_spi.media_generation().unwrap(), 2);
+        block_spi.read(&mut read, BlockIdx(5), "test").unwrap();
+    }
+
+    /// Reads the simulated card's card detect switch.
+    struct SimCardDetect(SimSpi);
+
+    impl CardDetect for SimCardDetect {
+        fn is_card_inserted(&mut self) -> Result<bool, Error> {
+            Ok(self.0.card().present)
+        }
+    }
+
+    #[test]
+    fn test_card_detect() {
+        let spi = SimSpi::new(SimCard::new(SimKind::Sdhc));
+        let card_detect = SimCardDetect(spi.clone());
+        let mut sdmmc = SdMmcSpi::with_card_detect(spi.clone(), spi.cs(), spi.delay(), card_detect);
+        let block_spi = sdmmc.acquire().unwrap();
+        assert_eq!(block_spi.media_generation().unwrap(), 1);
+
+        // The card isn't used while it is out
+        spi.card().set_present(false);
+        let mut read = [Block::new()];
+        assert!(matches!(
+            block_spi.read(&mut read, BlockIdx(5), "test"),
+            Err(Error::CardNotFound)
+        ));
+        assert!(!block_spi.check_card_present().unwrap());
+
+        // and the next card is initialised before it is used
+        spi.card().set_present(true);
+        spi.card().serial = 0x8765_4321;
+        let commands = spi.card().stats.commands.len();
+        assert_eq!(block_spi.media_generation().unwrap(), 2);
+        assert_eq!(spi.card().stats.commands[commands].0, CMD0);
+        block_spi.read(&mut read, BlockIdx(5), "test").unwrap();
+    }
+
+    #[test]
+    fn test_retries() {
+        let spi = SimSpi::new(SimCard::new(SimKind::Sdhc));
+        let mut sdmmc = SdMmcSpi::new(spi.clone(), spi.cs(), spi.delay());
+        let block_spi = sdmmc.acquire().unwrap();
+        let starts = |spi: &SimSpi, command: u8| -> Vec<u32> {
+            let card = spi.card();
+            let commands = card.stats.commands.iter();
+            commands
+                .filter(|(c, _)| *c == command)
+                .map(|(_, arg)| *arg)
+                .collect()
+        };
+
+        // A rejected block is written again, carrying on from that block
+        spi.card().reject_writes = vec![32];
+        let written = blocks(6, 0x77);
+        block_spi.write(&written, BlockIdx(30)).unwrap();
+        assert_eq!(starts(&spi, CMD25), [30, 32]);
+        for (block, written) in (30..).zip(&written) {
+            assert_eq!(spi.card().block(block), written.contents);
+        }
+
+        // And so is a block which arrives with a bad CRC
+        spi.card().corrupt_reads = vec![33];
+        let mut read = vec![Block::new(); 6];
+        block_spi.read(&mut read, BlockIdx(30), "test").unwrap();
+        assert_eq!(starts(&spi, CMD18), [30, 33]);
+        for (read, written) in read.iter().zip(&written) {
+            assert_eq!(read.contents, written.contents);
+        }
+        assert_eq!(
+            block_spi.retry_stats(),
+            RetryStats {
+                read_retries: 1,
+                write_retries: 1,
+                ..Default::default()
+            }
+        );
+
+        // Until we run out of retries
+        block_spi.reset_retry_stats();
+        spi.card().corrupt_reads = vec![31; 4];
+        assert!(matches!(
+            block_spi.read(&mut read, BlockIdx(30), "test"),
+            Err(Error::CrcError(..))
+        ));
+        assert_eq!(starts(&spi, CMD18), [30, 33, 30, 31, 31, 31]);
+        assert_eq!(
+            block_spi.retry_stats(),
+            RetryStats {
+                read_retries: 3,
+                read_failures: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_erase_ahead() {
+        // Saying how many blocks are coming lets the card erase them all
+        // at once. A card which doesn't understand, or doesn't answer,
+        // still gets the blocks.
+        let written = blocks(8, 0x3C);
+        let mut work = Vec::new();
+        for acmd23 in [
+            SimAcmd23::Supported,
+            SimAcmd23::Illegal,
+            SimAcmd23::NoAnswer,
+        ] {
+            let spi = SimSpi::new(SimCard::new(SimKind::Sdhc));
+            let mut sdmmc = SdMmcSpi::new(spi.clone(), spi.cs(), spi.delay());
+            let block_spi = sdmmc.acquire().unwrap();
+            spi.card().acmd23 = acmd23;
+            block_spi.write(&written, BlockIdx(50)).unwrap();
+            for (block, written) in (50..).zip(&written) {
+                assert_eq!(spi.card().block(block), written.contents, "{:?}", acmd23);
+            }
+            assert_eq!(spi.card().stats.count(CMD25), 1);
+            assert_eq!(block_spi.retry_stats(), RetryStats::default());
+            let stats = &spi.card().stats;
+            assert_eq!(stats.programs, 8);
+            work.push((stats.erases, stats.busy_bytes));
+        }
+        let programs = 8 * PROGRAM_BUSY;
+        assert_eq!(work[0], (1, ERASE_BUSY + programs + 2));
+        assert_eq!(work[1], (8, 8 * ERASE_BUSY + programs + 2));
+        assert_eq!(work[2], work[1]);
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(Error::CrcError(0x1234, 0x4321).is_retryable());
+        assert!(Error::TimeoutReadBuffer.is_retryable());
+        assert!(Error::WriteRejected.is_retryable());
+        // Nor will a card which failed to program a block
+        assert!(!Error::WriteError.is_retryable());
+        // A card which has gone won't come back by asking again
+        assert!(!Error::TimeoutCommand(CMD17).is_retryable());
+        assert!(!Error::TimeoutWaitNotBusy.is_retryable());
+        assert!(!Error::BlockOutOfRange.is_retryable());
+    }
+
+    #[test]
+    fn test_erase_range() {
+        // Erases single blocks
+        let mut csd = CsdV1::new();
+        csd.data[10] = 0x40;
+        let csd = Csd::V1(csd);
+        assert_eq!(
+            erase_range(&csd, BlockIdx(5), BlockIdx(9)).unwrap(),
+            Some((5, 9))
+        );
+        assert_eq!(
+            erase_range(&csd, BlockIdx(5), BlockIdx(5)).unwrap(),
+            Some((5, 5))
+        );
+        assert_eq!(erase_range(&csd, BlockIdx(5), BlockIdx(4)).unwrap(), None);
+
+        // Erases 32 block sectors
+        let mut csd = CsdV1::new();
+        csd.data[10] = 0x0F;
+        csd.data[11] = 0x80;
+        let csd = Csd::V1(csd);
+        assert_eq!(
+            erase_range(&csd, BlockIdx(0), BlockIdx(63)).unwrap(),
+            Some((0, 63))
+        );
+        assert_eq!(
+            erase_range(&csd, BlockIdx(1), BlockIdx(100)).unwrap(),
+            Some((32, 95))
+        );
+        assert_eq!(erase_range(&csd, BlockIdx(1), BlockIdx(62)).unwrap(), None);
     }
 }
 
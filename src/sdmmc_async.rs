@@ -0,0 +1,1232 @@
+//! The SD/MMC Protocol, asynchronously
+//!
+//! Implements the SD/MMC protocol on an `embedded-hal-async` SPI bus. It
+//! sends commands and moves whole blocks in single transfers (so a
+//! DMA-backed bus can do the work), and while the card is busy it awaits a
+//! delay between polls instead of spinning the CPU. The blocking
+//! [`SdMmcSpi`](crate::SdMmcSpi) runs this same code on a blocking bus.
+
+use super::sdmmc::{
+    clock_hz, command_frame, decode_csd, erase_range, erase_timeout_ms, lock_data, AcquireOpts,
+    CardDetect, CardType, EraseInfo, Error, NoCardDetect, RetryStats, State, Timeout,
+    FORCE_ERASE_TIMEOUT_MS, INIT_CLOCK_HZ,
+};
+use super::sdmmc_proto::*;
+use super::{AsyncBlockDevice, Block, BlockCount, BlockIdx, BlockNumber};
+use core::cell::Cell;
+use core::ops::{Deref, DerefMut};
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::spi::SpiBus;
+
+#[cfg(feature = "log")]
+use log::{debug, trace, warn};
+
+#[cfg(feature = "defmt-log")]
+use defmt::{debug, trace, warn};
+
+/// Represents an inactive SD Card interface, driven asynchronously.
+///
+/// Built from an SPI bus, a Chip Select pin and a delay. We take the bus
+/// rather than an `SpiDevice` because we need to clock out some bytes
+/// without Chip Select asserted (which puts the card into SPI mode), and we
+/// need to keep Chip Select asserted while we wait for the card. So this
+/// must have the bus to itself.
+pub struct AsyncSdMmcSpi<SPI, CS, DELAY, CD = NoCardDetect>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+    CD: CardDetect,
+{
+    card: Cell<Option<Card<SPI, CS, DELAY, CD>>>,
+    state: Cell<State>,
+    /// How many different cards have been initialised
+    generation: Cell<u32>,
+    /// The CID of the card which was initialised last
+    cid: Cell<Option<[u8; 16]>>,
+    stats: Cell<RetryStats>,
+}
+
+/// An initialized async block device used to access the SD card.
+/// **Caution**: any data must be flushed manually before dropping `AsyncBlockSpi`.
+/// Uses SPI mode.
+pub struct AsyncBlockSpi<'a, SPI, CS, DELAY, CD = NoCardDetect>(
+    &'a mut AsyncSdMmcSpi<SPI, CS, DELAY, CD>,
+)
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+    CD: CardDetect;
+
+/// The hardware used to talk to the card, and what we know about the card.
+///
+/// The `AsyncBlockDevice` functions only get `&self`, so this lives in a
+/// `Cell` and is taken out for the length of each operation.
+struct Card<SPI, CS, DELAY, CD> {
+    spi: SPI,
+    cs: CS,
+    delay: DELAY,
+    card_detect: CD,
+    card_type: CardType,
+    options: AcquireOpts,
+    erase_info: Option<EraseInfo>,
+    /// Sets the SPI clock, if the card was acquired with
+    /// `acquire_with_clock`
+    set_clock: Option<fn(&mut SPI, u32)>,
+}
+
+/// Borrows the `Card` out of its `Cell`, and puts it back when dropped. That
+/// happens even if the future using it is dropped before it completes.
+struct CardGuard<'a, SPI, CS, DELAY, CD> {
+    cell: &'a Cell<Option<Card<SPI, CS, DELAY, CD>>>,
+    card: Option<Card<SPI, CS, DELAY, CD>>,
+}
+
+impl<SPI, CS, DELAY> AsyncSdMmcSpi<SPI, CS, DELAY>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+{
+    /// Create a new SD/MMC interface using a raw SPI bus.
+    pub fn new(spi: SPI, cs: CS, delay: DELAY) -> AsyncSdMmcSpi<SPI, CS, DELAY> {
+        Self::with_card_detect(spi, cs, delay, NoCardDetect)
+    }
+}
+
+impl<SPI, CS, DELAY, CD> AsyncSdMmcSpi<SPI, CS, DELAY, CD>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+    CD: CardDetect,
+{
+    /// Create a new SD/MMC interface using a raw SPI bus, for a socket with
+    /// a card detect switch. The card is only used while the switch says it
+    /// is inserted.
+    pub fn with_card_detect(
+        spi: SPI,
+        cs: CS,
+        delay: DELAY,
+        card_detect: CD,
+    ) -> AsyncSdMmcSpi<SPI, CS, DELAY, CD> {
+        AsyncSdMmcSpi {
+            card: Cell::new(Some(Card {
+                spi,
+                cs,
+                delay,
+                card_detect,
+                card_type: CardType::SD1,
+                options: AcquireOpts::default(),
+                erase_info: None,
+                set_clock: None,
+            })),
+            state: Cell::new(State::NoInit),
+            generation: Cell::new(0),
+            cid: Cell::new(None),
+            stats: Cell::new(RetryStats::default()),
+        }
+    }
+
+    /// Initializes the card into a known state
+    pub async fn acquire(&mut self) -> Result<AsyncBlockSpi<'_, SPI, CS, DELAY, CD>, Error> {
+        self.acquire_with_opts(Default::default()).await
+    }
+
+    /// Initializes the card into a known state
+    pub async fn acquire_with_opts(
+        &mut self,
+        options: AcquireOpts,
+    ) -> Result<AsyncBlockSpi<'_, SPI, CS, DELAY, CD>, Error> {
+        debug!("acquiring card with opts: {:?}", options);
+        let mut card = self.lock()?;
+        card.options = options;
+        card.set_clock = None;
+        drop(card);
+        self.reinit().await?;
+        Ok(AsyncBlockSpi(self))
+    }
+
+    /// Initialise the card, if there is one in the socket. It counts as a
+    /// new card unless its CID is the same as the last card's.
+    async fn init_card(&self) -> Result<(), Error> {
+        // Assume it hasn't worked
+        self.state.set(State::Error);
+        let mut card = self.lock()?;
+        if !card.card_detect.is_card_inserted()? {
+            return Err(Error::CardNotFound);
+        }
+        card.erase_info = None;
+        let result = match card.init().await {
+            Ok(()) => card.read_erase_info().await,
+            Err(e) => Err(e),
+        };
+        let result = match result {
+            Ok(()) => card.read_cid().await,
+            Err(e) => Err(e),
+        };
+        let result = card.deselect(result).await;
+        let _ = card.receive().await;
+        drop(card);
+        let cid = result?;
+        self.state.set(State::Idle);
+        if self.cid.get() != Some(cid.data) {
+            debug!("New card");
+            self.cid.set(Some(cid.data));
+            self.generation.set(self.generation.get().wrapping_add(1));
+        }
+        Ok(())
+    }
+
+    /// Initialise the card. If we have a `set_clock` callback, the SPI
+    /// clock is set to 400 kHz first, and then switched to the fastest
+    /// clock speed the card supports.
+    async fn reinit(&self) -> Result<(), Error> {
+        let Some(set_clock) = self.lock()?.set_clock else {
+            return self.init_card().await;
+        };
+        set_clock(&mut self.lock()?.spi, INIT_CLOCK_HZ);
+        self.init_card().await?;
+        let mut card = self.lock()?;
+        card.cs_low()?;
+        let result = card.read_csd().await;
+        let csd = card.deselect(result).await?;
+        let hz = clock_hz(&csd, &card.options);
+        debug!("Switching SPI clock to {} Hz", hz);
+        set_clock(&mut card.spi, hz);
+        Ok(())
+    }
+
+    /// Initializes the card into a known state, with the SPI clock at
+    /// 400 kHz as the specification requires, and then speeds it up.
+    /// `set_clock` is given the SPI bus and a clock speed in Hz - first
+    /// 400 kHz, and then the fastest the card supports (no faster than
+    /// `options.max_clock_hz`) - and should switch the SPI clock to it, or
+    /// to the fastest speed below it which the SPI can do. It is kept, and
+    /// called again whenever the card is initialised again.
+    pub async fn acquire_with_clock(
+        &mut self,
+        options: AcquireOpts,
+        set_clock: fn(&mut SPI, u32),
+    ) -> Result<AsyncBlockSpi<'_, SPI, CS, DELAY, CD>, Error> {
+        debug!("acquiring card with opts: {:?}", options);
+        let mut card = self.lock()?;
+        card.options = options;
+        card.set_clock = Some(set_clock);
+        drop(card);
+        self.reinit().await?;
+        Ok(AsyncBlockSpi(self))
+    }
+
+    /// Get a temporary borrow on the underlying SPI bus. Useful if you need
+    /// to re-clock the SPI.
+    pub fn spi(&mut self) -> Result<&mut SPI, Error> {
+        match self.card.get_mut() {
+            Some(card) => Ok(&mut card.spi),
+            None => Err(Error::BadState),
+        }
+    }
+
+    /// Take the card out of its `Cell` for the length of an operation.
+    fn lock(&self) -> Result<CardGuard<'_, SPI, CS, DELAY, CD>, Error> {
+        let card = self.card.take().ok_or(Error::BadState)?;
+        Ok(CardGuard {
+            cell: &self.card,
+            card: Some(card),
+        })
+    }
+
+    /// Add one to a count in the retry statistics.
+    fn count(&self, counter: fn(&mut RetryStats) -> &mut u32) {
+        let mut stats = self.stats.get();
+        let count = counter(&mut stats);
+        *count = count.saturating_add(1);
+        self.stats.set(stats);
+    }
+}
+
+impl<SPI, CS, DELAY, CD> Card<SPI, CS, DELAY, CD>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+    CD: CardDetect,
+{
+    /// Reset the card and find out what sort it is. The caller must release
+    /// the chip select afterwards.
+    async fn init(&mut self) -> Result<(), Error> {
+        trace!("Reset card..");
+        // Supply minimum of 74 clock cycles without CS asserted.
+        self.cs_high()?;
+        self.write(&[0xFF; 10]).await?;
+        self.flush().await?;
+        // Assert CS
+        self.cs_low()?;
+        // Enter SPI mode
+        let mut timeout = Timeout::new(self.options.init_timeout_ms);
+        let mut attempts = 32;
+        while attempts > 0 {
+            trace!("Enter SPI mode, attempt: {}..", 32i32 - attempts);
+
+            match self.card_command(CMD0, 0).await {
+                Err(Error::TimeoutCommand(0)) => {
+                    // Try again?
+                    warn!("Timed out, trying again..");
+                    // Try flushing the card as done here: https://github.com/greiman/SdFat/blob/master/src/SdCard/SdSpiCard.cpp#L170,
+                    // https://github.com/rust-embedded-community/embedded-sdmmc-rs/pull/65#issuecomment-1270709448
+                    self.write(&[0xFF; 0xFF]).await?;
+                    attempts -= 1;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+                Ok(R1_IDLE_STATE) => {
+                    break;
+                }
+                Ok(r) => {
+                    // Try again
+                    warn!("Got response: {:x}, trying again..", r);
+                }
+            }
+
+            self.wait(&mut timeout, Error::TimeoutCommand(CMD0)).await?;
+        }
+        if attempts == 0 {
+            return Err(Error::CardNotFound);
+        }
+        // Enable CRC
+        debug!("Enable CRC: {}", self.options.require_crc);
+        if self.card_command(CMD59, 1).await? != R1_IDLE_STATE && self.options.require_crc {
+            return Err(Error::CantEnableCRC);
+        }
+        // Check card version
+        let mut timeout = Timeout::new(self.options.init_timeout_ms);
+        loop {
+            if self.card_command(CMD8, 0x1AA).await? == (R1_ILLEGAL_COMMAND | R1_IDLE_STATE) {
+                self.card_type = CardType::SD1;
+                break;
+            }
+            let mut r7 = [0xFF; 4];
+            self.transfer_in_place(&mut r7).await?;
+            if r7[3] == 0xAA {
+                self.card_type = CardType::SD2;
+                break;
+            }
+            self.wait(&mut timeout, Error::TimeoutCommand(CMD8)).await?;
+        }
+        debug!("Card version: {:?}", self.card_type);
+
+        let arg = match self.card_type {
+            CardType::SD1 | CardType::Mmc | CardType::MmcHc => 0,
+            CardType::SD2 | CardType::SDHC => 0x4000_0000,
+        };
+
+        let mut timeout = Timeout::new(self.options.init_timeout_ms);
+        loop {
+            let r1 = self.card_acmd(ACMD41, arg).await?;
+            if r1 == R1_READY_STATE {
+                break;
+            }
+            if self.card_type == CardType::SD1 && (r1 & R1_ILLEGAL_COMMAND) != 0 {
+                // Not an SD card, so it might be an MMC
+                self.card_type = CardType::Mmc;
+                break;
+            }
+            self.wait(&mut timeout, Error::TimeoutACommand(ACMD41))
+                .await?;
+        }
+
+        if self.card_type == CardType::Mmc {
+            // MMCs use CMD1 instead, saying we can do sector addressing
+            let mut timeout = Timeout::new(self.options.init_timeout_ms);
+            while self.card_command(CMD1, 0x4000_0000).await? != R1_READY_STATE {
+                self.wait(&mut timeout, Error::TimeoutCommand(CMD1)).await?;
+            }
+        }
+
+        if self.card_type == CardType::SD2 || self.card_type == CardType::Mmc {
+            let ocr = self.read_ocr().await?;
+            if ocr.power_up_complete() && ocr.card_capacity_status() {
+                self.card_type = if self.card_type == CardType::Mmc {
+                    CardType::MmcHc
+                } else {
+                    CardType::SDHC
+                };
+            }
+        }
+        if self.card_type == CardType::Mmc {
+            // A standard capacity MMC might not use 512 byte blocks
+            if self.card_command(CMD16, Block::LEN_U32).await? != R1_READY_STATE {
+                return Err(Error::Cmd16Error);
+            }
+        }
+        Ok(())
+    }
+
+    fn cs_high(&mut self) -> Result<(), Error> {
+        self.cs.set_high().map_err(|_| Error::GpioError)
+    }
+
+    fn cs_low(&mut self) -> Result<(), Error> {
+        self.cs.set_low().map_err(|_| Error::GpioError)
+    }
+
+    /// Wait for the bus to finish whatever it was doing, then release the
+    /// chip select. Returns `result` unless that fails.
+    async fn deselect<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        let flushed = self.flush().await;
+        self.cs_high()?;
+        let value = result?;
+        flushed?;
+        Ok(value)
+    }
+
+    /// Perform an application-specific command.
+    async fn card_acmd(&mut self, command: u8, arg: u32) -> Result<u8, Error> {
+        self.card_command(CMD55, 0).await?;
+        self.card_command(command, arg).await
+    }
+
+    /// Perform a command.
+    async fn card_command(&mut self, command: u8, arg: u32) -> Result<u8, Error> {
+        if command != CMD0 && command != CMD12 {
+            self.wait_not_busy().await?;
+        }
+
+        self.write(&command_frame(command, arg)).await?;
+
+        // skip stuff byte for stop read
+        if command == CMD12 {
+            let _result = self.receive().await?;
+        }
+
+        let mut timeout = Timeout::new(self.options.command_timeout_ms);
+        loop {
+            let result = self.receive().await?;
+            if (result & 0x80) == ERROR_OK {
+                return Ok(result);
+            }
+            self.wait(&mut timeout, Error::TimeoutCommand(command))
+                .await?;
+        }
+    }
+
+    /// Read the 'card specific data' block.
+    async fn read_csd(&mut self) -> Result<Csd, Error> {
+        let mut data = [0; 16];
+        if self.card_command(CMD9, 0).await? != 0 {
+            return Err(Error::RegisterReadError);
+        }
+        self.read_data(&mut data).await?;
+        decode_csd(data, self.card_type, &self.options)
+    }
+
+    /// Read the 'card identification' register.
+    async fn read_cid(&mut self) -> Result<Cid, Error> {
+        let mut cid = Cid::new();
+        if self.card_command(CMD10, 0).await? != 0 {
+            return Err(Error::RegisterReadError);
+        }
+        self.read_data(&mut cid.data).await?;
+        Ok(cid)
+    }
+
+    /// Read an MMC's 'extended CSD' register.
+    async fn read_ext_csd(&mut self) -> Result<ExtCsd, Error> {
+        if !self.card_type.is_mmc() {
+            return Err(Error::RegisterReadError);
+        }
+        let mut ext_csd = ExtCsd::new();
+        if self.card_command(CMD8, 0).await? != 0 {
+            return Err(Error::RegisterReadError);
+        }
+        self.read_data(&mut ext_csd.data).await?;
+        Ok(ext_csd)
+    }
+
+    /// Read the 'SD configuration register'.
+    async fn read_scr(&mut self) -> Result<Scr, Error> {
+        let mut scr = Scr::new();
+        if self.card_acmd(ACMD51, 0).await? != 0 {
+            return Err(Error::RegisterReadError);
+        }
+        self.read_data(&mut scr.data).await?;
+        Ok(scr)
+    }
+
+    /// Read the 'operation conditions register'.
+    async fn read_ocr(&mut self) -> Result<Ocr, Error> {
+        if self.card_command(CMD58, 0).await? != 0 {
+            return Err(Error::Cmd58Error);
+        }
+        let mut ocr = Ocr::new();
+        ocr.data.fill(0xFF);
+        self.transfer_in_place(&mut ocr.data).await?;
+        Ok(ocr)
+    }
+
+    /// Read the 'SD Status' register.
+    async fn read_sd_status(&mut self) -> Result<SdStatus, Error> {
+        let mut status = SdStatus::new();
+        if self.card_acmd(ACMD13, 0).await? != 0 {
+            return Err(Error::RegisterReadError);
+        }
+        // This is an R2 response, so there's another status byte
+        if self.receive().await? != 0 {
+            return Err(Error::RegisterReadError);
+        }
+        self.read_data(&mut status.data).await?;
+        Ok(status)
+    }
+
+    /// Find out how the card erases blocks, so that erasing doesn't have to
+    /// ask it each time. MMCs erase differently, so they can't.
+    async fn read_erase_info(&mut self) -> Result<(), Error> {
+        self.erase_info = if self.card_type.is_mmc() {
+            None
+        } else {
+            Some(EraseInfo {
+                csd: self.read_csd().await?,
+                status: self.read_sd_status().await.ok(),
+            })
+        };
+        Ok(())
+    }
+
+    /// Read the card status register.
+    async fn read_status(&mut self) -> Result<CardStatus, Error> {
+        let mut status = CardStatus::new();
+        status.data[0] = self.card_command(CMD13, 0).await?;
+        status.data[1] = self.receive().await?;
+        Ok(status)
+    }
+
+    /// Send a CMD42 with the given lock card data structure, and check it
+    /// worked.
+    async fn lock_unlock(&mut self, data: &[u8], timeout_ms: u32) -> Result<(), Error> {
+        debug!("Lock/unlock with flags {:x}", data[0]);
+        if self.card_command(CMD16, data.len() as u32).await? != 0 {
+            return Err(Error::LockUnlockError);
+        }
+        let result = self.send_lock_unlock(data, timeout_ms).await;
+        // Put the block length back, whatever happened
+        let restored = self.card_command(CMD16, Block::LEN_U32).await;
+        result?;
+        if restored? != 0 {
+            return Err(Error::LockUnlockError);
+        }
+        if self.read_status().await?.lock_unlock_failed() {
+            return Err(Error::LockUnlockError);
+        }
+        Ok(())
+    }
+
+    /// Send a CMD42 and its data block, then wait for the card to finish.
+    async fn send_lock_unlock(&mut self, data: &[u8], timeout_ms: u32) -> Result<(), Error> {
+        if self.card_command(CMD42, 0).await? != 0 {
+            return Err(Error::LockUnlockError);
+        }
+        self.write_data(DATA_START_BLOCK, data).await?;
+        self.wait_not_busy_for(timeout_ms).await
+    }
+
+    /// Erase the blocks from `first` to `last` inclusive, waiting up to
+    /// `timeout_ms` for the card to finish.
+    async fn erase_blocks(&mut self, first: u32, last: u32, timeout_ms: u32) -> Result<(), Error> {
+        let start_idx = self
+            .card_type
+            .card_address(BlockIdx(first as BlockNumber))?;
+        let end_idx = self.card_type.card_address(BlockIdx(last as BlockNumber))?;
+        if self.card_command(CMD32, start_idx).await? != 0x00
+            || self.card_command(CMD33, end_idx).await? != 0x00
+            || self.card_command(CMD38, 0).await? != 0x00
+        {
+            return Err(Error::EraseError);
+        }
+        self.wait_not_busy_for(timeout_ms).await?;
+        if self.card_command(CMD13, 0).await? != 0x00 {
+            return Err(Error::EraseError);
+        }
+        if self.receive().await? != 0x00 {
+            return Err(Error::EraseError);
+        }
+        Ok(())
+    }
+
+    /// Read blocks with one command, starting at the given card address and
+    /// counting those read in `done`.
+    async fn read_run(
+        &mut self,
+        blocks: &mut [Block],
+        start_idx: u32,
+        done: &mut usize,
+    ) -> Result<(), Error> {
+        if blocks.len() == 1 {
+            // Start a single-block read
+            if self.card_command(CMD17, start_idx).await? != 0 {
+                return Err(Error::ReadError);
+            }
+            self.read_data(&mut blocks[0].contents).await?;
+            *done += 1;
+            return Ok(());
+        }
+        // Start a multi-block read
+        if self.card_command(CMD18, start_idx).await? != 0 {
+            return Err(Error::ReadError);
+        }
+        let mut result = Ok(());
+        for block in blocks.iter_mut() {
+            result = self.read_data(&mut block.contents).await;
+            if result.is_err() {
+                break;
+            }
+            *done += 1;
+        }
+        // Stop the read, even if a block went wrong
+        let stopped = self.card_command(CMD12, 0).await;
+        result?;
+        stopped?;
+        Ok(())
+    }
+
+    /// Write blocks with one command, starting at the given card address and
+    /// counting those the card accepted in `done`.
+    async fn write_run(
+        &mut self,
+        blocks: &[Block],
+        start_idx: u32,
+        done: &mut usize,
+    ) -> Result<(), Error> {
+        if blocks.len() == 1 {
+            // Start a single-block write
+            self.card_command(CMD24, start_idx).await?;
+            self.write_data(DATA_START_BLOCK, &blocks[0].contents)
+                .await?;
+            self.wait_not_busy().await?;
+            if self.card_command(CMD13, 0).await? != 0x00 {
+                return Err(Error::WriteError);
+            }
+            if self.receive().await? != 0x00 {
+                return Err(Error::WriteError);
+            }
+            *done += 1;
+            return Ok(());
+        }
+        // Say how many blocks are coming, so the card can erase them first.
+        // This is only a hint, and MMCs don't have it, so the write goes
+        // ahead whatever the card says.
+        if !self.card_type.is_mmc() {
+            match self.card_acmd(ACMD23, blocks.len() as u32).await {
+                Ok(0) => {}
+                Ok(r1) => debug!("Card refused ACMD23: {:x}", r1),
+                Err(e) => warn!("ACMD23 failed: {:?}", e),
+            }
+        }
+        // Start a multi-block write
+        self.card_command(CMD25, start_idx).await?;
+        let mut result = Ok(());
+        for block in blocks.iter() {
+            result = self.write_block(block).await;
+            if result.is_err() {
+                break;
+            }
+            *done += 1;
+        }
+        // Stop the write, even if a block was rejected
+        self.wait_not_busy().await?;
+        self.write(&[STOP_TRAN_TOKEN]).await?;
+        result
+    }
+
+    /// Write one block of a multi-block write.
+    async fn write_block(&mut self, block: &Block) -> Result<(), Error> {
+        self.wait_not_busy().await?;
+        self.write_data(WRITE_MULTIPLE_TOKEN, &block.contents).await
+    }
+
+    /// Read an arbitrary number of bytes from the card, in one transfer.
+    /// Always fills the given buffer, so make sure it's the right size.
+    async fn read_data(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        // Get first non-FF byte.
+        let mut timeout = Timeout::new(self.options.read_timeout_ms);
+        let status = loop {
+            let s = self.receive().await?;
+            if s != 0xFF {
+                break s;
+            }
+            self.wait(&mut timeout, Error::TimeoutReadBuffer).await?;
+        };
+        if status != DATA_START_BLOCK {
+            return Err(Error::ReadError);
+        }
+
+        // The card needs to see 0xFF while it sends, which `SpiBus::read`
+        // doesn't promise.
+        buffer.fill(0xFF);
+        self.transfer_in_place(buffer).await?;
+
+        let mut crc = [0xFF; 2];
+        self.transfer_in_place(&mut crc).await?;
+        let crc = u16::from_be_bytes(crc);
+
+        let calc_crc = crc16(buffer);
+        if crc != calc_crc {
+            return Err(Error::CrcError(crc, calc_crc));
+        }
+
+        Ok(())
+    }
+
+    /// Write an arbitrary number of bytes to the card, in one transfer.
+    async fn write_data(&mut self, token: u8, buffer: &[u8]) -> Result<(), Error> {
+        let calc_crc = crc16(buffer);
+        self.write(&[token]).await?;
+        self.write(buffer).await?;
+        self.write(&calc_crc.to_be_bytes()).await?;
+        let status = self.receive().await?;
+        if (status & DATA_RES_MASK) != DATA_RES_ACCEPTED {
+            Err(Error::WriteRejected)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Wait until the card returns 0xFF, or we have waited too long and
+    /// timeout.
+    async fn wait_not_busy(&mut self) -> Result<(), Error> {
+        self.wait_not_busy_for(self.options.busy_timeout_ms).await
+    }
+
+    /// Wait until the card returns 0xFF, or we have waited `limit_ms` and
+    /// timeout.
+    async fn wait_not_busy_for(&mut self, limit_ms: u32) -> Result<(), Error> {
+        let mut timeout = Timeout::new(limit_ms);
+        loop {
+            let s = self.receive().await?;
+            if s == 0xFF {
+                break;
+            }
+            self.wait(&mut timeout, Error::TimeoutWaitNotBusy).await?;
+        }
+        Ok(())
+    }
+
+    /// Sleep before polling the card again, unless we have run out of time.
+    async fn wait(&mut self, timeout: &mut Timeout, err: Error) -> Result<(), Error> {
+        let interval_us = timeout.poll(err)?;
+        self.delay.delay_us(interval_us).await;
+        Ok(())
+    }
+
+    /// Receive a byte from the SD card by clocking in an 0xFF byte.
+    async fn receive(&mut self) -> Result<u8, Error> {
+        let mut buf = [0xFF];
+        self.transfer_in_place(&mut buf).await?;
+        Ok(buf[0])
+    }
+
+    /// Send some bytes, ignoring what comes back.
+    async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.spi.write(data).await.map_err(|_e| Error::Transport)
+    }
+
+    /// Send some bytes, replacing them with the ones received.
+    async fn transfer_in_place(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        self.spi
+            .transfer_in_place(data)
+            .await
+            .map_err(|_e| Error::Transport)
+    }
+
+    /// Wait until the bus has finished sending.
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.spi.flush().await.map_err(|_e| Error::Transport)
+    }
+}
+
+impl<SPI, CS, DELAY, CD> Deref for CardGuard<'_, SPI, CS, DELAY, CD> {
+    type Target = Card<SPI, CS, DELAY, CD>;
+
+    fn deref(&self) -> &Self::Target {
+        // The card is only taken out when we are dropped
+        self.card.as_ref().unwrap()
+    }
+}
+
+impl<SPI, CS, DELAY, CD> DerefMut for CardGuard<'_, SPI, CS, DELAY, CD> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // The card is only taken out when we are dropped
+        self.card.as_mut().unwrap()
+    }
+}
+
+impl<SPI, CS, DELAY, CD> Drop for CardGuard<'_, SPI, CS, DELAY, CD> {
+    fn drop(&mut self) {
+        self.cell.set(self.card.take());
+    }
+}
+
+impl<SPI, CS, DELAY, CD> AsyncBlockSpi<'_, SPI, CS, DELAY, CD>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+    CD: CardDetect,
+{
+    /// Get a temporary borrow on the underlying SPI bus. Useful if you need
+    /// to re-clock the SPI.
+    pub fn spi(&mut self) -> Result<&mut SPI, Error> {
+        self.0.spi()
+    }
+
+    /// Mark the card as unused.
+    /// This should be kept infallible, because Drop is unable to fail.
+    /// See https://github.com/rust-lang/rfcs/issues/814
+    // If there is any need to flush data, it should be implemented here.
+    fn deinit(&mut self) {
+        self.0.state.set(State::NoInit);
+    }
+
+    /// Is the card still there? This asks the card for its status with
+    /// CMD13, which only a card that has been initialised answers. Once the
+    /// card has been removed, this instead tries to initialise whichever
+    /// card is in the socket now, as the next read or write would.
+    pub async fn check_card_present(&self) -> Result<bool, Error> {
+        if self.0.state.get() != State::Idle {
+            return match self.check_ready().await {
+                Ok(()) => Ok(true),
+                Err(Error::CardNotFound) => Ok(false),
+                Err(e) => Err(e),
+            };
+        }
+        let mut card = self.0.lock()?;
+        self.card_present(&mut card).await
+    }
+
+    /// Check the card is still there, with the `Card` already locked.
+    async fn card_present(&self, card: &mut Card<SPI, CS, DELAY, CD>) -> Result<bool, Error> {
+        if !card.card_detect.is_card_inserted()? {
+            self.0.state.set(State::NoInit);
+            return Ok(false);
+        }
+        card.cs_low()?;
+        let result = card.read_status().await;
+        match card.deselect(result).await {
+            Ok(_) => Ok(true),
+            Err(Error::TimeoutCommand(_) | Error::TimeoutWaitNotBusy) => {
+                debug!("Card has gone");
+                self.0.state.set(State::NoInit);
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Initialise the card again now, rather than on the next read or
+    /// write. Unless it is the same card as before (with the same CID), it
+    /// counts as a new card, so any volumes opened on the old one must be
+    /// opened again. If the card was acquired with `acquire_with_clock`,
+    /// the SPI clock is set again the same way.
+    pub async fn reacquire(&self) -> Result<(), Error> {
+        self.0.reinit().await
+    }
+
+    /// Fail unless the card is in the socket. A card which has been
+    /// removed, or has stopped answering, is initialised again first.
+    async fn check_ready(&self) -> Result<(), Error> {
+        if !self.0.lock()?.card_detect.is_card_inserted()? {
+            self.0.state.set(State::NoInit);
+            return Err(Error::CardNotFound);
+        }
+        if self.0.state.get() != State::Idle {
+            debug!("Initialising the card again");
+            self.0.reinit().await?;
+        }
+        Ok(())
+    }
+
+    /// A card which stops answering might have been removed. Unless it
+    /// still answers CMD13, it must be initialised again before it is used.
+    async fn check_result<T>(
+        &self,
+        card: &mut Card<SPI, CS, DELAY, CD>,
+        result: Result<T, Error>,
+    ) -> Result<T, Error> {
+        if let Err(Error::TimeoutCommand(_) | Error::TimeoutWaitNotBusy) = result {
+            if !matches!(self.card_present(card).await, Ok(true)) {
+                self.0.state.set(State::Error);
+            }
+        }
+        result
+    }
+
+    /// How often blocks have had to be read or written again, since the
+    /// `AsyncSdMmcSpi` was created or
+    /// [`reset_retry_stats`](Self::reset_retry_stats) was called.
+    pub fn retry_stats(&self) -> RetryStats {
+        self.0.stats.get()
+    }
+
+    /// Set the retry statistics back to zero.
+    pub fn reset_retry_stats(&self) {
+        self.0.stats.set(RetryStats::default());
+    }
+
+    /// Read one or more blocks, starting at the given block index. If a
+    /// block can't be read, the read is started again from that block, up
+    /// to `retries` times.
+    async fn read_blocks(
+        &self,
+        card: &mut Card<SPI, CS, DELAY, CD>,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+    ) -> Result<(), Error> {
+        let mut done = 0;
+        let mut retries = 0;
+        loop {
+            let block_idx = start_block_idx + BlockCount(done as BlockNumber);
+            let start_idx = card.card_type.card_address(block_idx)?;
+            match card
+                .read_run(&mut blocks[done..], start_idx, &mut done)
+                .await
+            {
+                Err(e) if e.is_retryable() && retries < card.options.retries => {
+                    warn!("Reading block {} again after {:?}", block_idx.0, e);
+                    retries += 1;
+                    self.0.count(|s| &mut s.read_retries);
+                }
+                Err(e) if e.is_retryable() => {
+                    self.0.count(|s| &mut s.read_failures);
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Write one or more blocks, starting at the given block index. If a
+    /// block is rejected, the write is started again from that block, up to
+    /// `retries` times.
+    async fn write_blocks(
+        &self,
+        card: &mut Card<SPI, CS, DELAY, CD>,
+        blocks: &[Block],
+        start_block_idx: BlockIdx,
+    ) -> Result<(), Error> {
+        let mut done = 0;
+        let mut retries = 0;
+        loop {
+            let block_idx = start_block_idx + BlockCount(done as BlockNumber);
+            let start_idx = card.card_type.card_address(block_idx)?;
+            match card.write_run(&blocks[done..], start_idx, &mut done).await {
+                Err(e) if e.is_retryable() && retries < card.options.retries => {
+                    warn!("Writing block {} again after {:?}", block_idx.0, e);
+                    retries += 1;
+                    self.0.count(|s| &mut s.write_retries);
+                }
+                Err(e) if e.is_retryable() => {
+                    self.0.count(|s| &mut s.write_failures);
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Return the usable size of this SD card in bytes. MMCs larger than
+    /// 2 GiB give their size in the Extended CSD.
+    pub async fn card_size_bytes(&self) -> Result<u64, Error> {
+        if self.0.lock()?.card_type == CardType::MmcHc {
+            return Ok(self.read_ext_csd().await?.card_capacity_bytes());
+        }
+        Ok(self.read_csd().await?.card_capacity_bytes())
+    }
+
+    /// Erase some blocks on the card. Both ends of the range are included.
+    /// Erased blocks read back as all zeros or all ones, depending on the
+    /// card.
+    ///
+    /// Cards which can't erase single blocks (see
+    /// [`erase_single_block_enabled`](Self::erase_single_block_enabled))
+    /// erase whole erase sectors, so only the sectors which lie entirely
+    /// within the range are erased.
+    pub async fn erase(&self, first_block: BlockIdx, last_block: BlockIdx) -> Result<(), Error> {
+        self.check_ready().await?;
+        let mut card = self.0.lock()?;
+        // MMCs erase erase groups, with different commands
+        let Some(erase_info) = card.erase_info.as_ref() else {
+            return Err(Error::EraseError);
+        };
+        let Some((first, last)) = erase_range(&erase_info.csd, first_block, last_block)? else {
+            return Ok(());
+        };
+        let timeout_ms =
+            erase_timeout_ms(erase_info.status.as_ref(), last - first + 1, &card.options);
+        debug!(
+            "Erasing blocks {} to {}, timeout {} ms",
+            first, last, timeout_ms
+        );
+        card.cs_low()?;
+        let result = card.erase_blocks(first, last, timeout_ms).await;
+        let result = card.deselect(result).await;
+        self.check_result(&mut card, result).await
+    }
+
+    /// Can this card erase single blocks?
+    pub async fn erase_single_block_enabled(&self) -> Result<bool, Error> {
+        Ok(self.read_csd().await?.erase_single_block_enabled())
+    }
+
+    /// Read the 'card identification' register, which says who made the
+    /// card and gives its product name and serial number.
+    pub async fn read_cid(&self) -> Result<Cid, Error> {
+        let mut card = self.0.lock()?;
+        card.cs_low()?;
+        let result = card.read_cid().await;
+        card.deselect(result).await
+    }
+
+    /// Read an MMC's 'extended CSD' register, which gives the size of MMCs
+    /// larger than 2 GiB. SD cards don't have one.
+    pub async fn read_ext_csd(&self) -> Result<ExtCsd, Error> {
+        let mut card = self.0.lock()?;
+        card.cs_low()?;
+        let result = card.read_ext_csd().await;
+        card.deselect(result).await
+    }
+
+    /// Read the 'SD configuration register', which says which version of
+    /// the SD specification the card supports.
+    pub async fn read_scr(&self) -> Result<Scr, Error> {
+        let mut card = self.0.lock()?;
+        card.cs_low()?;
+        let result = card.read_scr().await;
+        card.deselect(result).await
+    }
+
+    /// Read the 'operation conditions register', which gives the card's
+    /// supported voltages and whether it is high capacity.
+    pub async fn read_ocr(&self) -> Result<Ocr, Error> {
+        let mut card = self.0.lock()?;
+        card.cs_low()?;
+        let result = card.read_ocr().await;
+        card.deselect(result).await
+    }
+
+    /// Read the 'SD Status' register, which gives the card's speed class
+    /// and Allocation Unit size.
+    pub async fn read_sd_status(&self) -> Result<SdStatus, Error> {
+        let mut card = self.0.lock()?;
+        card.cs_low()?;
+        let result = card.read_sd_status().await;
+        card.deselect(result).await
+    }
+
+    /// Is the card write protected, either temporarily or permanently? This
+    /// comes from the CSD, so the write protect switch on an SD card's
+    /// case isn't included.
+    pub async fn is_write_protected(&self) -> Result<bool, Error> {
+        Ok(self.read_csd().await?.is_write_protected())
+    }
+
+    /// Read the card status register.
+    pub async fn card_status(&self) -> Result<CardStatus, Error> {
+        let mut card = self.0.lock()?;
+        card.cs_low()?;
+        let result = card.read_status().await;
+        card.deselect(result).await
+    }
+
+    /// Is the card locked with a password? A locked card can't be read or
+    /// written until it is unlocked.
+    pub async fn is_locked(&self) -> Result<bool, Error> {
+        Ok(self.card_status().await?.card_is_locked())
+    }
+
+    /// Set the card's password, replacing `old_password` (which is empty if
+    /// the card doesn't have one yet). The card is locked the next time it
+    /// is powered up. Passwords can be up to 16 bytes long.
+    pub async fn set_password(
+        &self,
+        old_password: &[u8],
+        new_password: &[u8],
+    ) -> Result<(), Error> {
+        let (data, len) = lock_data(LOCK_SET_PWD, old_password, new_password)?;
+        self.lock_unlock(&data[0..len], None).await
+    }
+
+    /// Remove the card's password.
+    pub async fn clear_password(&self, password: &[u8]) -> Result<(), Error> {
+        let (data, len) = lock_data(LOCK_CLR_PWD, password, &[])?;
+        self.lock_unlock(&data[0..len], None).await
+    }
+
+    /// Lock the card, so it can't be read or written until it is unlocked.
+    pub async fn lock(&self, password: &[u8]) -> Result<(), Error> {
+        let (data, len) = lock_data(LOCK_LOCK_UNLOCK, password, &[])?;
+        self.lock_unlock(&data[0..len], None).await
+    }
+
+    /// Unlock a locked card.
+    pub async fn unlock(&self, password: &[u8]) -> Result<(), Error> {
+        let (data, len) = lock_data(0, password, &[])?;
+        self.lock_unlock(&data[0..len], None).await
+    }
+
+    /// Erase a card whose password has been forgotten. Everything on the
+    /// card is lost, along with the password. This can take a few minutes.
+    pub async fn force_erase(&self) -> Result<(), Error> {
+        let (data, len) = lock_data(LOCK_ERASE, &[], &[])?;
+        self.lock_unlock(&data[0..len], Some(FORCE_ERASE_TIMEOUT_MS))
+            .await
+    }
+
+    /// Send a CMD42, waiting `timeout_ms` (or the busy timeout) for the
+    /// card to finish.
+    async fn lock_unlock(&self, data: &[u8], timeout_ms: Option<u32>) -> Result<(), Error> {
+        let mut card = self.0.lock()?;
+        let timeout_ms = timeout_ms.unwrap_or(card.options.busy_timeout_ms);
+        card.cs_low()?;
+        let result = card.lock_unlock(data, timeout_ms).await;
+        card.deselect(result).await
+    }
+
+    /// Read the 'card specific data' register, which describes the card's
+    /// capacity, speed and erase and write protect features.
+    pub async fn read_csd(&self) -> Result<Csd, Error> {
+        let mut card = self.0.lock()?;
+        card.cs_low()?;
+        let result = card.read_csd().await;
+        card.deselect(result).await
+    }
+}
+
+impl<SPI, CS, DELAY, CD> AsyncBlockDevice for AsyncBlockSpi<'_, SPI, CS, DELAY, CD>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+    CD: CardDetect,
+{
+    type Error = Error;
+
+    /// Read one or more blocks, starting at the given block index.
+    async fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        self.check_ready().await?;
+        let mut card = self.0.lock()?;
+        card.cs_low()?;
+        let result = self.read_blocks(&mut card, blocks, start_block_idx).await;
+        let result = card.deselect(result).await;
+        self.check_result(&mut card, result).await
+    }
+
+    /// Write one or more blocks, starting at the given block index.
+    async fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.check_ready().await?;
+        let mut card = self.0.lock()?;
+        card.cs_low()?;
+        let result = self.write_blocks(&mut card, blocks, start_block_idx).await;
+        let result = card.deselect(result).await;
+        self.check_result(&mut card, result).await
+    }
+
+    /// Determine how many blocks this device can hold.
+    async fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        let num_bytes = self.card_size_bytes().await?;
+        let num_blocks = (num_bytes / 512) as BlockNumber;
+        Ok(BlockCount(num_blocks))
+    }
+
+    /// Erase blocks which are no longer needed.
+    async fn discard(
+        &self,
+        start_block_idx: BlockIdx,
+        num_blocks: BlockCount,
+    ) -> Result<(), Self::Error> {
+        if num_blocks.0 == 0 {
+            return Ok(());
+        }
+        self.erase(
+            start_block_idx,
+            start_block_idx + BlockCount(num_blocks.0 - 1),
+        )
+        .await
+    }
+
+    /// Count how many different cards have been initialised. A card which
+    /// has been removed, or has stopped answering, is initialised again
+    /// first, so this goes up if a different card has been put in.
+    async fn media_generation(&self) -> Result<u32, Self::Error> {
+        self.check_ready().await?;
+        Ok(self.0.generation.get())
+    }
+}
+
+impl<SPI, CS, DELAY, CD> Drop for AsyncBlockSpi<'_, SPI, CS, DELAY, CD>
+where
+    SPI: SpiBus<u8>,
+    CS: OutputPin,
+    DELAY: DelayNs,
+    CD: CardDetect,
+{
+    fn drop(&mut self) {
+        self.deinit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockdevice::block_on;
+    use crate::sdmmc_sim::{SimCard, SimKind, SimSpi};
+    use core::future::{poll_fn, Future};
+    use core::pin::pin;
+    use core::task::Poll;
+
+    /// Poll a future once, and then drop it.
+    async fn poll_once<F: Future>(future: F) -> Poll<F::Output> {
+        let mut future = pin!(future);
+        poll_fn(|cx| Poll::Ready(future.as_mut().poll(cx))).await
+    }
+
+    #[test]
+    fn test_yielding_bus() {
+        // The bus makes us wait for every transfer
+        let spi = SimSpi::new(SimCard::new(SimKind::Sdhc));
+        let mut sdmmc = AsyncSdMmcSpi::new(spi.clone(), spi.cs(), spi.delay());
+        let block_spi = block_on(sdmmc.acquire()).unwrap();
+        let mut written = [Block::new(), Block::new()];
+        written[0].contents.fill(0x5A);
+        written[1].contents.fill(0xA5);
+        block_on(block_spi.write(&written, BlockIdx(3))).unwrap();
+        let mut read = [Block::new(), Block::new()];
+        block_on(block_spi.read(&mut read, BlockIdx(3), "test")).unwrap();
+        assert_eq!(read[0].contents, written[0].contents);
+        assert_eq!(read[1].contents, written[1].contents);
+        assert_eq!(
+            block_on(block_spi.read_cid()).unwrap().serial_number(),
+            0x1234_5678
+        );
+        assert_eq!(spi.card().stats.split_exchanges, 0);
+
+        // An operation which is dropped while it waits gives the card back
+        let mut read = [Block::new()];
+        let poll = block_on(poll_once(block_spi.read(&mut read, BlockIdx(4), "test")));
+        assert!(poll.is_pending());
+        block_on(block_spi.read(&mut read, BlockIdx(4), "test")).unwrap();
+        assert_eq!(read[0].contents, written[1].contents);
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
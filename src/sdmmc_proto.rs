@@ -25,6 +25,8 @@
 //! > FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 //! > DEALINGS IN THE SOFTWARE.
 
+use byteorder::{ByteOrder, LittleEndian};
+
 //==============================================================================
 
 // Possible errors the SD card can return
@@ -38,14 +40,29 @@ pub const ERROR_OK: u8 = 0x00;
 
 /// GO_IDLE_STATE - init card in spi mode if CS low
 pub const CMD0: u8 = 0x00;
+/// SEND_OP_COND - activates an MMC's initialization process
+pub const CMD1: u8 = 0x01;
+/// ALL_SEND_CID - ask every card on an SD bus for its CID
+pub const CMD2: u8 = 0x02;
+/// SEND_RELATIVE_ADDR - ask an SD bus card to publish a relative address
+pub const CMD3: u8 = 0x03;
+/// SWITCH_FUNC - check or switch a card function, such as high speed mode
+pub const CMD6: u8 = 0x06;
+/// SELECT/DESELECT_CARD - move a card on an SD bus into the transfer state
+pub const CMD7: u8 = 0x07;
 /// SEND_IF_COND - verify SD Memory Card interface operating condition.*/
+/// On an MMC this is SEND_EXT_CSD, which reads the Extended CSD register.
 pub const CMD8: u8 = 0x08;
 /// SEND_CSD - read the Card Specific Data (CSD register)
 pub const CMD9: u8 = 0x09;
+/// SEND_CID - read the Card Identification (CID register)
+pub const CMD10: u8 = 0x0A;
 /// STOP_TRANSMISSION - end multiple block read sequence
 pub const CMD12: u8 = 0x0C;
 /// SEND_STATUS - read the card status register
 pub const CMD13: u8 = 0x0D;
+/// SET_BLOCKLEN - set the length of the CMD42 data block
+pub const CMD16: u8 = 0x10;
 /// READ_SINGLE_BLOCK - read a single data block from the card
 pub const CMD17: u8 = 0x11;
 /// READ_MULTIPLE_BLOCK - read a multiple data blocks from the card
@@ -54,6 +71,14 @@ pub const CMD18: u8 = 0x12;
 pub const CMD24: u8 = 0x18;
 /// WRITE_MULTIPLE_BLOCK - write blocks of data until a STOP_TRANSMISSION
 pub const CMD25: u8 = 0x19;
+/// ERASE_WR_BLK_START - sets the address of the first block to be erased
+pub const CMD32: u8 = 0x20;
+/// ERASE_WR_BLK_END - sets the address of the last block to be erased
+pub const CMD33: u8 = 0x21;
+/// ERASE - erase the blocks selected by CMD32 and CMD33
+pub const CMD38: u8 = 0x26;
+/// LOCK_UNLOCK - set or clear the password, lock or unlock the card
+pub const CMD42: u8 = 0x2A;
 /// APP_CMD - escape for application specific command
 pub const CMD55: u8 = 0x37;
 /// READ_OCR - read the OCR register of a card
@@ -63,6 +88,15 @@ pub const CMD59: u8 = 0x3B;
 /// SD_SEND_OP_COMD - Sends host capacity support information and activates
 /// the card's initialization process
 pub const ACMD41: u8 = 0x29;
+/// SET_BUS_WIDTH - set the data bus width of a card on an SD bus
+pub const ACMD6: u8 = 0x06;
+/// SD_STATUS - read the SD Status register
+pub const ACMD13: u8 = 0x0D;
+/// SET_WR_BLK_ERASE_COUNT - say how many blocks the next multi-block write
+/// will write, so the card can erase them first
+pub const ACMD23: u8 = 0x17;
+/// SEND_SCR - read the SD Configuration Register
+pub const ACMD51: u8 = 0x33;
 
 //==============================================================================
 
@@ -90,21 +124,86 @@ pub const DATA_RES_MASK: u8 = 0x1F;
 /// write data accepted token
 pub const DATA_RES_ACCEPTED: u8 = 0x05;
 
+// CMD42 lock card data structure flags
+
+/// set a new password
+pub const LOCK_SET_PWD: u8 = 0x01;
+/// clear the password
+pub const LOCK_CLR_PWD: u8 = 0x02;
+/// lock the card (or unlock it, if clear)
+pub const LOCK_LOCK_UNLOCK: u8 = 0x04;
+/// force erase the card, clearing the password
+pub const LOCK_ERASE: u8 = 0x08;
+
+/// the longest password a card can have, in bytes
+pub const MAX_PASSWORD_LEN: usize = 16;
+
 /// Card Specific Data, version 1
-#[derive(Default)]
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Default, Clone)]
 pub struct CsdV1 {
     /// The 16-bytes of data in this Card Specific Data block
     pub data: [u8; 16],
 }
 
 /// Card Specific Data, version 2
-#[derive(Default)]
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Default, Clone)]
 pub struct CsdV2 {
     /// The 16-bytes of data in this Card Specific Data block
     pub data: [u8; 16],
 }
 
+/// The SD Status register
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Clone)]
+pub struct SdStatus {
+    /// The 64-bytes of data in this SD Status block
+    pub data: [u8; 64],
+}
+
+/// The Card Identification register
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Default, Clone)]
+pub struct Cid {
+    /// The 16-bytes of data in this Card Identification block
+    pub data: [u8; 16],
+}
+
+/// The SD Configuration Register
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Default, Clone)]
+pub struct Scr {
+    /// The 8-bytes of data in this SD Configuration Register
+    pub data: [u8; 8],
+}
+
+/// The Operation Conditions Register
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Default, Clone)]
+pub struct Ocr {
+    /// The 4-bytes of data in this Operation Conditions Register
+    pub data: [u8; 4],
+}
+
+/// The Extended CSD register of an MMC
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Clone)]
+pub struct ExtCsd {
+    /// The 512-bytes of data in this Extended CSD block
+    pub data: [u8; 512],
+}
+
+/// The card status, as returned by CMD13 in SPI mode (an R2 response)
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Default, Clone)]
+pub struct CardStatus {
+    /// The 2-bytes of the R2 response
+    pub data: [u8; 2],
+}
+
 /// Card Specific Data
+#[derive(Debug, Clone)]
 pub enum Csd {
     /// A version 1 CSD
     V1(CsdV1),
@@ -112,6 +211,42 @@ pub enum Csd {
     V2(CsdV2),
 }
 
+/// The kind of file system a card says it holds, from the CSD's
+/// `FILE_FORMAT_GRP` and `FILE_FORMAT` fields.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileFormat {
+    /// A hard disk like file system, with a partition table
+    PartitionTable,
+    /// A DOS FAT file system with a boot sector but no partition table
+    BootSectorOnly,
+    /// The Universal File Format
+    Universal,
+    /// Some other or unknown format
+    Other,
+    /// A reserved value
+    Reserved,
+}
+
+/// The mantissas of the TAAC and TRAN_SPEED fields, multiplied by ten.
+const TIME_VALUES_X10: [u32; 16] = [
+    0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80,
+];
+
+/// Defines a function on [`Csd`] which reads a field from whichever
+/// version of CSD it holds.
+macro_rules! define_csd_field {
+    ($name:ident, $type:ty) => {
+        /// Get the value from the $name field
+        pub fn $name(&self) -> $type {
+            match self {
+                Csd::V1(contents) => contents.$name(),
+                Csd::V2(contents) => contents.$name(),
+            }
+        }
+    };
+}
+
 impl CsdV1 {
     /// Create a new, empty, CSD
     pub fn new() -> CsdV1 {
@@ -161,6 +296,38 @@ impl CsdV1 {
     }
 }
 
+impl CsdV1 {
+    /// Returns the maximum read current at the minimum supply voltage, in
+    /// microamps.
+    pub fn max_read_current_vdd_min_ua(&self) -> u32 {
+        [500, 1_000, 5_000, 10_000, 25_000, 35_000, 60_000, 100_000]
+            [usize::from(self.max_read_current_vdd_min())]
+    }
+
+    /// Returns the maximum read current at the maximum supply voltage, in
+    /// microamps.
+    pub fn max_read_current_vdd_max_ua(&self) -> u32 {
+        [
+            1_000, 5_000, 10_000, 25_000, 35_000, 45_000, 80_000, 200_000,
+        ][usize::from(self.max_read_current_vdd_max())]
+    }
+
+    /// Returns the maximum write current at the minimum supply voltage, in
+    /// microamps.
+    pub fn max_write_current_vdd_min_ua(&self) -> u32 {
+        [500, 1_000, 5_000, 10_000, 25_000, 35_000, 60_000, 100_000]
+            [usize::from(self.max_write_current_vdd_min())]
+    }
+
+    /// Returns the maximum write current at the maximum supply voltage, in
+    /// microamps.
+    pub fn max_write_current_vdd_max_ua(&self) -> u32 {
+        [
+            1_000, 5_000, 10_000, 25_000, 35_000, 45_000, 80_000, 200_000,
+        ][usize::from(self.max_write_current_vdd_max())]
+    }
+}
+
 impl CsdV2 {
     /// Create a new, empty, CSD
     pub fn new() -> CsdV2 {
@@ -203,6 +370,437 @@ impl CsdV2 {
     }
 }
 
+impl Csd {
+    /// Decode some Card Specific Data, as read from the card. The version
+    /// is taken from the `CSD_STRUCTURE` field.
+    pub fn from_data(data: [u8; 16]) -> Csd {
+        if (data[0] >> 6) == 0 {
+            Csd::V1(CsdV1 { data })
+        } else {
+            Csd::V2(CsdV2 { data })
+        }
+    }
+
+    /// Returns the raw 16 bytes of Card Specific Data.
+    pub fn data(&self) -> &[u8; 16] {
+        match self {
+            Csd::V1(contents) => &contents.data,
+            Csd::V2(contents) => &contents.data,
+        }
+    }
+
+    define_csd_field!(csd_ver, u8);
+    define_csd_field!(data_read_access_time1, u8);
+    define_csd_field!(data_read_access_time2, u8);
+    define_csd_field!(max_data_transfer_rate, u8);
+    define_csd_field!(card_command_classes, u16);
+    define_csd_field!(read_block_length, u8);
+    define_csd_field!(read_partial_blocks, bool);
+    define_csd_field!(write_block_misalignment, bool);
+    define_csd_field!(read_block_misalignment, bool);
+    define_csd_field!(dsr_implemented, bool);
+    define_csd_field!(device_size, u32);
+    define_csd_field!(erase_single_block_enabled, bool);
+    define_csd_field!(erase_sector_size, u8);
+    define_csd_field!(write_protect_group_size, u8);
+    define_csd_field!(write_protect_group_enable, bool);
+    define_csd_field!(write_speed_factor, u8);
+    define_csd_field!(max_write_data_length, u8);
+    define_csd_field!(write_partial_blocks, bool);
+    define_csd_field!(file_format, u8);
+    define_csd_field!(temporary_write_protection, bool);
+    define_csd_field!(permanent_write_protection, bool);
+    define_csd_field!(copy_flag_set, bool);
+    define_csd_field!(file_format_group_set, bool);
+    define_csd_field!(crc, u8);
+    define_csd_field!(card_capacity_bytes, u64);
+    define_csd_field!(card_capacity_blocks, u32);
+
+    /// Returns the asynchronous part of the read access time (TAAC) in
+    /// nanoseconds, or `None` if the field holds a reserved value.
+    pub fn read_access_time_ns(&self) -> Option<u32> {
+        let taac = self.data_read_access_time1();
+        let value = TIME_VALUES_X10[usize::from((taac >> 3) & 0x0F)];
+        if value == 0 || taac & 0x80 != 0 {
+            return None;
+        }
+        Some(value * 10u32.pow(u32::from(taac & 0x07)) / 10)
+    }
+
+    /// Returns the clock dependent part of the read access time (NSAC), in
+    /// clock cycles.
+    pub fn read_access_clocks(&self) -> u32 {
+        u32::from(self.data_read_access_time2()) * 100
+    }
+
+    /// Returns the maximum data transfer rate (TRAN_SPEED) in kbit/s, or
+    /// `None` if the field holds a reserved value.
+    pub fn max_transfer_rate_kbps(&self) -> Option<u32> {
+        let tran_speed = self.max_data_transfer_rate();
+        let value = TIME_VALUES_X10[usize::from((tran_speed >> 3) & 0x0F)];
+        let unit = u32::from(tran_speed & 0x07);
+        if value == 0 || unit > 3 || tran_speed & 0x80 != 0 {
+            return None;
+        }
+        Some(value * 10u32.pow(unit + 1))
+    }
+
+    /// Returns the maximum read block length (READ_BL_LEN) in bytes.
+    pub fn read_block_length_bytes(&self) -> u32 {
+        1 << self.read_block_length()
+    }
+
+    /// Returns the maximum write block length (WRITE_BL_LEN) in bytes.
+    pub fn write_block_length_bytes(&self) -> u32 {
+        1 << self.max_write_data_length()
+    }
+
+    /// Returns the size of an erase sector, in write blocks.
+    pub fn erase_sector_blocks(&self) -> u32 {
+        u32::from(self.erase_sector_size()) + 1
+    }
+
+    /// Returns the size of a write protect group, in erase sectors.
+    pub fn write_protect_group_sectors(&self) -> u32 {
+        u32::from(self.write_protect_group_size()) + 1
+    }
+
+    /// Returns how many times longer writing a block takes than reading
+    /// one (R2W_FACTOR).
+    pub fn write_speed_multiplier(&self) -> u32 {
+        1 << self.write_speed_factor()
+    }
+
+    /// Returns the kind of file system the card says it holds.
+    pub fn file_format_type(&self) -> FileFormat {
+        match (self.file_format_group_set(), self.file_format()) {
+            (false, 0) => FileFormat::PartitionTable,
+            (false, 1) => FileFormat::BootSectorOnly,
+            (false, 2) => FileFormat::Universal,
+            (false, _) => FileFormat::Other,
+            (true, _) => FileFormat::Reserved,
+        }
+    }
+
+    /// Is the whole card write protected, either temporarily or
+    /// permanently?
+    pub fn is_write_protected(&self) -> bool {
+        self.temporary_write_protection() || self.permanent_write_protection()
+    }
+
+    /// Does the CRC7 in the last byte match the rest of the data?
+    pub fn crc_is_valid(&self) -> bool {
+        crc7(&self.data()[0..15]) == self.crc()
+    }
+}
+
+impl core::fmt::Display for Csd {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "CSD v{}.0: {} bytes, TAAC ",
+            self.csd_ver() + 1,
+            self.card_capacity_bytes()
+        )?;
+        match self.read_access_time_ns() {
+            Some(ns) => write!(f, "{} ns", ns)?,
+            None => write!(f, "reserved")?,
+        }
+        write!(
+            f,
+            ", NSAC {} clocks, TRAN_SPEED ",
+            self.read_access_clocks()
+        )?;
+        match self.max_transfer_rate_kbps() {
+            Some(kbps) => write!(f, "{} kbit/s", kbps)?,
+            None => write!(f, "reserved")?,
+        }
+        write!(
+            f,
+            ", CCC {:#05x}, READ_BL_LEN {} bytes, WRITE_BL_LEN {} bytes, R2W x{}, erase sector {} blocks, single block erase {}, WP temporary {} permanent {}, file format {:?}, CRC {}",
+            self.card_command_classes(),
+            self.read_block_length_bytes(),
+            self.write_block_length_bytes(),
+            self.write_speed_multiplier(),
+            self.erase_sector_blocks(),
+            self.erase_single_block_enabled(),
+            self.temporary_write_protection(),
+            self.permanent_write_protection(),
+            self.file_format_type(),
+            if self.crc_is_valid() { "ok" } else { "bad" }
+        )
+    }
+}
+
+#[cfg(feature = "defmt-log")]
+impl defmt::Format for Csd {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "CSD v{}.0: {} bytes, TAAC {} ns, NSAC {} clocks, TRAN_SPEED {} kbit/s, CCC {:#x}, READ_BL_LEN {} bytes, WRITE_BL_LEN {} bytes, R2W x{}, erase sector {} blocks, single block erase {}, WP temporary {} permanent {}, file format {}, CRC {}",
+            self.csd_ver() + 1,
+            self.card_capacity_bytes(),
+            self.read_access_time_ns(),
+            self.read_access_clocks(),
+            self.max_transfer_rate_kbps(),
+            self.card_command_classes(),
+            self.read_block_length_bytes(),
+            self.write_block_length_bytes(),
+            self.write_speed_multiplier(),
+            self.erase_sector_blocks(),
+            self.erase_single_block_enabled(),
+            self.temporary_write_protection(),
+            self.permanent_write_protection(),
+            self.file_format_type(),
+            if self.crc_is_valid() { "ok" } else { "bad" }
+        )
+    }
+}
+
+impl SdStatus {
+    /// Create a new, empty, SD Status
+    pub fn new() -> SdStatus {
+        SdStatus { data: [0; 64] }
+    }
+
+    define_field!(dat_bus_width, u8, 0, 6, 2);
+    define_field!(secured_mode, bool, 0, 5);
+    define_field!(sd_card_type, u16, [(2, 0, 8), (3, 0, 8)]);
+    define_field!(
+        size_of_protected_area,
+        u32,
+        [(4, 0, 8), (5, 0, 8), (6, 0, 8), (7, 0, 8)]
+    );
+    define_field!(speed_class, u8, 8, 0, 8);
+    define_field!(performance_move, u8, 9, 0, 8);
+    define_field!(au_size, u8, 10, 4, 4);
+    define_field!(erase_size, u16, [(11, 0, 8), (12, 0, 8)]);
+    define_field!(erase_timeout, u8, 13, 2, 6);
+    define_field!(erase_offset, u8, 13, 0, 2);
+    define_field!(uhs_speed_grade, u8, 14, 4, 4);
+    define_field!(uhs_au_size, u8, 14, 0, 4);
+    define_field!(video_speed_class, u8, 15, 0, 8);
+    define_field!(app_perf_class, u8, 21, 0, 4);
+
+    /// Returns the card's Speed Class (0, 2, 4, 6 or 10), which is its
+    /// minimum write speed in MB/s, or `None` if the value isn't one the
+    /// specification defines.
+    pub fn speed_class_number(&self) -> Option<u8> {
+        match self.speed_class() {
+            0 => Some(0),
+            1 => Some(2),
+            2 => Some(4),
+            3 => Some(6),
+            4 => Some(10),
+            _ => None,
+        }
+    }
+
+    /// Returns the size of an Allocation Unit in bytes, or `None` if the
+    /// card doesn't say.
+    pub fn au_size_bytes(&self) -> Option<u32> {
+        match self.au_size() {
+            0 => None,
+            n @ 1..=9 => Some((16 * 1024) << (n - 1)),
+            0xA => Some(8 * 1024 * 1024),
+            0xB => Some(12 * 1024 * 1024),
+            0xC => Some(16 * 1024 * 1024),
+            0xD => Some(24 * 1024 * 1024),
+            0xE => Some(32 * 1024 * 1024),
+            _ => Some(64 * 1024 * 1024),
+        }
+    }
+
+    /// Returns the longest that erasing `num_bytes` can take, in
+    /// milliseconds, or `None` if the card doesn't say.
+    pub fn erase_timeout_ms(&self, num_bytes: u64) -> Option<u32> {
+        let au_size = self.au_size_bytes()?;
+        if self.erase_size() == 0 || self.erase_timeout() == 0 {
+            return None;
+        }
+        // ERASE_TIMEOUT seconds covers erasing ERASE_SIZE AUs, and then
+        // ERASE_OFFSET seconds is added on
+        let num_aus = num_bytes.div_ceil(u64::from(au_size));
+        let ms = (u64::from(self.erase_timeout()) * num_aus * 1000)
+            .div_ceil(u64::from(self.erase_size()))
+            + u64::from(self.erase_offset()) * 1000;
+        Some(u32::try_from(ms).unwrap_or(u32::MAX))
+    }
+}
+
+impl Default for SdStatus {
+    fn default() -> Self {
+        SdStatus::new()
+    }
+}
+
+impl Cid {
+    /// Create a new, empty, CID
+    pub fn new() -> Cid {
+        Cid::default()
+    }
+
+    define_field!(manufacturer_id, u8, 0, 0, 8);
+    define_field!(oem_id, u16, [(1, 0, 8), (2, 0, 8)]);
+    define_field!(product_revision_major, u8, 8, 4, 4);
+    define_field!(product_revision_minor, u8, 8, 0, 4);
+    define_field!(
+        serial_number,
+        u32,
+        [(9, 0, 8), (10, 0, 8), (11, 0, 8), (12, 0, 8)]
+    );
+    define_field!(manufacturing_year_offset, u8, [(13, 0, 4), (14, 4, 4)]);
+    define_field!(manufacturing_month, u8, 14, 0, 4);
+    define_field!(crc, u8, 15, 1, 7);
+
+    /// Returns the two character OEM/Application ID, or an empty string
+    /// if it isn't ASCII.
+    pub fn oem_id_str(&self) -> &str {
+        ascii_str(&self.data[1..3])
+    }
+
+    /// Returns the five character product name, or an empty string if it
+    /// isn't ASCII.
+    pub fn product_name(&self) -> &str {
+        ascii_str(&self.data[3..8])
+    }
+
+    /// Returns the year the card was made.
+    pub fn manufacturing_year(&self) -> u16 {
+        2000 + u16::from(self.manufacturing_year_offset())
+    }
+}
+
+impl Scr {
+    /// Create a new, empty, SCR
+    pub fn new() -> Scr {
+        Scr::default()
+    }
+
+    define_field!(scr_structure, u8, 0, 4, 4);
+    define_field!(sd_spec, u8, 0, 0, 4);
+    define_field!(data_stat_after_erase, bool, 1, 7);
+    define_field!(sd_security, u8, 1, 4, 3);
+    define_field!(sd_bus_widths, u8, 1, 0, 4);
+    define_field!(sd_spec3, bool, 2, 7);
+    define_field!(ex_security, u8, 2, 3, 4);
+    define_field!(sd_spec4, bool, 2, 2);
+    define_field!(sd_specx, u8, [(2, 0, 2), (3, 6, 2)]);
+    define_field!(cmd_support, u8, 3, 0, 4);
+
+    /// Returns the major and minor version of the Physical Layer
+    /// Specification the card supports, or `None` if the combination of
+    /// version fields isn't one the specification defines.
+    pub fn spec_version(&self) -> Option<(u8, u8)> {
+        match (
+            self.sd_spec(),
+            self.sd_spec3(),
+            self.sd_spec4(),
+            self.sd_specx(),
+        ) {
+            (0, false, false, 0) => Some((1, 0)),
+            (1, false, false, 0) => Some((1, 10)),
+            (2, false, false, 0) => Some((2, 0)),
+            (2, true, false, 0) => Some((3, 0)),
+            (2, true, true, 0) => Some((4, 0)),
+            (2, true, _, n @ 1..=5) => Some((n + 4, 0)),
+            _ => None,
+        }
+    }
+
+    /// Can the card use a 1-bit data bus?
+    pub fn supports_1bit_bus(&self) -> bool {
+        (self.sd_bus_widths() & 0x01) != 0
+    }
+
+    /// Can the card use a 4-bit data bus?
+    pub fn supports_4bit_bus(&self) -> bool {
+        (self.sd_bus_widths() & 0x04) != 0
+    }
+}
+
+impl Ocr {
+    /// Create a new, empty, OCR
+    pub fn new() -> Ocr {
+        Ocr::default()
+    }
+
+    define_field!(power_up_complete, bool, 0, 7);
+    define_field!(card_capacity_status, bool, 0, 6);
+    define_field!(uhs2_card_status, bool, 0, 5);
+    define_field!(switching_to_1v8_accepted, bool, 0, 0);
+    define_field!(voltage_window, u16, [(1, 0, 8), (2, 7, 1)]);
+}
+
+impl ExtCsd {
+    /// Create a new, empty, Extended CSD
+    pub fn new() -> ExtCsd {
+        ExtCsd { data: [0; 512] }
+    }
+
+    define_field!(partition_support, u8, 160);
+    define_field!(rpmb_size_mult, u8, 168);
+    define_field!(erase_group_def, u8, 175);
+    define_field!(bus_width, u8, 183);
+    define_field!(hs_timing, u8, 185);
+    define_field!(ext_csd_rev, u8, 192);
+    define_field!(csd_structure, u8, 194);
+    define_field!(device_type, u8, 196);
+    define_field!(sec_count, u32, 212);
+    define_field!(hc_wp_grp_size, u8, 221);
+    define_field!(hc_erase_grp_size, u8, 224);
+    define_field!(boot_size_mult, u8, 226);
+    define_field!(sec_feature_support, u8, 231);
+
+    /// Returns the capacity of the user data area in bytes
+    pub fn card_capacity_bytes(&self) -> u64 {
+        u64::from(self.sec_count()) * 512
+    }
+
+    /// Returns the capacity of the user data area in 512-byte blocks
+    pub fn card_capacity_blocks(&self) -> u32 {
+        self.sec_count()
+    }
+}
+
+impl Default for ExtCsd {
+    fn default() -> Self {
+        ExtCsd::new()
+    }
+}
+
+impl CardStatus {
+    /// Create a new, empty, card status
+    pub fn new() -> CardStatus {
+        CardStatus::default()
+    }
+
+    define_field!(in_idle_state, bool, 0, 0);
+    define_field!(erase_reset, bool, 0, 1);
+    define_field!(illegal_command, bool, 0, 2);
+    define_field!(com_crc_error, bool, 0, 3);
+    define_field!(erase_sequence_error, bool, 0, 4);
+    define_field!(address_error, bool, 0, 5);
+    define_field!(parameter_error, bool, 0, 6);
+    define_field!(card_is_locked, bool, 1, 0);
+    define_field!(lock_unlock_failed, bool, 1, 1);
+    define_field!(error, bool, 1, 2);
+    define_field!(cc_error, bool, 1, 3);
+    define_field!(card_ecc_failed, bool, 1, 4);
+    define_field!(wp_violation, bool, 1, 5);
+    define_field!(erase_param, bool, 1, 6);
+    define_field!(out_of_range, bool, 1, 7);
+}
+
+/// Interpret some bytes from a register as an ASCII string.
+fn ascii_str(data: &[u8]) -> &str {
+    if data.is_ascii() {
+        core::str::from_utf8(data).unwrap_or("")
+    } else {
+        ""
+    }
+}
+
 /// Perform the 7-bit CRC used on the SD card
 pub fn crc7(data: &[u8]) -> u8 {
     let mut crc = 0u8;
@@ -714,6 +1312,148 @@ mod test {
         assert_eq!(EXAMPLE.card_capacity_bytes(), 7_861_174_272);
         assert_eq!(EXAMPLE.card_capacity_blocks(), 15_353_856);
     }
+
+    #[test]
+    fn test_csd_decode() {
+        let csd = Csd::from_data(hex!("00 26 00 32 5F 59 83 C8 AD DB CF FF D2 40 40 A5"));
+        assert!(matches!(csd, Csd::V1(_)));
+        assert!(csd.crc_is_valid());
+        // 1.5 x 1 ms
+        assert_eq!(csd.read_access_time_ns(), Some(1_500_000));
+        assert_eq!(csd.read_access_clocks(), 0);
+        // 2.5 x 10 Mbit/s
+        assert_eq!(csd.max_transfer_rate_kbps(), Some(25_000));
+        assert_eq!(csd.read_block_length_bytes(), 512);
+        assert_eq!(csd.write_block_length_bytes(), 512);
+        assert_eq!(csd.write_speed_multiplier(), 16);
+        assert_eq!(csd.erase_sector_blocks(), 32);
+        assert_eq!(csd.write_protect_group_sectors(), 128);
+        assert_eq!(csd.file_format_type(), FileFormat::PartitionTable);
+        assert!(!csd.is_write_protected());
+        if let Csd::V1(ref contents) = csd {
+            assert_eq!(contents.max_read_current_vdd_min_ua(), 35_000);
+            assert_eq!(contents.max_read_current_vdd_max_ua(), 45_000);
+            assert_eq!(contents.max_write_current_vdd_min_ua(), 60_000);
+            assert_eq!(contents.max_write_current_vdd_max_ua(), 80_000);
+        }
+        assert_eq!(
+            format!("{}", csd),
+            "CSD v1.0: 1015808000 bytes, TAAC 1500000 ns, NSAC 0 clocks, TRAN_SPEED 25000 kbit/s, CCC 0x5f5, READ_BL_LEN 512 bytes, WRITE_BL_LEN 512 bytes, R2W x16, erase sector 32 blocks, single block erase true, WP temporary false permanent false, file format PartitionTable, CRC ok"
+        );
+
+        let mut data = hex!("40 0E 00 32 5B 59 00 00 1D 69 7F 80 0A 40 00 8B");
+        let csd = Csd::from_data(data);
+        assert!(matches!(csd, Csd::V2(_)));
+        assert!(csd.crc_is_valid());
+        // 1.0 x 1 ms
+        assert_eq!(csd.read_access_time_ns(), Some(1_000_000));
+        assert_eq!(csd.max_transfer_rate_kbps(), Some(25_000));
+        assert_eq!(csd.card_capacity_bytes(), 3_947_888_640);
+
+        // Temporarily write protected, and no longer matching the CRC
+        data[14] |= 0x10;
+        let csd = Csd::from_data(data);
+        assert!(csd.is_write_protected());
+        assert!(!csd.crc_is_valid());
+    }
+
+    #[test]
+    fn test_sd_status_erase_timeout() {
+        let mut status = SdStatus::new();
+        assert_eq!(status.erase_timeout_ms(512), None);
+
+        // 4 MiB AUs, and 4 seconds to erase 8 of them, plus 1 second
+        status.data[10] = 0x90;
+        status.data[11..13].copy_from_slice(&[0x00, 0x08]);
+        status.data[13] = (4 << 2) | 1;
+        assert_eq!(status.au_size_bytes(), Some(4 * 1024 * 1024));
+        assert_eq!(status.erase_size(), 8);
+        assert_eq!(status.erase_timeout(), 4);
+        assert_eq!(status.erase_offset(), 1);
+        assert_eq!(status.erase_timeout_ms(512), Some(1500));
+        assert_eq!(status.erase_timeout_ms(32 * 1024 * 1024), Some(5000));
+        assert_eq!(status.erase_timeout_ms(32 * 1024 * 1024 + 1), Some(5500));
+    }
+
+    #[test]
+    fn test_sd_status() {
+        let mut status = SdStatus::new();
+        // 4-bit bus, Class 10, U1 with 4 MiB UHS AUs, V10, A1
+        status.data[0] = 0x80;
+        status.data[8] = 0x04;
+        status.data[14] = 0x19;
+        status.data[15] = 0x0A;
+        status.data[21] = 0x01;
+        assert_eq!(status.dat_bus_width(), 2);
+        assert!(!status.secured_mode());
+        assert_eq!(status.speed_class(), 4);
+        assert_eq!(status.speed_class_number(), Some(10));
+        assert_eq!(status.uhs_speed_grade(), 1);
+        assert_eq!(status.uhs_au_size(), 9);
+        assert_eq!(status.video_speed_class(), 10);
+        assert_eq!(status.app_perf_class(), 1);
+    }
+
+    #[test]
+    fn test_cid() {
+        const EXAMPLE: Cid = Cid {
+            data: hex!("03 53 44 53 55 30 38 47 80 12 34 56 78 01 3A EB"),
+        };
+        assert_eq!(EXAMPLE.manufacturer_id(), 0x03);
+        assert_eq!(EXAMPLE.oem_id(), 0x5344);
+        assert_eq!(EXAMPLE.oem_id_str(), "SD");
+        assert_eq!(EXAMPLE.product_name(), "SU08G");
+        assert_eq!(EXAMPLE.product_revision_major(), 8);
+        assert_eq!(EXAMPLE.product_revision_minor(), 0);
+        assert_eq!(EXAMPLE.serial_number(), 0x1234_5678);
+        assert_eq!(EXAMPLE.manufacturing_year(), 2019);
+        assert_eq!(EXAMPLE.manufacturing_month(), 10);
+        assert_eq!(EXAMPLE.crc(), crc7(&EXAMPLE.data[0..15]) >> 1);
+    }
+
+    #[test]
+    fn test_scr() {
+        const EXAMPLE: Scr = Scr {
+            data: hex!("02 35 80 03 00 00 00 00"),
+        };
+        assert_eq!(EXAMPLE.scr_structure(), 0);
+        assert_eq!(EXAMPLE.spec_version(), Some((3, 0)));
+        assert_eq!(EXAMPLE.sd_security(), 3);
+        assert!(EXAMPLE.supports_1bit_bus());
+        assert!(EXAMPLE.supports_4bit_bus());
+        assert_eq!(EXAMPLE.cmd_support(), 3);
+
+        let mut scr = Scr::new();
+        // SD_SPEC 2, SD_SPEC3, SD_SPECX 2
+        scr.data[0] = 0x02;
+        scr.data[2] = 0x80;
+        scr.data[3] = 0x80;
+        assert_eq!(scr.spec_version(), Some((6, 0)));
+    }
+
+    #[test]
+    fn test_ext_csd() {
+        let mut ext_csd = ExtCsd::new();
+        // A 3.6 GiB eMMC, version 4.41
+        ext_csd.data[192] = 5;
+        ext_csd.data[212..216].copy_from_slice(&[0x00, 0x00, 0x73, 0x00]);
+        assert_eq!(ext_csd.ext_csd_rev(), 5);
+        assert_eq!(ext_csd.sec_count(), 0x0073_0000);
+        assert_eq!(ext_csd.card_capacity_blocks(), 7_536_640);
+        assert_eq!(ext_csd.card_capacity_bytes(), 3_858_759_680);
+    }
+
+    #[test]
+    fn test_ocr() {
+        const EXAMPLE: Ocr = Ocr {
+            data: hex!("C0 FF 80 00"),
+        };
+        assert!(EXAMPLE.power_up_complete());
+        assert!(EXAMPLE.card_capacity_status());
+        assert!(!EXAMPLE.uhs2_card_status());
+        assert!(!EXAMPLE.switching_to_1v8_accepted());
+        assert_eq!(EXAMPLE.voltage_window(), 0x1FF);
+    }
 }
 
 // ****************************************************************************
@@ -0,0 +1,945 @@
+//! The SD Protocol on a native SD bus
+//!
+//! Implements the SD protocol on a host controller which drives the SD bus
+//! itself - the command line and one or four data lines - such as the SDMMC
+//! or SDIO peripheral on many microcontrollers. Unlike SPI mode, this can
+//! move four bits per clock, and at up to 50 MHz in high speed mode.
+//!
+//! The host controller only has to send commands and move blocks of data,
+//! through the [`SdHost`] trait. [`SdMmcSdio`] does the rest: it identifies
+//! the card, gives it a relative address, selects it and switches it to the
+//! widest bus and fastest clock both sides support.
+
+use super::sdmmc::{clock_hz, AcquireOpts, CardType, Error, State, Timeout};
+use super::sdmmc_proto::*;
+use super::{Block, BlockCount, BlockDevice, BlockIdx, BlockNumber};
+use core::cell::RefCell;
+use embedded_hal::delay::DelayNs;
+
+#[cfg(feature = "log")]
+use log::{debug, trace};
+
+#[cfg(feature = "defmt-log")]
+use defmt::{debug, trace};
+
+/// The clock speed a card must be identified at, in Hz.
+const IDENTIFICATION_CLOCK_HZ: u32 = 400_000;
+
+/// The fastest clock speed in the default speed mode, in Hz.
+const DEFAULT_SPEED_CLOCK_HZ: u32 = 25_000_000;
+
+/// The fastest clock speed in high speed mode, in Hz.
+const HIGH_SPEED_CLOCK_HZ: u32 = 50_000_000;
+
+/// The voltages we offer a card in ACMD41: 2.7 V to 3.6 V.
+const VOLTAGE_WINDOW: u32 = 0x00FF_8000;
+
+/// Tell the card in ACMD41 that we support high capacity cards.
+const HOST_CAPACITY_SUPPORT: u32 = 0x4000_0000;
+
+/// The bits of the card status which report an error.
+const STATUS_ERRORS: u32 = 0xFDF8_0000;
+
+/// The bit of the card status which says the card can take more data.
+const STATUS_READY_FOR_DATA: u32 = 1 << 8;
+
+/// The CURRENT_STATE of a card which is selected and not busy.
+const STATE_TRAN: u32 = 4;
+
+/// The kind of response a command expects.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResponseType {
+    /// No response at all (CMD0).
+    None,
+    /// A 48-bit response with a CRC7 (R1, R6 and R7).
+    Short,
+    /// A 48-bit response, after which the card holds DAT0 low while it is
+    /// busy (R1b). The host should wait for DAT0 to go high again.
+    ShortBusy,
+    /// A 48-bit response without a valid CRC7 (R3, which holds the OCR).
+    ShortNoCrc,
+    /// A 136-bit response, which holds the CID or CSD (R2).
+    Long,
+}
+
+/// A response from the card.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// The command has no response.
+    None,
+    /// The 32 bits between the command index and the CRC7 of a 48-bit
+    /// response.
+    Short(u32),
+    /// The 128 bits of a 136-bit response, most significant byte first, as
+    /// the card's register holds them. The last byte holds the CRC7, which
+    /// hosts that check and strip it can leave as zero.
+    Long([u8; 16]),
+}
+
+/// How many data lines are used.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BusWidth {
+    /// Only DAT0 is used. Every card starts like this.
+    One,
+    /// DAT0 to DAT3 are used.
+    Four,
+}
+
+/// A host controller for a native SD bus.
+///
+/// Implement this for your microcontroller's SDMMC or SDIO peripheral. The
+/// host sends commands and moves data, checking CRCs and timing out if the
+/// card doesn't answer; it doesn't need to know anything about what the
+/// commands mean.
+pub trait SdHost {
+    /// The errors the host controller can report, such as a command timing
+    /// out or a CRC mismatch.
+    type Error: core::fmt::Debug;
+
+    /// Set the bus clock speed, in Hz. Use the fastest speed the host can
+    /// do which isn't faster than `hz`.
+    fn set_clock_hz(&mut self, hz: u32) -> Result<(), Self::Error>;
+
+    /// Set how many data lines are used.
+    fn set_bus_width(&mut self, width: BusWidth) -> Result<(), Self::Error>;
+
+    /// The widest bus wired up between the host and the card. Defaults to
+    /// four data lines.
+    fn max_bus_width(&self) -> BusWidth {
+        BusWidth::Four
+    }
+
+    /// Send a command, and wait for a response of the given type.
+    fn command(
+        &mut self,
+        command: u8,
+        arg: u32,
+        response: ResponseType,
+    ) -> Result<Response, Self::Error>;
+
+    /// Send a command which has an R1 response and makes the card send one
+    /// block of `data.len()` bytes (such as the 8 byte SCR), and read that
+    /// block. Returns the card status from the response.
+    fn read_data(&mut self, command: u8, arg: u32, data: &mut [u8]) -> Result<u32, Self::Error>;
+
+    /// Send a command which has an R1 response and makes the card send 512
+    /// byte blocks, and read `blocks.len()` of them. Returns the card
+    /// status from the response. We stop multiple block reads with
+    /// STOP_TRANSMISSION afterwards.
+    fn read_blocks(
+        &mut self,
+        command: u8,
+        arg: u32,
+        blocks: &mut [Block],
+    ) -> Result<u32, Self::Error>;
+
+    /// Send a command which has an R1 response and makes the card accept
+    /// 512 byte blocks, and write `blocks`, waiting while the card holds
+    /// DAT0 low after each one. Returns the card status from the response.
+    /// We stop multiple block writes with STOP_TRANSMISSION afterwards.
+    fn write_blocks(&mut self, command: u8, arg: u32, blocks: &[Block])
+        -> Result<u32, Self::Error>;
+}
+
+/// Represents an inactive SD Card interface on a native SD bus.
+/// Built from an [`SdHost`] and a delay.
+pub struct SdMmcSdio<HOST, DELAY>
+where
+    HOST: SdHost,
+    DELAY: DelayNs,
+{
+    host: RefCell<HOST>,
+    delay: RefCell<DELAY>,
+    card_type: CardType,
+    state: State,
+    options: AcquireOpts,
+    rca: u16,
+    cid: Cid,
+    csd: Csd,
+    bus_width: BusWidth,
+    clock_hz: u32,
+}
+
+/// An initialized block device used to access the SD card.
+/// Uses a native SD bus.
+pub struct BlockSdio<'a, HOST, DELAY>(&'a mut SdMmcSdio<HOST, DELAY>)
+where
+    HOST: SdHost,
+    DELAY: DelayNs;
+
+impl<HOST, DELAY> SdMmcSdio<HOST, DELAY>
+where
+    HOST: SdHost,
+    DELAY: DelayNs,
+{
+    /// Create a new SD/MMC interface using a native SD bus host controller.
+    pub fn new(host: HOST, delay: DELAY) -> SdMmcSdio<HOST, DELAY> {
+        SdMmcSdio {
+            host: RefCell::new(host),
+            delay: RefCell::new(delay),
+            card_type: CardType::SD1,
+            state: State::NoInit,
+            options: AcquireOpts::default(),
+            rca: 0,
+            cid: Cid::new(),
+            csd: Csd::from_data([0; 16]),
+            bus_width: BusWidth::One,
+            clock_hz: IDENTIFICATION_CLOCK_HZ,
+        }
+    }
+
+    /// Initializes the card into a known state
+    pub fn acquire(&mut self) -> Result<BlockSdio<'_, HOST, DELAY>, Error> {
+        self.acquire_with_opts(Default::default())
+    }
+
+    /// Initializes the card into a known state.
+    ///
+    /// The card is identified at 400 kHz on one data line, and then
+    /// switched to four data lines if the host has them, and to the
+    /// fastest clock the card supports. That is 25 MHz, or 50 MHz in high
+    /// speed mode - which is only tried if `options.max_clock_hz` is more
+    /// than 25 MHz, as the board must be able to carry it. `require_crc`
+    /// isn't used, as the host always checks CRCs on an SD bus.
+    pub fn acquire_with_opts(
+        &mut self,
+        options: AcquireOpts,
+    ) -> Result<BlockSdio<'_, HOST, DELAY>, Error> {
+        debug!("acquiring card with opts: {:?}", options);
+        self.options = options;
+        // Assume it hasn't worked
+        self.state = State::Error;
+        self.init()?;
+        self.state = State::Idle;
+        Ok(BlockSdio(self))
+    }
+
+    /// Take the card from power up to the transfer state, on the widest
+    /// bus and fastest clock we can use.
+    fn init(&mut self) -> Result<(), Error> {
+        self.set_bus_width(BusWidth::One)?;
+        self.set_clock_hz(IDENTIFICATION_CLOCK_HZ)?;
+
+        trace!("Reset card..");
+        self.command(CMD0, 0, ResponseType::None)?;
+
+        // Check card version. Version 1 cards don't answer CMD8.
+        self.card_type = match self.command(CMD8, 0x1AA, ResponseType::Short) {
+            Ok(Response::Short(r)) if (r & 0xFFF) == 0x1AA => CardType::SD2,
+            Ok(_) => return Err(Error::CardNotFound),
+            Err(_) => CardType::SD1,
+        };
+        debug!("Card version: {:?}", self.card_type);
+
+        let arg = match self.card_type {
+            CardType::SD2 => VOLTAGE_WINDOW | HOST_CAPACITY_SUPPORT,
+            _ => VOLTAGE_WINDOW,
+        };
+        let mut timeout = Timeout::new(self.options.init_timeout_ms);
+        let ocr = loop {
+            check_status(self.card_command(CMD55, 0)?)?;
+            let mut ocr = Ocr::new();
+            ocr.data = short(self.command(ACMD41, arg, ResponseType::ShortNoCrc)?)?.to_be_bytes();
+            if ocr.power_up_complete() {
+                break ocr;
+            }
+            self.wait(&mut timeout, Error::TimeoutACommand(ACMD41))?;
+        };
+        if self.card_type == CardType::SD2 && ocr.card_capacity_status() {
+            self.card_type = CardType::SDHC;
+        }
+
+        // Move the card from the ready state, through the identification
+        // state, to the stand-by state where it has an address
+        self.cid.data = long(self.command(CMD2, 0, ResponseType::Long)?)?;
+        let r6 = short(self.command(CMD3, 0, ResponseType::Short)?)?;
+        check_status(r6_card_status(r6))?;
+        self.rca = (r6 >> 16) as u16;
+        debug!("Card has relative address {:x}", self.rca);
+        // The CSD can only be read in the stand-by state
+        self.csd = Csd::from_data(long(self.command(
+            CMD9,
+            self.rca_arg(),
+            ResponseType::Long,
+        )?)?);
+
+        // Select the card, which moves it to the transfer state
+        check_status(short(self.command(
+            CMD7,
+            self.rca_arg(),
+            ResponseType::ShortBusy,
+        )?)?)?;
+        // Standard capacity cards might not use 512 byte blocks by default
+        if self.card_type != CardType::SDHC {
+            check_status(self.card_command(CMD16, Block::LEN_U32)?)?;
+        }
+
+        let scr = self.read_scr()?;
+        if self.host.borrow().max_bus_width() == BusWidth::Four && scr.supports_4bit_bus() {
+            debug!("Switching to a 4-bit bus");
+            check_status(self.card_acmd(ACMD6, 2)?)?;
+            self.set_bus_width(BusWidth::Four)?;
+        }
+
+        // SWITCH_FUNC arrived in version 1.10 of the specification
+        let mut hz = clock_hz(&self.csd, &self.options);
+        if self.options.max_clock_hz > DEFAULT_SPEED_CLOCK_HZ
+            && scr.sd_spec() >= 1
+            && self.switch_high_speed()?
+        {
+            hz = HIGH_SPEED_CLOCK_HZ.min(self.options.max_clock_hz);
+        }
+        debug!("Switching clock to {} Hz", hz);
+        self.set_clock_hz(hz)
+    }
+
+    /// Switch the card to high speed mode, if it supports it. Returns
+    /// whether it did.
+    fn switch_high_speed(&self) -> Result<bool, Error> {
+        let mut status = [0u8; 64];
+        // Check whether group 1 (access mode) supports function 1 (high
+        // speed), leaving the other groups alone
+        check_status(self.read_data(CMD6, 0x00FF_FFF1, &mut status)?)?;
+        if (status[13] & 0x02) == 0 {
+            debug!("Card doesn't support high speed mode");
+            return Ok(false);
+        }
+        check_status(self.read_data(CMD6, 0x80FF_FFF1, &mut status)?)?;
+        Ok((status[16] & 0x0F) == 1)
+    }
+
+    /// Read the 'SD configuration register'.
+    fn read_scr(&self) -> Result<Scr, Error> {
+        let mut scr = Scr::new();
+        if self.card_command(CMD55, self.rca_arg())? & STATUS_ERRORS != 0
+            || self.read_data(ACMD51, 0, &mut scr.data)? & STATUS_ERRORS != 0
+        {
+            return Err(Error::RegisterReadError);
+        }
+        Ok(scr)
+    }
+
+    /// The argument for commands which are addressed to our card.
+    fn rca_arg(&self) -> u32 {
+        u32::from(self.rca) << 16
+    }
+
+    /// Perform an application-specific command with an R1 response.
+    fn card_acmd(&self, command: u8, arg: u32) -> Result<u32, Error> {
+        check_status(self.card_command(CMD55, self.rca_arg())?)?;
+        self.card_command(command, arg)
+    }
+
+    /// Perform a command with an R1 response, returning the card status.
+    fn card_command(&self, command: u8, arg: u32) -> Result<u32, Error> {
+        short(self.command(command, arg, ResponseType::Short)?)
+    }
+
+    /// Perform a command.
+    fn command(&self, command: u8, arg: u32, response: ResponseType) -> Result<Response, Error> {
+        self.host
+            .borrow_mut()
+            .command(command, arg, response)
+            .map_err(|_e| Error::Transport)
+    }
+
+    /// Perform a command which reads a short block of data.
+    fn read_data(&self, command: u8, arg: u32, data: &mut [u8]) -> Result<u32, Error> {
+        self.host
+            .borrow_mut()
+            .read_data(command, arg, data)
+            .map_err(|_e| Error::Transport)
+    }
+
+    /// Wait until the card is back in the transfer state and ready for
+    /// data, or we have waited too long and timeout.
+    fn wait_ready(&self) -> Result<u32, Error> {
+        let mut timeout = Timeout::new(self.options.busy_timeout_ms);
+        loop {
+            let status = self.card_command(CMD13, self.rca_arg())?;
+            if (status & STATUS_READY_FOR_DATA) != 0 && ((status >> 9) & 0xF) == STATE_TRAN {
+                return Ok(status);
+            }
+            self.wait(&mut timeout, Error::TimeoutWaitNotBusy)?;
+        }
+    }
+
+    /// Sleep before polling the card again, unless we have run out of time.
+    fn wait(&self, timeout: &mut Timeout, err: Error) -> Result<(), Error> {
+        let interval_us = timeout.poll(err)?;
+        self.delay.borrow_mut().delay_us(interval_us);
+        Ok(())
+    }
+
+    /// Set the bus width on the host.
+    fn set_bus_width(&mut self, width: BusWidth) -> Result<(), Error> {
+        self.host
+            .borrow_mut()
+            .set_bus_width(width)
+            .map_err(|_e| Error::Transport)?;
+        self.bus_width = width;
+        Ok(())
+    }
+
+    /// Set the clock speed on the host.
+    fn set_clock_hz(&mut self, hz: u32) -> Result<(), Error> {
+        self.host
+            .borrow_mut()
+            .set_clock_hz(hz)
+            .map_err(|_e| Error::Transport)?;
+        self.clock_hz = hz;
+        Ok(())
+    }
+
+    /// Get a temporary borrow on the underlying host controller.
+    pub fn host(&mut self) -> core::cell::RefMut<'_, HOST> {
+        self.host.borrow_mut()
+    }
+}
+
+/// Get the argument of a 48-bit response.
+fn short(response: Response) -> Result<u32, Error> {
+    match response {
+        Response::Short(r) => Ok(r),
+        _ => Err(Error::Transport),
+    }
+}
+
+/// Get the register contents of a 136-bit response.
+fn long(response: Response) -> Result<[u8; 16], Error> {
+    match response {
+        Response::Long(data) => Ok(data),
+        _ => Err(Error::RegisterReadError),
+    }
+}
+
+/// Fail if a card status reports an error.
+fn check_status(status: u32) -> Result<u32, Error> {
+    if status & STATUS_ERRORS != 0 {
+        return Err(Error::CardStatusError(status));
+    }
+    Ok(status)
+}
+
+/// Turn the 16 status bits of an R6 response back into a card status. They
+/// are bits 23, 22, 19 and 12 to 0 of it.
+fn r6_card_status(r6: u32) -> u32 {
+    ((r6 & 0xC000) << 8) | ((r6 & 0x2000) << 6) | (r6 & 0x1FFF)
+}
+
+impl<HOST, DELAY> BlockSdio<'_, HOST, DELAY>
+where
+    HOST: SdHost,
+    DELAY: DelayNs,
+{
+    /// Get a temporary borrow on the underlying host controller.
+    pub fn host(&mut self) -> core::cell::RefMut<'_, HOST> {
+        self.0.host.borrow_mut()
+    }
+
+    /// Mark the card as unused.
+    /// This should be kept infallible, because Drop is unable to fail.
+    fn deinit(&mut self) {
+        self.0.state = State::NoInit;
+    }
+
+    /// The relative card address the card published during initialisation.
+    pub fn rca(&self) -> u16 {
+        self.0.rca
+    }
+
+    /// How many data lines are being used.
+    pub fn bus_width(&self) -> BusWidth {
+        self.0.bus_width
+    }
+
+    /// The bus clock speed that was asked for, in Hz.
+    pub fn clock_hz(&self) -> u32 {
+        self.0.clock_hz
+    }
+
+    /// Return the usable size of this SD card in bytes.
+    pub fn card_size_bytes(&self) -> Result<u64, Error> {
+        Ok(self.0.csd.card_capacity_bytes())
+    }
+
+    /// Read the 'card specific data' register. This is the copy read while
+    /// the card was initialised, as it can't be read once it is selected.
+    pub fn read_csd(&self) -> Result<Csd, Error> {
+        Ok(self.0.csd.clone())
+    }
+
+    /// Read the 'card identification' register. This is the copy read while
+    /// the card was initialised, as it can't be read once it is selected.
+    pub fn read_cid(&self) -> Result<Cid, Error> {
+        Ok(self.0.cid.clone())
+    }
+
+    /// Read the 'SD configuration register', which says which version of
+    /// the SD specification the card supports.
+    pub fn read_scr(&self) -> Result<Scr, Error> {
+        self.0.read_scr()
+    }
+
+    /// Read the 'SD Status' register, which gives the card's speed class
+    /// and Allocation Unit size.
+    pub fn read_sd_status(&self) -> Result<SdStatus, Error> {
+        let mut status = SdStatus::new();
+        if self.0.card_command(CMD55, self.0.rca_arg())? & STATUS_ERRORS != 0
+            || self.0.read_data(ACMD13, 0, &mut status.data)? & STATUS_ERRORS != 0
+        {
+            return Err(Error::RegisterReadError);
+        }
+        Ok(status)
+    }
+
+    /// Read the card status, as the 32 bits of an R1 response.
+    pub fn card_status(&self) -> Result<u32, Error> {
+        self.0.card_command(CMD13, self.0.rca_arg())
+    }
+}
+
+impl<HOST, DELAY> BlockDevice for BlockSdio<'_, HOST, DELAY>
+where
+    HOST: SdHost,
+    DELAY: DelayNs,
+{
+    type Error = Error;
+
+    /// Read one or more blocks, starting at the given block index.
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let start_idx = self.0.card_type.card_address(start_block_idx)?;
+        let command = if blocks.len() == 1 { CMD17 } else { CMD18 };
+        let result = match self
+            .0
+            .host
+            .borrow_mut()
+            .read_blocks(command, start_idx, blocks)
+        {
+            Ok(status) if status & STATUS_ERRORS == 0 => Ok(()),
+            _ => Err(Error::ReadError),
+        };
+        if command == CMD18 {
+            // Stop the read, even if it failed part way through, or the
+            // card won't take any other command
+            let stopped = self.0.command(CMD12, 0, ResponseType::ShortBusy);
+            result?;
+            stopped?;
+        }
+        result
+    }
+
+    /// Write one or more blocks, starting at the given block index.
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        let start_idx = self.0.card_type.card_address(start_block_idx)?;
+        let command = if blocks.len() == 1 { CMD24 } else { CMD25 };
+        let result = match self
+            .0
+            .host
+            .borrow_mut()
+            .write_blocks(command, start_idx, blocks)
+        {
+            Ok(status) if status & STATUS_ERRORS == 0 => Ok(()),
+            _ => Err(Error::WriteError),
+        };
+        if command == CMD25 {
+            // Stop the write, even if it failed part way through
+            let stopped = self.0.command(CMD12, 0, ResponseType::ShortBusy);
+            result?;
+            stopped?;
+        }
+        result?;
+        // The card reports programming errors once it has finished
+        if self.0.wait_ready()? & STATUS_ERRORS != 0 {
+            return Err(Error::WriteError);
+        }
+        Ok(())
+    }
+
+    /// Determine how many blocks this device can hold.
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        let num_bytes = self.card_size_bytes()?;
+        let num_blocks = (num_bytes / 512) as BlockNumber;
+        Ok(BlockCount(num_blocks))
+    }
+}
+
+impl<HOST, DELAY> Drop for BlockSdio<'_, HOST, DELAY>
+where
+    HOST: SdHost,
+    DELAY: DelayNs,
+{
+    fn drop(&mut self) {
+        self.deinit()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::cell::Cell;
+    use hex_literal::hex;
+    use std::rc::Rc;
+
+    /// A host controller with a simulated SDHC card on the end.
+    struct SimHost {
+        state: u32,
+        app_cmd: bool,
+        bus_width: BusWidth,
+        clock_hz: u32,
+        max_bus_width: BusWidth,
+        high_speed: bool,
+        blocks: Vec<Block>,
+        /// Fail a transfer after this many blocks.
+        fail_after: Option<usize>,
+        /// How many times a multi-block transfer was stopped.
+        stops: u32,
+        /// Never finish powering up.
+        stuck: bool,
+        /// Report a general error in the card status when we get this
+        /// command. Application specific commands have bit 7 set.
+        error_on: Option<u8>,
+        /// How much time has passed on the bus, in nanoseconds.
+        elapsed_ns: Rc<Cell<u64>>,
+    }
+
+    impl SimHost {
+        fn new(max_bus_width: BusWidth) -> SimHost {
+            SimHost {
+                state: 0,
+                app_cmd: false,
+                bus_width: BusWidth::One,
+                clock_hz: 0,
+                max_bus_width,
+                high_speed: false,
+                blocks: vec![Block::new(); 16],
+                fail_after: None,
+                stops: 0,
+                stuck: false,
+                error_on: None,
+                elapsed_ns: Rc::new(Cell::new(0)),
+            }
+        }
+
+        /// A delay which lets time pass for the card.
+        fn delay(&self) -> SimDelay {
+            SimDelay(self.elapsed_ns.clone())
+        }
+
+        /// A card status with the given current state, ready for data.
+        fn status(&self) -> u32 {
+            (self.state << 9) | STATUS_READY_FOR_DATA | if self.app_cmd { 1 << 5 } else { 0 }
+        }
+
+        /// The card status for a command, with the ERROR bit set if it
+        /// should report an error.
+        fn status_for(&self, command: u8) -> u32 {
+            if self.error_on == Some(command) {
+                self.status() | (1 << 19)
+            } else {
+                self.status()
+            }
+        }
+    }
+
+    impl SdHost for SimHost {
+        type Error = ();
+
+        fn set_clock_hz(&mut self, hz: u32) -> Result<(), ()> {
+            self.clock_hz = hz;
+            Ok(())
+        }
+
+        fn set_bus_width(&mut self, width: BusWidth) -> Result<(), ()> {
+            self.bus_width = width;
+            Ok(())
+        }
+
+        fn max_bus_width(&self) -> BusWidth {
+            self.max_bus_width
+        }
+
+        fn command(
+            &mut self,
+            command: u8,
+            arg: u32,
+            response: ResponseType,
+        ) -> Result<Response, ()> {
+            let app_cmd = core::mem::replace(&mut self.app_cmd, false);
+            // A command and its response are 48 bits each
+            let ns = 96_000_000_000 / u64::from(self.clock_hz);
+            self.elapsed_ns.set(self.elapsed_ns.get() + ns);
+            // Identification runs at no more than 400 kHz
+            if self.state < 3 && self.clock_hz > IDENTIFICATION_CLOCK_HZ {
+                return Err(());
+            }
+            match (app_cmd, command) {
+                (false, CMD0) => {
+                    self.state = 0;
+                    Ok(Response::None)
+                }
+                (false, CMD8) => Ok(Response::Short(arg & 0xFFF)),
+                (false, CMD55) => {
+                    self.app_cmd = true;
+                    Ok(Response::Short(self.status()))
+                }
+                (true, ACMD41) if self.stuck => Ok(Response::Short(0x40FF_8000)),
+                (true, ACMD41) => {
+                    assert_eq!(response, ResponseType::ShortNoCrc);
+                    assert_ne!(arg & HOST_CAPACITY_SUPPORT, 0);
+                    self.state = 1;
+                    Ok(Response::Short(0xC0FF_8000))
+                }
+                (false, CMD2) if self.state == 1 => {
+                    self.state = 2;
+                    Ok(Response::Long([0x03; 16]))
+                }
+                (false, CMD3) if self.state == 2 => {
+                    self.state = 3;
+                    // Bit 19 of the status is bit 13 of an R6 response
+                    let error = if self.error_on == Some(CMD3) {
+                        1 << 13
+                    } else {
+                        0
+                    };
+                    Ok(Response::Short(0xAAAA_0500 | error))
+                }
+                (false, CMD9) if self.state == 3 && arg == 0xAAAA_0000 => Ok(Response::Long(hex!(
+                    "40 0E 00 32 5B 59 00 00 1D 69 7F 80 0A 40 00 8B"
+                ))),
+                (false, CMD7) if self.state == 3 && arg == 0xAAAA_0000 => {
+                    assert_eq!(response, ResponseType::ShortBusy);
+                    self.state = 4;
+                    Ok(Response::Short(self.status_for(CMD7)))
+                }
+                (true, ACMD6) if self.state == 4 => {
+                    self.bus_width = if arg == 2 {
+                        BusWidth::Four
+                    } else {
+                        BusWidth::One
+                    };
+                    Ok(Response::Short(self.status_for(0x80 | ACMD6)))
+                }
+                (false, CMD12) if self.state == 5 || self.state == 6 => {
+                    assert_eq!(response, ResponseType::ShortBusy);
+                    self.state = 4;
+                    self.stops += 1;
+                    Ok(Response::Short(self.status()))
+                }
+                (false, CMD13) if self.state == 4 => Ok(Response::Short(self.status())),
+                _ => Ok(Response::Short(1 << 22)),
+            }
+        }
+
+        fn read_data(&mut self, command: u8, arg: u32, data: &mut [u8]) -> Result<u32, ()> {
+            let app_cmd = core::mem::replace(&mut self.app_cmd, false);
+            assert_eq!(self.state, 4);
+            match (app_cmd, command) {
+                (true, ACMD51) => {
+                    // Version 2.00, 1-bit and 4-bit buses
+                    data.copy_from_slice(&hex!("02 05 00 00 00 00 00 00"));
+                }
+                (false, CMD6) => {
+                    assert_eq!(self.bus_width, BusWidth::Four);
+                    data.fill(0);
+                    data[13] = 0x03;
+                    data[16] = 0x01;
+                    self.high_speed = (arg >> 31) != 0;
+                }
+                _ => return Ok(1 << 22),
+            }
+            Ok(self.status_for(if app_cmd { 0x80 | command } else { command }))
+        }
+
+        fn read_blocks(&mut self, command: u8, arg: u32, blocks: &mut [Block]) -> Result<u32, ()> {
+            assert_eq!(self.state, 4);
+            assert_eq!(command, if blocks.len() == 1 { CMD17 } else { CMD18 });
+            if command == CMD18 {
+                // Sending data until it gets CMD12
+                self.state = 5;
+            }
+            let count = self.fail_after.unwrap_or(blocks.len());
+            for (block, stored) in blocks[..count].iter_mut().zip(&self.blocks[arg as usize..]) {
+                block.contents = stored.contents;
+            }
+            if count < blocks.len() {
+                return Err(());
+            }
+            Ok(self.status())
+        }
+
+        fn write_blocks(&mut self, command: u8, arg: u32, blocks: &[Block]) -> Result<u32, ()> {
+            assert_eq!(self.state, 4);
+            assert_eq!(command, if blocks.len() == 1 { CMD24 } else { CMD25 });
+            if command == CMD25 {
+                // Receiving data until it gets CMD12
+                self.state = 6;
+            }
+            let count = self.fail_after.unwrap_or(blocks.len());
+            for (block, stored) in blocks[..count].iter().zip(&mut self.blocks[arg as usize..]) {
+                stored.contents = block.contents;
+            }
+            if count < blocks.len() {
+                return Err(());
+            }
+            Ok(self.status())
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    /// A delay which doesn't wait, but counts the time as passed.
+    struct SimDelay(Rc<Cell<u64>>);
+
+    impl DelayNs for SimDelay {
+        fn delay_ns(&mut self, ns: u32) {
+            self.0.set(self.0.get() + u64::from(ns));
+        }
+    }
+
+    #[test]
+    fn test_acquire() {
+        let mut sdio = SdMmcSdio::new(SimHost::new(BusWidth::Four), NoDelay);
+        let block_sdio = sdio.acquire().unwrap();
+        assert_eq!(block_sdio.rca(), 0xAAAA);
+        assert_eq!(block_sdio.bus_width(), BusWidth::Four);
+        assert_eq!(block_sdio.clock_hz(), 25_000_000);
+        assert_eq!(block_sdio.num_blocks().unwrap(), BlockCount(7_710_720));
+        assert_eq!(block_sdio.read_cid().unwrap().data, [0x03; 16]);
+        drop(block_sdio);
+        let host = sdio.host();
+        assert_eq!(host.bus_width, BusWidth::Four);
+        assert!(!host.high_speed);
+    }
+
+    #[test]
+    fn test_init_timeout() {
+        // A card which never finishes powering up is given up on after
+        // about as long as we are allowed to wait, even at 400 kHz
+        let mut host = SimHost::new(BusWidth::Four);
+        host.stuck = true;
+        let delay = host.delay();
+        let elapsed_ns = host.elapsed_ns.clone();
+        let mut sdio = SdMmcSdio::new(host, delay);
+        assert!(matches!(
+            sdio.acquire(),
+            Err(Error::TimeoutACommand(ACMD41))
+        ));
+        let elapsed_ms = elapsed_ns.get() / 1_000_000;
+        let limit_ms = u64::from(AcquireOpts::default().init_timeout_ms);
+        assert!(
+            (limit_ms..limit_ms * 6 / 5).contains(&elapsed_ms),
+            "{} ms",
+            elapsed_ms
+        );
+    }
+
+    #[test]
+    fn test_status_errors() {
+        // A card which reports an error while it is being set up gives us
+        // its status, rather than a timeout
+        for command in [CMD3, CMD7, 0x80 | ACMD6, CMD6] {
+            let mut host = SimHost::new(BusWidth::Four);
+            host.error_on = Some(command);
+            let mut sdio = SdMmcSdio::new(host, NoDelay);
+            let options = AcquireOpts {
+                max_clock_hz: 48_000_000,
+                ..Default::default()
+            };
+            match sdio.acquire_with_opts(options).map(drop) {
+                Err(Error::CardStatusError(status)) => assert_ne!(status & (1 << 19), 0),
+                Err(e) => panic!("CMD{}: {:?}", command & 0x7F, e),
+                Ok(_) => panic!("CMD{} worked", command & 0x7F),
+            }
+        }
+    }
+
+    #[test]
+    fn test_acquire_high_speed() {
+        let mut sdio = SdMmcSdio::new(SimHost::new(BusWidth::Four), NoDelay);
+        let options = AcquireOpts {
+            max_clock_hz: 48_000_000,
+            ..Default::default()
+        };
+        let block_sdio = sdio.acquire_with_opts(options).unwrap();
+        assert_eq!(block_sdio.clock_hz(), 48_000_000);
+        drop(block_sdio);
+        assert!(sdio.host().high_speed);
+    }
+
+    #[test]
+    fn test_acquire_one_bit() {
+        let mut sdio = SdMmcSdio::new(SimHost::new(BusWidth::One), NoDelay);
+        let block_sdio = sdio.acquire().unwrap();
+        assert_eq!(block_sdio.bus_width(), BusWidth::One);
+        drop(block_sdio);
+        assert_eq!(sdio.host().bus_width, BusWidth::One);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mut sdio = SdMmcSdio::new(SimHost::new(BusWidth::Four), NoDelay);
+        let block_sdio = sdio.acquire().unwrap();
+        let mut blocks = [Block::new(), Block::new(), Block::new()];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            block.contents.fill(i as u8 + 1);
+        }
+        block_sdio.write(&blocks, BlockIdx(4)).unwrap();
+        block_sdio.write(&blocks[2..], BlockIdx(9)).unwrap();
+        let mut read_back = [Block::new(), Block::new()];
+        block_sdio
+            .read(&mut read_back, BlockIdx(5), "test")
+            .unwrap();
+        assert_eq!(read_back[0].contents, [2; 512]);
+        assert_eq!(read_back[1].contents, [3; 512]);
+        block_sdio
+            .read(&mut read_back[0..1], BlockIdx(9), "test")
+            .unwrap();
+        assert_eq!(read_back[0].contents, [3; 512]);
+    }
+
+    #[test]
+    fn test_stop_after_error() {
+        // A multi-block transfer which fails part way through is still
+        // stopped, so the card takes the next command
+        let mut sdio = SdMmcSdio::new(SimHost::new(BusWidth::Four), NoDelay);
+        let block_sdio = sdio.acquire().unwrap();
+        let mut blocks = [Block::new(), Block::new(), Block::new()];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            block.contents.fill(i as u8 + 1);
+        }
+        block_sdio.0.host.borrow_mut().fail_after = Some(1);
+        assert!(matches!(
+            block_sdio.write(&blocks, BlockIdx(4)),
+            Err(Error::WriteError)
+        ));
+        assert_eq!(block_sdio.0.host.borrow().stops, 1);
+        let mut read_back = [Block::new(), Block::new()];
+        assert!(matches!(
+            block_sdio.read(&mut read_back, BlockIdx(4), "test"),
+            Err(Error::ReadError)
+        ));
+        assert_eq!(block_sdio.0.host.borrow().stops, 2);
+
+        block_sdio.0.host.borrow_mut().fail_after = None;
+        block_sdio.write(&blocks, BlockIdx(4)).unwrap();
+        block_sdio
+            .read(&mut read_back, BlockIdx(5), "test")
+            .unwrap();
+        assert_eq!(read_back[0].contents, [2; 512]);
+        assert_eq!(read_back[1].contents, [3; 512]);
+        assert_eq!(block_sdio.0.host.borrow().stops, 4);
+    }
+}
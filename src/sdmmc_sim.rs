@@ -0,0 +1,690 @@
+//! A simulated SD card or MMC on an SPI bus, for the tests of the SPI
+//! drivers. It answers each byte the way a card in SPI mode would, and
+//! keeps count of what it was asked to do.
+
+use crate::sdmmc_proto::*;
+use crate::testing::YieldOnce;
+use core::convert::Infallible;
+use std::cell::{RefCell, RefMut};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+/// How many busy bytes the card sends after programming a block.
+pub(crate) const PROGRAM_BUSY: usize = 4;
+
+/// How many busy bytes the card sends after erasing before a write.
+pub(crate) const ERASE_BUSY: usize = 16;
+
+/// What sort of card to simulate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SimKind {
+    /// A high capacity SD card, which has block addresses.
+    Sdhc,
+    /// A standard capacity MMC, which has byte addresses and starts with a
+    /// block length of 1024 bytes.
+    Mmc,
+}
+
+/// How the card answers ACMD23, which says how many blocks to erase before
+/// a multi-block write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SimAcmd23 {
+    /// Erase them all at once, before the first block is programmed.
+    Supported,
+    /// Say it is an illegal command, and erase each block as it comes.
+    Illegal,
+    /// Don't answer, and erase each block as it comes.
+    NoAnswer,
+}
+
+/// What the card is doing between commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Idle,
+    /// Sending blocks, starting with this one, until CMD12.
+    MultiRead(u32),
+    /// Waiting for the token which starts a block to write here.
+    WaitToken {
+        multi: bool,
+        block: u32,
+    },
+    /// Receiving a block to write here.
+    Receiving {
+        multi: bool,
+        block: u32,
+    },
+}
+
+/// What the card was asked to do.
+#[derive(Debug, Default)]
+pub(crate) struct SimStats {
+    /// Each command the card answered, and its argument. Application
+    /// specific commands have bit 7 set.
+    pub(crate) commands: Vec<(u8, u32)>,
+    /// How many times Chip Select went high in the middle of a command, its
+    /// response or a block of data, which the card gave up on. A
+    /// multi-block read counts as one exchange, until CMD12.
+    pub(crate) split_exchanges: usize,
+    /// How many erases the card did for writes.
+    pub(crate) erases: usize,
+    /// How many blocks the card programmed.
+    pub(crate) programs: usize,
+    /// How many busy bytes the card sent.
+    pub(crate) busy_bytes: usize,
+}
+
+impl SimStats {
+    /// How many times the card was sent this command.
+    pub(crate) fn count(&self, command: u8) -> usize {
+        self.commands.iter().filter(|(c, _)| *c == command).count()
+    }
+}
+
+/// The card.
+pub(crate) struct SimCard {
+    pub(crate) kind: SimKind,
+    /// The blocks which have been written. The rest are all zeros.
+    pub(crate) blocks: HashMap<u32, Vec<u8>>,
+    /// Is the card in the socket?
+    pub(crate) present: bool,
+    /// The serial number in the CID.
+    pub(crate) serial: u32,
+    /// How many 0xFF bytes the card sends before a response.
+    pub(crate) response_delay: usize,
+    /// How many 0xFF bytes the card sends before a block of data.
+    pub(crate) read_delay: usize,
+    /// How many times ACMD41 or CMD1 says the card is still initialising.
+    pub(crate) init_polls: u32,
+    /// How many commands the card misses, and doesn't answer.
+    pub(crate) ignore_commands: usize,
+    /// How the card answers ACMD23.
+    pub(crate) acmd23: SimAcmd23,
+    /// Blocks which are sent with a bad CRC the next time they are read.
+    pub(crate) corrupt_reads: Vec<u32>,
+    /// Blocks which are rejected the next time they are written.
+    pub(crate) reject_writes: Vec<u32>,
+    /// How fast the SPI clock runs, in Hz.
+    pub(crate) clock_hz: u32,
+    /// How much time has passed on the bus, in nanoseconds.
+    pub(crate) elapsed_ns: u64,
+    pub(crate) stats: SimStats,
+    selected: bool,
+    /// Bytes clocked with Chip Select high since power up.
+    cs_high_bytes: usize,
+    spi_mode: bool,
+    idle: bool,
+    polls: u32,
+    app: bool,
+    block_len: u32,
+    frame: Vec<u8>,
+    out: VecDeque<u8>,
+    busy: usize,
+    mode: Mode,
+    received: Vec<u8>,
+    erase_ahead: u32,
+    erase_pending: bool,
+    erase_start: u32,
+    erase_end: u32,
+}
+
+impl SimCard {
+    /// A card in its socket, which has just been powered up.
+    pub(crate) fn new(kind: SimKind) -> SimCard {
+        let mut card = SimCard {
+            kind,
+            blocks: HashMap::new(),
+            present: true,
+            serial: 0x1234_5678,
+            response_delay: 1,
+            read_delay: 1,
+            init_polls: 2,
+            ignore_commands: 0,
+            acmd23: SimAcmd23::Supported,
+            corrupt_reads: Vec::new(),
+            reject_writes: Vec::new(),
+            clock_hz: 400_000,
+            elapsed_ns: 0,
+            stats: SimStats::default(),
+            selected: false,
+            cs_high_bytes: 0,
+            spi_mode: false,
+            idle: true,
+            polls: 0,
+            app: false,
+            block_len: 512,
+            frame: Vec::new(),
+            out: VecDeque::new(),
+            busy: 0,
+            mode: Mode::Idle,
+            received: Vec::new(),
+            erase_ahead: 0,
+            erase_pending: false,
+            erase_start: 0,
+            erase_end: 0,
+        };
+        card.power_up();
+        card
+    }
+
+    /// Forget everything but what is stored on the card.
+    fn power_up(&mut self) {
+        self.cs_high_bytes = 0;
+        self.spi_mode = false;
+        self.frame.clear();
+        self.out.clear();
+        self.reset();
+    }
+
+    /// Go back to the idle state, as CMD0 does.
+    fn reset(&mut self) {
+        self.idle = true;
+        self.polls = self.init_polls;
+        self.app = false;
+        self.block_len = if self.kind == SimKind::Mmc { 1024 } else { 512 };
+        self.busy = 0;
+        self.mode = Mode::Idle;
+    }
+
+    /// Take the card out of its socket, or put it back. A card which is put
+    /// back has just been powered up.
+    pub(crate) fn set_present(&mut self, present: bool) {
+        if !present {
+            self.spi_mode = false;
+            self.cs_high_bytes = 0;
+            self.frame.clear();
+            self.out.clear();
+            self.reset();
+        }
+        self.present = present;
+    }
+
+    /// The contents of a block.
+    pub(crate) fn block(&self, block: u32) -> Vec<u8> {
+        self.blocks
+            .get(&block)
+            .cloned()
+            .unwrap_or_else(|| vec![0; 512])
+    }
+
+    fn select(&mut self) {
+        self.selected = true;
+    }
+
+    /// Chip Select going high in the middle of an exchange makes the card
+    /// give up on it, as the specification doesn't allow it. The card
+    /// carries on being busy, though.
+    fn deselect(&mut self) {
+        if !self.frame.is_empty()
+            || !self.out.is_empty()
+            || matches!(self.mode, Mode::Receiving { .. } | Mode::MultiRead(_))
+        {
+            self.stats.split_exchanges += 1;
+            self.frame.clear();
+            self.out.clear();
+            self.received.clear();
+            self.mode = Mode::Idle;
+        }
+        self.selected = false;
+    }
+
+    /// How long it takes to clock one byte on the bus, in nanoseconds.
+    fn byte_ns(&self) -> u64 {
+        8_000_000_000 / u64::from(self.clock_hz)
+    }
+
+    /// Let some time pass without the bus being clocked. The card carries
+    /// on getting ready to answer, or being busy, as it would if it was
+    /// being polled.
+    fn wait(&mut self, ns: u32) {
+        self.elapsed_ns += u64::from(ns);
+        let mut bytes = u64::from(ns) / self.byte_ns();
+        while bytes > 0 && self.out.front() == Some(&0xFF) {
+            self.out.pop_front();
+            bytes -= 1;
+        }
+        if self.out.is_empty() {
+            self.busy -= self.busy.min(bytes as usize);
+        }
+    }
+
+    /// Clock one byte on the bus, returning what the card sends back.
+    fn clock(&mut self, mosi: u8) -> u8 {
+        self.elapsed_ns += self.byte_ns();
+        if !self.present {
+            return 0xFF;
+        }
+        if !self.selected {
+            self.cs_high_bytes += 1;
+            return 0xFF;
+        }
+        let miso = if let Some(b) = self.out.pop_front() {
+            b
+        } else if self.busy > 0 {
+            self.busy -= 1;
+            0x00
+        } else {
+            0xFF
+        };
+        match self.mode {
+            Mode::Receiving { multi, block } => {
+                self.receive(mosi, multi, block);
+                return miso;
+            }
+            Mode::WaitToken { multi, block } => match mosi {
+                DATA_START_BLOCK if !multi => {
+                    self.mode = Mode::Receiving { multi, block };
+                    return miso;
+                }
+                WRITE_MULTIPLE_TOKEN if multi => {
+                    self.mode = Mode::Receiving { multi, block };
+                    return miso;
+                }
+                STOP_TRAN_TOKEN if multi => {
+                    self.mode = Mode::Idle;
+                    self.erase_ahead = 0;
+                    self.set_busy(2);
+                    return miso;
+                }
+                _ => {}
+            },
+            Mode::MultiRead(block) if self.out.is_empty() => {
+                self.queue_block(block);
+                self.mode = Mode::MultiRead(block + 1);
+            }
+            _ => {}
+        }
+        if self.frame.is_empty() && (mosi & 0xC0) != 0x40 {
+            return miso;
+        }
+        self.frame.push(mosi);
+        if self.frame.len() == 6 {
+            let frame = core::mem::take(&mut self.frame);
+            self.command(&frame);
+        }
+        miso
+    }
+
+    /// Take a byte of a block being written.
+    fn receive(&mut self, mosi: u8, multi: bool, block: u32) {
+        self.received.push(mosi);
+        if self.received.len() < 514 {
+            return;
+        }
+        let data = self.received[0..512].to_vec();
+        let crc = u16::from_be_bytes([self.received[512], self.received[513]]);
+        self.received.clear();
+        if crc == crc16(&data) && !take_fault(&mut self.reject_writes, block) {
+            self.out.push_back(0xE5);
+            if self.erase_ahead > 0 {
+                self.erase_ahead -= 1;
+                if core::mem::take(&mut self.erase_pending) {
+                    self.erase();
+                }
+            } else {
+                self.erase();
+            }
+            self.stats.programs += 1;
+            self.set_busy(PROGRAM_BUSY);
+            self.blocks.insert(block, data);
+        } else {
+            self.out.push_back(0xEB);
+        }
+        self.mode = if multi {
+            Mode::WaitToken {
+                multi,
+                block: block + 1,
+            }
+        } else {
+            Mode::Idle
+        };
+    }
+
+    fn erase(&mut self) {
+        self.stats.erases += 1;
+        self.set_busy(ERASE_BUSY);
+    }
+
+    fn set_busy(&mut self, bytes: usize) {
+        self.busy += bytes;
+        self.stats.busy_bytes += bytes;
+    }
+
+    /// Queue a block of data, after the read delay.
+    fn queue_data(&mut self, data: &[u8]) {
+        self.out
+            .extend(core::iter::repeat(0xFF).take(self.read_delay));
+        self.out.push_back(DATA_START_BLOCK);
+        self.out.extend(data);
+        self.out.extend(crc16(data).to_be_bytes());
+    }
+
+    fn queue_block(&mut self, block: u32) {
+        self.queue_data(&self.block(block));
+        if take_fault(&mut self.corrupt_reads, block) {
+            // Flip a bit in the last byte before the CRC
+            let last = self.out.len() - 3;
+            self.out[last] ^= 0x01;
+        }
+    }
+
+    /// Which block an address in a command is in.
+    fn block_number(&self, arg: u32) -> u32 {
+        match self.kind {
+            SimKind::Sdhc => arg,
+            SimKind::Mmc => arg / 512,
+        }
+    }
+
+    fn csd(&self) -> [u8; 16] {
+        let mut csd = match self.kind {
+            // Version 2.0, 25 MHz, 1 GiB
+            SimKind::Sdhc => [
+                0x40, 0x0E, 0x00, 0x32, 0x5B, 0x59, 0x00, 0x00, 0x07, 0xFF, 0x7F, 0x80, 0x0A, 0x40,
+                0x00, 0x00,
+            ],
+            // Version 1.2, 20 MHz, 1 GiB
+            SimKind::Mmc => [
+                0x90, 0x26, 0x00, 0x2A, 0x1F, 0x59, 0x83, 0xFF, 0xC0, 0x03, 0x80, 0x00, 0x00, 0x00,
+                0x00, 0x00,
+            ],
+        };
+        csd[15] = crc7(&csd[0..15]);
+        csd
+    }
+
+    fn cid(&self) -> [u8; 16] {
+        let mut cid = [
+            0x03, 0x53, 0x44, 0x53, 0x55, 0x30, 0x38, 0x47, 0x80, 0, 0, 0, 0, 0x01, 0x3A, 0,
+        ];
+        cid[9..13].copy_from_slice(&self.serial.to_be_bytes());
+        cid[15] = crc7(&cid[0..15]);
+        cid
+    }
+
+    /// Answer a command.
+    fn command(&mut self, frame: &[u8]) {
+        let command = frame[0] & 0x3F;
+        let arg = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]);
+        assert_eq!(crc7(&frame[0..5]), frame[5], "bad CRC on CMD{}", command);
+        if !self.spi_mode {
+            // A card enters SPI mode when it gets CMD0 with Chip Select
+            // low, but only after 74 clocks with it high
+            if command != CMD0 || self.cs_high_bytes < 10 {
+                return;
+            }
+            self.spi_mode = true;
+        }
+        if self.ignore_commands > 0 {
+            self.ignore_commands -= 1;
+            return;
+        }
+        let app = core::mem::take(&mut self.app);
+        self.stats
+            .commands
+            .push((if app { 0x80 | command } else { command }, arg));
+        self.out.clear();
+        if command == CMD12 {
+            // The stuff byte
+            self.out.push_back(0xFF);
+            self.mode = Mode::Idle;
+        }
+        self.out
+            .extend(core::iter::repeat(0xFF).take(self.response_delay));
+        let r1 = if self.idle { R1_IDLE_STATE } else { 0 };
+        let mmc = self.kind == SimKind::Mmc;
+        match (app, command) {
+            (_, CMD0) => {
+                self.reset();
+                self.out.push_back(R1_IDLE_STATE);
+            }
+            (_, CMD59) => self.out.push_back(r1),
+            (_, CMD8) if !mmc => {
+                self.out
+                    .extend([r1, 0, 0, (arg >> 8) as u8 & 0x0F, arg as u8]);
+            }
+            (_, CMD55) if !mmc => {
+                self.app = true;
+                self.out.push_back(r1);
+            }
+            (true, ACMD41) | (false, CMD1) if (command == CMD1) == mmc => {
+                if self.polls > 0 {
+                    self.polls -= 1;
+                } else {
+                    self.idle = false;
+                }
+                self.out
+                    .push_back(if self.idle { R1_IDLE_STATE } else { 0 });
+            }
+            (_, CMD58) => {
+                let ccs = if self.kind == SimKind::Sdhc { 0x40 } else { 0 };
+                let busy = if self.idle { 0 } else { 0x80 };
+                self.out.extend([r1, busy | ccs, 0xFF, 0x80, 0x00]);
+            }
+            _ if self.idle => self.out.push_back(r1 | R1_ILLEGAL_COMMAND),
+            (_, CMD9) => {
+                self.out.push_back(0);
+                self.queue_data(&self.csd());
+            }
+            (_, CMD10) => {
+                self.out.push_back(0);
+                self.queue_data(&self.cid());
+            }
+            (_, CMD16) => {
+                self.block_len = arg;
+                self.out.push_back(0);
+            }
+            (true, ACMD51) => {
+                self.out.push_back(0);
+                self.queue_data(&[0x02, 0x35, 0x80, 0x03, 0, 0, 0, 0]);
+            }
+            (true, ACMD13) => {
+                // A 4 MiB Allocation Unit, which erases in 8 units of 1 s
+                let mut status = [0; 64];
+                status[10] = 0x90;
+                status[12] = 8;
+                status[13] = (1 << 2) | 1;
+                self.out.extend([0, 0]);
+                self.queue_data(&status);
+            }
+            (_, CMD13) => self.out.extend([0, 0]),
+            (true, ACMD23) => match self.acmd23 {
+                SimAcmd23::Supported => {
+                    self.erase_ahead = arg;
+                    self.erase_pending = true;
+                    self.out.push_back(0);
+                }
+                SimAcmd23::Illegal => self.out.push_back(R1_ILLEGAL_COMMAND),
+                SimAcmd23::NoAnswer => {}
+            },
+            (_, CMD17 | CMD18 | CMD24 | CMD25) if self.block_len != 512 => {
+                // Parameter error
+                self.out.push_back(0x40);
+            }
+            (_, CMD17) => {
+                self.out.push_back(0);
+                self.queue_block(self.block_number(arg));
+            }
+            (_, CMD18) => {
+                self.out.push_back(0);
+                self.mode = Mode::MultiRead(self.block_number(arg));
+            }
+            (_, CMD12) => {
+                self.out.push_back(0);
+                self.set_busy(2);
+            }
+            (_, CMD24 | CMD25) => {
+                self.out.push_back(0);
+                self.mode = Mode::WaitToken {
+                    multi: command == CMD25,
+                    block: self.block_number(arg),
+                };
+            }
+            (_, CMD32) => {
+                self.erase_start = self.block_number(arg);
+                self.out.push_back(0);
+            }
+            (_, CMD33) => {
+                self.erase_end = self.block_number(arg);
+                self.out.push_back(0);
+            }
+            (_, CMD38) => {
+                for block in self.erase_start..=self.erase_end {
+                    self.blocks.remove(&block);
+                }
+                self.out.push_back(0);
+                self.set_busy(ERASE_BUSY);
+            }
+            _ => self.out.push_back(R1_ILLEGAL_COMMAND),
+        }
+    }
+}
+
+/// The card on an SPI bus, as a blocking or an async [`SpiBus`]. The async
+/// bus makes the caller wait before every transfer, like a bus doing DMA
+/// would.
+///
+/// [`SpiBus`]: embedded_hal::spi::SpiBus
+#[derive(Clone)]
+pub(crate) struct SimSpi(Rc<RefCell<SimCard>>);
+
+impl SimSpi {
+    pub(crate) fn new(card: SimCard) -> SimSpi {
+        SimSpi(Rc::new(RefCell::new(card)))
+    }
+
+    pub(crate) fn card(&self) -> RefMut<'_, SimCard> {
+        self.0.borrow_mut()
+    }
+
+    /// The card's Chip Select pin.
+    pub(crate) fn cs(&self) -> SimCs {
+        SimCs(self.0.clone())
+    }
+
+    /// A delay which lets time pass for the card.
+    pub(crate) fn delay(&self) -> SimDelay {
+        SimDelay(self.0.clone())
+    }
+
+    fn exchange_in_place(&self, words: &mut [u8]) {
+        let mut card = self.card();
+        for word in words {
+            *word = card.clock(*word);
+        }
+    }
+
+    fn exchange(&self, read: &mut [u8], write: &[u8]) {
+        let mut card = self.card();
+        for idx in 0..read.len().max(write.len()) {
+            let miso = card.clock(write.get(idx).copied().unwrap_or(0xFF));
+            if let Some(word) = read.get_mut(idx) {
+                *word = miso;
+            }
+        }
+    }
+}
+
+impl embedded_hal::spi::ErrorType for SimSpi {
+    type Error = Infallible;
+}
+
+impl embedded_hal::spi::SpiBus<u8> for SimSpi {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+        self.exchange(words, &[]);
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        self.exchange(&mut [], words);
+        Ok(())
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Infallible> {
+        self.exchange(read, write);
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+        self.exchange_in_place(words);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+impl embedded_hal_async::spi::SpiBus<u8> for SimSpi {
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+        YieldOnce(false).await;
+        self.exchange(words, &[]);
+        Ok(())
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        YieldOnce(false).await;
+        self.exchange(&mut [], words);
+        Ok(())
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Infallible> {
+        YieldOnce(false).await;
+        self.exchange(read, write);
+        Ok(())
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+        YieldOnce(false).await;
+        self.exchange_in_place(words);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// The card's Chip Select pin, which selects it while low.
+pub(crate) struct SimCs(Rc<RefCell<SimCard>>);
+
+impl embedded_hal::digital::ErrorType for SimCs {
+    type Error = Infallible;
+}
+
+impl embedded_hal::digital::OutputPin for SimCs {
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().select();
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().deselect();
+        Ok(())
+    }
+}
+
+/// A delay which doesn't wait, but tells the card that the time has passed.
+pub(crate) struct SimDelay(Rc<RefCell<SimCard>>);
+
+impl embedded_hal::delay::DelayNs for SimDelay {
+    fn delay_ns(&mut self, ns: u32) {
+        self.0.borrow_mut().wait(ns);
+    }
+}
+
+impl embedded_hal_async::delay::DelayNs for SimDelay {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.0.borrow_mut().wait(ns);
+    }
+}
+
+/// Take a block off a list of blocks to go wrong, if it is there.
+fn take_fault(blocks: &mut Vec<u32>, block: u32) -> bool {
+    match blocks.iter().position(|b| *b == block) {
+        Some(pos) => {
+            blocks.remove(pos);
+            true
+        }
+        None => false,
+    }
+}
@@ -55,6 +55,13 @@ macro_rules! define_field {
             LittleEndian::read_u32(&self.data[$offset..$offset+4])
         }
     };
+
+    ($name:ident, u64, $offset:expr) => {
+        /// Get the $name field
+        pub fn $name(&self) -> u64 {
+            LittleEndian::read_u64(&self.data[$offset..$offset+8])
+        }
+    };
 }
 
 // ****************************************************************************
@@ -0,0 +1,242 @@
+//! Things the unit tests share: a block device holding a disk image in
+//! memory, and a clock.
+
+use crate::blockdevice::{AsyncBlockDevice, Block, BlockCount, BlockDevice, BlockIdx};
+use crate::filesystem::{TimeSource, Timestamp};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::OnceLock;
+
+/// The example disk image. It has a FAT16 volume and a FAT32 volume, each
+/// holding README.TXT, EMPTY.DAT, 64MB.DAT and TEST/TEST.DAT.
+pub(crate) fn disk_image() -> &'static [u8] {
+    static IMAGE: OnceLock<Vec<u8>> = OnceLock::new();
+    IMAGE.get_or_init(|| unpack(include_bytes!("../disk.img.gz")))
+}
+
+/// A small exFAT disk image, described in `testdata/README.md`.
+pub(crate) fn exfat_image() -> &'static [u8] {
+    static IMAGE: OnceLock<Vec<u8>> = OnceLock::new();
+    IMAGE.get_or_init(|| unpack(include_bytes!("../testdata/exfat.img.gz")))
+}
+
+/// The same as `exfat_image`, but with 4096 byte sectors.
+pub(crate) fn exfat_4k_image() -> &'static [u8] {
+    static IMAGE: OnceLock<Vec<u8>> = OnceLock::new();
+    IMAGE.get_or_init(|| unpack(include_bytes!("../testdata/exfat-4k.img.gz")))
+}
+
+/// A small FAT16 disk image with 4096 byte sectors, described in
+/// `testdata/README.md`.
+pub(crate) fn fat16_4k_image() -> &'static [u8] {
+    static IMAGE: OnceLock<Vec<u8>> = OnceLock::new();
+    IMAGE.get_or_init(|| unpack(include_bytes!("../testdata/fat16-4k.img.gz")))
+}
+
+/// A small disk partitioned with a GUID Partition Table, described in
+/// `testdata/README.md`.
+pub(crate) fn gpt_image() -> &'static [u8] {
+    static IMAGE: OnceLock<Vec<u8>> = OnceLock::new();
+    IMAGE.get_or_init(|| unpack(include_bytes!("../testdata/gpt.img.gz")))
+}
+
+/// Unpack a gzipped disk image.
+pub(crate) fn unpack(gzipped: &[u8]) -> Vec<u8> {
+    let mut image = Vec::new();
+    flate2::read::GzDecoder::new(gzipped)
+        .read_to_end(&mut image)
+        .unwrap();
+    image
+}
+
+/// A block device holding a disk image. Blocks which are written are kept
+/// to one side, so many `RamDisk`s can share one image.
+pub(crate) struct RamDisk {
+    image: &'static [u8],
+    written: RefCell<HashMap<u64, Block>>,
+    sector_size: usize,
+    /// How many blocks have been read
+    pub(crate) reads: Cell<usize>,
+    /// Every call to `discard`
+    pub(crate) discards: RefCell<Vec<(BlockIdx, BlockCount)>>,
+}
+
+impl RamDisk {
+    /// A disk with 512 byte sectors.
+    pub(crate) fn new(image: &'static [u8]) -> RamDisk {
+        RamDisk::with_sector_size(image, Block::LEN)
+    }
+
+    /// A disk whose sectors are `sector_size` bytes long.
+    pub(crate) fn with_sector_size(image: &'static [u8], sector_size: usize) -> RamDisk {
+        RamDisk {
+            image,
+            written: RefCell::new(HashMap::new()),
+            sector_size,
+            reads: Cell::new(0),
+            discards: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Get a copy of one block.
+    pub(crate) fn block(&self, idx: u64) -> Block {
+        if let Some(block) = self.written.borrow().get(&idx) {
+            return block.clone();
+        }
+        let start = idx as usize * Block::LEN;
+        let mut block = Block::new();
+        block
+            .contents
+            .copy_from_slice(&self.image[start..start + Block::LEN]);
+        block
+    }
+
+    /// Overwrite some bytes of the disk, starting at byte `offset`.
+    pub(crate) fn patch(&self, offset: u64, bytes: &[u8]) {
+        let mut offset = offset;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let idx = offset / Block::LEN as u64;
+            let start = (offset % Block::LEN as u64) as usize;
+            let count = core::cmp::min(Block::LEN - start, bytes.len());
+            let mut block = self.block(idx);
+            block.contents[start..start + count].copy_from_slice(&bytes[..count]);
+            self.written.borrow_mut().insert(idx, block);
+            offset += count as u64;
+            bytes = &bytes[count..];
+        }
+    }
+
+    /// Get a copy of `len` bytes, starting at byte `offset` of the disk.
+    pub(crate) fn bytes(&self, offset: u64, len: usize) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(len);
+        let mut offset = offset;
+        while bytes.len() < len {
+            let block = self.block(offset / Block::LEN as u64);
+            let start = (offset % Block::LEN as u64) as usize;
+            let count = core::cmp::min(Block::LEN - start, len - bytes.len());
+            bytes.extend_from_slice(&block.contents[start..start + count]);
+            offset += count as u64;
+        }
+        bytes
+    }
+}
+
+impl BlockDevice for RamDisk {
+    type Error = ();
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let start = start_block_idx.into_bytes() / Block::LEN as u64;
+        for (i, block) in blocks.iter_mut().enumerate() {
+            *block = self.block(start + i as u64);
+        }
+        self.reads.set(self.reads.get() + blocks.len());
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        let start = start_block_idx.into_bytes() / Block::LEN as u64;
+        let mut written = self.written.borrow_mut();
+        for (i, block) in blocks.iter().enumerate() {
+            written.insert(start + i as u64, block.clone());
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        Ok(BlockCount((self.image.len() / Block::LEN) as _))
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn discard(
+        &self,
+        start_block_idx: BlockIdx,
+        num_blocks: BlockCount,
+    ) -> Result<(), Self::Error> {
+        self.discards
+            .borrow_mut()
+            .push((start_block_idx, num_blocks));
+        Ok(())
+    }
+}
+
+/// A `Future` which is pending the first time it is polled.
+pub(crate) struct YieldOnce(pub(crate) bool);
+
+impl core::future::Future for YieldOnce {
+    type Output = ();
+    fn poll(
+        mut self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<()> {
+        if self.0 {
+            core::task::Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            core::task::Poll::Pending
+        }
+    }
+}
+
+/// The `RamDisk` as an async device, which makes the caller wait before
+/// every transfer like a device doing DMA would.
+impl AsyncBlockDevice for RamDisk {
+    type Error = ();
+
+    async fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        reason: &str,
+    ) -> Result<(), Self::Error> {
+        YieldOnce(false).await;
+        BlockDevice::read(self, blocks, start_block_idx, reason)
+    }
+
+    async fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        YieldOnce(false).await;
+        BlockDevice::write(self, blocks, start_block_idx)
+    }
+
+    async fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        BlockDevice::num_blocks(self)
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    async fn discard(
+        &self,
+        start_block_idx: BlockIdx,
+        num_blocks: BlockCount,
+    ) -> Result<(), Self::Error> {
+        BlockDevice::discard(self, start_block_idx, num_blocks)
+    }
+}
+
+/// A clock which is always at the same time.
+pub(crate) struct Clock;
+
+impl TimeSource for Clock {
+    fn get_timestamp(&self) -> Timestamp {
+        Timestamp {
+            year_since_1970: 50,
+            zero_indexed_month: 0,
+            zero_indexed_day: 0,
+            hours: 0,
+            minutes: 0,
+            seconds: 0,
+        }
+    }
+}
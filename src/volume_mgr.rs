@@ -4,20 +4,57 @@ use byteorder::{ByteOrder, LittleEndian};
 use core::convert::TryFrom;
 
 #[cfg(feature = "log")]
-use log::debug;
+use log::{debug, warn};
 
 #[cfg(feature = "defmt-log")]
-use defmt::debug;
+use defmt::{debug, warn};
 
+use crate::blockdevice::{block_on, Blocking};
+use crate::exfat;
 use crate::fat::{self, RESERVED_ENTRIES};
 use crate::filesystem::{
-    Attributes, Cluster, DirEntry, Directory, File, Mode, ShortFileName, TimeSource, MAX_FILE_SIZE,
+    Attributes, Cluster, DirEntry, Directory, ExtentCache, File, Mode, ShortFileName, TimeSource,
+    MAX_FILE_SIZE,
 };
+use crate::gpt::{self, GptEntry, GptHeader};
 use crate::{
-    Block, BlockCount, BlockDevice, BlockIdx, Error, Volume, VolumeIdx, VolumeType,
-    PARTITION_ID_FAT16, PARTITION_ID_FAT16_LBA, PARTITION_ID_FAT32_CHS_LBA, PARTITION_ID_FAT32_LBA,
+    AsyncBlockDevice, Block, BlockCount, BlockDevice, BlockIdx, BlockNumber, Error, Volume,
+    VolumeIdx, VolumeType, PARTITION_ID_EXFAT, PARTITION_ID_FAT16, PARTITION_ID_FAT16_LBA,
+    PARTITION_ID_FAT32_CHS_LBA, PARTITION_ID_FAT32_LBA, PARTITION_ID_GPT_PROTECTIVE,
 };
 
+/// How a `VolumeManager` picks free clusters when a file or directory grows.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AllocationPolicy {
+    /// Always use the lowest-numbered free cluster on the volume.
+    FirstFit,
+    /// Carry on searching from just after the last cluster we allocated. This
+    /// is the default.
+    NextFit,
+    /// Keep files contiguous. A file is extended in-place whenever the
+    /// following cluster is free. Otherwise, a new file (or a file that could
+    /// not be extended) starts in the largest free gap on the volume, and
+    /// [`VolumeManager::allocate`] uses the smallest gap that will hold the
+    /// whole request. This costs a scan of the whole FAT when a file can't be
+    /// extended in-place, but keeps high-rate writes sequential on the card.
+    /// Directories are grown as with `NextFit`.
+    BestFitContiguous,
+}
+
+/// Whether a `VolumeManager` tells the block device about clusters it frees.
+#[cfg_attr(feature = "defmt-log", derive(defmt::Format))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DiscardPolicy {
+    /// Don't tell the block device. This is the default.
+    Never,
+    /// Call [`BlockDevice::discard`] for each run of clusters which is freed
+    /// when a file is truncated or deleted. An SD card erases them, which
+    /// takes time now but can make later writes faster. The free space is
+    /// still reused if discarding fails.
+    FreedClusters,
+}
+
 /// A `VolumeManager` wraps a block device and gives access to the volumes within it.
 pub struct VolumeManager<D, T, const MAX_DIRS: usize = 4, const MAX_FILES: usize = 4>
 where
@@ -25,10 +62,7 @@ where
     T: TimeSource,
     <D as BlockDevice>::Error: core::fmt::Debug,
 {
-    pub(crate) block_device: D,
-    pub(crate) timesource: T,
-    open_dirs: [(VolumeIdx, Cluster); MAX_DIRS],
-    open_files: [(VolumeIdx, Cluster); MAX_FILES],
+    inner: AsyncVolumeManager<Blocking<D>, T, MAX_DIRS, MAX_FILES>,
 }
 
 impl<D, T> VolumeManager<D, T, 4, 4>
@@ -62,13 +96,356 @@ where
         block_device: D,
         timesource: T,
     ) -> VolumeManager<D, T, MAX_DIRS, MAX_FILES> {
-        debug!("Creating new embedded-sdmmc::VolumeManager");
         VolumeManager {
+            inner: AsyncVolumeManager::new_with_limits(Blocking(block_device), timesource),
+        }
+    }
+
+    /// Get the policy used to pick free clusters when a file or directory
+    /// grows.
+    pub fn allocation_policy(&self) -> AllocationPolicy {
+        self.inner.allocation_policy()
+    }
+
+    /// Set the policy used to pick free clusters when a file or directory
+    /// grows. Clusters which are already allocated are not moved.
+    pub fn set_allocation_policy(&mut self, policy: AllocationPolicy) {
+        self.inner.set_allocation_policy(policy)
+    }
+
+    /// Get the policy which says whether the block device is told about
+    /// freed clusters.
+    pub fn discard_policy(&self) -> DiscardPolicy {
+        self.inner.discard_policy()
+    }
+
+    /// Set the policy which says whether the block device is told about
+    /// freed clusters.
+    pub fn set_discard_policy(&mut self, policy: DiscardPolicy) {
+        self.inner.set_discard_policy(policy)
+    }
+
+    /// Temporarily get access to the underlying block device.
+    pub fn device(&mut self) -> &mut D {
+        &mut self.inner.device().0
+    }
+
+    /// Get a volume (or partition) based on entries in the Master Boot
+    /// Record, or in the GUID Partition Table if the disk has one. We do not
+    /// support any concept of drive letters - that is for a higher layer to
+    /// handle.
+    pub fn get_volume(&mut self, volume_idx: VolumeIdx) -> Result<Volume, Error<D::Error>> {
+        block_on(self.inner.get_volume(volume_idx))
+    }
+
+    /// Open a directory.
+    ///
+    /// You can then read the directory entries with `iterate_dir` and `open_file_in_dir`.
+    ///
+    /// TODO: Work out how to prevent damage occuring to the file system while
+    /// this directory handle is open. In particular, stop this directory
+    /// being unlinked.
+    pub fn open_root_dir(&mut self, volume: &Volume) -> Result<Directory, Error<D::Error>> {
+        self.inner.open_root_dir(volume)
+    }
+
+    /// Open a directory.
+    ///
+    /// You can then read the directory entries with `iterate_dir` and `open_file_in_dir`.
+    ///
+    /// TODO: Work out how to prevent damage occuring to the file system while
+    /// this directory handle is open. In particular, stop this directory
+    /// being unlinked.
+    pub fn open_dir(
+        &mut self,
+        volume: &Volume,
+        parent_dir: &Directory,
+        name: &str,
+    ) -> Result<Directory, Error<D::Error>> {
+        block_on(self.inner.open_dir(volume, parent_dir, name))
+    }
+
+    /// Close a directory. You cannot perform operations on an open directory
+    /// and so must close it if you want to do something with it.
+    pub fn close_dir(&mut self, volume: &Volume, dir: Directory) {
+        self.inner.close_dir(volume, dir)
+    }
+
+    /// Look in a directory for a named file.
+    pub fn find_directory_entry(
+        &mut self,
+        volume: &Volume,
+        dir: &Directory,
+        name: &str,
+    ) -> Result<DirEntry, Error<D::Error>> {
+        block_on(self.inner.find_directory_entry(volume, dir, name))
+    }
+
+    /// Call a callback function for each directory entry in a directory.
+    pub fn iterate_dir<F>(
+        &mut self,
+        volume: &Volume,
+        dir: &Directory,
+        func: F,
+    ) -> Result<(), Error<D::Error>>
+    where
+        F: FnMut(&DirEntry),
+    {
+        block_on(self.inner.iterate_dir(volume, dir, func))
+    }
+
+    /// Open a file from DirEntry. This is obtained by calling iterate_dir. A file can only be opened once.
+    pub fn open_dir_entry(
+        &mut self,
+        volume: &mut Volume,
+        dir_entry: DirEntry,
+        mode: Mode,
+    ) -> Result<File, Error<D::Error>> {
+        block_on(self.inner.open_dir_entry(volume, dir_entry, mode))
+    }
+
+    /// Open a file with the given full path. A file can only be opened once.
+    pub fn open_file_in_dir(
+        &mut self,
+        volume: &mut Volume,
+        dir: &Directory,
+        name: &str,
+        mode: Mode,
+    ) -> Result<File, Error<D::Error>> {
+        block_on(self.inner.open_file_in_dir(volume, dir, name, mode))
+    }
+
+    /// Delete a closed file with the given full path, if exists.
+    pub fn delete_file_in_dir(
+        &mut self,
+        volume: &mut Volume,
+        dir: &Directory,
+        name: &str,
+    ) -> Result<(), Error<D::Error>> {
+        block_on(self.inner.delete_file_in_dir(volume, dir, name))
+    }
+
+    /// Read from an open file.
+    pub fn read(
+        &mut self,
+        volume: &Volume,
+        file: &mut File,
+        buffer: &mut [u8],
+    ) -> Result<usize, Error<D::Error>> {
+        block_on(self.inner.read(volume, file, buffer))
+    }
+
+    /// Write to a open file.
+    pub fn write(
+        &mut self,
+        volume: &mut Volume,
+        file: &mut File,
+        buffer: &[u8],
+    ) -> Result<usize, Error<D::Error>> {
+        block_on(self.inner.write(volume, file, buffer))
+    }
+
+    /// Reserve space on the volume for an open file, so that later writes
+    /// do not have to allocate clusters.
+    ///
+    /// The file's cluster chain is extended until it can hold `size` bytes,
+    /// preferring a single contiguous run of free clusters. If `extend` is
+    /// false the length of the file is unchanged and the reserved clusters
+    /// are used as the file grows. If `extend` is true the file length is
+    /// set to `size` and the new space is filled with zeros.
+    ///
+    /// On exFAT volumes, `extend` must be true - we don't support files with
+    /// more clusters than their length needs. On FAT volumes, `size` cannot
+    /// be larger than `MAX_FILE_SIZE`.
+    pub fn allocate(
+        &mut self,
+        volume: &mut Volume,
+        file: &mut File,
+        size: u64,
+        extend: bool,
+    ) -> Result<(), Error<D::Error>> {
+        block_on(self.inner.allocate(volume, file, size, extend))
+    }
+
+    /// Change the length of an open file.
+    ///
+    /// If `new_length` is shorter than the file, the file is cut short and
+    /// any clusters no longer needed are freed. If it is longer, the file is
+    /// extended and the new space is filled with zeros. If the current
+    /// position is beyond the new end of the file, it is moved to the end.
+    pub fn truncate(
+        &mut self,
+        volume: &mut Volume,
+        file: &mut File,
+        new_length: u64,
+    ) -> Result<(), Error<D::Error>> {
+        block_on(self.inner.truncate(volume, file, new_length))
+    }
+
+    /// Flush an open file to disk without closing it.
+    ///
+    /// This writes the file's directory entry (size, modification time and
+    /// starting cluster) and the volume's free space information. Data
+    /// blocks and FAT entries are not cached by the `VolumeManager` - they
+    /// are written to the block device during `write` - so once this
+    /// function returns, everything written so far is on the device.
+    pub fn flush_file(&mut self, volume: &mut Volume, file: &File) -> Result<(), Error<D::Error>> {
+        block_on(self.inner.flush_file(volume, file))
+    }
+
+    /// Close a file with the given full path.
+    pub fn close_file(&mut self, volume: &Volume, file: File) -> Result<(), Error<D::Error>> {
+        self.inner.close_file(volume, file)
+    }
+
+    /// Check if any files or folders are open.
+    pub fn has_open_handles(&self) -> bool {
+        self.inner.has_open_handles()
+    }
+
+    /// Consume self and return BlockDevice and TimeSource
+    pub fn free(self) -> (D, T) {
+        let (block_device, timesource) = self.inner.free();
+        (block_device.0, timesource)
+    }
+}
+
+/// An `AsyncVolumeManager` is a `VolumeManager` for an `AsyncBlockDevice`.
+/// Every function which might touch the device is `async`, so other tasks
+/// can run while we wait for it.
+///
+/// All the file system code lives here. A `VolumeManager` is just an
+/// `AsyncVolumeManager` whose device never makes it wait.
+pub struct AsyncVolumeManager<D, T, const MAX_DIRS: usize = 4, const MAX_FILES: usize = 4>
+where
+    D: AsyncBlockDevice,
+    T: TimeSource,
+    <D as AsyncBlockDevice>::Error: core::fmt::Debug,
+{
+    pub(crate) block_device: D,
+    pub(crate) timesource: T,
+    open_dirs: [(VolumeIdx, Cluster); MAX_DIRS],
+    open_files: [(VolumeIdx, Cluster); MAX_FILES],
+    pub(crate) allocation_policy: AllocationPolicy,
+    discard_policy: DiscardPolicy,
+    /// The block device's `media_generation` the open directories and
+    /// files belong to
+    generation: u32,
+}
+
+impl<D, T> AsyncVolumeManager<D, T, 4, 4>
+where
+    D: AsyncBlockDevice,
+    T: TimeSource,
+    <D as AsyncBlockDevice>::Error: core::fmt::Debug,
+{
+    /// Create a new Volume Manager using a generic `AsyncBlockDevice`. From
+    /// this object we can open volumes (partitions) and with those we can
+    /// open files.
+    ///
+    /// This creates an `AsyncVolumeManager` with default values
+    /// MAX_DIRS = 4, MAX_FILES = 4. Call `AsyncVolumeManager::new_with_limits(block_device, timesource)`
+    /// if you need different limits.
+    pub fn new(block_device: D, timesource: T) -> AsyncVolumeManager<D, T, 4, 4> {
+        Self::new_with_limits(block_device, timesource)
+    }
+}
+
+impl<D, T, const MAX_DIRS: usize, const MAX_FILES: usize>
+    AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES>
+where
+    D: AsyncBlockDevice,
+    T: TimeSource,
+    <D as AsyncBlockDevice>::Error: core::fmt::Debug,
+{
+    /// Create a new Volume Manager using a generic `AsyncBlockDevice`. From
+    /// this object we can open volumes (partitions) and with those we can
+    /// open files.
+    pub fn new_with_limits(
+        block_device: D,
+        timesource: T,
+    ) -> AsyncVolumeManager<D, T, MAX_DIRS, MAX_FILES> {
+        debug!("Creating new embedded-sdmmc::VolumeManager");
+        AsyncVolumeManager {
             block_device,
             timesource,
             open_dirs: [(VolumeIdx(0), Cluster::INVALID); MAX_DIRS],
             open_files: [(VolumeIdx(0), Cluster::INVALID); MAX_FILES],
+            allocation_policy: AllocationPolicy::NextFit,
+            discard_policy: DiscardPolicy::Never,
+            generation: 0,
+        }
+    }
+
+    /// Get the policy used to pick free clusters when a file or directory
+    /// grows.
+    pub fn allocation_policy(&self) -> AllocationPolicy {
+        self.allocation_policy
+    }
+
+    /// Set the policy used to pick free clusters when a file or directory
+    /// grows. Clusters which are already allocated are not moved.
+    pub fn set_allocation_policy(&mut self, policy: AllocationPolicy) {
+        self.allocation_policy = policy;
+    }
+
+    /// Get the policy which says whether the block device is told about
+    /// freed clusters.
+    pub fn discard_policy(&self) -> DiscardPolicy {
+        self.discard_policy
+    }
+
+    /// Set the policy which says whether the block device is told about
+    /// freed clusters.
+    pub fn set_discard_policy(&mut self, policy: DiscardPolicy) {
+        self.discard_policy = policy;
+    }
+
+    /// Tell the block device that some freed blocks are no longer needed,
+    /// if the [`DiscardPolicy`] says to. This is only a hint, so failures
+    /// are logged and otherwise ignored.
+    pub(crate) async fn discard_blocks(&self, start_block_idx: BlockIdx, num_blocks: BlockCount) {
+        if self.discard_policy == DiscardPolicy::Never || num_blocks.0 == 0 {
+            return;
+        }
+        if self
+            .block_device
+            .discard(start_block_idx, num_blocks)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Failed to discard {} blocks at {}",
+                num_blocks.0, start_block_idx.0
+            );
+        }
+    }
+
+    /// Ask the block device which medium it holds. If that has changed,
+    /// the open directories and files are on the old one, so forget them.
+    async fn media_generation(&mut self) -> Result<u32, Error<D::Error>> {
+        let generation = self
+            .block_device
+            .media_generation()
+            .await
+            .map_err(Error::DeviceError)?;
+        if generation != self.generation {
+            if self.has_open_handles() {
+                warn!("Medium changed, closing all directories and files");
+            }
+            self.open_dirs = [(VolumeIdx(0), Cluster::INVALID); MAX_DIRS];
+            self.open_files = [(VolumeIdx(0), Cluster::INVALID); MAX_FILES];
+            self.generation = generation;
+        }
+        Ok(generation)
+    }
+
+    /// Check that the volume is on the medium which is in the device now.
+    async fn check_volume(&mut self, volume: &Volume) -> Result<(), Error<D::Error>> {
+        if self.media_generation().await? != volume.generation {
+            return Err(Error::MediaChanged);
         }
+        Ok(())
     }
 
     /// Temporarily get access to the underlying block device.
@@ -76,11 +453,8 @@ where
         &mut self.block_device
     }
 
-    /// Get a volume (or partition) based on entries in the Master Boot
-    /// Record. We do not support GUID Partition Table disks. Nor do we
-    /// support any concept of drive letters - that is for a higher layer to
-    /// handle.
-    pub fn get_volume(&mut self, volume_idx: VolumeIdx) -> Result<Volume, Error<D::Error>> {
+    /// The async version of [`VolumeManager::get_volume`].
+    pub async fn get_volume(&mut self, volume_idx: VolumeIdx) -> Result<Volume, Error<D::Error>> {
         const PARTITION1_START: usize = 446;
         const PARTITION2_START: usize = PARTITION1_START + PARTITION_INFO_LENGTH;
         const PARTITION3_START: usize = PARTITION2_START + PARTITION_INFO_LENGTH;
@@ -93,17 +467,24 @@ where
         const PARTITION_INFO_LBA_START_INDEX: usize = 8;
         const PARTITION_INFO_NUM_BLOCKS_INDEX: usize = 12;
 
+        // A removable device might have a new medium to start using
+        self.media_generation().await?;
+        let blocks_per_sector = self.blocks_per_sector()?;
         let (part_type, lba_start, num_blocks) = {
             let mut blocks = [Block::new()];
             self.block_device
                 .read(&mut blocks, BlockIdx(0), "read_mbr")
+                .await
                 .map_err(Error::DeviceError)?;
             let block = &blocks[0];
-            // We only support Master Boot Record (MBR) partitioned cards, not
-            // GUID Partition Table (GPT)
             if LittleEndian::read_u16(&block[FOOTER_START..FOOTER_START + 2]) != FOOTER_VALUE {
                 return Err(Error::FormatError("Invalid MBR signature"));
             }
+            // A GPT partitioned disk has a protective MBR, with one partition
+            // covering the whole disk
+            if block[PARTITION1_START + PARTITION_INFO_TYPE_INDEX] == PARTITION_ID_GPT_PROTECTIVE {
+                return self.get_gpt_volume(volume_idx, blocks_per_sector).await;
+            }
             let partition = match volume_idx {
                 VolumeIdx(0) => {
                     &block[PARTITION1_START..(PARTITION1_START + PARTITION_INFO_LENGTH)]
@@ -131,21 +512,128 @@ where
             let num_blocks = LittleEndian::read_u32(
                 &partition[PARTITION_INFO_NUM_BLOCKS_INDEX..(PARTITION_INFO_NUM_BLOCKS_INDEX + 4)],
             );
+            // These count in sectors, which may be bigger than our blocks
+            let lba_start = sectors_to_blocks(u64::from(lba_start), blocks_per_sector);
+            let num_blocks = sectors_to_blocks(u64::from(num_blocks), blocks_per_sector);
+            match (lba_start, num_blocks) {
+                (Some(lba_start), Some(num_blocks)) => (
+                    partition[PARTITION_INFO_TYPE_INDEX],
+                    BlockIdx(lba_start.0),
+                    num_blocks,
+                ),
+                _ => return Err(Error::FormatError("Partition out of range")),
+            }
+        };
+        self.parse_volume(volume_idx, part_type, lba_start, num_blocks)
+            .await
+    }
+
+    /// Get a volume (or partition) based on an entry in the GUID Partition
+    /// Table.
+    async fn get_gpt_volume(
+        &mut self,
+        volume_idx: VolumeIdx,
+        blocks_per_sector: BlockNumber,
+    ) -> Result<Volume, Error<D::Error>> {
+        let mut blocks = [Block::new()];
+        // The header is in the second sector
+        self.block_device
+            .read(&mut blocks, BlockIdx(blocks_per_sector), "read_gpt_header")
+            .await
+            .map_err(Error::DeviceError)?;
+        let (entries_start, num_entries, entry_size, expected_crc) = {
+            let header = GptHeader::create_from_bytes(&blocks[0]).map_err(Error::FormatError)?;
             (
-                partition[PARTITION_INFO_TYPE_INDEX],
-                BlockIdx(lba_start),
-                BlockCount(num_blocks),
+                sectors_to_blocks(header.partition_entry_lba(), blocks_per_sector)
+                    .ok_or(Error::FormatError("GPT partition entries out of range"))?,
+                header.num_partition_entries() as usize,
+                header.partition_entry_size() as usize,
+                header.partition_array_crc32(),
             )
         };
+        if volume_idx.0 >= num_entries {
+            return Err(Error::NoSuchVolume);
+        }
+        // Read the whole partition entry array, so we can check it
+        let mut partition = None;
+        let mut crc = 0;
+        let mut block_idx = BlockIdx(entries_start.0);
+        let mut entry_idx = 0;
+        while entry_idx < num_entries {
+            self.block_device
+                .read(&mut blocks, block_idx, "read_gpt_entries")
+                .await
+                .map_err(Error::DeviceError)?;
+            for entry in blocks[0].chunks(entry_size).take(num_entries - entry_idx) {
+                crc = gpt::crc32(crc, entry);
+                if entry_idx == volume_idx.0 {
+                    let entry = GptEntry::new(entry);
+                    if entry.is_unused() {
+                        return Err(Error::NoSuchVolume);
+                    }
+                    let lba_start = sectors_to_blocks(entry.first_lba(), blocks_per_sector);
+                    let num_blocks = entry
+                        .num_sectors()
+                        .and_then(|n| sectors_to_blocks(n, blocks_per_sector));
+                    let range = match (lba_start, num_blocks) {
+                        (Some(lba_start), Some(num_blocks)) => (BlockIdx(lba_start.0), num_blocks),
+                        _ => return Err(Error::FormatError("Partition out of range")),
+                    };
+                    let is_basic_data = entry.type_guid() == gpt::BASIC_DATA_PARTITION;
+                    partition = Some((is_basic_data, range));
+                }
+                entry_idx += 1;
+            }
+            block_idx += BlockCount(1);
+        }
+        if crc != expected_crc {
+            return Err(Error::FormatError("Bad GPT partition entries checksum"));
+        }
+        let (is_basic_data, (lba_start, num_blocks)) = partition.ok_or(Error::NoSuchVolume)?;
+        if !is_basic_data {
+            return Err(Error::FormatError("Partition type not supported"));
+        }
+        // GPT doesn't say which file system a Basic Data partition holds, so
+        // look at its boot sector and pretend it had the matching MBR type
+        self.block_device
+            .read(&mut blocks, lba_start, "read_boot_sector")
+            .await
+            .map_err(Error::DeviceError)?;
+        let part_type = if &blocks[0][3..11] == b"EXFAT   " {
+            PARTITION_ID_EXFAT
+        } else {
+            PARTITION_ID_FAT32_LBA
+        };
+        self.parse_volume(volume_idx, part_type, lba_start, num_blocks)
+            .await
+    }
+
+    /// Load the file system from a partition, given its MBR partition type.
+    async fn parse_volume(
+        &mut self,
+        volume_idx: VolumeIdx,
+        part_type: u8,
+        lba_start: BlockIdx,
+        num_blocks: BlockCount,
+    ) -> Result<Volume, Error<D::Error>> {
         match part_type {
             PARTITION_ID_FAT32_CHS_LBA
             | PARTITION_ID_FAT32_LBA
             | PARTITION_ID_FAT16_LBA
             | PARTITION_ID_FAT16 => {
-                let volume = fat::parse_volume(self, lba_start, num_blocks)?;
+                let volume = fat::parse_volume(self, lba_start, num_blocks).await?;
+                Ok(Volume {
+                    idx: volume_idx,
+                    volume_type: volume,
+                    generation: self.generation,
+                })
+            }
+            PARTITION_ID_EXFAT => {
+                let volume = exfat::parse_volume(self, lba_start, num_blocks).await?;
                 Ok(Volume {
                     idx: volume_idx,
                     volume_type: volume,
+                    generation: self.generation,
                 })
             }
             _ => Err(Error::FormatError("Partition type not supported")),
@@ -182,19 +670,14 @@ where
         })
     }
 
-    /// Open a directory.
-    ///
-    /// You can then read the directory entries with `iterate_dir` and `open_file_in_dir`.
-    ///
-    /// TODO: Work out how to prevent damage occuring to the file system while
-    /// this directory handle is open. In particular, stop this directory
-    /// being unlinked.
-    pub fn open_dir(
+    /// The async version of [`VolumeManager::open_dir`].
+    pub async fn open_dir(
         &mut self,
         volume: &Volume,
         parent_dir: &Directory,
         name: &str,
     ) -> Result<Directory, Error<D::Error>> {
+        self.check_volume(volume).await?;
         // Find a free open directory table row
         let mut open_dirs_row = None;
         for (i, d) in self.open_dirs.iter().enumerate() {
@@ -206,7 +689,8 @@ where
 
         // Open the directory
         let dir_entry = match &volume.volume_type {
-            VolumeType::Fat(fat) => fat.find_directory_entry(self, parent_dir, name)?,
+            VolumeType::Fat(fat) => fat.find_directory_entry(self, parent_dir, name).await?,
+            VolumeType::ExFat(exfat) => exfat.find_directory_entry(self, parent_dir, name).await?,
         };
 
         if !dir_entry.attributes.is_directory() {
@@ -240,20 +724,22 @@ where
         drop(dir);
     }
 
-    /// Look in a directory for a named file.
-    pub fn find_directory_entry(
+    /// The async version of [`VolumeManager::find_directory_entry`].
+    pub async fn find_directory_entry(
         &mut self,
         volume: &Volume,
         dir: &Directory,
         name: &str,
     ) -> Result<DirEntry, Error<D::Error>> {
+        self.check_volume(volume).await?;
         match &volume.volume_type {
-            VolumeType::Fat(fat) => fat.find_directory_entry(self, dir, name),
+            VolumeType::Fat(fat) => fat.find_directory_entry(self, dir, name).await,
+            VolumeType::ExFat(exfat) => exfat.find_directory_entry(self, dir, name).await,
         }
     }
 
-    /// Call a callback function for each directory entry in a directory.
-    pub fn iterate_dir<F>(
+    /// The async version of [`VolumeManager::iterate_dir`].
+    pub async fn iterate_dir<F>(
         &mut self,
         volume: &Volume,
         dir: &Directory,
@@ -262,23 +748,26 @@ where
     where
         F: FnMut(&DirEntry),
     {
+        self.check_volume(volume).await?;
         match &volume.volume_type {
-            VolumeType::Fat(fat) => fat.iterate_dir(self, dir, func),
+            VolumeType::Fat(fat) => fat.iterate_dir(self, dir, func).await,
+            VolumeType::ExFat(exfat) => exfat.iterate_dir(self, dir, func).await,
         }
     }
 
-    /// Open a file from DirEntry. This is obtained by calling iterate_dir. A file can only be opened once.
-    pub fn open_dir_entry(
+    /// The async version of [`VolumeManager::open_dir_entry`].
+    pub async fn open_dir_entry(
         &mut self,
         volume: &mut Volume,
-        dir_entry: DirEntry,
+        mut dir_entry: DirEntry,
         mode: Mode,
     ) -> Result<File, Error<D::Error>> {
+        self.check_volume(volume).await?;
         let open_files_row = self.get_open_files_row()?;
         // Check it's not already open
         for dir_table_row in self.open_files.iter() {
             if *dir_table_row == (volume.idx, dir_entry.cluster) {
-                return Err(Error::DirAlreadyOpen);
+                return Err(Error::FileAlreadyOpen);
             }
         }
         if dir_entry.attributes.is_directory() {
@@ -289,10 +778,19 @@ where
         }
 
         let mode = solve_mode_variant(mode, true);
-        let file = match mode {
-            Mode::ReadOnly => File {
+        match &volume.volume_type {
+            VolumeType::Fat(_) => {}
+            VolumeType::ExFat(exfat) => {
+                exfat
+                    .prepare_to_open(self, &mut dir_entry, mode != Mode::ReadOnly)
+                    .await?
+            }
+        }
+        let mut file = match mode {
+            Mode::ReadOnly => File {
                 starting_cluster: dir_entry.cluster,
                 current_cluster: (0, dir_entry.cluster),
+                extents: ExtentCache::new(),
                 current_offset: 0,
                 length: dir_entry.size,
                 mode,
@@ -302,6 +800,7 @@ where
                 let mut file = File {
                     starting_cluster: dir_entry.cluster,
                     current_cluster: (0, dir_entry.cluster),
+                    extents: ExtentCache::new(),
                     current_offset: 0,
                     length: dir_entry.size,
                     mode,
@@ -315,6 +814,7 @@ where
                 let mut file = File {
                     starting_cluster: dir_entry.cluster,
                     current_cluster: (0, dir_entry.cluster),
+                    extents: ExtentCache::new(),
                     current_offset: 0,
                     length: dir_entry.size,
                     mode,
@@ -322,37 +822,55 @@ where
                 };
                 match &mut volume.volume_type {
                     VolumeType::Fat(fat) => {
-                        fat.truncate_cluster_chain(self, file.starting_cluster)?
+                        fat.truncate_cluster_chain(self, file.starting_cluster)
+                            .await?
+                    }
+                    VolumeType::ExFat(exfat) => {
+                        // Empty exFAT files have no clusters at all
+                        exfat.free_file(self, &mut file.entry).await?;
+                        file.starting_cluster = file.entry.cluster;
+                        file.current_cluster = (0, file.starting_cluster);
                     }
                 };
                 file.update_length(0);
                 // TODO update entry Timestamps
-                match &volume.volume_type {
-                    VolumeType::Fat(fat) => {
-                        let fat_type = fat.get_fat_type();
-                        self.write_entry_to_disk(fat_type, &file.entry)?;
-                    }
-                };
+                self.update_dir_entry(volume, &file.entry).await?;
 
                 file
             }
             _ => return Err(Error::Unsupported),
         };
+        // We only learn how the rest of the file is laid out as we seek
+        // through it, so opening a file doesn't walk its whole chain
+        let num_clusters = match &volume.volume_type {
+            // Contiguous exFAT files may have nothing in the FAT
+            VolumeType::ExFat(exfat) => match &file.entry.exfat {
+                Some(info) if info.no_fat_chain => exfat.clusters_for(info.data_length),
+                _ => 1,
+            },
+            VolumeType::Fat(_) => 1,
+        };
+        if file.starting_cluster.0 >= RESERVED_ENTRIES {
+            file.extents
+                .record_run(0, file.starting_cluster, num_clusters);
+        }
         // Remember this open file
         self.open_files[open_files_row] = (volume.idx, file.starting_cluster);
         Ok(file)
     }
 
-    /// Open a file with the given full path. A file can only be opened once.
-    pub fn open_file_in_dir(
+    /// The async version of [`VolumeManager::open_file_in_dir`].
+    pub async fn open_file_in_dir(
         &mut self,
         volume: &mut Volume,
         dir: &Directory,
         name: &str,
         mode: Mode,
     ) -> Result<File, Error<D::Error>> {
+        self.check_volume(volume).await?;
         let dir_entry = match &volume.volume_type {
-            VolumeType::Fat(fat) => fat.find_directory_entry(self, dir, name),
+            VolumeType::Fat(fat) => fat.find_directory_entry(self, dir, name).await,
+            VolumeType::ExFat(exfat) => exfat.find_directory_entry(self, dir, name).await,
         };
 
         let open_files_row = self.get_open_files_row()?;
@@ -375,18 +893,25 @@ where
                 if dir_entry.is_some() {
                     return Err(Error::FileAlreadyExists);
                 }
-                let file_name =
-                    ShortFileName::create_from_str(name).map_err(Error::FilenameError)?;
                 let att = Attributes::create_from_fat(0);
                 let entry = match &mut volume.volume_type {
                     VolumeType::Fat(fat) => {
-                        fat.write_new_directory_entry(self, dir, file_name, att)?
+                        let file_name =
+                            ShortFileName::create_from_str(name).map_err(Error::FilenameError)?;
+                        fat.write_new_directory_entry(self, dir, file_name, att)
+                            .await?
+                    }
+                    VolumeType::ExFat(exfat) => {
+                        exfat
+                            .write_new_directory_entry(self, dir, name, att)
+                            .await?
                     }
                 };
 
                 let file = File {
                     starting_cluster: entry.cluster,
                     current_cluster: (0, entry.cluster),
+                    extents: ExtentCache::new(),
                     current_offset: 0,
                     length: entry.size,
                     mode,
@@ -401,11 +926,22 @@ where
                 let dir_entry = dir_entry.unwrap();
                 // FIXME: if 2 files are in the same cluster this will cause an error when opening
                 // a file for a first time in a different than `ReadWriteCreate` mode.
-                self.open_dir_entry(volume, dir_entry, mode)
+                self.open_dir_entry(volume, dir_entry, mode).await
             }
         }
     }
 
+    /// Give an open file a new first cluster. The open file table knows
+    /// files by their first cluster, so it is updated too.
+    fn set_starting_cluster(&mut self, volume: &Volume, file: &mut File, cluster: Cluster) {
+        let old = (volume.idx, file.starting_cluster);
+        if let Some(row) = self.open_files.iter_mut().find(|row| **row == old) {
+            row.1 = cluster;
+        }
+        file.starting_cluster = cluster;
+        file.entry.cluster = cluster;
+    }
+
     /// Get the next entry in open_files list
     fn get_open_files_row(&self) -> Result<usize, Error<D::Error>> {
         // Find a free directory entry
@@ -418,19 +954,21 @@ where
         open_files_row.ok_or(Error::TooManyOpenDirs)
     }
 
-    /// Delete a closed file with the given full path, if exists.
-    pub fn delete_file_in_dir(
+    /// The async version of [`VolumeManager::delete_file_in_dir`].
+    pub async fn delete_file_in_dir(
         &mut self,
-        volume: &Volume,
+        volume: &mut Volume,
         dir: &Directory,
         name: &str,
     ) -> Result<(), Error<D::Error>> {
+        self.check_volume(volume).await?;
         debug!(
             "delete_file(volume={:?}, dir={:?}, filename={:?}",
             volume, dir, name
         );
         let dir_entry = match &volume.volume_type {
-            VolumeType::Fat(fat) => fat.find_directory_entry(self, dir, name),
+            VolumeType::Fat(fat) => fat.find_directory_entry(self, dir, name).await,
+            VolumeType::ExFat(exfat) => exfat.find_directory_entry(self, dir, name).await,
         }?;
 
         if dir_entry.attributes.is_directory() {
@@ -444,49 +982,77 @@ where
             }
         }
 
-        match &volume.volume_type {
-            VolumeType::Fat(fat) => fat.delete_directory_entry(self, dir, name),
+        match &mut volume.volume_type {
+            VolumeType::Fat(fat) => fat.delete_directory_entry(self, dir, name).await,
+            VolumeType::ExFat(exfat) => exfat.delete_directory_entry(self, dir, name).await,
         }
     }
 
-    /// Read from an open file.
-    pub fn read(
+    /// The async version of [`VolumeManager::read`].
+    pub async fn read(
         &mut self,
         volume: &Volume,
         file: &mut File,
         buffer: &mut [u8],
     ) -> Result<usize, Error<D::Error>> {
+        self.check_volume(volume).await?;
         // Calculate which file block the current offset lies within
         // While there is more to read, read the block and copy in to the buffer.
         // If we need to find the next cluster, walk the FAT.
         let mut space = buffer.len();
         let mut read = 0;
+        // exFAT files can have space which hasn't been written yet
+        let valid_length = match &file.entry.exfat {
+            Some(info) => info.valid_length,
+            None => file.length,
+        };
         while space > 0 && !file.eof() {
-            let (block_idx, block_offset, block_avail) =
-                self.find_data_on_disk(volume, &mut file.current_cluster, file.current_offset)?;
+            if file.current_offset >= valid_length {
+                // It reads as zeros, so there's no need to look on the disk
+                let to_copy = core::cmp::min(space as u64, file.left()) as usize;
+                for b in buffer[read..read + to_copy].iter_mut() {
+                    *b = 0;
+                }
+                read += to_copy;
+                space -= to_copy;
+                file.seek_from_current(to_copy as i64).unwrap();
+                continue;
+            }
+            let valid_left = valid_length - file.current_offset;
+            let (block_idx, block_offset, block_avail) = self
+                .find_data_on_disk(
+                    volume,
+                    &mut file.extents,
+                    &mut file.current_cluster,
+                    file.current_offset,
+                )
+                .await?;
             let mut blocks = [Block::new()];
             self.block_device
                 .read(&mut blocks, block_idx, "read")
+                .await
                 .map_err(Error::DeviceError)?;
             let block = &blocks[0];
-            let to_copy = block_avail.min(space).min(file.left() as usize);
+            let to_copy =
+                core::cmp::min(block_avail.min(space) as u64, file.left().min(valid_left)) as usize;
             assert!(to_copy != 0);
             buffer[read..read + to_copy]
                 .copy_from_slice(&block[block_offset..block_offset + to_copy]);
             read += to_copy;
             space -= to_copy;
-            file.seek_from_current(to_copy as i32).unwrap();
+            file.seek_from_current(to_copy as i64).unwrap();
         }
         Ok(read)
     }
 
-    /// Write to a open file.
-    pub fn write(
+    /// The async version of [`VolumeManager::write`].
+    pub async fn write(
         &mut self,
         volume: &mut Volume,
         file: &mut File,
         buffer: &[u8],
     ) -> Result<usize, Error<D::Error>> {
+        self.check_volume(volume).await?;
         #[cfg(feature = "defmt-log")]
         debug!(
             "write(volume={:?}, file={:?}, buffer={:x}",
@@ -504,19 +1070,17 @@ where
         }
         if file.starting_cluster.0 < RESERVED_ENTRIES {
             // file doesn't have a valid allocated cluster (possible zero-length file), allocate one
-            file.starting_cluster = match &mut volume.volume_type {
-                VolumeType::Fat(fat) => fat.alloc_cluster(self, None, false)?,
-            };
-            file.entry.cluster = file.starting_cluster;
+            let cluster = self.alloc_cluster(volume, None).await?;
+            self.set_starting_cluster(volume, file, cluster);
+            file.extents.record_run(0, file.starting_cluster, 1);
             debug!("Alloc first cluster {:?}", file.starting_cluster);
         }
         if (file.current_cluster.1).0 < file.starting_cluster.0 {
             debug!("Rewinding to start");
             file.current_cluster = (0, file.starting_cluster);
         }
-        let bytes_until_max = usize::try_from(MAX_FILE_SIZE - file.current_offset)
-            .map_err(|_| Error::ConversionError)?;
-        let bytes_to_write = core::cmp::min(buffer.len(), bytes_until_max);
+        let bytes_until_max = Self::max_file_size(volume) - file.current_offset;
+        let bytes_to_write = core::cmp::min(buffer.len() as u64, bytes_until_max) as usize;
         let mut written = 0;
 
         while written < bytes_to_write {
@@ -525,46 +1089,53 @@ where
                 "Have written bytes {}/{}, finding cluster {:?}",
                 written, bytes_to_write, current_cluster
             );
-            let (block_idx, block_offset, block_avail) =
-                match self.find_data_on_disk(volume, &mut current_cluster, file.current_offset) {
-                    Ok(vars) => {
-                        debug!(
-                            "Found block_idx={:?}, block_offset={:?}, block_avail={}",
-                            vars.0, vars.1, vars.2
-                        );
-                        vars
-                    }
-                    Err(Error::EndOfFile) => {
-                        debug!("Extending file");
-                        match &mut volume.volume_type {
-                            VolumeType::Fat(ref mut fat) => {
-                                if fat
-                                    .alloc_cluster(self, Some(current_cluster.1), false)
-                                    .is_err()
-                                {
-                                    return Ok(written);
-                                }
-                                debug!("Allocated new FAT cluster, finding offsets...");
-                                let new_offset = self
-                                    .find_data_on_disk(
-                                        volume,
-                                        &mut current_cluster,
-                                        file.current_offset,
-                                    )
-                                    .map_err(|_| Error::AllocationError)?;
-                                debug!("New offset {:?}", new_offset);
-                                new_offset
-                            }
-                        }
+            let (block_idx, block_offset, block_avail) = match self
+                .find_data_on_disk(
+                    volume,
+                    &mut file.extents,
+                    &mut current_cluster,
+                    file.current_offset,
+                )
+                .await
+            {
+                Ok(vars) => {
+                    debug!(
+                        "Found block_idx={:?}, block_offset={:?}, block_avail={}",
+                        vars.0, vars.1, vars.2
+                    );
+                    vars
+                }
+                Err(Error::EndOfFile) => {
+                    debug!("Extending file");
+                    if self
+                        .alloc_cluster(volume, Some(current_cluster.1))
+                        .await
+                        .is_err()
+                    {
+                        return Ok(written);
                     }
-                    Err(e) => return Err(e),
-                };
+                    debug!("Allocated new FAT cluster, finding offsets...");
+                    let new_offset = self
+                        .find_data_on_disk(
+                            volume,
+                            &mut file.extents,
+                            &mut current_cluster,
+                            file.current_offset,
+                        )
+                        .await
+                        .map_err(|_| Error::AllocationError)?;
+                    debug!("New offset {:?}", new_offset);
+                    new_offset
+                }
+                Err(e) => return Err(e),
+            };
             let mut blocks = [Block::new()];
             let to_copy = core::cmp::min(block_avail, bytes_to_write - written);
             if block_offset != 0 {
                 debug!("Partial block write");
                 self.block_device
                     .read(&mut blocks, block_idx, "read")
+                    .await
                     .map_err(Error::DeviceError)?;
             }
             let block = &mut blocks[0];
@@ -573,25 +1144,241 @@ where
             debug!("Writing block {:?}", block_idx);
             self.block_device
                 .write(&blocks, block_idx)
+                .await
                 .map_err(Error::DeviceError)?;
             written += to_copy;
             file.current_cluster = current_cluster;
-            let to_copy = i32::try_from(to_copy).map_err(|_| Error::ConversionError)?;
+            let to_copy = i64::try_from(to_copy).map_err(|_| Error::ConversionError)?;
             // TODO: Should we do this once when the whole file is written?
-            file.update_length(file.length + (to_copy as u32));
+            file.update_length(file.length + (to_copy as u64));
             file.seek_from_current(to_copy).unwrap();
             file.entry.attributes.set_archive(true);
             file.entry.mtime = self.timesource.get_timestamp();
-            debug!("Updating FAT info sector");
-            match &mut volume.volume_type {
-                VolumeType::Fat(fat) => {
-                    fat.update_info_sector(self)?;
-                    debug!("Updating dir entry");
-                    self.write_entry_to_disk(fat.get_fat_type(), &file.entry)?;
+            debug!("Updating FAT info sector and dir entry");
+            self.update_dir_entry(volume, &file.entry).await?;
+        }
+        Ok(written)
+    }
+
+    /// The async version of [`VolumeManager::allocate`].
+    pub async fn allocate(
+        &mut self,
+        volume: &mut Volume,
+        file: &mut File,
+        size: u64,
+        extend: bool,
+    ) -> Result<(), Error<D::Error>> {
+        self.check_volume(volume).await?;
+        debug!(
+            "allocate(volume={:?}, file={:?}, size={}, extend={})",
+            volume, file, size, extend
+        );
+        if file.mode == Mode::ReadOnly {
+            return Err(Error::ReadOnly);
+        }
+        let bytes_per_cluster = match &volume.volume_type {
+            VolumeType::Fat(fat) => fat.bytes_per_cluster(),
+            VolumeType::ExFat(_) if !extend => return Err(Error::Unsupported),
+            VolumeType::ExFat(exfat) => exfat.bytes_per_cluster(),
+        };
+        if size > Self::max_file_size(volume) {
+            return Err(Error::NotEnoughSpace);
+        }
+        let clusters_needed = if size == 0 {
+            0
+        } else {
+            u32::try_from(((size - 1) / u64::from(bytes_per_cluster)) + 1)
+                .map_err(|_| Error::NotEnoughSpace)?
+        };
+        if file.starting_cluster.0 < RESERVED_ENTRIES {
+            if clusters_needed > 0 {
+                let cluster = self.alloc_clusters(volume, None, clusters_needed).await?;
+                self.set_starting_cluster(volume, file, cluster);
+                file.current_cluster = (0, file.starting_cluster);
+                let num_clusters = self
+                    .contiguous_clusters(volume, file.starting_cluster, clusters_needed)
+                    .await?;
+                file.extents
+                    .record_run(0, file.starting_cluster, num_clusters);
+            }
+        } else {
+            // Find the end of the existing chain, starting from the
+            // furthest cluster we already know about
+            let (mut last_idx, mut last_cluster) = file
+                .extents
+                .find(u32::MAX)
+                .unwrap_or((0, file.starting_cluster));
+            loop {
+                match self.next_cluster(volume, last_cluster).await {
+                    Ok(n) => {
+                        last_cluster = n;
+                        last_idx += 1;
+                        file.extents.record_run(last_idx, last_cluster, 1);
+                    }
+                    Err(Error::EndOfFile) => break,
+                    Err(e) => return Err(e),
                 }
             }
+            let num_clusters = last_idx + 1;
+            if clusters_needed > num_clusters {
+                self.alloc_clusters(volume, Some(last_cluster), clusters_needed - num_clusters)
+                    .await?;
+                let run_length = self
+                    .contiguous_clusters(volume, last_cluster, clusters_needed - last_idx)
+                    .await?;
+                file.extents.record_run(last_idx, last_cluster, run_length);
+            }
         }
-        Ok(written)
+        self.update_dir_entry(volume, &file.entry).await?;
+        if extend && size > file.length {
+            self.zero_fill(volume, file, size).await?;
+        }
+        Ok(())
+    }
+
+    /// The async version of [`VolumeManager::truncate`].
+    pub async fn truncate(
+        &mut self,
+        volume: &mut Volume,
+        file: &mut File,
+        new_length: u64,
+    ) -> Result<(), Error<D::Error>> {
+        self.check_volume(volume).await?;
+        debug!(
+            "truncate(volume={:?}, file={:?}, new_length={})",
+            volume, file, new_length
+        );
+        if file.mode == Mode::ReadOnly {
+            return Err(Error::ReadOnly);
+        }
+        if new_length > file.length {
+            return self.zero_fill(volume, file, new_length).await;
+        } else if new_length == file.length {
+            return Ok(());
+        }
+        match &mut volume.volume_type {
+            VolumeType::ExFat(exfat) if new_length == 0 => {
+                // Empty exFAT files have no clusters at all
+                exfat.free_file(self, &mut file.entry).await?;
+                let cluster = file.entry.cluster;
+                self.set_starting_cluster(volume, file, cluster);
+                file.extents = ExtentCache::new();
+            }
+            _ => {
+                // Find the cluster holding the last byte we're keeping. We
+                // keep the first cluster of a zero length FAT file, like
+                // `Mode::ReadWriteTruncate` does.
+                let bytes_per_cluster = u64::from(Self::bytes_per_cluster(volume));
+                let last_idx = if new_length == 0 {
+                    0
+                } else {
+                    ((new_length - 1) / bytes_per_cluster) as u32
+                };
+                let (mut idx, mut last_cluster) = file
+                    .extents
+                    .find(last_idx)
+                    .unwrap_or((0, file.starting_cluster));
+                while idx < last_idx {
+                    last_cluster = self.next_cluster(volume, last_cluster).await?;
+                    idx += 1;
+                }
+                match &mut volume.volume_type {
+                    VolumeType::Fat(fat) => fat.truncate_cluster_chain(self, last_cluster).await?,
+                    VolumeType::ExFat(exfat) => {
+                        exfat.truncate_cluster_chain(self, last_cluster).await?
+                    }
+                }
+                file.extents.truncate(last_idx + 1);
+            }
+        }
+        file.update_length(new_length);
+        if file.current_offset > new_length {
+            file.current_offset = new_length;
+        }
+        // The cluster we were at may have just been freed
+        file.current_cluster = (0, file.starting_cluster);
+        file.entry.attributes.set_archive(true);
+        file.entry.mtime = self.timesource.get_timestamp();
+        self.update_dir_entry(volume, &file.entry).await
+    }
+
+    /// Extend a file to `new_length` bytes, filling the new space with
+    /// zeros. The current position within the file is unchanged.
+    async fn zero_fill(
+        &mut self,
+        volume: &mut Volume,
+        file: &mut File,
+        new_length: u64,
+    ) -> Result<(), Error<D::Error>> {
+        if new_length > Self::max_file_size(volume) {
+            return Err(Error::NotEnoughSpace);
+        }
+        if file.starting_cluster.0 < RESERVED_ENTRIES {
+            let cluster = self.alloc_cluster(volume, None).await?;
+            self.set_starting_cluster(volume, file, cluster);
+            file.current_cluster = (0, file.starting_cluster);
+            file.extents.record_run(0, file.starting_cluster, 1);
+        }
+        let saved_offset = file.current_offset;
+        while file.length < new_length {
+            let mut current_cluster = file.current_cluster;
+            let (block_idx, block_offset, block_avail) = match self
+                .find_data_on_disk(volume, &mut file.extents, &mut current_cluster, file.length)
+                .await
+            {
+                Ok(vars) => vars,
+                Err(Error::EndOfFile) => {
+                    self.alloc_cluster(volume, Some(current_cluster.1)).await?;
+                    self.find_data_on_disk(
+                        volume,
+                        &mut file.extents,
+                        &mut current_cluster,
+                        file.length,
+                    )
+                    .await
+                    .map_err(|_| Error::AllocationError)?
+                }
+                Err(e) => return Err(e),
+            };
+            let mut blocks = [Block::new()];
+            if block_offset != 0 {
+                self.block_device
+                    .read(&mut blocks, block_idx, "zero_fill")
+                    .await
+                    .map_err(Error::DeviceError)?;
+                for b in blocks[0][block_offset..].iter_mut() {
+                    *b = 0;
+                }
+            }
+            self.block_device
+                .write(&blocks, block_idx)
+                .await
+                .map_err(Error::DeviceError)?;
+            let to_fill = core::cmp::min(block_avail as u64, new_length - file.length);
+            file.current_cluster = current_cluster;
+            file.update_length(file.length + to_fill);
+            file.current_offset = file.length;
+        }
+        file.entry.attributes.set_archive(true);
+        file.entry.mtime = self.timesource.get_timestamp();
+        self.update_dir_entry(volume, &file.entry).await?;
+        // seek_from_start to an offset within the old length can't fail
+        file.seek_from_start(saved_offset).ok();
+        Ok(())
+    }
+
+    /// The async version of [`VolumeManager::flush_file`].
+    pub async fn flush_file(
+        &mut self,
+        volume: &mut Volume,
+        file: &File,
+    ) -> Result<(), Error<D::Error>> {
+        self.check_volume(volume).await?;
+        if file.mode == Mode::ReadOnly {
+            // Nothing can have changed
+            return Ok(());
+        }
+        self.update_dir_entry(volume, &file.entry).await
     }
 
     /// Close a file with the given full path.
@@ -624,38 +1411,160 @@ where
     /// This function turns `desired_offset` into an appropriate block to be
     /// read. It either calculates this based on the start of the file, or
     /// from the last cluster we read - whichever is better.
-    fn find_data_on_disk(
+    ///
+    /// We also start from the closest cluster in `extents` if that's further
+    /// on, and remember any clusters we find by walking the FAT.
+    async fn find_data_on_disk(
         &mut self,
         volume: &Volume,
-        start: &mut (u32, Cluster),
-        desired_offset: u32,
+        extents: &mut ExtentCache,
+        start: &mut (u64, Cluster),
+        desired_offset: u64,
     ) -> Result<(BlockIdx, usize, usize), Error<D::Error>> {
-        let bytes_per_cluster = match &volume.volume_type {
-            VolumeType::Fat(fat) => fat.bytes_per_cluster(),
-        };
+        let bytes_per_cluster = u64::from(Self::bytes_per_cluster(volume));
+        // Cluster indices fit in a `u32`, as there are fewer than 2^32
+        // clusters on any volume
+        let desired_idx = (desired_offset / bytes_per_cluster) as u32;
+        if let Some((cluster_idx, cluster)) = extents.find(desired_idx) {
+            if u64::from(cluster_idx) * bytes_per_cluster > start.0 {
+                *start = (u64::from(cluster_idx) * bytes_per_cluster, cluster);
+            }
+        }
         // How many clusters forward do we need to go?
         let offset_from_cluster = desired_offset - start.0;
-        let num_clusters = offset_from_cluster / bytes_per_cluster;
-        for _ in 0..num_clusters {
-            start.1 = match &volume.volume_type {
-                VolumeType::Fat(fat) => fat.next_cluster(self, start.1)?,
-            };
-            start.0 += bytes_per_cluster;
+        let mut num_clusters = (offset_from_cluster / bytes_per_cluster) as u32;
+        while num_clusters > 0 {
+            let file_cluster = (start.0 / bytes_per_cluster) as u32;
+            // Skip to the end of the run of contiguous clusters we're in,
+            // looking no further ahead than we need to
+            let run_length = self
+                .contiguous_clusters(volume, start.1, num_clusters + 1)
+                .await?;
+            if run_length > 1 {
+                extents.record_run(file_cluster, start.1, run_length);
+                let skip = run_length - 1;
+                start.1 += skip;
+                start.0 += u64::from(skip) * bytes_per_cluster;
+                num_clusters -= skip;
+            } else {
+                start.1 = self.next_cluster(volume, start.1).await?;
+                start.0 += bytes_per_cluster;
+                extents.record_run(file_cluster + 1, start.1, 1);
+                num_clusters -= 1;
+            }
         }
         // How many blocks in are we?
         let offset_from_cluster = desired_offset - start.0;
         assert!(offset_from_cluster < bytes_per_cluster);
-        let num_blocks = BlockCount(offset_from_cluster / Block::LEN_U32);
+        let num_blocks =
+            BlockCount((offset_from_cluster / u64::from(Block::LEN_U32)) as BlockNumber);
         let block_idx = match &volume.volume_type {
             VolumeType::Fat(fat) => fat.cluster_to_block(start.1),
+            VolumeType::ExFat(exfat) => exfat.cluster_to_block(start.1),
         } + num_blocks;
-        let block_offset = (desired_offset % Block::LEN_U32) as usize;
+        let block_offset = (desired_offset % u64::from(Block::LEN_U32)) as usize;
         let available = Block::LEN - block_offset;
         Ok((block_idx, block_offset, available))
     }
 
+    /// How many of our blocks make up one of the block device's logical
+    /// sectors.
+    fn blocks_per_sector(&self) -> Result<BlockNumber, Error<D::Error>> {
+        match self.block_device.sector_size() {
+            size @ (512 | 1024 | 2048 | 4096) => Ok((size / Block::LEN) as BlockNumber),
+            size => Err(Error::BadBlockSize(u16::try_from(size).unwrap_or(u16::MAX))),
+        }
+    }
+
+    /// Number of bytes in a cluster of the given volume.
+    fn bytes_per_cluster(volume: &Volume) -> u32 {
+        match &volume.volume_type {
+            VolumeType::Fat(fat) => fat.bytes_per_cluster(),
+            VolumeType::ExFat(exfat) => exfat.bytes_per_cluster(),
+        }
+    }
+
+    /// The largest file the given volume can hold.
+    fn max_file_size(volume: &Volume) -> u64 {
+        match &volume.volume_type {
+            VolumeType::Fat(_) => MAX_FILE_SIZE,
+            VolumeType::ExFat(_) => u64::MAX,
+        }
+    }
+
+    /// Look in the FAT to see which cluster comes next.
+    async fn next_cluster(
+        &self,
+        volume: &Volume,
+        cluster: Cluster,
+    ) -> Result<Cluster, Error<D::Error>> {
+        match &volume.volume_type {
+            VolumeType::Fat(fat) => fat.next_cluster(self, cluster).await,
+            VolumeType::ExFat(exfat) => exfat.next_cluster(self, cluster).await,
+        }
+    }
+
+    /// Counts how many clusters, starting with `cluster`, follow one another
+    /// on disk, up to `max`.
+    async fn contiguous_clusters(
+        &mut self,
+        volume: &Volume,
+        cluster: Cluster,
+        max: u32,
+    ) -> Result<u32, Error<D::Error>> {
+        match &volume.volume_type {
+            VolumeType::Fat(fat) => fat.contiguous_clusters(self, cluster, max).await,
+            VolumeType::ExFat(exfat) => exfat.contiguous_clusters(self, cluster, max).await,
+        }
+    }
+
+    /// Allocate a cluster, and append it to `prev_cluster` (if given).
+    async fn alloc_cluster(
+        &mut self,
+        volume: &mut Volume,
+        prev_cluster: Option<Cluster>,
+    ) -> Result<Cluster, Error<D::Error>> {
+        match &mut volume.volume_type {
+            VolumeType::Fat(fat) => fat.alloc_cluster(self, prev_cluster, false).await,
+            VolumeType::ExFat(exfat) => exfat.alloc_cluster(self, prev_cluster, false).await,
+        }
+    }
+
+    /// Allocate `count` clusters, and append them to `prev_cluster` (if
+    /// given).
+    async fn alloc_clusters(
+        &mut self,
+        volume: &mut Volume,
+        prev_cluster: Option<Cluster>,
+        count: u32,
+    ) -> Result<Cluster, Error<D::Error>> {
+        match &mut volume.volume_type {
+            VolumeType::Fat(fat) => fat.alloc_clusters(self, prev_cluster, count).await,
+            VolumeType::ExFat(exfat) => exfat.alloc_clusters(self, prev_cluster, count).await,
+        }
+    }
+
+    /// Updates the volume's free space information, and writes a file's
+    /// directory entry to the disk.
+    async fn update_dir_entry(
+        &mut self,
+        volume: &mut Volume,
+        entry: &DirEntry,
+    ) -> Result<(), Error<D::Error>> {
+        match &mut volume.volume_type {
+            VolumeType::Fat(fat) => {
+                fat.update_info_sector(self).await?;
+                self.write_entry_to_disk(fat.get_fat_type(), entry).await
+            }
+            VolumeType::ExFat(exfat) => {
+                exfat.update_info_sector(self).await?;
+                exfat.write_entry(self, entry).await
+            }
+        }
+    }
+
     /// Writes a Directory Entry to the disk
-    fn write_entry_to_disk(
+    async fn write_entry_to_disk(
         &mut self,
         fat_type: fat::FatType,
         entry: &DirEntry,
@@ -663,6 +1572,7 @@ where
         let mut blocks = [Block::new()];
         self.block_device
             .read(&mut blocks, entry.entry_block, "read")
+            .await
             .map_err(Error::DeviceError)?;
         let block = &mut blocks[0];
 
@@ -671,6 +1581,7 @@ where
 
         self.block_device
             .write(&blocks, entry.entry_block)
+            .await
             .map_err(Error::DeviceError)?;
         Ok(())
     }
@@ -695,3 +1606,1221 @@ fn solve_mode_variant(mode: Mode, dir_entry_is_some: bool) -> Mode {
     }
     mode
 }
+/// Converts a number of the block device's logical sectors into a number of
+/// blocks, if it fits in a `BlockNumber`.
+fn sectors_to_blocks(sectors: u64, blocks_per_sector: BlockNumber) -> Option<BlockCount> {
+    let sectors = BlockCount::from_u64(sectors)?;
+    sectors.0.checked_mul(blocks_per_sector).map(BlockCount)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fat::{FatSpecificInfo, FatVolume};
+    use crate::filesystem::FileError;
+    use crate::testing::{
+        disk_image, exfat_4k_image, exfat_image, fat16_4k_image, gpt_image, Clock, RamDisk,
+    };
+    use crate::ExFatVolume;
+
+    type TestVolumeManager = VolumeManager<RamDisk, Clock, 4, 4>;
+
+    fn open_disk() -> TestVolumeManager {
+        VolumeManager::new_with_limits(RamDisk::new(disk_image()), Clock)
+    }
+
+    fn fat_volume(volume: &Volume) -> &FatVolume {
+        match &volume.volume_type {
+            VolumeType::Fat(fat) => fat,
+            VolumeType::ExFat(_) => panic!("not a FAT volume"),
+        }
+    }
+
+    fn fat_volume_mut(volume: &mut Volume) -> &mut FatVolume {
+        match &mut volume.volume_type {
+            VolumeType::Fat(fat) => fat,
+            VolumeType::ExFat(_) => panic!("not a FAT volume"),
+        }
+    }
+
+    /// Read a cluster's entry from the first FAT.
+    fn fat_entry(disk: &RamDisk, fat: &FatVolume, cluster: Cluster) -> u32 {
+        let fat_start = (fat.lba_start + fat.fat_start).into_bytes();
+        match fat.fat_specific_info {
+            FatSpecificInfo::Fat16(_) => {
+                let bytes = disk.bytes(fat_start + u64::from(cluster.0) * 2, 2);
+                u32::from(LittleEndian::read_u16(&bytes))
+            }
+            FatSpecificInfo::Fat32(_) => {
+                let bytes = disk.bytes(fat_start + u64::from(cluster.0) * 4, 4);
+                LittleEndian::read_u32(&bytes) & 0x0FFF_FFFF
+            }
+        }
+    }
+
+    fn is_end_of_chain(fat: &FatVolume, entry: u32) -> bool {
+        match fat.fat_specific_info {
+            FatSpecificInfo::Fat16(_) => entry >= 0xFFF8,
+            FatSpecificInfo::Fat32(_) => entry >= 0x0FFF_FFF8,
+        }
+    }
+
+    /// Count the free clusters by reading the whole FAT.
+    fn free_clusters(disk: &RamDisk, fat: &FatVolume) -> u32 {
+        let (fat_start, entry_size) = match fat.fat_specific_info {
+            FatSpecificInfo::Fat16(_) => ((fat.lba_start + fat.fat_start).into_bytes(), 2),
+            FatSpecificInfo::Fat32(_) => ((fat.lba_start + fat.fat_start).into_bytes(), 4),
+        };
+        let first = u64::from(RESERVED_ENTRIES);
+        let count = u64::from(fat.cluster_count);
+        let entries = disk.bytes(
+            fat_start + first * entry_size,
+            (count * entry_size) as usize,
+        );
+        entries
+            .chunks(entry_size as usize)
+            .filter(|entry| entry.iter().all(|b| *b == 0))
+            .count() as u32
+    }
+
+    /// Follow a cluster chain, returning every cluster in it.
+    fn chain(disk: &RamDisk, fat: &FatVolume, start: Cluster) -> Vec<Cluster> {
+        let mut clusters = vec![start];
+        loop {
+            let entry = fat_entry(disk, fat, *clusters.last().unwrap());
+            if is_end_of_chain(fat, entry) {
+                return clusters;
+            }
+            assert!(entry >= RESERVED_ENTRIES, "chain runs into free cluster");
+            clusters.push(Cluster(entry));
+        }
+    }
+
+    fn is_contiguous(clusters: &[Cluster]) -> bool {
+        clusters.windows(2).all(|pair| pair[1].0 == pair[0].0 + 1)
+    }
+
+    #[test]
+    fn allocate_contiguous() {
+        for volume_idx in [0, 1] {
+            let mut c = open_disk();
+            let mut volume = c.get_volume(VolumeIdx(volume_idx)).unwrap();
+            let bytes_per_cluster = u64::from(fat_volume(&volume).bytes_per_cluster());
+            let free_before = free_clusters(c.device(), fat_volume(&volume));
+            let root = c.open_root_dir(&volume).unwrap();
+            let mut file = c
+                .open_file_in_dir(&mut volume, &root, "ALLOC.DAT", Mode::ReadWriteCreate)
+                .unwrap();
+
+            // Reserve 101 clusters without changing the length
+            c.allocate(&mut volume, &mut file, bytes_per_cluster * 100 + 1, false)
+                .unwrap();
+            assert_eq!(file.length, 0);
+            let clusters = chain(c.device(), fat_volume(&volume), file.starting_cluster);
+            assert_eq!(clusters.len(), 101);
+            assert!(is_contiguous(&clusters));
+            assert_eq!(
+                free_clusters(c.device(), fat_volume(&volume)),
+                free_before - 101
+            );
+
+            // Asking for less than we have changes nothing
+            c.allocate(&mut volume, &mut file, bytes_per_cluster, false)
+                .unwrap();
+            let clusters = chain(c.device(), fat_volume(&volume), file.starting_cluster);
+            assert_eq!(clusters.len(), 101);
+
+            // Grow the chain and the file
+            c.allocate(&mut volume, &mut file, bytes_per_cluster * 150, true)
+                .unwrap();
+            assert_eq!(file.length, bytes_per_cluster * 150);
+            let clusters = chain(c.device(), fat_volume(&volume), file.starting_cluster);
+            assert_eq!(clusters.len(), 150);
+            assert!(is_contiguous(&clusters));
+            assert_eq!(
+                free_clusters(c.device(), fat_volume(&volume)),
+                free_before - 150
+            );
+            if let Some(free) = fat_volume(&volume).free_clusters_count {
+                assert_eq!(free, free_clusters(c.device(), fat_volume(&volume)));
+            }
+
+            // The new space reads back as zeros
+            let mut buffer = [0xFF; 1000];
+            let mut total = 0;
+            while total < file.length as usize {
+                let n = c.read(&volume, &mut file, &mut buffer).unwrap();
+                assert!(buffer[..n].iter().all(|b| *b == 0));
+                total += n;
+            }
+            assert_eq!(total, file.length as usize);
+
+            // And the directory entry has the new length
+            c.close_file(&volume, file).unwrap();
+            let entry = c.find_directory_entry(&volume, &root, "ALLOC.DAT").unwrap();
+            assert_eq!(entry.size, bytes_per_cluster * 150);
+            assert_eq!(entry.cluster, clusters[0]);
+        }
+    }
+
+    #[test]
+    fn discard_freed_clusters() {
+        for policy in [DiscardPolicy::Never, DiscardPolicy::FreedClusters] {
+            for volume_idx in [0, 1] {
+                let mut c = open_disk();
+                c.set_discard_policy(policy);
+                let mut volume = c.get_volume(VolumeIdx(volume_idx)).unwrap();
+                let bytes_per_cluster = u64::from(fat_volume(&volume).bytes_per_cluster());
+                let blocks_per_cluster = BlockNumber::from(fat_volume(&volume).blocks_per_cluster);
+                let free_before = free_clusters(c.device(), fat_volume(&volume));
+                let root = c.open_root_dir(&volume).unwrap();
+                let mut file = c
+                    .open_file_in_dir(&mut volume, &root, "DISCARD.DAT", Mode::ReadWriteCreate)
+                    .unwrap();
+                c.allocate(&mut volume, &mut file, bytes_per_cluster * 10, true)
+                    .unwrap();
+                let clusters = chain(c.device(), fat_volume(&volume), file.starting_cluster);
+                assert_eq!(clusters.len(), 10);
+                assert!(is_contiguous(&clusters));
+
+                // Truncating discards the clusters after the new end
+                c.truncate(&mut volume, &mut file, bytes_per_cluster * 4)
+                    .unwrap();
+                let expected = match policy {
+                    DiscardPolicy::Never => vec![],
+                    DiscardPolicy::FreedClusters => vec![(
+                        fat_volume(&volume).cluster_to_block(clusters[4]),
+                        BlockCount(blocks_per_cluster * 6),
+                    )],
+                };
+                assert_eq!(*c.device().discards.borrow(), expected);
+                c.close_file(&volume, file).unwrap();
+
+                // Deleting discards the rest, and frees them
+                c.device().discards.borrow_mut().clear();
+                c.delete_file_in_dir(&mut volume, &root, "DISCARD.DAT")
+                    .unwrap();
+                let expected = match policy {
+                    DiscardPolicy::Never => vec![],
+                    DiscardPolicy::FreedClusters => vec![(
+                        fat_volume(&volume).cluster_to_block(clusters[0]),
+                        BlockCount(blocks_per_cluster * 4),
+                    )],
+                };
+                assert_eq!(*c.device().discards.borrow(), expected);
+                assert_eq!(free_clusters(c.device(), fat_volume(&volume)), free_before);
+                for cluster in &clusters {
+                    assert_eq!(fat_entry(c.device(), fat_volume(&volume), *cluster), 0);
+                }
+                if let Some(free) = fat_volume(&volume).free_clusters_count {
+                    assert_eq!(free, free_before);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn allocate_not_enough_space() {
+        let mut c = open_disk();
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        let bytes_per_cluster = u64::from(fat_volume(&volume).bytes_per_cluster());
+        let free_before = free_clusters(c.device(), fat_volume(&volume));
+        let root = c.open_root_dir(&volume).unwrap();
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, "ALLOC.DAT", Mode::ReadWriteCreate)
+            .unwrap();
+
+        // One cluster more than there is
+        let size = bytes_per_cluster * u64::from(free_before + 1);
+        assert!(matches!(
+            c.allocate(&mut volume, &mut file, size, false),
+            Err(Error::NotEnoughSpace)
+        ));
+        // Nothing was leaked
+        assert_eq!(file.starting_cluster, Cluster(0));
+        assert_eq!(free_clusters(c.device(), fat_volume(&volume)), free_before);
+
+        // Now exactly what there is, on top of one cluster we already have
+        c.write(&mut volume, &mut file, b"Hello").unwrap();
+        assert!(matches!(
+            c.allocate(&mut volume, &mut file, size, false),
+            Err(Error::NotEnoughSpace)
+        ));
+        let clusters = chain(c.device(), fat_volume(&volume), file.starting_cluster);
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(
+            free_clusters(c.device(), fat_volume(&volume)),
+            free_before - 1
+        );
+    }
+
+    /// Read a whole file from the start.
+    fn read_all(c: &mut TestVolumeManager, volume: &Volume, file: &mut File) -> Vec<u8> {
+        file.seek_from_start(0).unwrap();
+        let mut contents = Vec::new();
+        let mut buffer = [0; 700];
+        while !file.eof() {
+            let n = c.read(volume, file, &mut buffer).unwrap();
+            contents.extend_from_slice(&buffer[..n]);
+        }
+        contents
+    }
+
+    #[test]
+    fn truncate_shrink() {
+        for volume_idx in [0, 1] {
+            let mut c = open_disk();
+            let mut volume = c.get_volume(VolumeIdx(volume_idx)).unwrap();
+            let bytes_per_cluster = fat_volume(&volume).bytes_per_cluster() as usize;
+            let free_before = free_clusters(c.device(), fat_volume(&volume));
+            let root = c.open_root_dir(&volume).unwrap();
+            let mut file = c
+                .open_file_in_dir(&mut volume, &root, "SHRINK.DAT", Mode::ReadWriteCreate)
+                .unwrap();
+            let data: Vec<u8> = (0..bytes_per_cluster * 10).map(|i| i as u8).collect();
+            c.write(&mut volume, &mut file, &data).unwrap();
+            let clusters = chain(c.device(), fat_volume(&volume), file.starting_cluster);
+            assert_eq!(clusters.len(), 10);
+            assert_eq!(
+                free_clusters(c.device(), fat_volume(&volume)),
+                free_before - 10
+            );
+
+            // Cut it to a few bytes into the fourth cluster
+            let new_length = bytes_per_cluster * 3 + 10;
+            c.truncate(&mut volume, &mut file, new_length as u64)
+                .unwrap();
+            assert_eq!(file.length, new_length as u64);
+            assert_eq!(file.current_offset, new_length as u64);
+            assert_eq!(
+                chain(c.device(), fat_volume(&volume), file.starting_cluster),
+                clusters[..4]
+            );
+            for cluster in &clusters[4..] {
+                assert_eq!(fat_entry(c.device(), fat_volume(&volume), *cluster), 0);
+            }
+            assert_eq!(
+                free_clusters(c.device(), fat_volume(&volume)),
+                free_before - 4
+            );
+            if let Some(free) = fat_volume(&volume).free_clusters_count {
+                assert_eq!(free, free_before - 4);
+            }
+            assert_eq!(read_all(&mut c, &volume, &mut file), data[..new_length]);
+
+            // Cut it to exactly two clusters
+            c.truncate(&mut volume, &mut file, bytes_per_cluster as u64 * 2)
+                .unwrap();
+            assert_eq!(
+                chain(c.device(), fat_volume(&volume), file.starting_cluster),
+                clusters[..2]
+            );
+            assert_eq!(
+                free_clusters(c.device(), fat_volume(&volume)),
+                free_before - 2
+            );
+
+            // An empty FAT file keeps its first cluster
+            c.truncate(&mut volume, &mut file, 0).unwrap();
+            assert_eq!(
+                chain(c.device(), fat_volume(&volume), file.starting_cluster),
+                clusters[..1]
+            );
+            assert_eq!(
+                free_clusters(c.device(), fat_volume(&volume)),
+                free_before - 1
+            );
+
+            c.close_file(&volume, file).unwrap();
+            let entry = c
+                .find_directory_entry(&volume, &root, "SHRINK.DAT")
+                .unwrap();
+            assert_eq!(entry.size, 0);
+        }
+    }
+
+    #[test]
+    fn truncate_grow() {
+        for volume_idx in [0, 1] {
+            let mut c = open_disk();
+            let mut volume = c.get_volume(VolumeIdx(volume_idx)).unwrap();
+            let bytes_per_cluster = fat_volume(&volume).bytes_per_cluster() as usize;
+            let root = c.open_root_dir(&volume).unwrap();
+            let mut file = c
+                .open_file_in_dir(&mut volume, &root, "GROW.DAT", Mode::ReadWriteCreate)
+                .unwrap();
+            // Leave junk in the clusters, then free all but the first
+            c.write(&mut volume, &mut file, &vec![0xAA; bytes_per_cluster * 3])
+                .unwrap();
+            let junk = chain(c.device(), fat_volume(&volume), file.starting_cluster);
+            c.truncate(&mut volume, &mut file, 5).unwrap();
+            file.seek_from_start(2).unwrap();
+
+            // Growing it has to zero the rest of the first cluster, and the
+            // freed clusters it gets back
+            let new_length = bytes_per_cluster * 3 + 100;
+            c.truncate(&mut volume, &mut file, new_length as u64)
+                .unwrap();
+            assert_eq!(file.length, new_length as u64);
+            assert_eq!(file.current_offset, 2);
+            let clusters = chain(c.device(), fat_volume(&volume), file.starting_cluster);
+            assert_eq!(clusters.len(), 4);
+            assert_eq!(clusters[..3], junk);
+            let contents = read_all(&mut c, &volume, &mut file);
+            assert_eq!(contents.len(), new_length);
+            assert_eq!(contents[..5], [0xAA; 5]);
+            assert!(contents[5..].iter().all(|b| *b == 0));
+
+            c.close_file(&volume, file).unwrap();
+            let entry = c.find_directory_entry(&volume, &root, "GROW.DAT").unwrap();
+            assert_eq!(entry.size, new_length as u64);
+        }
+    }
+
+    #[test]
+    fn close_new_files() {
+        let mut c = open_disk();
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        let root = c.open_root_dir(&volume).unwrap();
+        // More files than we have handles for, each of which gets its
+        // first cluster while it is open
+        for (i, name) in ["A.DAT", "B.DAT", "C.DAT", "D.DAT", "E.DAT"]
+            .iter()
+            .enumerate()
+        {
+            let mut file = c
+                .open_file_in_dir(&mut volume, &root, name, Mode::ReadWriteCreate)
+                .unwrap();
+            if i % 2 == 0 {
+                c.write(&mut volume, &mut file, b"x").unwrap();
+            } else {
+                c.allocate(&mut volume, &mut file, 100, true).unwrap();
+            }
+            assert!(matches!(
+                c.open_file_in_dir(&mut volume, &root, name, Mode::ReadOnly),
+                Err(Error::FileAlreadyOpen)
+            ));
+            c.close_file(&volume, file).unwrap();
+        }
+        c.close_dir(&volume, root);
+        assert!(!c.has_open_handles());
+    }
+
+    #[test]
+    fn flush_open_file() {
+        for (image, volume_idx) in [(disk_image(), 0), (disk_image(), 1), (exfat_image(), 0)] {
+            let mut c: TestVolumeManager =
+                VolumeManager::new_with_limits(RamDisk::new(image), Clock);
+            let mut volume = c.get_volume(VolumeIdx(volume_idx)).unwrap();
+            let root = c.open_root_dir(&volume).unwrap();
+            let mut file = c
+                .open_file_in_dir(&mut volume, &root, "FLUSH.DAT", Mode::ReadWriteCreate)
+                .unwrap();
+            let data = pattern(10000, 8);
+            c.write(&mut volume, &mut file, &data).unwrap();
+            c.flush_file(&mut volume, &file).unwrap();
+
+            // The file is still open, but its entry on disk is up to date
+            let entry = c.find_directory_entry(&volume, &root, "FLUSH.DAT").unwrap();
+            assert_eq!(entry.size, data.len() as u64);
+            assert_eq!(entry.cluster, file.starting_cluster);
+            if let VolumeType::Fat(fat) = &volume.volume_type {
+                let bytes_per_cluster = fat.bytes_per_cluster() as usize;
+                let clusters = chain(c.device(), fat, entry.cluster);
+                assert_eq!(clusters.len(), data.len().div_ceil(bytes_per_cluster));
+            }
+
+            // Writing more and flushing again updates the entry again
+            c.write(&mut volume, &mut file, &data).unwrap();
+            c.flush_file(&mut volume, &file).unwrap();
+            let entry = c.find_directory_entry(&volume, &root, "FLUSH.DAT").unwrap();
+            assert_eq!(entry.size, 2 * data.len() as u64);
+            assert_eq!(entry.cluster, file.starting_cluster);
+            c.close_file(&volume, file).unwrap();
+            c.close_dir(&volume, root);
+        }
+    }
+
+    /// Every run of free clusters on the volume, as the first cluster and
+    /// the length.
+    fn free_runs(disk: &RamDisk, fat: &FatVolume) -> Vec<(Cluster, u32)> {
+        let mut runs: Vec<(Cluster, u32)> = Vec::new();
+        for cluster in RESERVED_ENTRIES..fat.cluster_count + RESERVED_ENTRIES {
+            if fat_entry(disk, fat, Cluster(cluster)) != 0 {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((start, length)) if start.0 + *length == cluster => *length += 1,
+                _ => runs.push((Cluster(cluster), 1)),
+            }
+        }
+        runs
+    }
+
+    /// Create a file holding one byte, and return its first cluster.
+    fn new_file(c: &mut TestVolumeManager, volume: &mut Volume, name: &str) -> Cluster {
+        let root = c.open_root_dir(volume).unwrap();
+        let mut file = c
+            .open_file_in_dir(volume, &root, name, Mode::ReadWriteCreate)
+            .unwrap();
+        c.write(volume, &mut file, b"x").unwrap();
+        let cluster = file.starting_cluster;
+        c.close_file(volume, file).unwrap();
+        c.close_dir(volume, root);
+        cluster
+    }
+
+    /// Create an empty file, reserve `clusters` clusters for it, and return
+    /// its first cluster.
+    fn new_allocated_file(
+        c: &mut TestVolumeManager,
+        volume: &mut Volume,
+        name: &str,
+        clusters: u64,
+    ) -> Cluster {
+        let bytes_per_cluster = u64::from(fat_volume(volume).bytes_per_cluster());
+        let root = c.open_root_dir(volume).unwrap();
+        let mut file = c
+            .open_file_in_dir(volume, &root, name, Mode::ReadWriteCreate)
+            .unwrap();
+        c.allocate(volume, &mut file, bytes_per_cluster * clusters, false)
+            .unwrap();
+        let cluster = file.starting_cluster;
+        c.close_file(volume, file).unwrap();
+        c.close_dir(volume, root);
+        cluster
+    }
+
+    // Volume 0 of the test disk has free runs of 3, 1, 1 and 32622 clusters.
+    const FAT16_FREE_RUNS: [(Cluster, u32); 4] = [
+        (Cluster(2), 3),
+        (Cluster(32774), 1),
+        (Cluster(32777), 1),
+        (Cluster(32779), 32622),
+    ];
+
+    #[test]
+    fn first_fit() {
+        let mut c = open_disk();
+        c.set_allocation_policy(AllocationPolicy::FirstFit);
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        assert_eq!(free_runs(c.device(), fat_volume(&volume)), FAT16_FREE_RUNS);
+        fat_volume_mut(&mut volume).next_free_cluster = Some(Cluster(32775));
+        // The lowest free cluster, whatever the hint says
+        assert_eq!(new_file(&mut c, &mut volume, "A.DAT"), Cluster(2));
+        assert_eq!(new_file(&mut c, &mut volume, "B.DAT"), Cluster(3));
+        // Runs which are too small are skipped
+        assert_eq!(
+            new_allocated_file(&mut c, &mut volume, "C.DAT", 2),
+            Cluster(32779)
+        );
+        assert_eq!(new_file(&mut c, &mut volume, "D.DAT"), Cluster(4));
+    }
+
+    #[test]
+    fn next_fit() {
+        let mut c = open_disk();
+        assert_eq!(c.allocation_policy(), AllocationPolicy::NextFit);
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        assert_eq!(free_runs(c.device(), fat_volume(&volume)), FAT16_FREE_RUNS);
+        fat_volume_mut(&mut volume).next_free_cluster = Some(Cluster(32775));
+        // The first free cluster after the last one we allocated
+        assert_eq!(new_file(&mut c, &mut volume, "A.DAT"), Cluster(32777));
+        assert_eq!(new_file(&mut c, &mut volume, "B.DAT"), Cluster(32779));
+        assert_eq!(
+            new_allocated_file(&mut c, &mut volume, "C.DAT", 2),
+            Cluster(32780)
+        );
+        // Going back to the start when we get to the end of the volume
+        let last_cluster = Cluster(fat_volume(&volume).cluster_count + RESERVED_ENTRIES - 1);
+        fat_volume_mut(&mut volume).next_free_cluster = Some(last_cluster);
+        assert_eq!(new_file(&mut c, &mut volume, "D.DAT"), last_cluster);
+        assert_eq!(new_file(&mut c, &mut volume, "E.DAT"), Cluster(2));
+    }
+
+    #[test]
+    fn best_fit_contiguous() {
+        let mut c = open_disk();
+        c.set_allocation_policy(AllocationPolicy::BestFitContiguous);
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        assert_eq!(free_runs(c.device(), fat_volume(&volume)), FAT16_FREE_RUNS);
+        // A file which is written to starts in the largest gap...
+        let root = c.open_root_dir(&volume).unwrap();
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, "A.DAT", Mode::ReadWriteCreate)
+            .unwrap();
+        let bytes_per_cluster = fat_volume(&volume).bytes_per_cluster() as usize;
+        c.write(&mut volume, &mut file, &vec![1; bytes_per_cluster])
+            .unwrap();
+        assert_eq!(file.starting_cluster, Cluster(32779));
+        // ...and grows in place
+        c.write(&mut volume, &mut file, &vec![2; bytes_per_cluster * 3])
+            .unwrap();
+        let clusters = chain(c.device(), fat_volume(&volume), file.starting_cluster);
+        assert_eq!(clusters, [32779, 32780, 32781, 32782].map(Cluster));
+        c.close_file(&volume, file).unwrap();
+        c.close_dir(&volume, root);
+
+        // Space reserved up front goes in the smallest gap which holds it
+        assert_eq!(
+            new_allocated_file(&mut c, &mut volume, "B.DAT", 1),
+            Cluster(32774)
+        );
+        assert_eq!(
+            new_allocated_file(&mut c, &mut volume, "C.DAT", 2),
+            Cluster(2)
+        );
+        assert_eq!(
+            new_allocated_file(&mut c, &mut volume, "D.DAT", 2),
+            Cluster(32783)
+        );
+        assert_eq!(
+            new_allocated_file(&mut c, &mut volume, "E.DAT", 1),
+            Cluster(4)
+        );
+    }
+
+    #[test]
+    fn best_fit_contiguous_directory() {
+        let mut c = open_disk();
+        c.set_allocation_policy(AllocationPolicy::BestFitContiguous);
+        let mut volume = c.get_volume(VolumeIdx(1)).unwrap();
+        let root_cluster = match fat_volume(&volume).fat_specific_info {
+            FatSpecificInfo::Fat32(ref info) => info.first_root_dir_cluster,
+            FatSpecificInfo::Fat16(_) => unreachable!(),
+        };
+        let fat_blocks = fat_volume(&volume).cluster_count as usize * 4 / Block::LEN;
+        let root_clusters = chain(c.device(), fat_volume(&volume), root_cluster).len();
+        let root = c.open_root_dir(&volume).unwrap();
+        // Fill the root directory until it needs another cluster
+        for i in 0.. {
+            let reads = c.device().reads.get();
+            let file = c
+                .open_file_in_dir(
+                    &mut volume,
+                    &root,
+                    &format!("{}.DAT", i),
+                    Mode::ReadWriteCreate,
+                )
+                .unwrap();
+            c.close_file(&volume, file).unwrap();
+            if chain(c.device(), fat_volume(&volume), root_cluster).len() > root_clusters {
+                // Growing it didn't scan the whole FAT for the biggest gap
+                assert!(c.device().reads.get() - reads < fat_blocks / 10);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn open_and_seek_contiguous() {
+        let mut c = open_disk();
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        let root = c.open_root_dir(&volume).unwrap();
+        let reads = c.device().reads.get();
+        let entry = c.find_directory_entry(&volume, &root, "64MB.DAT").unwrap();
+        let find_reads = c.device().reads.get() - reads;
+
+        // Opening the file reads no more than finding it does
+        let reads = c.device().reads.get();
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, "64MB.DAT", Mode::ReadOnly)
+            .unwrap();
+        assert_eq!(c.device().reads.get() - reads, find_reads);
+
+        // Seeking to the end reads the FAT blocks covering the file once,
+        // and then the data
+        let fat = fat_volume(&volume);
+        let file_clusters = (entry.size / u64::from(fat.bytes_per_cluster())) as usize;
+        let fat_blocks = file_clusters * 2 / Block::LEN + 1;
+        let reads = c.device().reads.get();
+        file.seek_from_end(1).unwrap();
+        let mut buffer = [0; 1];
+        assert_eq!(c.read(&volume, &mut file, &mut buffer).unwrap(), 1);
+        assert!(c.device().reads.get() - reads <= fat_blocks + 1);
+
+        // After which we know where everything is
+        let reads = c.device().reads.get();
+        file.seek_from_start(entry.size / 2).unwrap();
+        assert_eq!(c.read(&volume, &mut file, &mut buffer).unwrap(), 1);
+        file.seek_from_start(1).unwrap();
+        assert_eq!(c.read(&volume, &mut file, &mut buffer).unwrap(), 1);
+        assert_eq!(c.device().reads.get() - reads, 2);
+        c.close_file(&volume, file).unwrap();
+    }
+
+    #[test]
+    fn open_and_seek_fragmented() {
+        let mut c = open_disk();
+        let mut volume = c.get_volume(VolumeIdx(1)).unwrap();
+        let bytes_per_cluster = fat_volume(&volume).bytes_per_cluster() as usize;
+        let root = c.open_root_dir(&volume).unwrap();
+        // Write two files a cluster at a time, so their clusters alternate
+        let mut a = c
+            .open_file_in_dir(&mut volume, &root, "A.DAT", Mode::ReadWriteCreate)
+            .unwrap();
+        let mut b = c
+            .open_file_in_dir(&mut volume, &root, "B.DAT", Mode::ReadWriteCreate)
+            .unwrap();
+        for i in 0..12 {
+            c.write(&mut volume, &mut a, &vec![i; bytes_per_cluster])
+                .unwrap();
+            c.write(&mut volume, &mut b, &vec![0xFF; bytes_per_cluster])
+                .unwrap();
+        }
+        let clusters = chain(c.device(), fat_volume(&volume), a.starting_cluster);
+        assert_eq!(clusters.len(), 12);
+        assert!(clusters.windows(2).all(|pair| pair[1].0 == pair[0].0 + 2));
+        c.close_file(&volume, a).unwrap();
+        c.close_file(&volume, b).unwrap();
+
+        let mut a = c
+            .open_file_in_dir(&mut volume, &root, "A.DAT", Mode::ReadOnly)
+            .unwrap();
+        let mut buffer = [0; 4];
+        for i in [5, 11, 0, 7, 6, 1] {
+            a.seek_from_start((i * bytes_per_cluster + 10) as u64)
+                .unwrap();
+            assert_eq!(c.read(&volume, &mut a, &mut buffer).unwrap(), 4);
+            assert_eq!(buffer, [i as u8; 4]);
+        }
+        let contents = read_all(&mut c, &volume, &mut a);
+        for (i, cluster) in contents.chunks(bytes_per_cluster).enumerate() {
+            assert!(cluster.iter().all(|b| *b == i as u8));
+        }
+    }
+
+    fn open_exfat_disk() -> TestVolumeManager {
+        VolumeManager::new_with_limits(RamDisk::new(exfat_image()), Clock)
+    }
+
+    /// The contents of a file on one of the disk images in `testdata`.
+    fn pattern(len: usize, seed: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| ((i * 7 + i / 1000 + seed) % 253) as u8)
+            .collect()
+    }
+
+    fn read_file(c: &mut TestVolumeManager, name: &str) -> Vec<u8> {
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        let root = c.open_root_dir(&volume).unwrap();
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, name, Mode::ReadOnly)
+            .unwrap();
+        let contents = read_all(c, &volume, &mut file);
+        c.close_file(&volume, file).unwrap();
+        c.close_dir(&volume, root);
+        contents
+    }
+
+    #[test]
+    fn exfat_backup_boot_region() {
+        let mut c = open_exfat_disk();
+        // Damage the OEM name in the Main Boot Sector, so its checksum is
+        // wrong
+        c.device().patch(2048 * 512 + 3, b"BROKEN  ");
+        let volume = c.get_volume(VolumeIdx(0)).unwrap();
+        assert!(matches!(volume.volume_type, VolumeType::ExFat(_)));
+        assert_eq!(read_file(&mut c, "README.TXT"), pattern(3000, 1));
+
+        // With both copies damaged, there's nothing to mount
+        let mut c = open_exfat_disk();
+        c.device().patch(2048 * 512 + 3, b"BROKEN  ");
+        c.device().patch((2048 + 12) * 512 + 3, b"BROKEN  ");
+        assert!(matches!(
+            c.get_volume(VolumeIdx(0)),
+            Err(Error::FormatError(_))
+        ));
+    }
+
+    #[test]
+    fn exfat_long_names() {
+        let mut c = open_exfat_disk();
+        assert_eq!(
+            read_file(&mut c, "A very long file name 1.txt"),
+            pattern(5000, 2)
+        );
+        assert_eq!(
+            read_file(&mut c, "a VERY long FILE name 2.TXT"),
+            pattern(6000, 3)
+        );
+
+        // Both files have the same made-up short name, so it can't be used
+        // to find either of them
+        let volume = c.get_volume(VolumeIdx(0)).unwrap();
+        let root = c.open_root_dir(&volume).unwrap();
+        let mut short_names = Vec::new();
+        c.iterate_dir(&volume, &root, |entry| {
+            if entry.size == 5000 || entry.size == 6000 {
+                short_names.push(entry.name.clone());
+            }
+        })
+        .unwrap();
+        assert_eq!(short_names.len(), 2);
+        assert_eq!(short_names[0], short_names[1]);
+        assert!(matches!(
+            c.find_directory_entry(&volume, &root, &format!("{}", short_names[0])),
+            Err(Error::FileNotFound)
+        ));
+        c.close_dir(&volume, root);
+    }
+
+    #[test]
+    fn exfat_non_ascii_names() {
+        let mut c = open_exfat_disk();
+        assert_eq!(read_file(&mut c, "CAFÉ.TXT"), pattern(100, 4));
+        assert_eq!(read_file(&mut c, "ΑΒΓ.txt"), pattern(200, 5));
+
+        // Each name is up-cased with one pass over the up-case table, which
+        // is one block long
+        let volume = c.get_volume(VolumeIdx(0)).unwrap();
+        let root = c.open_root_dir(&volume).unwrap();
+        let reads = c.device().reads.get();
+        assert!(matches!(
+            c.find_directory_entry(&volume, &root, "MISSING.TXT"),
+            Err(Error::FileNotFound)
+        ));
+        let scan_reads = c.device().reads.get() - reads;
+        let reads = c.device().reads.get();
+        c.find_directory_entry(&volume, &root, "ΑΒΓ.TXT").unwrap();
+        assert!(c.device().reads.get() - reads <= scan_reads + 2);
+        c.close_dir(&volume, root);
+    }
+
+    fn exfat_volume(volume: &Volume) -> &ExFatVolume {
+        match &volume.volume_type {
+            VolumeType::ExFat(exfat) => exfat,
+            VolumeType::Fat(_) => panic!("not an exFAT volume"),
+        }
+    }
+
+    /// Is a cluster marked as in use in the Allocation Bitmap?
+    fn exfat_in_bitmap(disk: &RamDisk, exfat: &ExFatVolume, cluster: Cluster) -> bool {
+        let bitmap_start = exfat.cluster_to_block(exfat.bitmap_cluster).into_bytes();
+        let idx = cluster.0 - RESERVED_ENTRIES;
+        let byte = disk.bytes(bitmap_start + u64::from(idx / 8), 1)[0];
+        byte & (1 << (idx % 8)) != 0
+    }
+
+    /// Count the clusters marked as in use in the Allocation Bitmap.
+    fn exfat_used_clusters(disk: &RamDisk, exfat: &ExFatVolume) -> u32 {
+        let bitmap_start = exfat.cluster_to_block(exfat.bitmap_cluster).into_bytes();
+        let bitmap = disk.bytes(bitmap_start, exfat.cluster_count.div_ceil(8) as usize);
+        bitmap.iter().map(|b| b.count_ones()).sum()
+    }
+
+    /// Follow a cluster chain in the exFAT FAT, returning every cluster in it.
+    fn exfat_chain(disk: &RamDisk, exfat: &ExFatVolume, start: Cluster) -> Vec<Cluster> {
+        let fat_start = (exfat.lba_start + exfat.fat_start).into_bytes();
+        let mut clusters = vec![start];
+        loop {
+            let cluster = clusters.last().unwrap().0;
+            let entry = LittleEndian::read_u32(&disk.bytes(fat_start + u64::from(cluster) * 4, 4));
+            if entry == 0xFFFF_FFFF {
+                return clusters;
+            }
+            assert!(entry >= RESERVED_ENTRIES, "chain runs into free cluster");
+            clusters.push(Cluster(entry));
+        }
+    }
+
+    /// The first byte of an entry set on the disk, which says whether it is
+    /// in use.
+    fn exfat_entry_type(disk: &RamDisk, entry: &DirEntry) -> u8 {
+        disk.bytes(
+            entry.entry_block.into_bytes() + u64::from(entry.entry_offset),
+            1,
+        )[0]
+    }
+
+    #[test]
+    fn exfat_boot_checksum() {
+        // The Volume Flags and Percent In Use fields change while the volume
+        // is mounted, so they aren't covered by the checksum
+        let mut c = open_exfat_disk();
+        c.device().patch(2048 * 512 + 106, &[0x02, 0x00]);
+        c.device().patch(2048 * 512 + 112, &[50]);
+        c.device().patch((2048 + 12) * 512 + 3, b"BROKEN  ");
+        c.get_volume(VolumeIdx(0)).unwrap();
+
+        // Everything else in the first eleven sectors is
+        for offset in [2048 * 512 + 113, (2048 + 9) * 512 + 100] {
+            let mut c = open_exfat_disk();
+            c.device().patch(offset, &[0x5A]);
+            c.device().patch((2048 + 12) * 512 + 3, b"BROKEN  ");
+            assert!(matches!(
+                c.get_volume(VolumeIdx(0)),
+                Err(Error::FormatError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn exfat_create_write_delete() {
+        let mut c = open_exfat_disk();
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        let used = exfat_used_clusters(c.device(), exfat_volume(&volume));
+        let root = c.open_root_dir(&volume).unwrap();
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, "New file.dat", Mode::ReadWriteCreate)
+            .unwrap();
+        let data = pattern(10000, 9);
+        c.write(&mut volume, &mut file, &data).unwrap();
+        c.close_file(&volume, file).unwrap();
+
+        // A File entry, a Stream Extension entry and one File Name entry,
+        // found by the up-cased name
+        let entry = c
+            .find_directory_entry(&volume, &root, "NEW FILE.DAT")
+            .unwrap();
+        assert_eq!(entry.size, data.len() as u64);
+        let info = entry.exfat.as_ref().unwrap();
+        assert_eq!(info.secondary_count, 2);
+        assert_eq!(info.valid_length, data.len() as u64);
+        assert_eq!(exfat_entry_type(c.device(), &entry), 0x85);
+        let exfat = exfat_volume(&volume);
+        let clusters = exfat_chain(c.device(), exfat, entry.cluster);
+        assert_eq!(clusters.len(), 3);
+        assert!(clusters
+            .iter()
+            .all(|cluster| exfat_in_bitmap(c.device(), exfat, *cluster)));
+        assert_eq!(exfat_used_clusters(c.device(), exfat), used + 3);
+        c.close_dir(&volume, root);
+        assert_eq!(read_file(&mut c, "new FILE.DAT"), data);
+
+        // Deleting it marks the entry set as unused, and frees the clusters
+        let root = c.open_root_dir(&volume).unwrap();
+        c.delete_file_in_dir(&mut volume, &root, "new file.DAT")
+            .unwrap();
+        assert_eq!(exfat_entry_type(c.device(), &entry), 0x05);
+        assert!(matches!(
+            c.find_directory_entry(&volume, &root, "New file.dat"),
+            Err(Error::FileNotFound)
+        ));
+        let exfat = exfat_volume(&volume);
+        assert!(clusters
+            .iter()
+            .all(|cluster| !exfat_in_bitmap(c.device(), exfat, *cluster)));
+        assert_eq!(exfat_used_clusters(c.device(), exfat), used);
+        c.close_dir(&volume, root);
+        assert_eq!(read_file(&mut c, "README.TXT"), pattern(3000, 1));
+        assert_eq!(read_file(&mut c, "FRAG.DAT"), pattern(12298, 6));
+    }
+
+    #[test]
+    fn exfat_append_to_contiguous_file() {
+        let mut c = open_exfat_disk();
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        let used = exfat_used_clusters(c.device(), exfat_volume(&volume));
+        let root = c.open_root_dir(&volume).unwrap();
+        let entry = c
+            .find_directory_entry(&volume, &root, "README.TXT")
+            .unwrap();
+        assert!(entry.exfat.as_ref().unwrap().no_fat_chain);
+
+        // Growing the file means recording its clusters in the FAT
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, "README.TXT", Mode::ReadWriteAppend)
+            .unwrap();
+        let extra = pattern(6000, 11);
+        c.write(&mut volume, &mut file, &extra).unwrap();
+        c.close_file(&volume, file).unwrap();
+        let entry = c
+            .find_directory_entry(&volume, &root, "README.TXT")
+            .unwrap();
+        assert_eq!(entry.size, 9000);
+        assert!(!entry.exfat.as_ref().unwrap().no_fat_chain);
+        let exfat = exfat_volume(&volume);
+        let clusters = exfat_chain(c.device(), exfat, entry.cluster);
+        assert_eq!(clusters.len(), 3);
+        assert!(clusters
+            .iter()
+            .all(|cluster| exfat_in_bitmap(c.device(), exfat, *cluster)));
+        assert_eq!(exfat_used_clusters(c.device(), exfat), used + 2);
+        let mut expected = pattern(3000, 1);
+        expected.extend_from_slice(&extra);
+        c.close_dir(&volume, root);
+        assert_eq!(read_file(&mut c, "README.TXT"), expected);
+
+        let root = c.open_root_dir(&volume).unwrap();
+        c.delete_file_in_dir(&mut volume, &root, "README.TXT")
+            .unwrap();
+        let exfat = exfat_volume(&volume);
+        assert!(clusters
+            .iter()
+            .all(|cluster| !exfat_in_bitmap(c.device(), exfat, *cluster)));
+        assert_eq!(exfat_used_clusters(c.device(), exfat), used - 1);
+        c.close_dir(&volume, root);
+    }
+
+    #[test]
+    fn exfat_valid_length() {
+        let mut c = open_exfat_disk();
+        let mut expected = pattern(1000, 7);
+        expected.resize(7000, 0);
+        assert_eq!(read_file(&mut c, "SPARSE.DAT"), expected);
+
+        // A read which spans the end of the valid data
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        let used = exfat_used_clusters(c.device(), exfat_volume(&volume));
+        let root = c.open_root_dir(&volume).unwrap();
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, "SPARSE.DAT", Mode::ReadOnly)
+            .unwrap();
+        file.seek_from_start(900).unwrap();
+        let mut buffer = [0xFF; 200];
+        assert_eq!(c.read(&volume, &mut file, &mut buffer).unwrap(), 200);
+        assert_eq!(buffer[..], expected[900..1100]);
+        c.close_file(&volume, file).unwrap();
+
+        // We can't write to it, but we can delete it, which frees all of
+        // its clusters
+        assert!(matches!(
+            c.open_file_in_dir(&mut volume, &root, "SPARSE.DAT", Mode::ReadWriteAppend),
+            Err(Error::Unsupported)
+        ));
+        c.delete_file_in_dir(&mut volume, &root, "SPARSE.DAT")
+            .unwrap();
+        assert_eq!(
+            exfat_used_clusters(c.device(), exfat_volume(&volume)),
+            used - 2
+        );
+        c.close_dir(&volume, root);
+    }
+
+    #[test]
+    fn seek_back_after_reading() {
+        let mut c = open_exfat_disk();
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        let root = c.open_root_dir(&volume).unwrap();
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, "FRAG.DAT", Mode::ReadOnly)
+            .unwrap();
+        let expected = pattern(12298, 6);
+        assert_eq!(read_all(&mut c, &volume, &mut file), expected);
+
+        // We've read the last cluster, and each seek goes back to the first
+        let mut buffer = [0; 100];
+        file.seek_from_end(12290).unwrap();
+        assert_eq!(c.read(&volume, &mut file, &mut buffer).unwrap(), 100);
+        assert_eq!(buffer[..], expected[8..108]);
+        file.seek_from_end(10).unwrap();
+        assert_eq!(c.read(&volume, &mut file, &mut buffer).unwrap(), 10);
+        file.seek_from_current(-12200).unwrap();
+        assert_eq!(c.read(&volume, &mut file, &mut buffer).unwrap(), 100);
+        assert_eq!(buffer[..], expected[98..198]);
+        c.close_file(&volume, file).unwrap();
+        c.close_dir(&volume, root);
+    }
+
+    #[test]
+    fn seek_beyond_4gib() {
+        let mut c = open_exfat_disk();
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        let root = c.open_root_dir(&volume).unwrap();
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, "SPARSE.DAT", Mode::ReadOnly)
+            .unwrap();
+        // Make out the file is 5 GiB long. Only the first 1000 bytes are
+        // valid, so we never need to find any clusters past the first.
+        let length = 5 << 30;
+        file.update_length(length);
+        file.entry.exfat.as_mut().unwrap().valid_length = 1000;
+        assert_eq!(file.length(), length);
+        let boundary = u64::from(u32::MAX);
+
+        file.seek_from_end(length - boundary + 10).unwrap();
+        assert_eq!(file.left(), length - boundary + 10);
+        let mut buffer = [0xFF; 20];
+        assert_eq!(c.read(&volume, &mut file, &mut buffer).unwrap(), 20);
+        assert!(buffer.iter().all(|b| *b == 0));
+        assert_eq!(file.current_offset, boundary + 10);
+
+        file.seek_from_current(-20).unwrap();
+        assert_eq!(file.current_offset, boundary - 10);
+        file.seek_from_current(1 << 30).unwrap();
+        assert_eq!(file.current_offset, boundary - 10 + (1 << 30));
+        file.seek_from_end(0).unwrap();
+        assert!(file.eof());
+        assert_eq!(c.read(&volume, &mut file, &mut buffer).unwrap(), 0);
+
+        assert!(matches!(
+            file.seek_from_current(1),
+            Err(FileError::InvalidOffset)
+        ));
+        assert!(matches!(
+            file.seek_from_end(length + 1),
+            Err(FileError::InvalidOffset)
+        ));
+        assert!(matches!(
+            file.seek_from_current(-(length as i64) - 1),
+            Err(FileError::InvalidOffset)
+        ));
+        assert!(matches!(
+            file.seek_from_current(i64::MIN),
+            Err(FileError::InvalidOffset)
+        ));
+
+        // Back across the boundary to the data that's been written
+        file.seek_from_current(-(length as i64) + 900).unwrap();
+        let mut buffer = [0xFF; 200];
+        assert_eq!(c.read(&volume, &mut file, &mut buffer).unwrap(), 200);
+        assert_eq!(buffer[..100], pattern(1000, 7)[900..]);
+        assert!(buffer[100..].iter().all(|b| *b == 0));
+        c.close_file(&volume, file).unwrap();
+        c.close_dir(&volume, root);
+    }
+
+    /// Check we can read and write a volume on a disk with 4096 byte
+    /// sectors.
+    fn check_4k_sectors(image: &'static [u8], files: &[(&str, usize, usize)]) {
+        let mut c = VolumeManager::new_with_limits(RamDisk::with_sector_size(image, 4096), Clock);
+        for (name, len, seed) in files {
+            assert_eq!(read_file(&mut c, name), pattern(*len, *seed));
+        }
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        let root = c.open_root_dir(&volume).unwrap();
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, "NEW.DAT", Mode::ReadWriteCreate)
+            .unwrap();
+        let data = pattern(70000, 10);
+        c.write(&mut volume, &mut file, &data).unwrap();
+        c.close_file(&volume, file).unwrap();
+        c.close_dir(&volume, root);
+        assert_eq!(read_file(&mut c, "NEW.DAT"), data);
+        for (name, len, seed) in files {
+            assert_eq!(read_file(&mut c, name), pattern(*len, *seed));
+        }
+    }
+
+    #[test]
+    fn fat16_4k_sectors() {
+        check_4k_sectors(
+            fat16_4k_image(),
+            &[("HELLO.TXT", 5000, 1), ("BIG.DAT", 100000, 2)],
+        );
+    }
+
+    #[test]
+    fn exfat_4k_sectors() {
+        check_4k_sectors(
+            exfat_4k_image(),
+            &[
+                ("README.TXT", 3000, 1),
+                ("A very long file name 1.txt", 5000, 2),
+                ("FRAG.DAT", 12298, 6),
+            ],
+        );
+    }
+
+    #[test]
+    fn gpt_disk() {
+        let mut c: TestVolumeManager =
+            VolumeManager::new_with_limits(RamDisk::new(gpt_image()), Clock);
+        assert_eq!(read_file(&mut c, "README.TXT"), pattern(3000, 1));
+        assert_eq!(read_file(&mut c, "FRAG.DAT"), pattern(12298, 6));
+        let mut volume = c.get_volume(VolumeIdx(0)).unwrap();
+        assert_eq!(
+            exfat_volume(&volume).lba_start,
+            BlockIdx(2048),
+            "volume should start where its GPT entry says"
+        );
+        let root = c.open_root_dir(&volume).unwrap();
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, "NEW.DAT", Mode::ReadWriteCreate)
+            .unwrap();
+        let data = pattern(20000, 10);
+        c.write(&mut volume, &mut file, &data).unwrap();
+        c.close_file(&volume, file).unwrap();
+        c.close_dir(&volume, root);
+        assert_eq!(read_file(&mut c, "NEW.DAT"), data);
+
+        assert!(matches!(
+            c.get_volume(VolumeIdx(1)),
+            Err(Error::FormatError("Partition type not supported"))
+        ));
+        assert!(matches!(
+            c.get_volume(VolumeIdx(2)),
+            Err(Error::NoSuchVolume)
+        ));
+        assert!(matches!(
+            c.get_volume(VolumeIdx(128)),
+            Err(Error::NoSuchVolume)
+        ));
+
+        // Changing an unused partition entry breaks the checksum
+        c.device().patch(2 * 512 + 5 * 128, &[1]);
+        assert!(matches!(
+            c.get_volume(VolumeIdx(0)),
+            Err(Error::FormatError("Bad GPT partition entries checksum"))
+        ));
+    }
+
+    /// Read a file and write a new one using an `AsyncVolumeManager`.
+    async fn read_and_write_async(
+        c: &mut AsyncVolumeManager<RamDisk, Clock, 4, 4>,
+        volume_idx: usize,
+        name: &str,
+        data: &[u8],
+    ) -> (Vec<u8>, Vec<u8>) {
+        let mut volume = c.get_volume(VolumeIdx(volume_idx)).await.unwrap();
+        let root = c.open_root_dir(&volume).unwrap();
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, name, Mode::ReadOnly)
+            .await
+            .unwrap();
+        let mut existing = Vec::new();
+        let mut buffer = [0; 700];
+        while !file.eof() {
+            let n = c.read(&volume, &mut file, &mut buffer).await.unwrap();
+            existing.extend_from_slice(&buffer[..n]);
+        }
+        c.close_file(&volume, file).unwrap();
+
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, "NEW.DAT", Mode::ReadWriteCreate)
+            .await
+            .unwrap();
+        c.write(&mut volume, &mut file, data).await.unwrap();
+        c.close_file(&volume, file).unwrap();
+        let mut file = c
+            .open_file_in_dir(&mut volume, &root, "NEW.DAT", Mode::ReadOnly)
+            .await
+            .unwrap();
+        let mut written = Vec::new();
+        while !file.eof() {
+            let n = c.read(&volume, &mut file, &mut buffer).await.unwrap();
+            written.extend_from_slice(&buffer[..n]);
+        }
+        c.close_file(&volume, file).unwrap();
+        c.close_dir(&volume, root);
+        (existing, written)
+    }
+
+    #[test]
+    fn open_read_write_async() {
+        let data = pattern(20000, 10);
+        for (image, volume_idx, name) in [
+            (disk_image(), 0, "README.TXT"),
+            (disk_image(), 1, "README.TXT"),
+            (exfat_image(), 0, "FRAG.DAT"),
+        ] {
+            let mut c = AsyncVolumeManager::new_with_limits(RamDisk::new(image), Clock);
+            let (existing, written) =
+                block_on(read_and_write_async(&mut c, volume_idx, name, &data));
+            assert_eq!(written, data);
+            assert!(!c.has_open_handles());
+
+            // We should have read the same as a `VolumeManager` would
+            let mut c: TestVolumeManager =
+                VolumeManager::new_with_limits(RamDisk::new(image), Clock);
+            let mut volume = c.get_volume(VolumeIdx(volume_idx)).unwrap();
+            let root = c.open_root_dir(&volume).unwrap();
+            let mut file = c
+                .open_file_in_dir(&mut volume, &root, name, Mode::ReadOnly)
+                .unwrap();
+            assert!(!existing.is_empty());
+            assert_eq!(existing, read_all(&mut c, &volume, &mut file));
+        }
+    }
+}